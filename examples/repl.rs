@@ -109,6 +109,7 @@ fn main() {
             socks5: cli_opt.proxy,
             retry: 10,
             timeout: 10,
+            subscribe: false,
         }));
 
     let wallet = Wallet::new(
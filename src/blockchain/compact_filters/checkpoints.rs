@@ -0,0 +1,159 @@
+// Magical Bitcoin Library
+// Written in 2020 by
+//     Alekos Filini <alekos.filini@gmail.com>
+//
+// Copyright (c) 2020 Magical Bitcoin
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! Header checkpoints
+//!
+//! A checkpoint is a block assumed to be part of the best chain. When the blocks before it are
+//! skipped anyway, a new wallet can start syncing the headers from the checkpoint instead of the
+//! genesis: only the header of the checkpoint is downloaded and checked against its hash, and the
+//! headers sent by our peers must then chain onto it.
+//!
+//! The filters of the blocks before the checkpoint can't be verified without their headers, so
+//! they are never downloaded. When the checkpoint is at the end of a bundle of filters and its
+//! filter header is known, the filter headers of our peers must chain onto it as well.
+
+use bitcoin::hash_types::FilterHash;
+use bitcoin::hashes::hex::FromHex;
+use bitcoin::util::uint::Uint256;
+use bitcoin::{BlockHash, Network};
+
+type CheckpointData = (usize, &'static str, &'static str, Option<&'static str>);
+
+/// Blocks that are part of the best chain of each network, as `(height, hash, chain work, basic
+/// filter header)`
+///
+/// New entries should be at heights that are a multiple of 1000, taken from a synced Bitcoin Core
+/// node started with `-blockfilterindex`:
+///
+/// - the hash is returned by `getblockhash <height>`
+/// - the chain work is the `chainwork` field of `getblockheader <hash>`
+/// - the filter header is the `header` field of `getblockfilter <hash>`
+const MAINNET_CHECKPOINTS: &[CheckpointData] = &[(
+    11111,
+    "0000000069e244f73d78e8fd29ba2fd2ed618bd6fa2ee92559f542fdb26e7c1d",
+    "00000000000000000000000000000000000000000000000000002b682b682b68",
+    None,
+)];
+const TESTNET_CHECKPOINTS: &[CheckpointData] = &[(
+    546,
+    "000000002a936ca763904c3c35fce2f3556c559c0214345d31b1bcebf76acb70",
+    "0000000000000000000000000000000000000000000000000000022302230223",
+    None,
+)];
+
+/// A block assumed to be part of the best chain, used to skip the headers that come before it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HeaderCheckpoint {
+    /// Height of the block
+    pub height: usize,
+    /// Hash of the block
+    pub hash: BlockHash,
+    /// Total work of the chain up to and including the block
+    pub chain_work: Uint256,
+    /// Header of the basic filter of the block. The filter headers of our peers are only checked
+    /// against it when the height is a multiple of 1000
+    pub filter_header: Option<FilterHash>,
+}
+
+impl HeaderCheckpoint {
+    /// Return the checkpoints hardcoded for `network`, sorted by height
+    pub fn for_network(network: Network) -> Vec<Self> {
+        let checkpoints = match network {
+            Network::Bitcoin => MAINNET_CHECKPOINTS,
+            Network::Testnet => TESTNET_CHECKPOINTS,
+            Network::Regtest => &[],
+        };
+
+        checkpoints
+            .iter()
+            .map(
+                |(height, hash, chain_work, filter_header)| HeaderCheckpoint {
+                    height: *height,
+                    hash: BlockHash::from_hex(hash).unwrap(),
+                    chain_work: Uint256::from_be_bytes(<[u8; 32]>::from_hex(chain_work).unwrap()),
+                    filter_header: filter_header.map(|hex| FilterHash::from_hex(hex).unwrap()),
+                },
+            )
+            .collect()
+    }
+}
+
+/// Return the highest checkpoint that doesn't go past `skip_blocks`, so that none of the headers
+/// of the blocks we have to scan are skipped
+pub(crate) fn best_checkpoint(
+    checkpoints: &[HeaderCheckpoint],
+    skip_blocks: usize,
+) -> Option<&HeaderCheckpoint> {
+    checkpoints
+        .iter()
+        .filter(|checkpoint| checkpoint.height > 0 && checkpoint.height <= skip_blocks)
+        .max_by_key(|checkpoint| checkpoint.height)
+}
+
+#[cfg(test)]
+mod test {
+    use bitcoin::blockdata::constants::genesis_block;
+
+    use super::*;
+
+    #[test]
+    fn test_hardcoded_checkpoints() {
+        for network in &[Network::Bitcoin, Network::Testnet] {
+            let checkpoints = HeaderCheckpoint::for_network(*network);
+            assert!(!checkpoints.is_empty());
+
+            // every block before the first difficulty adjustment has the same work as the genesis
+            let block_work = genesis_block(*network).header.work();
+            for checkpoint in checkpoints.iter().filter(|c| c.height < 32_256) {
+                assert_eq!(
+                    checkpoint.chain_work,
+                    block_work.mul_u32(checkpoint.height as u32 + 1)
+                );
+            }
+
+            // a filter header is useless if it can't be compared with the filter checkpoints
+            for checkpoint in checkpoints.iter().filter(|c| c.filter_header.is_some()) {
+                assert_eq!(checkpoint.height % 1000, 0);
+            }
+        }
+
+        assert!(HeaderCheckpoint::for_network(Network::Regtest).is_empty());
+    }
+
+    #[test]
+    fn test_best_checkpoint() {
+        let checkpoint = |height| HeaderCheckpoint {
+            height,
+            hash: Default::default(),
+            chain_work: Default::default(),
+            filter_header: None,
+        };
+        let checkpoints = vec![checkpoint(1_000), checkpoint(3_000), checkpoint(2_000)];
+
+        assert_eq!(best_checkpoint(&checkpoints, 999), None);
+        assert_eq!(best_checkpoint(&checkpoints, 1_000), Some(&checkpoints[0]));
+        assert_eq!(best_checkpoint(&checkpoints, 2_500), Some(&checkpoints[2]));
+        assert_eq!(best_checkpoint(&checkpoints, 10_000), Some(&checkpoints[1]));
+    }
+}
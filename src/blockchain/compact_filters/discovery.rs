@@ -0,0 +1,858 @@
+// Magical Bitcoin Library
+// Written in 2020 by
+//     Alekos Filini <alekos.filini@gmail.com>
+//
+// Copyright (c) 2020 Magical Bitcoin
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! Peer discovery and connection management
+
+use std::collections::HashSet;
+use std::fmt;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use bitcoin::network::constants::ServiceFlags;
+use bitcoin::Network;
+
+use super::peer::*;
+use super::store::*;
+use super::CompactFiltersError;
+
+/// Default value for [`PeerDiscovery::max_peers`]
+pub const DEFAULT_MAX_PEERS: usize = 4;
+
+/// How long to wait for a new peer to accept the connection
+const CONNECT_TIMEOUT_SECS: u64 = 10;
+/// Time to wait before trying again to connect to a peer after the first failure
+const BASE_BACKOFF_SECS: u64 = 30;
+/// Upper limit for the time to wait before trying again to connect to a peer
+const MAX_BACKOFF_SECS: u64 = 24 * 60 * 60;
+/// Minimum time between two scans of the address book looking for new peers
+const DISCOVERY_INTERVAL_SECS: u64 = 60;
+/// Maximum number of addresses kept in the address book
+const MAX_ADDRESSES: usize = 2_000;
+/// Number of failed connections after which an address is removed from the address book
+const MAX_FAILURES: u32 = 10;
+/// Time after which an address that hasn't been announced or connected to is removed from the
+/// address book
+const STALE_ADDRESS_SECS: u64 = 30 * 24 * 60 * 60;
+
+const MAINNET_DNS_SEEDS: &[&str] = &[
+    "seed.bitcoin.sipa.be",
+    "dnsseed.bluematt.me",
+    "dnsseed.bitcoin.dashjr.org",
+    "seed.bitcoinstats.com",
+    "seed.bitcoin.jonasschnelli.ch",
+    "seed.btc.petertodd.org",
+    "seed.bitcoin.sprovoost.nl",
+    "dnsseed.emzy.de",
+];
+const TESTNET_DNS_SEEDS: &[&str] = &[
+    "testnet-seed.bitcoin.jonasschnelli.ch",
+    "seed.tbtc.petertodd.org",
+    "seed.testnet.bitcoin.sprovoost.nl",
+    "testnet-seed.bluematt.me",
+];
+
+/// Resolve host names to addresses, used to query the DNS seeds
+///
+/// The default implementation is [`SystemResolver`], a custom one can be provided to resolve
+/// names through a different channel, or to avoid hitting the network in tests.
+pub trait DnsResolver: fmt::Debug + Send + Sync {
+    /// Return the addresses `host` resolves to, combined with `port`
+    fn lookup(&self, host: &str, port: u16) -> Result<Vec<SocketAddr>, std::io::Error>;
+}
+
+/// [`DnsResolver`] that uses the resolver of the operating system
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SystemResolver;
+
+impl DnsResolver for SystemResolver {
+    fn lookup(&self, host: &str, port: u16) -> Result<Vec<SocketAddr>, std::io::Error> {
+        Ok((host, port).to_socket_addrs()?.collect())
+    }
+}
+
+/// Options to automatically find and connect to peers that serve compact filters
+#[derive(Debug, Clone)]
+pub struct PeerDiscovery {
+    /// Maximum number of peers to be connected to at the same time
+    pub max_peers: usize,
+    /// Whether to query the DNS seeds of the network when there are no usable addresses left in
+    /// the address book
+    pub dns_seeds: bool,
+    /// Resolver used to query the DNS seeds
+    pub resolver: Arc<dyn DnsResolver>,
+    /// Proxy used to connect to the peers that are discovered. This is required to connect to
+    /// `.onion` addresses, which are otherwise ignored
+    ///
+    /// Note that the DNS seeds are still queried using `resolver`, disable `dns_seeds` to avoid
+    /// leaking the queries outside of the proxy
+    pub proxy: Option<Socks5Proxy>,
+}
+
+impl Default for PeerDiscovery {
+    fn default() -> Self {
+        PeerDiscovery {
+            max_peers: DEFAULT_MAX_PEERS,
+            dns_seeds: true,
+            resolver: Arc::new(SystemResolver),
+            proxy: None,
+        }
+    }
+}
+
+/// Return the default P2P port for `network`
+pub(crate) fn default_port(network: Network) -> u16 {
+    match network {
+        Network::Bitcoin => 8333,
+        Network::Testnet => 18333,
+        Network::Regtest => 18444,
+    }
+}
+
+fn dns_seeds(network: Network) -> &'static [&'static str] {
+    match network {
+        Network::Bitcoin => MAINNET_DNS_SEEDS,
+        Network::Testnet => TESTNET_DNS_SEEDS,
+        Network::Regtest => &[],
+    }
+}
+
+/// Time to wait before trying again to connect to a peer that failed `failures` times in a row
+pub(crate) fn backoff(failures: u32) -> u64 {
+    match failures {
+        0 => 0,
+        n => BASE_BACKOFF_SECS
+            .checked_shl(n - 1)
+            .unwrap_or(MAX_BACKOFF_SECS)
+            .min(MAX_BACKOFF_SECS),
+    }
+}
+
+fn is_onion(address: &str) -> bool {
+    match address.rsplitn(2, ':').last() {
+        Some(host) => host.ends_with(".onion"),
+        None => false,
+    }
+}
+
+fn now() -> Result<u64, CompactFiltersError> {
+    Ok(SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs())
+}
+
+#[derive(Debug)]
+struct ManagedPeer {
+    peer: Arc<Peer>,
+    // Peers provided by the user are always reconnected, the others are replaced
+    manual: bool,
+}
+
+/// Keep track of the peers we are connected to, and replace the ones that disconnect
+pub(crate) struct PeerManager {
+    network: Network,
+    mempool: Arc<Mempool>,
+    peer_store: PeerStore,
+    discovery: Option<PeerDiscovery>,
+    peers: RwLock<Vec<ManagedPeer>>,
+    // Time of the last scan of the address book. The lock is held by `maintain()`, so that only
+    // one thread at a time connects to new peers
+    last_discovery: Mutex<Option<u64>>,
+}
+
+impl PeerManager {
+    /// Create a new manager, disconnecting from the `peers` that are currently banned
+    pub fn new(
+        network: Network,
+        mempool: Arc<Mempool>,
+        peer_store: PeerStore,
+        peers: Vec<Peer>,
+        discovery: Option<PeerDiscovery>,
+    ) -> Result<Self, CompactFiltersError> {
+        let mut managed = Vec::with_capacity(peers.len());
+        for peer in peers {
+            if peer_store.is_banned(peer.get_address())? {
+                log::info!("Ignoring banned peer {}", peer.get_address());
+                peer.disconnect();
+                continue;
+            }
+
+            managed.push(ManagedPeer {
+                peer: Arc::new(peer),
+                manual: true,
+            });
+        }
+
+        Ok(PeerManager {
+            network,
+            mempool,
+            peer_store,
+            discovery,
+            peers: RwLock::new(managed),
+            last_discovery: Mutex::new(None),
+        })
+    }
+
+    /// Return the mempool shared by the peers
+    pub fn get_mempool(&self) -> Arc<Mempool> {
+        Arc::clone(&self.mempool)
+    }
+
+    /// Return the peers that are currently connected
+    pub fn connected_peers(&self) -> Vec<Arc<Peer>> {
+        self.peers
+            .read()
+            .unwrap()
+            .iter()
+            .filter(|managed| managed.peer.is_connected())
+            .map(|managed| Arc::clone(&managed.peer))
+            .collect()
+    }
+
+    /// Save the addresses announced by our peers, reconnect to the peers provided by the user
+    /// and, if discovery is enabled, connect to new peers until `max_peers` is reached
+    ///
+    /// The address book is only scanned for new peers once every `DISCOVERY_INTERVAL_SECS`,
+    /// unless we aren't connected to any peer. The peers can be used by other threads while
+    /// this is connecting to new ones.
+    pub fn maintain(&self) -> Result<(), CompactFiltersError> {
+        let mut last_discovery = self.last_discovery.lock().unwrap();
+        let now = now()?;
+
+        let (announced, disconnected) = {
+            let mut peers = self.peers.write().unwrap();
+
+            let announced = peers
+                .iter()
+                .flat_map(|managed| managed.peer.take_announced_addresses())
+                .collect::<Vec<_>>();
+            let (connected, disconnected): (Vec<_>, Vec<_>) = peers
+                .drain(..)
+                .partition(|managed| managed.peer.is_connected());
+            *peers = connected;
+
+            (announced, disconnected)
+        };
+        // The addresses are only useful to discover new peers
+        if self.discovery.is_some() {
+            self.save_announced(announced)?;
+        }
+
+        let mut kept = Vec::with_capacity(disconnected.len());
+        for managed in disconnected {
+            let address = managed.peer.get_address().to_string();
+            let mut entry = match self.peer_store.get_address(&address)? {
+                Some(entry) => entry,
+                None => AddressEntry::new(address.clone(), managed.peer.get_version().services, 0),
+            };
+
+            // discovered peers are dropped and can be picked again later among the candidates
+            if !managed.manual || self.peer_store.is_banned(&address)? {
+                entry.failures += 1;
+                self.peer_store.put_address(&entry)?;
+                continue;
+            }
+
+            if now < entry.last_tried + backoff(entry.failures) {
+                kept.push(managed);
+                continue;
+            }
+
+            log::info!("Reconnecting to peer {}", address);
+            entry.last_tried = now;
+            match managed.peer.reconnect() {
+                Ok(peer) => {
+                    entry.failures = 0;
+                    entry.last_seen = now;
+                    kept.push(ManagedPeer {
+                        peer: Arc::new(peer),
+                        manual: true,
+                    });
+                }
+                Err(e) => {
+                    log::debug!("Reconnection to {} failed: {:?}", address, e);
+                    entry.failures += 1;
+                    kept.push(managed);
+                }
+            }
+            self.peer_store.put_address(&entry)?;
+        }
+        self.peers.write().unwrap().extend(kept);
+
+        let discovery = match &self.discovery {
+            Some(discovery) => discovery,
+            None => return Ok(()),
+        };
+
+        let (mut connected, in_use) = {
+            let peers = self.peers.read().unwrap();
+            let connected = peers.iter().filter(|m| m.peer.is_connected()).count();
+            let in_use = peers
+                .iter()
+                .map(|m| m.peer.get_address().to_string())
+                .collect::<HashSet<_>>();
+
+            (connected, in_use)
+        };
+        if connected >= discovery.max_peers {
+            return Ok(());
+        }
+        match *last_discovery {
+            Some(last) if connected > 0 && now < last + DISCOVERY_INTERVAL_SECS => return Ok(()),
+            _ => *last_discovery = Some(now),
+        }
+
+        self.evict_addresses(now)?;
+        let mut candidates = self.candidates(&in_use, now)?;
+        if candidates.is_empty() && discovery.dns_seeds {
+            self.query_dns_seeds(discovery.resolver.as_ref())?;
+            candidates = self.candidates(&in_use, now)?;
+        }
+
+        for mut entry in candidates {
+            if connected >= discovery.max_peers {
+                break;
+            }
+
+            log::debug!("Connecting to peer {}", entry.address);
+            entry.last_tried = now;
+            let connection = match &discovery.proxy {
+                Some(proxy) => Peer::connect_socks5(
+                    entry.address.as_str(),
+                    proxy.clone(),
+                    Arc::clone(&self.mempool),
+                    self.network,
+                ),
+                None => match entry.address.parse::<SocketAddr>() {
+                    Ok(socket_addr) => Peer::connect_timeout(
+                        &socket_addr,
+                        Duration::from_secs(CONNECT_TIMEOUT_SECS),
+                        Arc::clone(&self.mempool),
+                        self.network,
+                    ),
+                    Err(_) => continue,
+                },
+            };
+            let peer = match connection {
+                Ok(peer) => peer,
+                Err(e) => {
+                    log::debug!("Connection to {} failed: {:?}", entry.address, e);
+                    entry.failures += 1;
+                    self.peer_store.put_address(&entry)?;
+                    continue;
+                }
+            };
+
+            entry.services = peer.get_version().services;
+            entry.last_seen = now;
+            entry.failures = 0;
+            self.peer_store.put_address(&entry)?;
+
+            if !entry.services.has(ServiceFlags::COMPACT_FILTERS) {
+                log::debug!("Peer {} doesn't serve compact filters", entry.address);
+                peer.disconnect();
+                continue;
+            }
+
+            log::info!("Connected to new peer {}", entry.address);
+            peer.request_addresses()?;
+            self.peers.write().unwrap().push(ManagedPeer {
+                peer: Arc::new(peer),
+                manual: false,
+            });
+            connected += 1;
+        }
+
+        Ok(())
+    }
+
+    /// Return the addresses we could connect to, sorted by preference
+    ///
+    /// Addresses with unknown services (like the ones returned by the DNS seeds) are included and
+    /// checked after connecting.
+    fn candidates(
+        &self,
+        in_use: &HashSet<String>,
+        now: u64,
+    ) -> Result<Vec<AddressEntry>, CompactFiltersError> {
+        let has_proxy = self
+            .discovery
+            .as_ref()
+            .map(|discovery| discovery.proxy.is_some())
+            .unwrap_or(false);
+
+        let mut candidates = Vec::new();
+        for entry in self.peer_store.iter_addresses()? {
+            if in_use.contains(&entry.address)
+                || (!has_proxy && is_onion(&entry.address))
+                || (entry.services != ServiceFlags::NONE
+                    && !entry.services.has(ServiceFlags::COMPACT_FILTERS))
+                || now < entry.last_tried + backoff(entry.failures)
+                || self.peer_store.is_banned(&entry.address)?
+            {
+                continue;
+            }
+
+            candidates.push(entry);
+        }
+
+        candidates.sort_by(|a, b| {
+            a.failures
+                .cmp(&b.failures)
+                .then(b.last_seen.cmp(&a.last_seen))
+        });
+
+        Ok(candidates)
+    }
+
+    /// Add the addresses announced by a peer to the address book
+    fn save_announced(&self, announced: Vec<AnnouncedAddress>) -> Result<(), CompactFiltersError> {
+        for announced in announced {
+            let entry = match self.peer_store.get_address(&announced.address)? {
+                Some(mut entry) => {
+                    entry.services = announced.services;
+                    entry.last_seen = entry.last_seen.max(announced.time as u64);
+                    entry
+                }
+                None => {
+                    AddressEntry::new(announced.address, announced.services, announced.time as u64)
+                }
+            };
+
+            self.peer_store.put_address(&entry)?;
+        }
+
+        Ok(())
+    }
+
+    /// Remove the addresses that failed too many times or haven't been seen for a long time
+    /// from the address book, and the worst ones if it's still larger than `MAX_ADDRESSES`
+    ///
+    /// Addresses that have never been seen, like the ones returned by the DNS seeds, are only
+    /// removed once they fail.
+    fn evict_addresses(&self, now: u64) -> Result<(), CompactFiltersError> {
+        let mut entries = self.peer_store.iter_addresses()?;
+        entries.sort_by(|a, b| {
+            a.failures
+                .cmp(&b.failures)
+                .then(b.last_seen.cmp(&a.last_seen))
+        });
+
+        for (index, entry) in entries.into_iter().enumerate() {
+            let stale = entry.last_seen != 0 && now > entry.last_seen + STALE_ADDRESS_SECS;
+            if index >= MAX_ADDRESSES || entry.failures >= MAX_FAILURES || stale {
+                log::trace!("Removing address {} from the address book", entry.address);
+                self.peer_store.del_address(&entry.address)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Query the DNS seeds of the network and add the results to the address book
+    ///
+    /// The seeds are asked to only return nodes that serve compact filters, but since not all of
+    /// them support filtering the services are checked again after connecting.
+    fn query_dns_seeds(&self, resolver: &dyn DnsResolver) -> Result<(), CompactFiltersError> {
+        let port = default_port(self.network);
+        let filter = ServiceFlags::NETWORK | ServiceFlags::WITNESS | ServiceFlags::COMPACT_FILTERS;
+
+        for seed in dns_seeds(self.network) {
+            let host = format!("x{:x}.{}", filter.as_u64(), seed);
+            let addresses = match resolver.lookup(&host, port) {
+                Ok(addresses) => addresses,
+                Err(e) => {
+                    log::debug!("DNS seed {} failed: {:?}", host, e);
+                    continue;
+                }
+            };
+
+            log::debug!("DNS seed {} returned {} addresses", host, addresses.len());
+            for address in addresses {
+                let address = address.to_string();
+                if self.peer_store.get_address(&address)?.is_none() {
+                    self.peer_store.put_address(&AddressEntry::new(
+                        address,
+                        ServiceFlags::NONE,
+                        0,
+                    ))?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl fmt::Debug for PeerManager {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PeerManager")
+            .field("network", &self.network)
+            .field("discovery", &self.discovery)
+            .field("peers", &self.peers)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashSet;
+    use std::net::SocketAddr;
+    use std::sync::{Arc, Mutex};
+
+    use bitcoin::network::constants::ServiceFlags;
+    use bitcoin::Network;
+
+    use super::super::store::test::get_chain_store;
+    use super::super::test_node::{TestChain, TestNode};
+    use super::*;
+
+    #[derive(Debug, Default)]
+    struct MockResolver {
+        queries: Mutex<Vec<(String, u16)>>,
+    }
+
+    impl DnsResolver for MockResolver {
+        fn lookup(&self, host: &str, port: u16) -> Result<Vec<SocketAddr>, std::io::Error> {
+            self.queries.lock().unwrap().push((host.to_string(), port));
+
+            if host.contains("sipa") {
+                Ok(vec![
+                    SocketAddr::new([10, 0, 0, 1].into(), port),
+                    SocketAddr::new([10, 0, 0, 2].into(), port),
+                ])
+            } else {
+                Err(std::io::ErrorKind::NotFound.into())
+            }
+        }
+    }
+
+    fn get_manager(network: Network, discovery: Option<PeerDiscovery>) -> PeerManager {
+        let headers = get_chain_store(network);
+
+        PeerManager::new(
+            network,
+            Arc::new(Mempool::default()),
+            PeerStore::new(&headers),
+            vec![],
+            discovery,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_backoff() {
+        assert_eq!(backoff(0), 0);
+        assert_eq!(backoff(1), 30);
+        assert_eq!(backoff(2), 60);
+        assert_eq!(backoff(5), 480);
+        assert_eq!(backoff(20), MAX_BACKOFF_SECS);
+        assert_eq!(backoff(100), MAX_BACKOFF_SECS);
+    }
+
+    #[test]
+    fn test_query_dns_seeds() {
+        let manager = get_manager(Network::Bitcoin, None);
+        let resolver = MockResolver::default();
+
+        manager.query_dns_seeds(&resolver).unwrap();
+
+        let queries = resolver.queries.lock().unwrap();
+        assert_eq!(queries.len(), MAINNET_DNS_SEEDS.len());
+        assert_eq!(queries[0], ("x49.seed.bitcoin.sipa.be".to_string(), 8333));
+
+        let mut addresses = manager
+            .peer_store
+            .iter_addresses()
+            .unwrap()
+            .into_iter()
+            .map(|entry| entry.address)
+            .collect::<Vec<_>>();
+        addresses.sort();
+        assert_eq!(addresses, vec!["10.0.0.1:8333", "10.0.0.2:8333"]);
+    }
+
+    #[test]
+    fn test_no_dns_seeds_regtest() {
+        let manager = get_manager(Network::Regtest, None);
+        let resolver = MockResolver::default();
+
+        manager.query_dns_seeds(&resolver).unwrap();
+
+        assert!(resolver.queries.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_candidates() {
+        let manager = get_manager(Network::Bitcoin, None);
+        let now = now().unwrap();
+
+        let cf_services = ServiceFlags::NETWORK | ServiceFlags::COMPACT_FILTERS;
+        let entries = vec![
+            AddressEntry::new("10.0.0.1:8333".into(), cf_services, now - 100),
+            AddressEntry::new("10.0.0.2:8333".into(), ServiceFlags::NETWORK, now),
+            AddressEntry::new("10.0.0.3:8333".into(), ServiceFlags::NONE, 0),
+            AddressEntry::new("10.0.0.4:8333".into(), cf_services, now),
+            AddressEntry::new("10.0.0.5:8333".into(), cf_services, now),
+            AddressEntry::new("10.0.0.6:8333".into(), cf_services, now),
+            AddressEntry {
+                last_tried: now,
+                failures: 1,
+                ..AddressEntry::new("10.0.0.7:8333".into(), cf_services, now)
+            },
+            AddressEntry {
+                last_tried: now - 60,
+                failures: 1,
+                ..AddressEntry::new("10.0.0.8:8333".into(), cf_services, now)
+            },
+        ];
+        for entry in &entries {
+            manager.peer_store.put_address(entry).unwrap();
+        }
+        manager
+            .peer_store
+            .ban_peer("10.0.0.5:8333", Duration::from_secs(60))
+            .unwrap();
+
+        let in_use = vec!["10.0.0.6:8333".to_string()]
+            .into_iter()
+            .collect::<HashSet<_>>();
+        let candidates = manager
+            .candidates(&in_use, now)
+            .unwrap()
+            .into_iter()
+            .map(|entry| entry.address)
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            candidates,
+            vec![
+                "10.0.0.4:8333",
+                "10.0.0.1:8333",
+                "10.0.0.3:8333",
+                "10.0.0.8:8333"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_save_announced() {
+        let manager = get_manager(Network::Bitcoin, None);
+
+        manager
+            .peer_store
+            .put_address(&AddressEntry {
+                failures: 2,
+                ..AddressEntry::new("10.0.0.1:8333".into(), ServiceFlags::NONE, 1000)
+            })
+            .unwrap();
+        manager
+            .save_announced(vec![
+                AnnouncedAddress {
+                    address: "10.0.0.1:8333".into(),
+                    services: ServiceFlags::COMPACT_FILTERS,
+                    time: 500,
+                },
+                AnnouncedAddress {
+                    address: "10.0.0.2:8333".into(),
+                    services: ServiceFlags::COMPACT_FILTERS,
+                    time: 2000,
+                },
+            ])
+            .unwrap();
+
+        let first = manager
+            .peer_store
+            .get_address("10.0.0.1:8333")
+            .unwrap()
+            .unwrap();
+        assert_eq!(first.services, ServiceFlags::COMPACT_FILTERS);
+        assert_eq!(first.last_seen, 1000);
+        assert_eq!(first.failures, 2);
+
+        let second = manager
+            .peer_store
+            .get_address("10.0.0.2:8333")
+            .unwrap()
+            .unwrap();
+        assert_eq!(second.last_seen, 2000);
+        assert_eq!(second.failures, 0);
+    }
+
+    #[test]
+    fn test_evict_addresses() {
+        let manager = get_manager(Network::Bitcoin, None);
+        let now = now().unwrap();
+
+        let entries = vec![
+            AddressEntry::new("10.0.0.1:8333".into(), ServiceFlags::NONE, 0),
+            AddressEntry::new("10.0.0.2:8333".into(), ServiceFlags::NONE, now - 100),
+            AddressEntry::new(
+                "10.0.0.3:8333".into(),
+                ServiceFlags::NONE,
+                now - STALE_ADDRESS_SECS - 1,
+            ),
+            AddressEntry {
+                failures: MAX_FAILURES,
+                ..AddressEntry::new("10.0.0.4:8333".into(), ServiceFlags::NONE, now)
+            },
+            AddressEntry {
+                failures: MAX_FAILURES - 1,
+                ..AddressEntry::new("10.0.0.5:8333".into(), ServiceFlags::NONE, now)
+            },
+        ];
+        for entry in &entries {
+            manager.peer_store.put_address(entry).unwrap();
+        }
+
+        manager.evict_addresses(now).unwrap();
+
+        let mut addresses = manager
+            .peer_store
+            .iter_addresses()
+            .unwrap()
+            .into_iter()
+            .map(|entry| entry.address)
+            .collect::<Vec<_>>();
+        addresses.sort();
+        assert_eq!(
+            addresses,
+            vec!["10.0.0.1:8333", "10.0.0.2:8333", "10.0.0.5:8333"]
+        );
+    }
+
+    #[test]
+    fn test_evict_addresses_cap() {
+        let manager = get_manager(Network::Bitcoin, None);
+        let now = now().unwrap();
+
+        for i in 0..MAX_ADDRESSES + 10 {
+            let entry = AddressEntry {
+                failures: (i >= MAX_ADDRESSES) as u32,
+                ..AddressEntry::new(
+                    format!("10.0.{}.{}:8333", i / 256, i % 256),
+                    ServiceFlags::NONE,
+                    now,
+                )
+            };
+            manager.peer_store.put_address(&entry).unwrap();
+        }
+
+        manager.evict_addresses(now).unwrap();
+
+        let entries = manager.peer_store.iter_addresses().unwrap();
+        assert_eq!(entries.len(), MAX_ADDRESSES);
+        assert!(entries.iter().all(|entry| entry.failures == 0));
+    }
+
+    #[test]
+    fn test_maintain_rate_limited() {
+        let manager = get_manager(
+            Network::Regtest,
+            Some(PeerDiscovery {
+                max_peers: 2,
+                dns_seeds: false,
+                ..Default::default()
+            }),
+        );
+        let chain = TestChain::new();
+        let first = TestNode::new(chain.clone());
+        let second = TestNode::new(chain);
+        let add_address = |node: &TestNode| {
+            manager
+                .peer_store
+                .put_address(&AddressEntry::new(
+                    node.address().to_string(),
+                    ServiceFlags::NONE,
+                    0,
+                ))
+                .unwrap()
+        };
+
+        add_address(&first);
+        manager.maintain().unwrap();
+        assert_eq!(manager.connected_peers().len(), 1);
+
+        // the address book isn't scanned again right away
+        add_address(&second);
+        manager.maintain().unwrap();
+        assert_eq!(manager.connected_peers().len(), 1);
+
+        *manager.last_discovery.lock().unwrap() = Some(now().unwrap() - DISCOVERY_INTERVAL_SECS);
+        manager.maintain().unwrap();
+        let mut addresses = manager
+            .connected_peers()
+            .iter()
+            .map(|peer| peer.get_address().to_string())
+            .collect::<Vec<_>>();
+        addresses.sort();
+        let mut expected = vec![first.address().to_string(), second.address().to_string()];
+        expected.sort();
+        assert_eq!(addresses, expected);
+
+        // unless we have no peers at all
+        let third = TestNode::new(TestChain::new());
+        add_address(&third);
+        for peer in manager.connected_peers() {
+            peer.disconnect();
+        }
+        manager.maintain().unwrap();
+        let connected = manager.connected_peers();
+        assert_eq!(connected.len(), 1);
+        assert_eq!(connected[0].get_address(), third.address().to_string());
+    }
+
+    #[test]
+    fn test_onion_candidates() {
+        let onion = "vov2xk5lvov2xk5lvov2xk5lvov2xk5lvov2xk5lvov2xk5lvovqm2qd.onion:8333";
+        let cf_services = ServiceFlags::NETWORK | ServiceFlags::COMPACT_FILTERS;
+        let entries = vec![
+            AddressEntry::new("10.0.0.1:8333".into(), cf_services, 0),
+            AddressEntry::new(onion.into(), cf_services, 0),
+        ];
+
+        let proxy = Socks5Proxy::new("127.0.0.1:9050").unwrap();
+        for (proxy, expected) in vec![
+            (None, vec!["10.0.0.1:8333"]),
+            (Some(proxy), vec!["10.0.0.1:8333", onion]),
+        ] {
+            let manager = get_manager(
+                Network::Bitcoin,
+                Some(PeerDiscovery {
+                    proxy,
+                    ..Default::default()
+                }),
+            );
+            for entry in &entries {
+                manager.peer_store.put_address(entry).unwrap();
+            }
+
+            let candidates = manager
+                .candidates(&HashSet::new(), now().unwrap())
+                .unwrap()
+                .into_iter()
+                .map(|entry| entry.address)
+                .collect::<Vec<_>>();
+            assert_eq!(candidates, expected);
+        }
+    }
+}
@@ -0,0 +1,200 @@
+// Magical Bitcoin Library
+// Written in 2020 by
+//     Alekos Filini <alekos.filini@gmail.com>
+//
+// Copyright (c) 2020 Magical Bitcoin
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! Fee estimation
+//!
+//! Light clients don't have the previous outputs spent by a transaction, so they can't compute
+//! its fee. The total fees paid in a block can still be derived from the coinbase, by
+//! subtracting the block subsidy from the value it claims: the average fee rate of the most
+//! recent blocks is used to estimate the fee rate required to confirm within a target.
+//!
+//! The `feefilter` sent by our peers, which is the minimum fee rate for a transaction to be
+//! accepted in their mempool, is used as a lower bound. It's also the only information we get
+//! about the state of their mempool: the fee rates of the transactions they announce are unknown
+//! for the same reason the fees of a block have to be derived from its coinbase.
+
+use bitcoin::{Block, Network};
+
+use crate::FeeRate;
+
+/// Number of recent blocks used to estimate the fees
+pub(crate) const FEE_ESTIMATION_BLOCKS: usize = 6;
+
+/// Return the subsidy of a block at `height`
+fn block_subsidy(height: usize, network: Network) -> u64 {
+    let halving_interval = match network {
+        Network::Bitcoin | Network::Testnet => 210_000,
+        Network::Regtest => 150,
+    };
+
+    match height / halving_interval {
+        halvings if halvings >= 64 => 0,
+        halvings => (50 * 100_000_000) >> halvings,
+    }
+}
+
+/// Return the average fee rate paid by the transactions in `block`, in sat/kvB
+///
+/// The fees are computed by subtracting the subsidy from the outputs of the coinbase. Blocks that
+/// only contain the coinbase return `None`.
+pub(crate) fn block_fee_rate(block: &Block, height: usize, network: Network) -> Option<u64> {
+    let coinbase = block.txdata.first()?;
+
+    let vsize = block
+        .txdata
+        .iter()
+        .skip(1)
+        .map(|tx| (tx.get_weight() as u64 + 3) / 4)
+        .sum::<u64>();
+    if vsize == 0 {
+        return None;
+    }
+
+    let fees = coinbase
+        .output
+        .iter()
+        .map(|output| output.value)
+        .sum::<u64>()
+        .saturating_sub(block_subsidy(height, network));
+
+    Some(fees * 1000 / vsize)
+}
+
+/// Return the median of the `feefilter`s sent by our peers
+///
+/// Peers that are still syncing announce a very high value, using the median ignores them as long
+/// as they are a minority.
+pub(crate) fn median_fee_filter(mut fee_filters: Vec<u64>) -> Option<u64> {
+    fee_filters.sort_unstable();
+    fee_filters.get(fee_filters.len() / 2).copied()
+}
+
+/// Estimate the fee rate required to confirm within `target` blocks, given the average fee rates
+/// of the recent blocks and the minimum fee rate accepted by our peers, both in sat/kvB
+///
+/// The more urgent the target, the higher the average we pick among the few recent blocks, so
+/// this is only a rough heuristic. Blocks with a fee rate of zero, like the empty ones, are
+/// ignored.
+pub(crate) fn estimate_fee_rate(
+    mut block_fee_rates: Vec<u64>,
+    min_fee_rate: Option<u64>,
+    target: usize,
+) -> FeeRate {
+    let percentile = match target {
+        0 | 1 => 0.9,
+        2 => 0.75,
+        3..=6 => 0.5,
+        _ => 0.25,
+    };
+
+    block_fee_rates.retain(|fee_rate| *fee_rate > 0);
+    block_fee_rates.sort_unstable();
+    let estimate = match block_fee_rates.len() {
+        0 => 0,
+        len => block_fee_rates[((len - 1) as f32 * percentile).round() as usize],
+    };
+
+    let sat_per_vb = estimate.max(min_fee_rate.unwrap_or(0)) as f32 / 1000.0;
+
+    FeeRate::from_sat_per_vb(sat_per_vb.max(FeeRate::default_min_relay_fee().as_sat_vb()))
+}
+
+#[cfg(test)]
+mod test {
+    use bitcoin::blockdata::constants::genesis_block;
+    use bitcoin::{Script, Transaction, TxIn, TxOut};
+
+    use super::*;
+
+    fn tx_with_outputs(values: &[u64]) -> Transaction {
+        Transaction {
+            version: 1,
+            lock_time: 0,
+            input: vec![TxIn::default()],
+            output: values
+                .iter()
+                .map(|value| TxOut {
+                    value: *value,
+                    script_pubkey: Script::from(vec![0x00, 0x14, 0x42]),
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn test_block_subsidy() {
+        assert_eq!(block_subsidy(0, Network::Bitcoin), 50 * 100_000_000);
+        assert_eq!(block_subsidy(210_000, Network::Bitcoin), 25 * 100_000_000);
+        assert_eq!(block_subsidy(630_000, Network::Bitcoin), 625_000_000);
+        assert_eq!(block_subsidy(150, Network::Regtest), 25 * 100_000_000);
+        assert_eq!(block_subsidy(64 * 210_000, Network::Bitcoin), 0);
+    }
+
+    #[test]
+    fn test_block_fee_rate() {
+        let mut block = genesis_block(Network::Bitcoin);
+        assert_eq!(block_fee_rate(&block, 0, Network::Bitcoin), None);
+
+        let tx = tx_with_outputs(&[10_000, 20_000]);
+        let vsize = (tx.get_weight() as u64 + 3) / 4;
+        block.txdata[0] = tx_with_outputs(&[625_000_000 + vsize * 5]);
+        block.txdata.push(tx);
+
+        assert_eq!(
+            block_fee_rate(&block, 630_000, Network::Bitcoin),
+            Some(5_000)
+        );
+    }
+
+    #[test]
+    fn test_median_fee_filter() {
+        assert_eq!(median_fee_filter(vec![]), None);
+        assert_eq!(median_fee_filter(vec![1_000]), Some(1_000));
+        assert_eq!(
+            median_fee_filter(vec![2_000, 1_000, 10_000_000_000_000]),
+            Some(2_000)
+        );
+    }
+
+    #[test]
+    fn test_estimate_fee_rate() {
+        let rates = vec![2_000, 10_000, 4_000, 20_000, 1_000, 8_000];
+
+        assert_eq!(estimate_fee_rate(rates.clone(), None, 1).as_sat_vb(), 20.0);
+        assert_eq!(estimate_fee_rate(rates.clone(), None, 2).as_sat_vb(), 10.0);
+        assert_eq!(estimate_fee_rate(rates.clone(), None, 6).as_sat_vb(), 8.0);
+        assert_eq!(estimate_fee_rate(rates.clone(), None, 25).as_sat_vb(), 2.0);
+        assert_eq!(estimate_fee_rate(rates, Some(3_000), 25).as_sat_vb(), 3.0);
+
+        // blocks without fees are ignored
+        assert_eq!(
+            estimate_fee_rate(vec![0, 0, 0, 4_000], None, 1).as_sat_vb(),
+            4.0
+        );
+
+        // never below the min relay fee
+        assert_eq!(estimate_fee_rate(vec![], None, 1).as_sat_vb(), 1.0);
+        assert_eq!(estimate_fee_rate(vec![], Some(500), 1).as_sat_vb(), 1.0);
+    }
+}
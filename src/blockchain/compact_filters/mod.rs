@@ -28,14 +28,42 @@
 //! uses BIP157 (aka "Neutrino") to populate the wallet's [database](crate::database::Database)
 //! by downloading compact filters from the P2P network.
 //!
-//! Since there are currently very few peers "in the wild" that advertise the required service
-//! flag, this implementation requires that one or more known peers are provided by the user.
-//! No dns or other kinds of peer discovery are done internally.
+//! By default only the peers provided by the user are used. Automatic peer discovery can be
+//! enabled with [`CompactFiltersBlockchain::with_peer_discovery`]: addresses are then taken from
+//! an address book persisted in the storage directory, filled with the `addr`/`addrv2` messages
+//! received from our peers and, when it runs out of usable addresses, by querying the DNS seeds of
+//! the network. Only peers that advertise the `NODE_COMPACT_FILTERS` service flag are kept.
 //!
-//! Moreover, this module doesn't currently support detecting and resolving conflicts between
-//! messages received by different peers. Thus, it's recommended to use this module by only
-//! connecting to a single peer at a time, optionally by opening multiple connections if it's
-//! desirable to use multiple threads at once to sync in parallel.
+//! Peers that disconnect are reconnected with an exponential backoff if they were provided by the
+//! user, or replaced by new ones if they were discovered automatically.
+//!
+//! Connections can be routed through a SOCKS5 proxy like Tor, optionally with stream isolation
+//! so that every peer is reached through a different circuit. When a proxy is set for discovery,
+//! the `.onion` addresses announced through `addrv2` (BIP155) messages are used as well.
+//!
+//! When connected to multiple peers, the filter headers returned by each of them are compared
+//! before starting the sync. If they disagree, the first filter on which they differ is checked
+//! against the content of the block to find out which peer is lying: misbehaving peers are
+//! disconnected, banned for 24 hours and the affected bundles of filters are downloaded again
+//! from the honest ones.
+//!
+//! Peers are asked to announce new transactions, and the unconfirmed ones that spend from or send
+//! to the wallet are kept in the [`Mempool`] and stored in the database at the next sync. The
+//! transactions that were already in the mempool of our peers can only be requested from the ones
+//! that advertise `NODE_BLOOM`.
+//!
+//! Fees are estimated from the average fee rate of the last few blocks, derived from the value
+//! claimed by their coinbase, using the `feefilter` announced by the peers as a lower bound.
+//!
+//! The filters of the blocks that come before the [birthday](crate::WalletBirthday) of the wallet
+//! or the `skip_blocks` set when creating the blockchain are not downloaded. A new wallet that
+//! skips the first blocks of the chain doesn't have to download all of their headers either: the
+//! sync starts from the highest [`HeaderCheckpoint`] below them, and the headers sent by our peers
+//! must chain onto it.
+//!
+//! When the headers of a heavier branch replace some of the blocks we had already synced, the
+//! filters of the replaced blocks are discarded, the transactions confirmed in them are removed
+//! from the database and the new branch is scanned again.
 //!
 //! This is an **EXPERIMENTAL** feature, API and other major changes are expected.
 //!
@@ -60,39 +88,52 @@
 //! # Ok::<(), CompactFiltersError>(())
 //! ```
 
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 use std::fmt;
 use std::path::Path;
 use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
 #[allow(unused_imports)]
-use log::{debug, error, info, trace};
+use log::{debug, error, info, trace, warn};
 
 use bitcoin::network::message_blockdata::Inventory;
 use bitcoin::{Network, OutPoint, Transaction, Txid};
 
 use rocksdb::{Options, SliceTransform, DB};
 
+mod checkpoints;
+mod discovery;
+mod fees;
 mod peer;
 mod store;
 mod sync;
+#[cfg(test)]
+mod test_node;
 
 use super::{Blockchain, Capability, ConfigurableBlockchain, Progress};
 use crate::database::{BatchDatabase, BatchOperations, DatabaseUtils};
 use crate::error::Error;
-use crate::types::{ScriptType, TransactionDetails, UTXO};
+use crate::types::{ScriptType, TransactionDetails, WalletBirthday, UTXO};
 use crate::FeeRate;
 
+use checkpoints::*;
+use discovery::*;
+use fees::*;
 use peer::*;
 use store::*;
 use sync::*;
 
-pub use peer::{Mempool, Peer};
+pub use checkpoints::HeaderCheckpoint;
+pub use discovery::{DnsResolver, PeerDiscovery, SystemResolver, DEFAULT_MAX_PEERS};
+pub use peer::{Mempool, Peer, Socks5Proxy};
 
 const SYNC_HEADERS_COST: f32 = 1.0;
 const SYNC_FILTERS_COST: f32 = 11.6 * 1_000.0;
 const PROCESS_BLOCKS_COST: f32 = 20_000.0;
+/// Number of times the filter checkpoints are requested again when our peers disagree on them
+const PREPARE_SYNC_ATTEMPTS: usize = 3;
 
 /// Structure implementing the required blockchain traits
 ///
@@ -100,9 +141,10 @@ const PROCESS_BLOCKS_COST: f32 = 20_000.0;
 /// See the [`blockchain::compact_filters`](crate::blockchain::compact_filters) module for a usage example.
 #[derive(Debug)]
 pub struct CompactFiltersBlockchain {
-    peers: Vec<Arc<Peer>>,
+    peers: PeerManager,
     headers: Arc<ChainStore<Full>>,
     skip_blocks: Option<usize>,
+    checkpoints: Vec<HeaderCheckpoint>,
 }
 
 impl CompactFiltersBlockchain {
@@ -111,9 +153,8 @@ impl CompactFiltersBlockchain {
     /// from the genesis while scanning for the wallet's outputs.
     ///
     /// For each [`Peer`] specified a new thread will be spawned to download and verify the filters
-    /// in parallel. It's currently recommended to only connect to a single peer to avoid
-    /// inconsistencies in the data returned, optionally with multiple connections in parallel to
-    /// speed-up the sync process.
+    /// in parallel. Peers that have been banned for misbehaving during a previous sync are
+    /// disconnected and ignored.
     pub fn new<P: AsRef<Path>>(
         peers: Vec<Peer>,
         storage_dir: P,
@@ -123,12 +164,41 @@ impl CompactFiltersBlockchain {
             return Err(CompactFiltersError::NoPeers);
         }
 
+        let network = peers[0].get_network();
+        let blockchain = Self::open(network, peers, storage_dir, skip_blocks, None)?;
+        if blockchain.peers.connected_peers().is_empty() {
+            return Err(CompactFiltersError::NoPeers);
+        }
+
+        Ok(blockchain)
+    }
+
+    /// Construct a new instance that automatically finds and connects to peers serving compact
+    /// filters, keeping up to [`max_peers`](PeerDiscovery::max_peers) connections open
+    ///
+    /// The `peers` specified, which can also be empty, are always used and reconnected if they
+    /// disconnect. See [`new`](CompactFiltersBlockchain::new) for the other arguments.
+    pub fn with_peer_discovery<P: AsRef<Path>>(
+        network: Network,
+        peers: Vec<Peer>,
+        storage_dir: P,
+        skip_blocks: Option<usize>,
+        discovery: PeerDiscovery,
+    ) -> Result<Self, CompactFiltersError> {
+        Self::open(network, peers, storage_dir, skip_blocks, Some(discovery))
+    }
+
+    fn open<P: AsRef<Path>>(
+        network: Network,
+        peers: Vec<Peer>,
+        storage_dir: P,
+        skip_blocks: Option<usize>,
+        discovery: Option<PeerDiscovery>,
+    ) -> Result<Self, CompactFiltersError> {
         let mut opts = Options::default();
         opts.create_if_missing(true);
         opts.set_prefix_extractor(SliceTransform::create_fixed_prefix(16));
 
-        let network = peers[0].get_network();
-
         let cfs = DB::list_cf(&opts, &storage_dir).unwrap_or(vec!["default".to_string()]);
         let db = DB::open_cf(&opts, &storage_dir, &cfs)?;
         let headers = Arc::new(ChainStore::new(db, network)?);
@@ -143,13 +213,128 @@ impl CompactFiltersBlockchain {
             headers.recover_snapshot(cf_name)?;
         }
 
+        let mempool = peers.first().map(Peer::get_mempool).unwrap_or_default();
+        let peers = PeerManager::new(network, mempool, PeerStore::new(&headers), peers, discovery)?;
+
         Ok(CompactFiltersBlockchain {
-            peers: peers.into_iter().map(Arc::new).collect(),
+            peers,
             headers,
             skip_blocks,
+            checkpoints: HeaderCheckpoint::for_network(network),
         })
     }
 
+    /// Add a checkpoint to the ones hardcoded for the network
+    ///
+    /// When `skip_blocks` is set, the first sync only downloads the headers that come after the
+    /// highest checkpoint that doesn't go past it.
+    pub fn add_checkpoint(&mut self, checkpoint: HeaderCheckpoint) {
+        self.checkpoints.push(checkpoint);
+    }
+
+    /// Return the number of blocks to skip for a wallet with the given `birthday`, which is never
+    /// less than the `skip_blocks` the blockchain was created with
+    ///
+    /// Timestamps are converted using the headers we have: until they reach the birthday, only the
+    /// blocks we already know are skipped.
+    fn get_skip_blocks(
+        &self,
+        birthday: Option<WalletBirthday>,
+    ) -> Result<usize, CompactFiltersError> {
+        let skip_blocks = self.skip_blocks.unwrap_or(0);
+
+        let birthday_height = match birthday {
+            Some(birthday) => self.headers.get_birthday_height(birthday)?,
+            None => return Ok(skip_blocks),
+        };
+
+        // Filters are downloaded in bundles of 1000 and the ones that start before `skip_blocks`
+        // are skipped entirely, so we have to stop at the beginning of the birthday's bundle
+        let birthday_skip_blocks = birthday_height.saturating_sub(1) / 1000 * 1000;
+
+        Ok(skip_blocks.max(birthday_skip_blocks))
+    }
+
+    /// Start the chain from the best checkpoint for `skip_blocks` if we haven't synced any header
+    /// yet, downloading the block at the checkpoint to make sure that it matches the hash
+    fn start_from_checkpoint(
+        &self,
+        peer: &Peer,
+        skip_blocks: usize,
+    ) -> Result<(), CompactFiltersError> {
+        if self.headers.get_height()? != 0 {
+            return Ok(());
+        }
+        let checkpoint = match best_checkpoint(&self.checkpoints, skip_blocks) {
+            Some(checkpoint) => checkpoint,
+            None => return Ok(()),
+        };
+
+        info!(
+            "Starting the headers sync from the checkpoint at height {}",
+            checkpoint.height
+        );
+
+        let block = peer
+            .get_block(checkpoint.hash)?
+            .ok_or(CompactFiltersError::MissingBlock)?;
+        self.headers.start_from_checkpoint(checkpoint, block.header)
+    }
+
+    /// Replace the peers that disconnected and return the ones that are connected
+    fn connected_peers(&self) -> Result<Vec<Arc<Peer>>, CompactFiltersError> {
+        self.peers.maintain()?;
+
+        let peers = self.peers.connected_peers();
+        if peers.is_empty() {
+            Err(CompactFiltersError::NoPeers)
+        } else {
+            Ok(peers)
+        }
+    }
+
+    /// Save the average fee rate of the most recent blocks, downloading the ones that haven't been
+    /// saved during the sync
+    fn update_fee_samples(&self, tip_height: usize) -> Result<(), CompactFiltersError> {
+        let peer = &self.connected_peers()?[0];
+        let first_height = (tip_height + 1)
+            .saturating_sub(FEE_ESTIMATION_BLOCKS)
+            .max(1);
+
+        for height in first_height..=tip_height {
+            let block_hash = match self.headers.get_block_hash(height)? {
+                Some(block_hash) => block_hash,
+                None => continue,
+            };
+            if self.headers.get_fee_sample(height)?.is_some() {
+                continue;
+            }
+
+            let block = match self.headers.get_full_block(height)? {
+                Some(block) if block.block_hash() == block_hash => block,
+                _ => match peer.get_block(block_hash) {
+                    Ok(Some(block)) => block,
+                    Ok(None) => continue,
+                    Err(e) => {
+                        debug!(
+                            "Can't download block {} for fee estimation: {:?}",
+                            height, e
+                        );
+                        continue;
+                    }
+                },
+            };
+
+            // Blocks without transactions are saved as zero, so that they aren't downloaded again
+            let fee_rate = block_fee_rate(&block, height, peer.get_network()).unwrap_or(0);
+            self.headers.save_fee_sample(height, block_hash, fee_rate)?;
+        }
+
+        self.headers.delete_fee_samples_until(first_height)?;
+
+        Ok(())
+    }
+
     /// Process a transaction by looking for inputs that spend from a UTXO in the database or
     /// outputs that send funds to a know script_pubkey.
     fn process_tx<D: BatchDatabase>(
@@ -158,8 +343,7 @@ impl CompactFiltersBlockchain {
         tx: &Transaction,
         height: Option<u32>,
         timestamp: u64,
-        internal_max_deriv: &mut Option<u32>,
-        external_max_deriv: &mut Option<u32>,
+        max_derivs: &mut HashMap<ScriptType, u32>,
     ) -> Result<(), Error> {
         let mut updates = database.begin_batch();
 
@@ -199,14 +383,9 @@ impl CompactFiltersBlockchain {
                 })?;
                 incoming += output.value;
 
-                if script_type == ScriptType::Internal
-                    && (internal_max_deriv.is_none() || child > internal_max_deriv.unwrap_or(0))
-                {
-                    *internal_max_deriv = Some(child);
-                } else if script_type == ScriptType::External
-                    && (external_max_deriv.is_none() || child > external_max_deriv.unwrap_or(0))
-                {
-                    *external_max_deriv = Some(child);
+                let max_deriv = max_derivs.entry(script_type).or_insert(child);
+                if child > *max_deriv {
+                    *max_deriv = child;
                 }
             }
         }
@@ -232,6 +411,62 @@ impl CompactFiltersBlockchain {
     }
 }
 
+/// Remove the transactions confirmed at or above `fork_height` from the database, together with
+/// the UTXOs they created, and restore the UTXOs they spent
+///
+/// The transactions that are still valid will be added back when the blocks of the new branch
+/// or the mempool are processed.
+fn rewind_txs<D: BatchDatabase>(database: &mut D, fork_height: usize) -> Result<(), Error> {
+    let invalidated = database
+        .iter_txs(true)?
+        .into_iter()
+        .filter(|details| match details.height {
+            Some(height) => height as usize >= fork_height,
+            None => false,
+        })
+        .collect::<Vec<_>>();
+    let invalidated_txids = invalidated
+        .iter()
+        .map(|details| details.txid)
+        .collect::<HashSet<_>>();
+
+    let mut updates = database.begin_batch();
+    for details in &invalidated {
+        debug!("Invalidating tx {}", details.txid);
+
+        if let Some(tx) = &details.transaction {
+            for input in &tx.input {
+                if invalidated_txids.contains(&input.previous_output.txid) {
+                    continue;
+                }
+
+                if let Some(previous_output) =
+                    database.get_previous_output(&input.previous_output)?
+                {
+                    if let Some((script_type, _)) =
+                        database.get_path_from_script_pubkey(&previous_output.script_pubkey)?
+                    {
+                        updates.set_utxo(&UTXO {
+                            outpoint: input.previous_output,
+                            txout: previous_output,
+                            script_type,
+                        })?;
+                    }
+                }
+            }
+
+            for vout in 0..tx.output.len() {
+                updates.del_utxo(&OutPoint::new(details.txid, vout as u32))?;
+            }
+        }
+
+        updates.del_tx(&details.txid, false)?;
+    }
+    database.commit_batch(updates)?;
+
+    Ok(())
+}
+
 impl Blockchain for CompactFiltersBlockchain {
     fn get_capabilities(&self) -> HashSet<Capability> {
         vec![Capability::FullHistory].into_iter().collect()
@@ -243,12 +478,45 @@ impl Blockchain for CompactFiltersBlockchain {
         database: &mut D,
         progress_update: P,
     ) -> Result<(), Error> {
-        let first_peer = &self.peers[0];
+        let peers = self.connected_peers()?;
+        let first_peer = &peers[0];
 
-        let skip_blocks = self.skip_blocks.unwrap_or(0);
+        let birthday = database.get_birthday()?;
+        let skip_blocks = self.get_skip_blocks(birthday)?;
+        self.start_from_checkpoint(first_peer, skip_blocks)?;
 
         let cf_sync = Arc::new(CFSync::new(Arc::clone(&self.headers), skip_blocks, 0x00)?);
 
+        let all_scripts = database
+            .iter_script_pubkeys(None)?
+            .into_iter()
+            .map(|s| s.to_bytes())
+            .collect::<Vec<_>>();
+
+        // Filters scanned during the previous syncs only have to be checked against the scripts
+        // derived since then, which requires downloading the pruned bundles again
+        let (mut last_scanned_height, new_scripts) = match cf_sync.get_sync_state()? {
+            Some((height, scanned_scripts)) => {
+                let scanned_scripts = scanned_scripts.into_iter().collect::<HashSet<_>>();
+                let new_scripts = all_scripts
+                    .iter()
+                    .filter(|s| !scanned_scripts.contains(*s))
+                    .cloned()
+                    .collect::<Vec<_>>();
+
+                (Some(height), new_scripts)
+            }
+            None => (None, vec![]),
+        };
+        if last_scanned_height.is_some() && !new_scripts.is_empty() {
+            let reset_bundles = cf_sync.reset_scanned_bundles()?;
+            info!(
+                "Found {} new scripts, rescanning {} bundles",
+                new_scripts.len(),
+                reset_bundles
+            );
+        }
+
         let initial_height = self.headers.get_height()?;
         let total_bundles = (first_peer.get_version().start_height as usize)
             .checked_sub(skip_blocks)
@@ -285,86 +553,121 @@ impl Blockchain for CompactFiltersBlockchain {
             }
         }
 
+        if let Some(fork_height) = self.headers.get_pending_reorg()? {
+            info!("Rewinding to the fork at height {}", fork_height);
+
+            cf_sync.rewind(fork_height)?;
+            rewind_txs(database, fork_height)?;
+            self.headers.clear_pending_reorg()?;
+
+            last_scanned_height = last_scanned_height.map(|height| height.min(fork_height - 1));
+        }
+
         let synced_height = self.headers.get_height()?;
         let buried_height = synced_height
             .checked_sub(sync::BURIED_CONFIRMATIONS)
             .unwrap_or(0);
         info!("Synced headers to height: {}", synced_height);
 
-        cf_sync.prepare_sync(Arc::clone(&first_peer))?;
+        // Now that the headers are synced we can find the height of a birthday timestamp
+        let cf_sync = match birthday {
+            Some(WalletBirthday::Timestamp(_)) => Arc::new(CFSync::new(
+                Arc::clone(&self.headers),
+                self.get_skip_blocks(birthday)?,
+                0x00,
+            )?),
+            _ => cf_sync,
+        };
+
+        // Peers that can't agree on the filters are disconnected, try again with the other ones
+        let mut attempts = 1;
+        loop {
+            match cf_sync.prepare_sync(&self.connected_peers()?) {
+                Err(CompactFiltersError::PeersDisagree) if attempts < PREPARE_SYNC_ATTEMPTS => {
+                    attempts += 1
+                }
+                result => break result?,
+            }
+        }
 
-        let all_scripts = Arc::new(
-            database
-                .iter_script_pubkeys(None)?
-                .into_iter()
-                .map(|s| s.to_bytes())
-                .collect::<Vec<_>>(),
-        );
+        let all_scripts = Arc::new(all_scripts);
+        let new_scripts = Arc::new(new_scripts);
 
         let last_synced_block = Arc::new(Mutex::new(synced_height));
         let synced_bundles = Arc::new(AtomicUsize::new(0));
         let progress_update = Arc::new(Mutex::new(progress_update));
 
-        let mut threads = Vec::with_capacity(self.peers.len());
-        for peer in &self.peers {
-            let cf_sync = Arc::clone(&cf_sync);
-            let peer = Arc::clone(&peer);
-            let headers = Arc::clone(&self.headers);
-            let all_scripts = Arc::clone(&all_scripts);
-            let last_synced_block = Arc::clone(&last_synced_block);
-            let progress_update = Arc::clone(&progress_update);
-            let synced_bundles = Arc::clone(&synced_bundles);
-
-            let thread = std::thread::spawn(move || {
-                cf_sync.capture_thread_for_sync(
-                    peer,
-                    |block_hash, filter| {
-                        if !filter
-                            .match_any(block_hash, &mut all_scripts.iter().map(AsRef::as_ref))?
-                        {
-                            return Ok(false);
-                        }
-
-                        let block_height = headers.get_height_for(block_hash)?.unwrap_or(0);
-                        let saved_correct_block = match headers.get_full_block(block_height)? {
-                            Some(block) if &block.block_hash() == block_hash => true,
-                            _ => false,
-                        };
-
-                        if saved_correct_block {
-                            Ok(false)
-                        } else {
-                            let mut last_synced_block = last_synced_block.lock().unwrap();
-
-                            // If we download a block older than `last_synced_block`, we update it so that
-                            // we know to delete and re-process all txs starting from that height
-                            if block_height < *last_synced_block {
-                                *last_synced_block = block_height;
+        // Peers that misbehave or disconnect give up their bundle, keep going until all of them are
+        // synced or we run out of peers
+        while cf_sync.remaining_bundles() > 0 {
+            let peers = self.connected_peers()?;
+            let mut threads = Vec::with_capacity(peers.len());
+            for peer in &peers {
+                let cf_sync = Arc::clone(&cf_sync);
+                let peer = Arc::clone(&peer);
+                let headers = Arc::clone(&self.headers);
+                let all_scripts = Arc::clone(&all_scripts);
+                let new_scripts = Arc::clone(&new_scripts);
+                let last_synced_block = Arc::clone(&last_synced_block);
+                let progress_update = Arc::clone(&progress_update);
+                let synced_bundles = Arc::clone(&synced_bundles);
+
+                let thread = std::thread::spawn(move || {
+                    cf_sync.capture_thread_for_sync(
+                        peer,
+                        |block_hash, filter| {
+                            let block_height = headers.get_height_for(block_hash)?.unwrap_or(0);
+                            let scripts = match last_scanned_height {
+                                Some(height) if block_height <= height => &new_scripts,
+                                _ => &all_scripts,
+                            };
+
+                            if scripts.is_empty()
+                                || !filter
+                                    .match_any(block_hash, &mut scripts.iter().map(AsRef::as_ref))?
+                            {
+                                return Ok(false);
                             }
+                            let saved_correct_block = match headers.get_full_block(block_height)? {
+                                Some(block) if &block.block_hash() == block_hash => true,
+                                _ => false,
+                            };
+
+                            if saved_correct_block {
+                                Ok(false)
+                            } else {
+                                let mut last_synced_block = last_synced_block.lock().unwrap();
+
+                                // If we download a block older than `last_synced_block`, we update it so that
+                                // we know to delete and re-process all txs starting from that height
+                                if block_height < *last_synced_block {
+                                    *last_synced_block = block_height;
+                                }
+
+                                Ok(true)
+                            }
+                        },
+                        |index| {
+                            let synced_bundles = synced_bundles.fetch_add(1, Ordering::SeqCst);
+                            let local_filters_cost = synced_bundles as f32 * SYNC_FILTERS_COST;
+                            progress_update.lock().unwrap().update(
+                                (headers_cost + local_filters_cost) / total_cost * 100.0,
+                                Some(format!(
+                                    "Synced filters {} - {}",
+                                    index * 1000 + 1,
+                                    (index + 1) * 1000
+                                )),
+                            )
+                        },
+                    )
+                });
+
+                threads.push(thread);
+            }
 
-                            Ok(true)
-                        }
-                    },
-                    |index| {
-                        let synced_bundles = synced_bundles.fetch_add(1, Ordering::SeqCst);
-                        let local_filters_cost = synced_bundles as f32 * SYNC_FILTERS_COST;
-                        progress_update.lock().unwrap().update(
-                            (headers_cost + local_filters_cost) / total_cost * 100.0,
-                            Some(format!(
-                                "Synced filters {} - {}",
-                                index * 1000 + 1,
-                                (index + 1) * 1000
-                            )),
-                        )
-                    },
-                )
-            });
-
-            threads.push(thread);
-        }
-
-        for t in threads {
-            t.join().unwrap()?;
+            for t in threads {
+                t.join().unwrap()?;
+            }
         }
 
         progress_update.lock().unwrap().update(
@@ -372,8 +675,15 @@ impl Blockchain for CompactFiltersBlockchain {
             Some("Processing downloaded blocks and mempool".into()),
         )?;
 
-        // delete all txs newer than last_synced_block
-        let last_synced_block = *last_synced_block.lock().unwrap();
+        // delete all txs newer than last_synced_block. blocks older than the buried height of the
+        // previous sync have been deleted, so the txs they contain must be kept even if we have
+        // downloaded older blocks for new scripts
+        let last_synced_block = std::cmp::max(
+            *last_synced_block.lock().unwrap(),
+            last_scanned_height
+                .and_then(|height| height.checked_sub(sync::BURIED_CONFIRMATIONS))
+                .unwrap_or(0),
+        );
         log::debug!(
             "Dropping transactions newer than `last_synced_block` = {}",
             last_synced_block
@@ -387,51 +697,79 @@ impl Blockchain for CompactFiltersBlockchain {
         }
         database.commit_batch(updates)?;
 
-        first_peer.ask_for_mempool()?;
+        // the txs we kept won't be processed again, so the outputs they spend have to be removed
+        // again after processing the older blocks downloaded for the new scripts
+        let spent = database
+            .iter_txs(true)?
+            .into_iter()
+            .filter_map(|details| details.transaction)
+            .flat_map(|tx| tx.input.into_iter().map(|input| input.previous_output))
+            .collect::<HashSet<_>>();
 
-        let mut internal_max_deriv = None;
-        let mut external_max_deriv = None;
+        let mempool = self.peers.get_mempool();
+        let mut max_derivs = HashMap::new();
 
         for (height, block) in self.headers.iter_full_blocks()? {
             for tx in &block.txdata {
-                self.process_tx(
-                    database,
-                    tx,
-                    Some(height as u32),
-                    0,
-                    &mut internal_max_deriv,
-                    &mut external_max_deriv,
-                )?;
+                self.process_tx(database, tx, Some(height as u32), 0, &mut max_derivs)?;
+                mempool.remove_tx(&tx.txid());
+                mempool.remove_conflicts(tx);
             }
         }
-        for tx in first_peer.get_mempool().iter_txs().iter() {
-            self.process_tx(
-                database,
-                tx,
-                None,
-                0,
-                &mut internal_max_deriv,
-                &mut external_max_deriv,
-            )?;
+
+        let mut updates = database.begin_batch();
+        for utxo in database.iter_utxos()? {
+            if spent.contains(&utxo.outpoint) {
+                debug!(
+                    "{} was spent by a previous tx, removing from utxo",
+                    utxo.outpoint
+                );
+                updates.del_utxo(&utxo.outpoint)?;
+            }
         }
+        database.commit_batch(updates)?;
 
-        let current_ext = database.get_last_index(ScriptType::External)?.unwrap_or(0);
-        let first_ext_new = external_max_deriv.map(|x| x + 1).unwrap_or(0);
-        if first_ext_new > current_ext {
-            info!("Setting external index to {}", first_ext_new);
-            database.set_last_index(ScriptType::External, first_ext_new)?;
+        // From now on only keep the unconfirmed txs that involve our scripts or our utxos, both
+        // the ones in the mempool of our peers and the ones they'll announce until the next sync
+        mempool.remove_expired(Duration::from_secs(MEMPOOL_EXPIRY_SECS));
+        mempool.watch(
+            database.iter_script_pubkeys(None)?,
+            database.iter_utxos()?.into_iter().map(|utxo| utxo.outpoint),
+        );
+        for peer in self.connected_peers()? {
+            if let Err(e) = peer.ask_for_mempool() {
+                debug!("Can't ask {} for its mempool: {:?}", peer.get_address(), e);
+            }
+        }
+        for tx in mempool.iter_txs().iter() {
+            self.process_tx(database, tx, None, 0, &mut max_derivs)?;
         }
+        // the unconfirmed txs may have created new utxos, which have to be watched as well so
+        // that the txs spending them are kept
+        mempool.watch(
+            database.iter_script_pubkeys(None)?,
+            database.iter_utxos()?.into_iter().map(|utxo| utxo.outpoint),
+        );
 
-        let current_int = database.get_last_index(ScriptType::Internal)?.unwrap_or(0);
-        let first_int_new = internal_max_deriv.map(|x| x + 1).unwrap_or(0);
-        if first_int_new > current_int {
-            info!("Setting internal index to {}", first_int_new);
-            database.set_last_index(ScriptType::Internal, first_int_new)?;
+        for (script_type, max_deriv) in max_derivs {
+            let current = database.get_last_index(script_type)?.unwrap_or(0);
+            let first_new = max_deriv + 1;
+            if first_new > current {
+                info!("Setting {:?} index to {}", script_type, first_new);
+                database.set_last_index(script_type, first_new)?;
+            }
+        }
+
+        // the wallet is already synced at this point, the estimates can be updated next time
+        if let Err(e) = self.update_fee_samples(synced_height) {
+            warn!("Failed to update the fee samples: {:?}", e);
         }
 
         info!("Dropping blocks until {}", buried_height);
         self.headers.delete_blocks_until(buried_height)?;
 
+        cf_sync.set_sync_state(synced_height, all_scripts.to_vec())?;
+
         progress_update
             .lock()
             .unwrap()
@@ -441,13 +779,14 @@ impl Blockchain for CompactFiltersBlockchain {
     }
 
     fn get_tx(&self, txid: &Txid) -> Result<Option<Transaction>, Error> {
-        Ok(self.peers[0]
+        Ok(self
+            .peers
             .get_mempool()
             .get_tx(&Inventory::Transaction(*txid)))
     }
 
     fn broadcast(&self, tx: &Transaction) -> Result<(), Error> {
-        self.peers[0].broadcast_tx(tx.clone())?;
+        self.connected_peers()?[0].broadcast_tx(tx.clone())?;
 
         Ok(())
     }
@@ -456,9 +795,39 @@ impl Blockchain for CompactFiltersBlockchain {
         Ok(self.headers.get_height()? as u32)
     }
 
-    fn estimate_fee(&self, _target: usize) -> Result<FeeRate, Error> {
-        // TODO
-        Ok(FeeRate::default())
+    /// Estimate the fee rate required to confirm within `target` blocks
+    ///
+    /// This is a heuristic based on block averages, not a proper fee estimator: the previous
+    /// outputs of the transactions in a block are unknown to a light client, so only the average
+    /// fee rate of each of the last 6 blocks can be derived from their coinbase. The estimate
+    /// picks one of those 6 averages, a higher one for a more urgent `target`, and never goes
+    /// below the median `feefilter` of our peers. The fee rates of the unconfirmed transactions
+    /// in the mempool are not taken into account.
+    fn estimate_fee(&self, target: usize) -> Result<FeeRate, Error> {
+        let tip_height = self.headers.get_height()?;
+        let first_height = (tip_height + 1)
+            .saturating_sub(FEE_ESTIMATION_BLOCKS)
+            .max(1);
+
+        let mut block_fee_rates = Vec::with_capacity(FEE_ESTIMATION_BLOCKS);
+        for height in first_height..=tip_height {
+            if let Some(fee_rate) = self.headers.get_fee_sample(height)? {
+                block_fee_rates.push(fee_rate);
+            }
+        }
+
+        let fee_filters = self
+            .peers
+            .connected_peers()
+            .iter()
+            .filter_map(|peer| peer.get_fee_filter())
+            .collect();
+
+        Ok(estimate_fee_rate(
+            block_fee_rates,
+            median_fee_filter(fee_filters),
+            target,
+        ))
     }
 }
 
@@ -471,6 +840,29 @@ pub struct BitcoinPeerConfig {
     pub socks5: Option<String>,
     /// Optional socks5 proxy credentials
     pub socks5_credentials: Option<(String, String)>,
+    /// Use new random credentials for every connection to the socks5 proxy, so that Tor opens a
+    /// different circuit for each of them (default: false)
+    pub socks5_isolate_streams: Option<bool>,
+    /// Require every connection to go through the socks5 proxy: this peer must have one, the
+    /// peers found through discovery are contacted using it, and the DNS seeds are not queried
+    /// (default: false)
+    pub socks5_only: Option<bool>,
+}
+
+impl BitcoinPeerConfig {
+    fn proxy(&self) -> Result<Option<Socks5Proxy>, CompactFiltersError> {
+        let address = match (&self.socks5, self.socks5_only) {
+            (Some(address), _) => address,
+            (None, Some(true)) => return Err(CompactFiltersError::MissingProxy),
+            (None, _) => return Ok(None),
+        };
+
+        let mut proxy = Socks5Proxy::new(address.as_str())?;
+        proxy.credentials = self.socks5_credentials.clone();
+        proxy.isolate_streams = self.socks5_isolate_streams.unwrap_or(false);
+
+        Ok(Some(proxy))
+    }
 }
 
 /// Configuration for a [`CompactFiltersBlockchain`]
@@ -484,6 +876,35 @@ pub struct CompactFiltersBlockchainConfig {
     pub storage_dir: String,
     /// Optionally skip initial `skip_blocks` blocks (default: 0)
     pub skip_blocks: Option<usize>,
+    /// Optionally discover new peers automatically, keeping up to `max_peers` connections open
+    /// (default: disabled, only the `peers` listed are used)
+    pub max_peers: Option<usize>,
+}
+
+impl CompactFiltersBlockchainConfig {
+    /// Create a new configuration that connects to `peers` and stores its data in
+    /// `storage_dir`, without skipping any block and without discovering new peers
+    pub fn new(peers: Vec<BitcoinPeerConfig>, network: Network, storage_dir: String) -> Self {
+        CompactFiltersBlockchainConfig {
+            peers,
+            network,
+            storage_dir,
+            skip_blocks: None,
+            max_peers: None,
+        }
+    }
+
+    /// Skip the initial `skip_blocks` blocks
+    pub fn skip_blocks(mut self, skip_blocks: usize) -> Self {
+        self.skip_blocks = Some(skip_blocks);
+        self
+    }
+
+    /// Discover new peers automatically, keeping up to `max_peers` connections open
+    pub fn max_peers(mut self, max_peers: usize) -> Self {
+        self.max_peers = Some(max_peers);
+        self
+    }
 }
 
 impl ConfigurableBlockchain for CompactFiltersBlockchain {
@@ -491,29 +912,54 @@ impl ConfigurableBlockchain for CompactFiltersBlockchain {
 
     fn from_config(config: &Self::Config) -> Result<Self, Error> {
         let mempool = Arc::new(Mempool::default());
+        let proxies = config
+            .peers
+            .iter()
+            .map(BitcoinPeerConfig::proxy)
+            .collect::<Result<Vec<_>, _>>()?;
+
+        // the peers we discover must not be contacted directly if one of ours requires a proxy
+        let discovery_proxy = config
+            .peers
+            .iter()
+            .zip(proxies.iter())
+            .find(|(peer_conf, _)| peer_conf.socks5_only.unwrap_or(false))
+            .and_then(|(_, proxy)| proxy.clone());
+
         let peers = config
             .peers
             .iter()
-            .map(|peer_conf| match &peer_conf.socks5 {
+            .zip(proxies)
+            .map(|(peer_conf, proxy)| match proxy {
                 None => Peer::connect(&peer_conf.address, Arc::clone(&mempool), config.network),
-                Some(proxy) => Peer::connect_proxy(
+                Some(proxy) => Peer::connect_socks5(
                     peer_conf.address.as_str(),
                     proxy,
-                    peer_conf
-                        .socks5_credentials
-                        .as_ref()
-                        .map(|(a, b)| (a.as_str(), b.as_str())),
                     Arc::clone(&mempool),
                     config.network,
                 ),
             })
             .collect::<Result<_, _>>()?;
 
-        Ok(CompactFiltersBlockchain::new(
-            peers,
-            &config.storage_dir,
-            config.skip_blocks,
-        )?)
+        match config.max_peers {
+            None => Ok(CompactFiltersBlockchain::new(
+                peers,
+                &config.storage_dir,
+                config.skip_blocks,
+            )?),
+            Some(max_peers) => Ok(CompactFiltersBlockchain::with_peer_discovery(
+                config.network,
+                peers,
+                &config.storage_dir,
+                config.skip_blocks,
+                PeerDiscovery {
+                    max_peers,
+                    dns_seeds: discovery_proxy.is_none(),
+                    proxy: discovery_proxy,
+                    ..Default::default()
+                },
+            )?),
+        }
     }
 }
 
@@ -532,14 +978,18 @@ pub enum CompactFiltersError {
     MissingBlock,
     /// The data stored in the block filters storage are corrupted
     DataCorruption,
+    /// Peers returned conflicting data and it wasn't possible to tell which ones are lying
+    PeersDisagree,
 
     /// A peer is not connected
     NotConnected,
     /// A peer took too long to reply to one of our messages
     Timeout,
 
-    /// No peers have been specified
+    /// No peers have been specified, or all of them have been disconnected
     NoPeers,
+    /// Connections are required to go through a proxy, but one of the peers doesn't have one
+    MissingProxy,
 
     /// Internal database error
     DB(rocksdb::Error),
@@ -562,6 +1012,30 @@ impl fmt::Display for CompactFiltersError {
 
 impl std::error::Error for CompactFiltersError {}
 
+impl CompactFiltersError {
+    /// Return whether the error was caused by a peer sending us invalid data
+    fn is_misbehavior(&self) -> bool {
+        matches!(
+            self,
+            CompactFiltersError::InvalidResponse
+                | CompactFiltersError::InvalidHeaders
+                | CompactFiltersError::InvalidFilterHeader
+                | CompactFiltersError::InvalidFilter
+        )
+    }
+
+    /// Return whether the error was caused by a peer that disconnected or stopped responding
+    fn is_connection_error(&self) -> bool {
+        matches!(
+            self,
+            CompactFiltersError::NotConnected
+                | CompactFiltersError::Timeout
+                | CompactFiltersError::MissingBlock
+                | CompactFiltersError::IO(_)
+        )
+    }
+}
+
 impl_error!(rocksdb::Error, DB, CompactFiltersError);
 impl_error!(std::io::Error, IO, CompactFiltersError);
 impl_error!(bitcoin::util::bip158::Error, BIP158, CompactFiltersError);
@@ -572,3 +1046,370 @@ impl From<crate::error::Error> for CompactFiltersError {
         CompactFiltersError::Global(Box::new(err))
     }
 }
+
+#[cfg(test)]
+mod test {
+    use bitcoin::{Script, TxIn, TxOut};
+
+    use super::store::test::get_storage_dir;
+    use super::test_node::{TestChain, TestNode};
+    use super::*;
+    use crate::blockchain::noop_progress;
+    use crate::database::{Database, MemoryDatabase};
+    use crate::wallet::{OfflineWallet, Wallet};
+
+    fn get_blockchain(node: &TestNode) -> CompactFiltersBlockchain {
+        let peer = node.connect(Arc::new(Mempool::default()));
+        CompactFiltersBlockchain::new(vec![peer], get_storage_dir(), None).unwrap()
+    }
+
+    fn utxos(database: &MemoryDatabase) -> HashSet<OutPoint> {
+        database
+            .iter_utxos()
+            .unwrap()
+            .into_iter()
+            .map(|utxo| utxo.outpoint)
+            .collect()
+    }
+
+    fn tx_details(tx: &Transaction, height: u32) -> TransactionDetails {
+        TransactionDetails {
+            transaction: Some(tx.clone()),
+            txid: tx.txid(),
+            timestamp: 0,
+            received: 0,
+            sent: 0,
+            fees: 0,
+            height: Some(height),
+        }
+    }
+
+    #[test]
+    fn test_rewind_txs() {
+        let mut database = MemoryDatabase::new();
+        let script = Script::from(vec![0x00, 0x14, 0x42]);
+        database
+            .set_script_pubkey(&script, ScriptType::External, 0)
+            .unwrap();
+
+        let funding = Transaction {
+            version: 1,
+            lock_time: 0,
+            input: vec![],
+            output: vec![TxOut {
+                value: 50_000,
+                script_pubkey: script.clone(),
+            }],
+        };
+        let spending = Transaction {
+            version: 1,
+            lock_time: 0,
+            input: vec![TxIn {
+                previous_output: OutPoint::new(funding.txid(), 0),
+                ..Default::default()
+            }],
+            output: vec![TxOut {
+                value: 40_000,
+                script_pubkey: script.clone(),
+            }],
+        };
+
+        database.set_tx(&tx_details(&funding, 5)).unwrap();
+        database.set_tx(&tx_details(&spending, 10)).unwrap();
+        database
+            .set_utxo(&UTXO {
+                outpoint: OutPoint::new(spending.txid(), 0),
+                txout: spending.output[0].clone(),
+                script_type: ScriptType::External,
+            })
+            .unwrap();
+
+        rewind_txs(&mut database, 8).unwrap();
+
+        assert!(database.get_tx(&funding.txid(), false).unwrap().is_some());
+        assert!(database.get_tx(&spending.txid(), false).unwrap().is_none());
+        assert_eq!(
+            database
+                .iter_utxos()
+                .unwrap()
+                .into_iter()
+                .map(|utxo| utxo.outpoint)
+                .collect::<Vec<_>>(),
+            vec![OutPoint::new(funding.txid(), 0)]
+        );
+    }
+
+    #[test]
+    fn test_sync_reorg() {
+        let script = Script::from(vec![0x00, 0x14, 0x42]);
+        let pay = |coinbase: &Transaction, value: u64| Transaction {
+            version: 1,
+            lock_time: 0,
+            input: vec![TxIn {
+                previous_output: OutPoint::new(coinbase.txid(), 0),
+                ..Default::default()
+            }],
+            output: vec![TxOut {
+                value,
+                script_pubkey: script.clone(),
+            }],
+        };
+
+        let mut chain = TestChain::new();
+        chain.mine_empty(1);
+        let kept = pay(&chain.block(1).txdata[0], 10_000);
+        chain.mine(vec![kept.clone()]);
+        let fork = chain.truncated(2);
+
+        // the tx confirmed in the stale block double-spends the one of the new branch
+        let dropped = pay(&chain.block(2).txdata[0], 20_000);
+        chain.mine(vec![dropped.clone()]);
+        chain.mine_empty(5);
+
+        let mut branch = fork;
+        let replacement = pay(&branch.block(2).txdata[0], 30_000);
+        assert_eq!(branch.mine(vec![replacement.clone()]), 3);
+        branch.mine_empty(10);
+
+        let storage_dir = get_storage_dir();
+        let mut database = MemoryDatabase::new();
+        database
+            .set_script_pubkey(&script, ScriptType::External, 0)
+            .unwrap();
+
+        let node = TestNode::new(chain);
+        let blockchain = CompactFiltersBlockchain::new(
+            vec![node.connect(Arc::new(Mempool::default()))],
+            &storage_dir,
+            None,
+        )
+        .unwrap();
+        blockchain
+            .setup(None, &mut database, noop_progress())
+            .unwrap();
+        assert_eq!(
+            utxos(&database),
+            vec![
+                OutPoint::new(kept.txid(), 0),
+                OutPoint::new(dropped.txid(), 0)
+            ]
+            .into_iter()
+            .collect()
+        );
+        // release the storage before opening it again
+        std::mem::drop(blockchain);
+
+        // the heavier branch replaces the tip
+        let node = TestNode::new(branch);
+        let blockchain = CompactFiltersBlockchain::new(
+            vec![node.connect(Arc::new(Mempool::default()))],
+            &storage_dir,
+            None,
+        )
+        .unwrap();
+        blockchain
+            .setup(None, &mut database, noop_progress())
+            .unwrap();
+
+        assert_eq!(blockchain.get_height().unwrap(), 13);
+        assert_eq!(blockchain.headers.get_pending_reorg().unwrap(), None);
+        assert!(database.get_tx(&dropped.txid(), false).unwrap().is_none());
+        assert!(database.get_tx(&kept.txid(), false).unwrap().is_some());
+        assert_eq!(
+            database
+                .get_tx(&replacement.txid(), false)
+                .unwrap()
+                .unwrap()
+                .height,
+            Some(3)
+        );
+        assert_eq!(
+            utxos(&database),
+            vec![
+                OutPoint::new(kept.txid(), 0),
+                OutPoint::new(replacement.txid(), 0)
+            ]
+            .into_iter()
+            .collect()
+        );
+    }
+
+    #[test]
+    fn test_rescan_keeps_spent_utxos() {
+        let old_script = Script::from(vec![0x00, 0x14, 0x01]);
+        let new_script = Script::from(vec![0x00, 0x14, 0x02]);
+
+        let mut chain = TestChain::new();
+        chain.mine_empty(1);
+        let funding = Transaction {
+            version: 1,
+            lock_time: 0,
+            input: vec![TxIn {
+                previous_output: OutPoint::new(chain.block(1).txdata[0].txid(), 0),
+                ..Default::default()
+            }],
+            output: vec![
+                TxOut {
+                    value: 10_000,
+                    script_pubkey: old_script.clone(),
+                },
+                TxOut {
+                    value: 20_000,
+                    script_pubkey: new_script.clone(),
+                },
+            ],
+        };
+        let spending = Transaction {
+            version: 1,
+            lock_time: 0,
+            input: vec![TxIn {
+                previous_output: OutPoint::new(funding.txid(), 0),
+                ..Default::default()
+            }],
+            output: vec![TxOut {
+                value: 9_000,
+                script_pubkey: Script::from(vec![0x51]),
+            }],
+        };
+        chain.mine(vec![funding.clone()]);
+        chain.mine(vec![spending.clone()]);
+        chain.mine_empty(200);
+
+        let node = TestNode::new(chain);
+        let blockchain = get_blockchain(&node);
+        let mut database = MemoryDatabase::new();
+        database
+            .set_script_pubkey(&old_script, ScriptType::External, 0)
+            .unwrap();
+
+        blockchain
+            .setup(None, &mut database, noop_progress())
+            .unwrap();
+        assert!(utxos(&database).is_empty());
+
+        // the blocks of the previous sync are rescanned for the new script, but the output spent
+        // in a block that isn't downloaded again must not come back
+        database
+            .set_script_pubkey(&new_script, ScriptType::External, 1)
+            .unwrap();
+        node.mine_empty(1);
+        blockchain
+            .setup(None, &mut database, noop_progress())
+            .unwrap();
+
+        assert_eq!(
+            utxos(&database),
+            vec![OutPoint::new(funding.txid(), 1)].into_iter().collect()
+        );
+        assert!(database.get_tx(&spending.txid(), false).unwrap().is_some());
+        assert_eq!(
+            database
+                .get_tx(&funding.txid(), false)
+                .unwrap()
+                .unwrap()
+                .received,
+            30_000
+        );
+    }
+
+    #[test]
+    fn test_sync_from_birthday() {
+        let descriptor = "wpkh(tpubEBr4i6yk5nf5DAaJpsi9N2pPYBeJ7fZ5Z9rmN4977iYLCGco1VyjB9tvvuvYtfZzjD5A8igzgw3HeWeeKFmanHYqksqZXYXGsw5zjnj7KM9/*)";
+        let script =
+            OfflineWallet::new_offline(descriptor, None, Network::Regtest, MemoryDatabase::new())
+                .unwrap()
+                .peek_address(0)
+                .unwrap()
+                .script_pubkey();
+        let pay = |coinbase: &Transaction, value: u64| Transaction {
+            version: 1,
+            lock_time: 0,
+            input: vec![TxIn {
+                previous_output: OutPoint::new(coinbase.txid(), 0),
+                ..Default::default()
+            }],
+            output: vec![TxOut {
+                value,
+                script_pubkey: script.clone(),
+            }],
+        };
+
+        let mut chain = TestChain::new();
+        chain.mine_empty(2);
+        let before_birthday = pay(&chain.block(1).txdata[0], 10_000);
+        chain.mine(vec![before_birthday]);
+        chain.mine_empty(2147);
+        let checkpoint = HeaderCheckpoint {
+            height: 2000,
+            hash: chain.block(2000).block_hash(),
+            chain_work: Default::default(),
+            filter_header: Some(chain.filter_header(2000)),
+        };
+        let after_birthday = pay(&chain.block(2).txdata[0], 20_000);
+
+        let node = TestNode::new(chain);
+        assert_eq!(node.mine(vec![after_birthday.clone()]), 2151);
+        node.mine_empty(150);
+
+        let mut blockchain = get_blockchain(&node);
+        blockchain.add_checkpoint(checkpoint);
+        let headers = Arc::clone(&blockchain.headers);
+
+        let wallet = Wallet::new_with_birthday(
+            descriptor,
+            None,
+            Network::Regtest,
+            MemoryDatabase::new(),
+            blockchain,
+            WalletBirthday::Height(2101),
+        )
+        .unwrap();
+        wallet.sync(noop_progress(), None).unwrap();
+
+        // the headers before the birthday's bundle are never downloaded, so the transaction
+        // confirmed before the checkpoint isn't found
+        assert_eq!(headers.get_checkpoint_height().unwrap(), 2000);
+        assert_eq!(headers.get_height().unwrap(), 2301);
+        assert_eq!(wallet.get_balance().unwrap(), 20_000);
+        assert_eq!(
+            wallet
+                .list_transactions(false)
+                .unwrap()
+                .into_iter()
+                .map(|tx| tx.txid)
+                .collect::<Vec<_>>(),
+            vec![after_birthday.txid()]
+        );
+    }
+
+    #[test]
+    fn test_socks5_only_requires_proxy() {
+        let node = TestNode::new(TestChain::new());
+        let peer = |socks5: Option<&str>, socks5_only: bool| BitcoinPeerConfig {
+            address: node.address().to_string(),
+            socks5: socks5.map(String::from),
+            socks5_credentials: None,
+            socks5_isolate_streams: None,
+            socks5_only: Some(socks5_only),
+        };
+        let config = |peers| {
+            CompactFiltersBlockchainConfig::new(
+                peers,
+                Network::Regtest,
+                get_storage_dir().to_str().unwrap().to_string(),
+            )
+        };
+
+        // checked before opening any connection
+        assert!(matches!(
+            CompactFiltersBlockchain::from_config(&config(vec![
+                peer(Some("127.0.0.1:9050"), false),
+                peer(None, true)
+            ])),
+            Err(Error::CompactFilters(CompactFiltersError::MissingProxy))
+        ));
+
+        // without the option the peer is connected to directly
+        assert!(CompactFiltersBlockchain::from_config(&config(vec![peer(None, false)])).is_ok());
+    }
+}
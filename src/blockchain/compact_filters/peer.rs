@@ -22,27 +22,30 @@
 // OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
 // SOFTWARE.
 
-use std::collections::HashMap;
-use std::net::{TcpStream, ToSocketAddrs};
-use std::sync::{Arc, Condvar, Mutex, RwLock};
+use std::collections::{HashMap, HashSet};
+use std::io::{Cursor, Read};
+use std::net::{Ipv4Addr, Ipv6Addr, Shutdown, SocketAddr, TcpStream, ToSocketAddrs};
+use std::sync::{mpsc, Arc, Condvar, Mutex, RwLock};
 use std::thread;
-use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
-use socks::{Socks5Stream, ToTargetAddr};
+use socks::{Socks5Stream, TargetAddr, ToTargetAddr};
 
 use rand::{thread_rng, Rng};
 
-use bitcoin::consensus::Encodable;
+use sha3::{Digest, Sha3_256};
+
+use bitcoin::consensus::encode::{self, CheckedData, VarInt};
+use bitcoin::consensus::{deserialize, Decodable, Encodable};
 use bitcoin::hash_types::BlockHash;
-use bitcoin::hashes::Hash;
+use bitcoin::hashes::{sha256d, Hash};
 use bitcoin::network::constants::ServiceFlags;
 use bitcoin::network::message::{NetworkMessage, RawNetworkMessage};
 use bitcoin::network::message_blockdata::*;
 use bitcoin::network::message_filter::*;
 use bitcoin::network::message_network::VersionMessage;
-use bitcoin::network::stream_reader::StreamReader;
 use bitcoin::network::Address;
-use bitcoin::{Block, Network, Transaction, Txid};
+use bitcoin::{Block, Network, OutPoint, Script, Transaction, Txid};
 
 use super::CompactFiltersError;
 
@@ -50,13 +53,43 @@ type ResponsesMap = HashMap<&'static str, Arc<(Mutex<Vec<NetworkMessage>>, Condv
 
 pub(crate) const TIMEOUT_SECS: u64 = 30;
 
+/// Upper limit for the size of the messages we accept from peers, the same used by Bitcoin Core
+const MAX_MESSAGE_SIZE: usize = 4_000_000;
+/// Maximum number of announced addresses kept until they are collected
+const MAX_ANNOUNCED_ADDRESSES: usize = 1000;
+/// Number of announced transactions to remember before starting again from scratch
+const MAX_SEEN_TXS: usize = 100_000;
+/// Time after which unconfirmed transactions are dropped from the [`Mempool`], the same used by
+/// Bitcoin Core
+pub(crate) const MEMPOOL_EXPIRY_SECS: u64 = 14 * 24 * 60 * 60;
+
+/// An address of a peer, as announced by one of our peers with an `addr` or `addrv2` message
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct AnnouncedAddress {
+    pub address: String,
+    pub services: ServiceFlags,
+    pub time: u32,
+}
+
 /// Container for unconfirmed, but valid Bitcoin transactions
 ///
 /// It is normally shared between [`Peer`]s with the use of [`Arc`], so that transactions are not
 /// duplicated in memory.
+///
+/// The transactions announced by the peers are only stored if they involve one of the scripts or
+/// outputs being watched. They are removed once they are replaced by a conflicting transaction, or
+/// after [`MEMPOOL_EXPIRY_SECS`] since peers are likely to have evicted them by then.
 #[derive(Debug, Default)]
 pub struct Mempool {
-    txs: RwLock<HashMap<Txid, Transaction>>,
+    txs: RwLock<HashMap<Txid, (Transaction, Instant)>>,
+    watched: RwLock<WatchedItems>,
+    seen: RwLock<HashSet<Txid>>,
+}
+
+#[derive(Debug, Default)]
+struct WatchedItems {
+    scripts: HashSet<Script>,
+    outpoints: HashSet<OutPoint>,
 }
 
 impl Mempool {
@@ -65,7 +98,10 @@ impl Mempool {
     /// Note that this doesn't propagate the transaction to other
     /// peers. To do that, [`broadcast`](crate::blockchain::Blockchain::broadcast) should be used.
     pub fn add_tx(&self, tx: Transaction) {
-        self.txs.write().unwrap().insert(tx.txid(), tx);
+        self.txs
+            .write()
+            .unwrap()
+            .insert(tx.txid(), (tx, Instant::now()));
     }
 
     /// Look-up a transaction in the mempool given an [`Inventory`] request
@@ -75,7 +111,11 @@ impl Mempool {
             Inventory::Transaction(txid) => *txid,
             Inventory::WitnessTransaction(wtxid) => Txid::from_inner(wtxid.into_inner()),
         };
-        self.txs.read().unwrap().get(&txid).cloned()
+        self.txs
+            .read()
+            .unwrap()
+            .get(&txid)
+            .map(|(tx, _)| tx.clone())
     }
 
     /// Return whether or not the mempool contains a transaction with a given txid
@@ -85,7 +125,181 @@ impl Mempool {
 
     /// Return the list of transactions contained in the mempool
     pub fn iter_txs(&self) -> Vec<Transaction> {
-        self.txs.read().unwrap().values().cloned().collect()
+        self.txs
+            .read()
+            .unwrap()
+            .values()
+            .map(|(tx, _)| tx.clone())
+            .collect()
+    }
+
+    /// Remove a transaction from the mempool, normally because it has been confirmed
+    pub fn remove_tx(&self, txid: &Txid) -> Option<Transaction> {
+        self.txs.write().unwrap().remove(txid).map(|(tx, _)| tx)
+    }
+
+    /// Remove the transactions that spend the same outputs as `tx`, which has replaced them, and
+    /// the ones that depend on them
+    pub(crate) fn remove_conflicts(&self, tx: &Transaction) {
+        let txid = tx.txid();
+        let spent = tx
+            .input
+            .iter()
+            .map(|input| input.previous_output)
+            .collect::<HashSet<_>>();
+        let mut removed = HashSet::new();
+
+        let mut txs = self.txs.write().unwrap();
+        loop {
+            let conflicts = txs
+                .iter()
+                .filter(|(other_txid, (other, _))| {
+                    **other_txid != txid
+                        && other.input.iter().any(|input| {
+                            spent.contains(&input.previous_output)
+                                || removed.contains(&input.previous_output.txid)
+                        })
+                })
+                .map(|(other_txid, _)| *other_txid)
+                .collect::<Vec<_>>();
+            if conflicts.is_empty() {
+                break;
+            }
+
+            for conflict in conflicts {
+                log::debug!("Removing tx {} replaced by {}", conflict, txid);
+                txs.remove(&conflict);
+                removed.insert(conflict);
+            }
+        }
+    }
+
+    /// Remove the transactions that have been in the mempool for longer than `expiry`
+    pub(crate) fn remove_expired(&self, expiry: Duration) {
+        self.txs.write().unwrap().retain(|txid, (_, added)| {
+            let expired = added.elapsed() >= expiry;
+            if expired {
+                log::debug!("Removing expired tx {}", txid);
+            }
+
+            !expired
+        });
+    }
+
+    /// Replace the scripts and outputs used to decide which announced transactions to keep
+    ///
+    /// The transactions seen so far are forgotten, so that the ones that weren't relevant before
+    /// are requested again when they are announced.
+    pub(crate) fn watch<S, O>(&self, scripts: S, outpoints: O)
+    where
+        S: IntoIterator<Item = Script>,
+        O: IntoIterator<Item = OutPoint>,
+    {
+        let mut watched = self.watched.write().unwrap();
+        watched.scripts = scripts.into_iter().collect();
+        watched.outpoints = outpoints.into_iter().collect();
+
+        self.seen.write().unwrap().clear();
+    }
+
+    /// Return whether `tx` spends one of the watched outputs or sends to one of the watched
+    /// scripts
+    pub(crate) fn is_relevant(&self, tx: &Transaction) -> bool {
+        let watched = self.watched.read().unwrap();
+
+        tx.input
+            .iter()
+            .any(|input| watched.outpoints.contains(&input.previous_output))
+            || tx
+                .output
+                .iter()
+                .any(|output| watched.scripts.contains(&output.script_pubkey))
+    }
+
+    /// Add a transaction announced by a peer, if it's relevant, replacing the ones it conflicts
+    /// with. Returns whether it was added
+    pub(crate) fn add_announced_tx(&self, tx: Transaction) -> bool {
+        if !self.is_relevant(&tx) {
+            return false;
+        }
+
+        self.remove_conflicts(&tx);
+        self.add_tx(tx);
+        true
+    }
+
+    /// Mark a transaction as seen, returning `false` if it had already been seen before
+    ///
+    /// This is used to avoid requesting the same transaction from every peer that announces it.
+    pub(crate) fn mark_seen(&self, txid: Txid) -> bool {
+        let mut seen = self.seen.write().unwrap();
+        if seen.len() >= MAX_SEEN_TXS {
+            seen.clear();
+        }
+
+        seen.insert(txid)
+    }
+}
+
+/// SOCKS5 proxy used to connect to a peer
+#[derive(Debug, Clone)]
+pub struct Socks5Proxy {
+    /// Address of the proxy
+    pub address: SocketAddr,
+    /// Optional credentials, as a tuple of `(username, password)`
+    pub credentials: Option<(String, String)>,
+    /// Authenticate with new random credentials for every connection, which makes Tor use a
+    /// different circuit for each of them ("stream isolation"). When enabled `credentials` are
+    /// ignored
+    pub isolate_streams: bool,
+    /// How long to wait for the proxy to open a connection before giving up
+    pub timeout: Duration,
+}
+
+impl Socks5Proxy {
+    /// Create a new proxy configuration without credentials and stream isolation, that gives up
+    /// on connections that take longer than [`TIMEOUT_SECS`] to open
+    pub fn new<P: ToSocketAddrs>(address: P) -> Result<Self, CompactFiltersError> {
+        let address = address
+            .to_socket_addrs()?
+            .next()
+            .ok_or(CompactFiltersError::NotConnected)?;
+
+        Ok(Socks5Proxy {
+            address,
+            credentials: None,
+            isolate_streams: false,
+            timeout: Duration::from_secs(TIMEOUT_SECS),
+        })
+    }
+
+    fn connect(&self, target: TargetAddr) -> Result<Socks5Stream, CompactFiltersError> {
+        // `socks` has no timeouts, so the connection is opened in a separate thread that we stop
+        // waiting for, which exits on its own once the proxy replies or gives up
+        let (sender, receiver) = mpsc::channel();
+        let proxy = self.clone();
+        thread::spawn(move || sender.send(proxy.connect_blocking(target)));
+
+        match receiver.recv_timeout(self.timeout) {
+            Ok(stream) => Ok(stream?),
+            Err(_) => Err(CompactFiltersError::Timeout),
+        }
+    }
+
+    fn connect_blocking(&self, target: TargetAddr) -> Result<Socks5Stream, std::io::Error> {
+        let stream = if self.isolate_streams {
+            let mut rng = thread_rng();
+            let username = format!("{:016x}", rng.gen::<u64>());
+            let password = format!("{:016x}", rng.gen::<u64>());
+
+            Socks5Stream::connect_with_password(self.address, target, &username, &password)?
+        } else if let Some((username, password)) = &self.credentials {
+            Socks5Stream::connect_with_password(self.address, target, username, password)?
+        } else {
+            Socks5Stream::connect(self.address, target)?
+        };
+
+        Ok(stream)
     }
 }
 
@@ -99,7 +313,11 @@ pub struct Peer {
     connected: Arc<RwLock<bool>>,
 
     mempool: Arc<Mempool>,
+    announced: Arc<Mutex<Vec<AnnouncedAddress>>>,
+    fee_filter: Arc<RwLock<Option<u64>>>,
 
+    address: String,
+    proxy: Option<Socks5Proxy>,
     version: VersionMessage,
     network: Network,
 }
@@ -115,8 +333,21 @@ impl Peer {
         network: Network,
     ) -> Result<Self, CompactFiltersError> {
         let stream = TcpStream::connect(address)?;
+        let address = stream.peer_addr()?.to_string();
+
+        Peer::from_stream(stream, address, None, mempool, network)
+    }
+
+    /// Connect to a peer over a plaintext TCP connection, giving up after `timeout`
+    pub(crate) fn connect_timeout(
+        address: &SocketAddr,
+        timeout: Duration,
+        mempool: Arc<Mempool>,
+        network: Network,
+    ) -> Result<Self, CompactFiltersError> {
+        let stream = TcpStream::connect_timeout(address, timeout)?;
 
-        Peer::from_stream(stream, mempool, network)
+        Peer::from_stream(stream, address.to_string(), None, mempool, network)
     }
 
     /// Connect to a peer through a SOCKS5 proxy, optionally by using some credentials, specified
@@ -131,30 +362,74 @@ impl Peer {
         mempool: Arc<Mempool>,
         network: Network,
     ) -> Result<Self, CompactFiltersError> {
-        let socks_stream = if let Some((username, password)) = credentials {
-            Socks5Stream::connect_with_password(proxy, target, username, password)?
-        } else {
-            Socks5Stream::connect(proxy, target)?
+        let mut proxy = Socks5Proxy::new(proxy)?;
+        proxy.credentials =
+            credentials.map(|(username, password)| (username.to_string(), password.to_string()));
+
+        Peer::connect_socks5(target, proxy, mempool, network)
+    }
+
+    /// Connect to a peer through a [`Socks5Proxy`]
+    ///
+    /// Unlike [`Peer::connect_proxy`] this allows enabling stream isolation, and it's the only way
+    /// to connect to `.onion` addresses
+    pub fn connect_socks5<T: ToTargetAddr>(
+        target: T,
+        proxy: Socks5Proxy,
+        mempool: Arc<Mempool>,
+        network: Network,
+    ) -> Result<Self, CompactFiltersError> {
+        let target = target.to_target_addr()?;
+        let address = match &target {
+            TargetAddr::Ip(addr) => addr.to_string(),
+            TargetAddr::Domain(host, port) => format!("{}:{}", host, port),
         };
 
-        Peer::from_stream(socks_stream.into_inner(), mempool, network)
+        let socks_stream = proxy.connect(target)?;
+
+        Peer::from_stream(
+            socks_stream.into_inner(),
+            address,
+            Some(proxy),
+            mempool,
+            network,
+        )
+    }
+
+    /// Open a new connection to the same peer, using the same proxy if there was one
+    pub fn reconnect(&self) -> Result<Self, CompactFiltersError> {
+        match &self.proxy {
+            None => Peer::connect(self.address.as_str(), self.get_mempool(), self.network),
+            Some(proxy) => Peer::connect_socks5(
+                self.address.as_str(),
+                proxy.clone(),
+                self.get_mempool(),
+                self.network,
+            ),
+        }
     }
 
     /// Create a [`Peer`] from an already connected TcpStream
     fn from_stream(
         stream: TcpStream,
+        address: String,
+        proxy: Option<Socks5Proxy>,
         mempool: Arc<Mempool>,
         network: Network,
     ) -> Result<Self, CompactFiltersError> {
         let writer = Arc::new(Mutex::new(stream.try_clone()?));
         let responses: Arc<RwLock<ResponsesMap>> = Arc::new(RwLock::new(HashMap::new()));
         let connected = Arc::new(RwLock::new(true));
+        let announced = Arc::new(Mutex::new(Vec::new()));
+        let fee_filter = Arc::new(RwLock::new(None));
 
         let mut locked_writer = writer.lock().unwrap();
 
         let reader_thread_responses = Arc::clone(&responses);
         let reader_thread_writer = Arc::clone(&writer);
         let reader_thread_mempool = Arc::clone(&mempool);
+        let reader_thread_announced = Arc::clone(&announced);
+        let reader_thread_fee_filter = Arc::clone(&fee_filter);
         let reader_thread_connected = Arc::clone(&connected);
         let reader_thread = thread::spawn(move || {
             Self::reader_thread(
@@ -163,6 +438,8 @@ impl Peer {
                 reader_thread_responses,
                 reader_thread_writer,
                 reader_thread_mempool,
+                reader_thread_announced,
+                reader_thread_fee_filter,
                 reader_thread_connected,
             )
         });
@@ -176,28 +453,38 @@ impl Peer {
             port: 0,
         };
 
+        let mut version_message = VersionMessage::new(
+            ServiceFlags::WITNESS,
+            timestamp,
+            receiver,
+            sender,
+            nonce,
+            "MagicalBitcoinWallet".into(),
+            0,
+        );
+        // Ask the peer to announce new transactions to us
+        version_message.relay = true;
         Self::_send(
             &mut locked_writer,
             network.magic(),
-            NetworkMessage::Version(VersionMessage::new(
-                ServiceFlags::WITNESS,
-                timestamp,
-                receiver,
-                sender,
-                nonce,
-                "MagicalBitcoinWallet".into(),
-                0,
-            )),
+            NetworkMessage::Version(version_message),
         )?;
+        let handshake_timeout = Some(Duration::from_secs(TIMEOUT_SECS));
         let version = if let NetworkMessage::Version(version) =
-            Self::_recv(&responses, "version", None)?.unwrap()
+            Self::_recv(&responses, "version", handshake_timeout)?
+                .ok_or(CompactFiltersError::Timeout)?
         {
             version
         } else {
             return Err(CompactFiltersError::InvalidResponse);
         };
 
-        if let NetworkMessage::Verack = Self::_recv(&responses, "verack", None)?.unwrap() {
+        // Signal support for `addrv2` messages (BIP155), this has to be sent before the `verack`
+        Self::_send_raw(&mut locked_writer, network.magic(), "sendaddrv2", &[])?;
+
+        if let NetworkMessage::Verack = Self::_recv(&responses, "verack", handshake_timeout)?
+            .ok_or(CompactFiltersError::Timeout)?
+        {
             Self::_send(&mut locked_writer, network.magic(), NetworkMessage::Verack)?;
         } else {
             return Err(CompactFiltersError::InvalidResponse);
@@ -211,6 +498,10 @@ impl Peer {
             responses,
             connected,
             mempool,
+            announced,
+            fee_filter,
+            address,
+            proxy,
             network,
             version,
         })
@@ -233,6 +524,29 @@ impl Peer {
         Ok(())
     }
 
+    /// Send a message that is not supported by [`NetworkMessage`]
+    fn _send_raw(
+        writer: &mut TcpStream,
+        magic: u32,
+        command: &str,
+        payload: &[u8],
+    ) -> Result<(), CompactFiltersError> {
+        log::trace!("==> {} {:?}", command, payload);
+
+        let mut command_bytes = [0u8; 12];
+        command_bytes[..command.len()].copy_from_slice(command.as_bytes());
+
+        let mut raw_message = magic.to_le_bytes().to_vec();
+        raw_message.extend_from_slice(&command_bytes);
+        raw_message.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+        raw_message.extend_from_slice(&sha256d::Hash::hash(payload)[..4]);
+        raw_message.extend_from_slice(payload);
+
+        std::io::Write::write_all(writer, &raw_message)?;
+
+        Ok(())
+    }
+
     /// Wait for a specific incoming Bitcoin message, optionally with a timeout
     fn _recv(
         responses: &Arc<RwLock<ResponsesMap>>,
@@ -270,6 +584,14 @@ impl Peer {
         &self.version
     }
 
+    /// Return the address of the peer
+    ///
+    /// For peers connected through a proxy this is the address of the final destination, not the
+    /// address of the proxy.
+    pub fn get_address(&self) -> &str {
+        &self.address
+    }
+
     /// Return the Bitcoin [`Network`] in use
     pub fn get_network(&self) -> Network {
         self.network
@@ -285,13 +607,43 @@ impl Peer {
         *self.connected.read().unwrap()
     }
 
+    /// Close the connection with the peer
+    ///
+    /// Any thread blocked waiting for a message from this peer will eventually time out.
+    pub fn disconnect(&self) {
+        *self.connected.write().unwrap() = false;
+        self.writer.lock().unwrap().shutdown(Shutdown::Both).ok();
+    }
+
+    /// Ask the peer for the addresses of other peers it knows about
+    ///
+    /// The addresses are received in the background and can be collected later with
+    /// [`take_announced_addresses`](Peer::take_announced_addresses).
+    pub(crate) fn request_addresses(&self) -> Result<(), CompactFiltersError> {
+        self.send(NetworkMessage::GetAddr)
+    }
+
+    /// Return the addresses announced by the peer since the last call
+    pub(crate) fn take_announced_addresses(&self) -> Vec<AnnouncedAddress> {
+        std::mem::take(&mut *self.announced.lock().unwrap())
+    }
+
+    /// Return the minimum fee rate in sat/kvB of the transactions accepted by the peer, if it has
+    /// sent a `feefilter` message
+    pub fn get_fee_filter(&self) -> Option<u64> {
+        *self.fee_filter.read().unwrap()
+    }
+
     /// Internal function called once the `reader_thread` is spawned
+    #[allow(clippy::too_many_arguments)]
     fn reader_thread(
         network: Network,
-        connection: TcpStream,
+        mut connection: TcpStream,
         reader_thread_responses: Arc<RwLock<ResponsesMap>>,
         reader_thread_writer: Arc<Mutex<TcpStream>>,
         reader_thread_mempool: Arc<Mempool>,
+        reader_thread_announced: Arc<Mutex<Vec<AnnouncedAddress>>>,
+        reader_thread_fee_filter: Arc<RwLock<Option<u64>>>,
         reader_thread_connected: Arc<RwLock<bool>>,
     ) {
         macro_rules! check_disconnect {
@@ -308,19 +660,40 @@ impl Peer {
             };
         }
 
-        let mut reader = StreamReader::new(connection, None);
         loop {
-            let raw_message: RawNetworkMessage = check_disconnect!(reader.read_next());
-
-            let in_message = if raw_message.magic != network.magic() {
-                continue;
-            } else {
-                raw_message.payload
+            let in_message = match check_disconnect!(read_message(&mut connection)) {
+                (magic, _) if magic != network.magic() => continue,
+                (_, PeerMessage::Known(message)) => message,
+                (_, PeerMessage::AddrV2(addresses)) => {
+                    log::trace!("<== addrv2 {:?}", addresses);
+
+                    let mut announced = reader_thread_announced.lock().unwrap();
+                    announced.extend(addresses);
+                    announced.truncate(MAX_ANNOUNCED_ADDRESSES);
+                    continue;
+                }
+                (_, PeerMessage::Unknown(command)) => {
+                    log::trace!("<== {} (ignored)", command);
+                    continue;
+                }
             };
 
             log::trace!("<== {:?}", in_message);
 
             match in_message {
+                NetworkMessage::Addr(ref addresses) => {
+                    let mut announced = reader_thread_announced.lock().unwrap();
+                    announced.extend(addresses.iter().filter_map(|(time, address)| {
+                        Some(AnnouncedAddress {
+                            address: address.socket_addr().ok()?.to_string(),
+                            services: address.services,
+                            time: *time,
+                        })
+                    }));
+                    announced.truncate(MAX_ANNOUNCED_ADDRESSES);
+
+                    continue;
+                }
                 NetworkMessage::Ping(nonce) => {
                     check_disconnect!(Self::_send(
                         &mut reader_thread_writer.lock().unwrap(),
@@ -331,6 +704,44 @@ impl Peer {
                     continue;
                 }
                 NetworkMessage::Alert(_) => continue,
+                NetworkMessage::FeeFilter(fee_rate) => {
+                    if fee_rate >= 0 {
+                        *reader_thread_fee_filter.write().unwrap() = Some(fee_rate as u64);
+                    }
+
+                    continue;
+                }
+                NetworkMessage::Inv(ref inv) => {
+                    let getdata = inv
+                        .iter()
+                        .filter_map(|item| match item {
+                            Inventory::Transaction(txid)
+                                if !reader_thread_mempool.has_tx(txid)
+                                    && reader_thread_mempool.mark_seen(*txid) =>
+                            {
+                                Some(Inventory::WitnessTransaction(*txid))
+                            }
+                            _ => None,
+                        })
+                        .collect::<Vec<_>>();
+
+                    if !getdata.is_empty() {
+                        check_disconnect!(Self::_send(
+                            &mut reader_thread_writer.lock().unwrap(),
+                            network.magic(),
+                            NetworkMessage::GetData(getdata),
+                        ));
+                    }
+
+                    continue;
+                }
+                NetworkMessage::Tx(ref tx) => {
+                    if reader_thread_mempool.add_announced_tx(tx.clone()) {
+                        log::debug!("Received relevant unconfirmed tx {}", tx.txid());
+                    }
+
+                    continue;
+                }
                 NetworkMessage::GetData(ref inv) => {
                     let (found, not_found): (Vec<_>, Vec<_>) = inv
                         .into_iter()
@@ -370,6 +781,21 @@ impl Peer {
         }
     }
 
+    /// Send a `ping` and wait for the matching `pong`
+    fn ping(&self) -> Result<(), CompactFiltersError> {
+        let nonce = thread_rng().gen();
+        self.send(NetworkMessage::Ping(nonce))?;
+
+        loop {
+            match self.recv("pong", Some(Duration::from_secs(TIMEOUT_SECS)))? {
+                Some(NetworkMessage::Pong(pong)) if pong == nonce => return Ok(()),
+                Some(NetworkMessage::Pong(_)) => continue,
+                None => return Err(CompactFiltersError::Timeout),
+                _ => return Err(CompactFiltersError::InvalidResponse),
+            }
+        }
+    }
+
     /// Send a raw Bitcoin message to the peer
     pub fn send(&self, payload: NetworkMessage) -> Result<(), CompactFiltersError> {
         let mut writer = self.writer.lock().unwrap();
@@ -386,6 +812,147 @@ impl Peer {
     }
 }
 
+/// A message received from a peer
+pub(crate) enum PeerMessage {
+    /// A message supported by [`NetworkMessage`]
+    Known(NetworkMessage),
+    /// An `addrv2` message (BIP155)
+    AddrV2(Vec<AnnouncedAddress>),
+    /// Any other message, identified by its command
+    Unknown(String),
+}
+
+/// Read the next message from the stream
+///
+/// Unlike [`RawNetworkMessage`]'s decoder this doesn't fail on messages with an unknown
+/// command, which are returned as [`PeerMessage::Unknown`] so that the connection can be kept
+/// open.
+pub(crate) fn read_message<R: Read>(
+    reader: &mut R,
+) -> Result<(u32, PeerMessage), CompactFiltersError> {
+    let mut header = [0u8; 24];
+    reader.read_exact(&mut header)?;
+
+    let magic = u32::from_le_bytes([header[0], header[1], header[2], header[3]]);
+    let command = String::from_utf8_lossy(&header[4..16])
+        .trim_end_matches('\0')
+        .to_string();
+    let length = u32::from_le_bytes([header[16], header[17], header[18], header[19]]) as usize;
+    if length > MAX_MESSAGE_SIZE {
+        return Err(CompactFiltersError::InvalidResponse);
+    }
+
+    let mut raw_message = header.to_vec();
+    raw_message.resize(24 + length, 0);
+    reader.read_exact(&mut raw_message[24..])?;
+
+    let message = match command.as_str() {
+        "addrv2" => {
+            let mut cursor = Cursor::new(&raw_message[16..]);
+            let payload = CheckedData::consensus_decode(&mut cursor)
+                .map_err(|_| CompactFiltersError::InvalidResponse)?;
+            PeerMessage::AddrV2(
+                parse_addrv2(&payload.0).map_err(|_| CompactFiltersError::InvalidResponse)?,
+            )
+        }
+        _ => match deserialize::<RawNetworkMessage>(&raw_message) {
+            Ok(message) => PeerMessage::Known(message.payload),
+            Err(encode::Error::UnrecognizedNetworkCommand(command)) => {
+                PeerMessage::Unknown(command)
+            }
+            Err(_) => return Err(CompactFiltersError::InvalidResponse),
+        },
+    };
+
+    Ok((magic, message))
+}
+
+/// Parse the payload of an `addrv2` message (BIP155)
+///
+/// Addresses on networks we can't connect to are skipped.
+fn parse_addrv2(payload: &[u8]) -> Result<Vec<AnnouncedAddress>, encode::Error> {
+    let mut cursor = Cursor::new(payload);
+
+    let num = VarInt::consensus_decode(&mut cursor)?.0;
+    if num > 1000 {
+        return Err(encode::Error::ParseFailed("Too many addresses"));
+    }
+
+    let mut addresses = Vec::new();
+    for _ in 0..num {
+        let time = u32::consensus_decode(&mut cursor)?;
+        let services = ServiceFlags::from(VarInt::consensus_decode(&mut cursor)?.0);
+        let network_id = u8::consensus_decode(&mut cursor)?;
+        let raw_address = Vec::<u8>::consensus_decode(&mut cursor)?;
+        let port = u16::from_be_bytes(<[u8; 2]>::consensus_decode(&mut cursor)?);
+
+        let ip = match (network_id, raw_address.len()) {
+            (0x01, 4) => {
+                let mut octets = [0u8; 4];
+                octets.copy_from_slice(&raw_address);
+                Ipv4Addr::from(octets).into()
+            }
+            (0x02, 16) => {
+                let mut octets = [0u8; 16];
+                octets.copy_from_slice(&raw_address);
+                Ipv6Addr::from(octets).into()
+            }
+            (0x04, 32) => {
+                addresses.push(AnnouncedAddress {
+                    address: format!("{}:{}", encode_onion_v3(&raw_address), port),
+                    services,
+                    time,
+                });
+                continue;
+            }
+            (0x01, _) | (0x02, _) | (0x04, _) => {
+                return Err(encode::Error::ParseFailed("Invalid address length"))
+            }
+            _ => continue,
+        };
+
+        addresses.push(AnnouncedAddress {
+            address: SocketAddr::new(ip, port).to_string(),
+            services,
+            time,
+        });
+    }
+
+    Ok(addresses)
+}
+
+/// Encode a Tor v3 public key as an `.onion` host name
+fn encode_onion_v3(pubkey: &[u8]) -> String {
+    const ALPHABET: &[u8] = b"abcdefghijklmnopqrstuvwxyz234567";
+    const VERSION: u8 = 0x03;
+
+    let mut hasher = Sha3_256::new();
+    hasher.update(b".onion checksum");
+    hasher.update(pubkey);
+    hasher.update([VERSION]);
+    let checksum = hasher.finalize();
+
+    let mut data = pubkey.to_vec();
+    data.extend_from_slice(&checksum[..2]);
+    data.push(VERSION);
+
+    // base32 without padding, 35 bytes are always encoded as 56 characters
+    let mut encoded = String::with_capacity(62);
+    let mut buffer = 0u16;
+    let mut bits = 0;
+    for byte in data {
+        buffer = (buffer << 8) | byte as u16;
+        bits += 8;
+        while bits >= 5 {
+            bits -= 5;
+            encoded.push(ALPHABET[((buffer >> bits) & 0x1f) as usize] as char);
+        }
+    }
+    encoded.push_str(".onion");
+
+    encoded
+}
+
 pub trait CompactFiltersPeer {
     fn get_cf_checkpt(
         &self,
@@ -490,6 +1057,11 @@ impl CompactFiltersPeer for Peer {
 
 pub trait InvPeer {
     fn get_block(&self, block_hash: BlockHash) -> Result<Option<Block>, CompactFiltersError>;
+    /// Ask the peer to announce the transactions in its mempool
+    ///
+    /// This does nothing if the peer doesn't advertise `NODE_BLOOM`, since most peers only reply
+    /// to `mempool` messages when they do. The transactions of those peers are only received as
+    /// they are announced.
     fn ask_for_mempool(&self) -> Result<(), CompactFiltersError>;
     fn broadcast_tx(&self, tx: Transaction) -> Result<(), CompactFiltersError>;
 }
@@ -508,35 +1080,19 @@ impl InvPeer for Peer {
     }
 
     fn ask_for_mempool(&self) -> Result<(), CompactFiltersError> {
+        // Peers that don't support bloom filters disconnect the ones that send `mempool`, for
+        // them we only rely on the transactions they announce
+        if !self.version.services.has(ServiceFlags::BLOOM) {
+            return Ok(());
+        }
+
         self.send(NetworkMessage::MemPool)?;
-        let inv = match self.recv("inv", Some(Duration::from_secs(5)))? {
-            None => return Ok(()), // empty mempool
-            Some(NetworkMessage::Inv(inv)) => inv,
-            _ => return Err(CompactFiltersError::InvalidResponse),
-        };
 
-        let getdata = inv
-            .iter()
-            .cloned()
-            .filter(|item| match item {
-                Inventory::Transaction(txid) if !self.mempool.has_tx(txid) => true,
-                _ => false,
-            })
-            .collect::<Vec<_>>();
-        let num_txs = getdata.len();
-        self.send(NetworkMessage::GetData(getdata))?;
-
-        for _ in 0..num_txs {
-            let tx = self
-                .recv("tx", Some(Duration::from_secs(TIMEOUT_SECS)))?
-                .ok_or(CompactFiltersError::Timeout)?;
-            let tx = match tx {
-                NetworkMessage::Tx(tx) => tx,
-                _ => return Err(CompactFiltersError::InvalidResponse),
-            };
-
-            self.mempool.add_tx(tx);
-        }
+        // The `inv`s and `tx`s are handled by the reader thread. Peers process messages in order,
+        // so once the first `pong` is received our `getdata` has been sent, and once the second
+        // one is received all the requested transactions have been received too
+        self.ping()?;
+        self.ping()?;
 
         Ok(())
     }
@@ -548,3 +1104,243 @@ impl InvPeer for Peer {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod test {
+    use bitcoin::hashes::hex::FromHex;
+    use bitcoin::network::constants::ServiceFlags;
+    use bitcoin::{TxIn, TxOut};
+
+    use super::*;
+
+    #[test]
+    fn test_parse_addrv2() {
+        // IPv4 1.2.3.4:8333, IPv6 [::1]:18333, a TorV3 address and an I2P address, which is
+        // skipped
+        let payload = Vec::<u8>::from_hex(
+            "04\
+             e8030000 49 01 04 01020304 208d\
+             e8030000 01 02 10 00000000000000000000000000000001 479d\
+             e8030000 49 04 20 abababababababababababababababababababababababababababababababab 208d\
+             e8030000 01 05 20 0000000000000000000000000000000000000000000000000000000000000000 0000"
+                .replace(' ', "")
+                .as_str(),
+        )
+        .unwrap();
+
+        let addresses = parse_addrv2(&payload).unwrap();
+        assert_eq!(
+            addresses,
+            vec![
+                AnnouncedAddress {
+                    address: "1.2.3.4:8333".into(),
+                    services: ServiceFlags::NETWORK
+                        | ServiceFlags::WITNESS
+                        | ServiceFlags::COMPACT_FILTERS,
+                    time: 1000,
+                },
+                AnnouncedAddress {
+                    address: "[::1]:18333".into(),
+                    services: ServiceFlags::NETWORK,
+                    time: 1000,
+                },
+                AnnouncedAddress {
+                    address: "vov2xk5lvov2xk5lvov2xk5lvov2xk5lvov2xk5lvov2xk5lvovqm2qd.onion:8333"
+                        .into(),
+                    services: ServiceFlags::NETWORK
+                        | ServiceFlags::WITNESS
+                        | ServiceFlags::COMPACT_FILTERS,
+                    time: 1000,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_encode_onion_v3() {
+        assert_eq!(
+            encode_onion_v3(&[0u8; 32]),
+            "aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaam2dqd.onion"
+        );
+    }
+
+    #[test]
+    fn test_mempool_relevant_txs() {
+        let mempool = Mempool::default();
+        let script = Script::from(vec![0x00, 0x14, 0x42]);
+        let outpoint = OutPoint::new(Txid::hash(&[1]), 0);
+
+        let receiving = Transaction {
+            version: 1,
+            lock_time: 0,
+            input: vec![],
+            output: vec![TxOut {
+                value: 50_000,
+                script_pubkey: script.clone(),
+            }],
+        };
+        let spending = Transaction {
+            version: 1,
+            lock_time: 0,
+            input: vec![TxIn {
+                previous_output: outpoint,
+                ..Default::default()
+            }],
+            output: vec![],
+        };
+        let unrelated = Transaction {
+            version: 1,
+            lock_time: 0,
+            input: vec![],
+            output: vec![TxOut {
+                value: 10_000,
+                script_pubkey: Script::new(),
+            }],
+        };
+
+        // nothing is watched yet
+        assert!(!mempool.add_announced_tx(receiving.clone()));
+
+        mempool.watch(vec![script], vec![outpoint]);
+        assert!(mempool.add_announced_tx(receiving.clone()));
+        assert!(mempool.add_announced_tx(spending.clone()));
+        assert!(!mempool.add_announced_tx(unrelated.clone()));
+
+        assert!(mempool.has_tx(&receiving.txid()));
+        assert!(mempool.has_tx(&spending.txid()));
+        assert!(!mempool.has_tx(&unrelated.txid()));
+
+        assert!(mempool.remove_tx(&receiving.txid()).is_some());
+        assert_eq!(mempool.iter_txs(), vec![spending]);
+    }
+
+    #[test]
+    fn test_mempool_mark_seen() {
+        let mempool = Mempool::default();
+        let txid = Txid::hash(&[1]);
+
+        assert!(mempool.mark_seen(txid));
+        assert!(!mempool.mark_seen(txid));
+
+        // txs that weren't relevant before can be requested again
+        mempool.watch(vec![], vec![]);
+        assert!(mempool.mark_seen(txid));
+    }
+
+    #[test]
+    fn test_mempool_remove_conflicts() {
+        let mempool = Mempool::default();
+        let script = Script::from(vec![0x00, 0x14, 0x42]);
+        let outpoint = OutPoint::new(Txid::hash(&[1]), 0);
+        mempool.watch(vec![script.clone()], vec![outpoint]);
+
+        let spend = |previous_output, value| Transaction {
+            version: 1,
+            lock_time: 0,
+            input: vec![TxIn {
+                previous_output,
+                ..Default::default()
+            }],
+            output: vec![TxOut {
+                value,
+                script_pubkey: script.clone(),
+            }],
+        };
+        let original = spend(outpoint, 50_000);
+        let child = spend(OutPoint::new(original.txid(), 0), 40_000);
+        let unrelated = spend(OutPoint::new(Txid::hash(&[2]), 0), 10_000);
+        let replacement = spend(outpoint, 45_000);
+
+        assert!(mempool.add_announced_tx(original.clone()));
+        assert!(mempool.add_announced_tx(child.clone()));
+        assert!(mempool.add_announced_tx(unrelated.clone()));
+        assert!(mempool.add_announced_tx(replacement.clone()));
+
+        assert!(!mempool.has_tx(&original.txid()));
+        assert!(!mempool.has_tx(&child.txid()));
+        assert!(mempool.has_tx(&unrelated.txid()));
+        assert!(mempool.has_tx(&replacement.txid()));
+    }
+
+    #[test]
+    fn test_mempool_remove_expired() {
+        let mempool = Mempool::default();
+        let tx = Transaction {
+            version: 1,
+            lock_time: 0,
+            input: vec![],
+            output: vec![],
+        };
+        mempool.add_tx(tx.clone());
+
+        mempool.remove_expired(Duration::from_secs(MEMPOOL_EXPIRY_SECS));
+        assert!(mempool.has_tx(&tx.txid()));
+
+        mempool.remove_expired(Duration::from_secs(0));
+        assert!(!mempool.has_tx(&tx.txid()));
+    }
+
+    #[test]
+    fn test_parse_addrv2_invalid_length() {
+        let payload = Vec::<u8>::from_hex("01e803000001010301020308d8").unwrap();
+
+        assert!(parse_addrv2(&payload).is_err());
+    }
+
+    #[test]
+    fn test_read_unknown_message() {
+        let mut raw_message = Network::Bitcoin.magic().to_le_bytes().to_vec();
+        raw_message.extend_from_slice(b"wtxidrelay\0\0");
+        raw_message.extend_from_slice(&0u32.to_le_bytes());
+        raw_message.extend_from_slice(&sha256d::Hash::hash(&[])[..4]);
+        // followed by a known message
+        raw_message.extend(bitcoin::consensus::serialize(&RawNetworkMessage {
+            magic: Network::Bitcoin.magic(),
+            payload: NetworkMessage::Verack,
+        }));
+
+        let mut reader = Cursor::new(raw_message);
+        match read_message(&mut reader).unwrap() {
+            (magic, PeerMessage::Unknown(command)) => {
+                assert_eq!(magic, Network::Bitcoin.magic());
+                assert_eq!(command, "wtxidrelay");
+            }
+            _ => panic!("expected an unknown message"),
+        }
+        match read_message(&mut reader).unwrap() {
+            (_, PeerMessage::Known(NetworkMessage::Verack)) => {}
+            _ => panic!("expected a verack"),
+        }
+    }
+
+    #[test]
+    fn test_read_message_too_large() {
+        let mut raw_message = Network::Bitcoin.magic().to_le_bytes().to_vec();
+        raw_message.extend_from_slice(b"block\0\0\0\0\0\0\0");
+        raw_message.extend_from_slice(&(MAX_MESSAGE_SIZE as u32 + 1).to_le_bytes());
+        raw_message.extend_from_slice(&[0; 4]);
+
+        assert!(matches!(
+            read_message(&mut Cursor::new(raw_message)),
+            Err(CompactFiltersError::InvalidResponse)
+        ));
+    }
+
+    #[test]
+    fn test_socks5_timeout() {
+        // a proxy that accepts connections but never replies
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut proxy = Socks5Proxy::new(listener.local_addr().unwrap()).unwrap();
+        proxy.timeout = Duration::from_millis(100);
+
+        assert!(matches!(
+            Peer::connect_socks5(
+                "127.0.0.1:8333",
+                proxy,
+                Arc::new(Mempool::default()),
+                Network::Bitcoin
+            ),
+            Err(CompactFiltersError::Timeout)
+        ));
+    }
+}
@@ -29,6 +29,7 @@ use std::marker::PhantomData;
 use std::ops::Deref;
 use std::sync::Arc;
 use std::sync::RwLock;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 use rand::distributions::Alphanumeric;
 use rand::{thread_rng, Rng};
@@ -39,6 +40,7 @@ use bitcoin::consensus::{deserialize, encode::VarInt, serialize, Decodable, Enco
 use bitcoin::hash_types::FilterHash;
 use bitcoin::hashes::hex::FromHex;
 use bitcoin::hashes::{sha256d, Hash};
+use bitcoin::network::constants::ServiceFlags;
 use bitcoin::util::bip158::BlockFilter;
 use bitcoin::util::uint::Uint256;
 use bitcoin::Block;
@@ -46,7 +48,9 @@ use bitcoin::BlockHash;
 use bitcoin::BlockHeader;
 use bitcoin::Network;
 
+use super::checkpoints::HeaderCheckpoint;
 use super::CompactFiltersError;
+use crate::types::WalletBirthday;
 
 lazy_static! {
     static ref MAINNET_GENESIS: Block = deserialize(&Vec::<u8>::from_hex("0100000000000000000000000000000000000000000000000000000000000000000000003BA3EDFD7A7B12B27AC72C3E67768F617FC81BC3888A51323A9FB8AA4B1E5E4A29AB5F49FFFF001D1DAC2B7C0101000000010000000000000000000000000000000000000000000000000000000000000000FFFFFFFF4D04FFFF001D0104455468652054696D65732030332F4A616E2F32303039204368616E63656C6C6F72206F6E206272696E6B206F66207365636F6E64206261696C6F757420666F722062616E6B73FFFFFFFF0100F2052A01000000434104678AFDB0FE5548271967F1A67130B7105CD6A828E03909A67962E0EA1F61DEB649F6BC3F4CEF38C4F35504E51EC112DE5C384DF7BA0B8D578A4C702B6BF11D5FAC00000000").unwrap()).unwrap();
@@ -68,6 +72,13 @@ pub enum StoreEntry {
     Block(Option<usize>),
     BlockHeaderIndex(Option<BlockHash>),
     CFilterTable((u8, Option<usize>)),
+    BannedPeer(Option<String>),
+    PeerAddress(Option<String>),
+    SyncState(u8),
+    PendingReorg,
+    FeeSample(Option<usize>),
+    HeaderCheckpoint,
+    CheckpointFilterHeader,
 }
 
 impl StoreEntry {
@@ -77,6 +88,13 @@ impl StoreEntry {
             StoreEntry::Block(_) => b"x",
             StoreEntry::BlockHeaderIndex(_) => b"i",
             StoreEntry::CFilterTable(_) => b"t",
+            StoreEntry::BannedPeer(_) => b"b",
+            StoreEntry::PeerAddress(_) => b"a",
+            StoreEntry::SyncState(_) => b"s",
+            StoreEntry::PendingReorg => b"r",
+            StoreEntry::FeeSample(_) => b"f",
+            StoreEntry::HeaderCheckpoint => b"c",
+            StoreEntry::CheckpointFilterHeader => b"h",
         }
         .to_vec()
     }
@@ -97,6 +115,10 @@ impl StoreEntry {
                     prefix.extend_from_slice(&bundle_index.to_be_bytes());
                 }
             }
+            StoreEntry::BannedPeer(Some(address)) => prefix.extend_from_slice(address.as_bytes()),
+            StoreEntry::PeerAddress(Some(address)) => prefix.extend_from_slice(address.as_bytes()),
+            StoreEntry::SyncState(filter_type) => prefix.push(*filter_type),
+            StoreEntry::FeeSample(Some(height)) => prefix.extend_from_slice(&height.to_be_bytes()),
             _ => {}
         }
 
@@ -295,7 +317,81 @@ impl ChainStore<Full> {
         })
     }
 
+    /// Start the chain from `checkpoint` instead of the genesis, given the `header` of the block
+    ///
+    /// This can only be done before syncing any header: the block at the checkpoint becomes the
+    /// oldest one we know after the genesis, and the headers of the blocks before it are never
+    /// downloaded.
+    pub fn start_from_checkpoint(
+        &self,
+        checkpoint: &HeaderCheckpoint,
+        header: BlockHeader,
+    ) -> Result<(), CompactFiltersError> {
+        if header.block_hash() != checkpoint.hash {
+            return Err(CompactFiltersError::InvalidHeaders);
+        }
+        if self.get_height()? != 0 {
+            return Err(CompactFiltersError::DataCorruption);
+        }
+
+        let read_store = self.store.read().unwrap();
+        let cf_handle = read_store.cf_handle(&self.cf_name).unwrap();
+
+        let mut batch = WriteBatch::default();
+        batch.put_cf(
+            cf_handle,
+            StoreEntry::BlockHeader(Some(checkpoint.height)).get_key(),
+            (header, checkpoint.chain_work).serialize(),
+        );
+        batch.put_cf(
+            cf_handle,
+            StoreEntry::BlockHeaderIndex(Some(checkpoint.hash)).get_key(),
+            &checkpoint.height.to_be_bytes(),
+        );
+        batch.put(
+            StoreEntry::HeaderCheckpoint.get_key(),
+            &checkpoint.height.to_be_bytes(),
+        );
+        if let Some(filter_header) = checkpoint.filter_header {
+            batch.put(
+                StoreEntry::CheckpointFilterHeader.get_key(),
+                filter_header.serialize(),
+            );
+        }
+        read_store.write(batch)?;
+
+        Ok(())
+    }
+
+    /// Return the height of the checkpoint the chain was started from, or zero if it was started
+    /// from the genesis
+    pub fn get_checkpoint_height(&self) -> Result<usize, CompactFiltersError> {
+        let read_store = self.store.read().unwrap();
+        let data = read_store.get_pinned(StoreEntry::HeaderCheckpoint.get_key())?;
+
+        Ok(data
+            .map(|data| {
+                Ok::<_, CompactFiltersError>(usize::from_be_bytes(
+                    data.as_ref()
+                        .try_into()
+                        .map_err(|_| CompactFiltersError::DataCorruption)?,
+                ))
+            })
+            .transpose()?
+            .unwrap_or(0))
+    }
+
+    /// Return the basic filter header of the checkpoint the chain was started from, if known
+    pub fn get_checkpoint_filter_header(&self) -> Result<Option<FilterHash>, CompactFiltersError> {
+        let read_store = self.store.read().unwrap();
+        let data = read_store.get_pinned(StoreEntry::CheckpointFilterHeader.get_key())?;
+
+        data.map(|data| FilterHash::deserialize(&data)).transpose()
+    }
+
     pub fn get_locators(&self) -> Result<Vec<(BlockHash, usize)>, CompactFiltersError> {
+        let checkpoint_height = self.get_checkpoint_height()?;
+
         let mut step = 1;
         let mut index = self.get_height()?;
         let mut answer = Vec::new();
@@ -315,10 +411,11 @@ impl ChainStore<Full> {
             )?;
             answer.push((header.block_hash(), index));
 
-            if let Some(new_index) = index.checked_sub(step) {
-                index = new_index;
-            } else {
-                break;
+            // We don't have the headers before the checkpoint, which is always the last locator
+            match index.checked_sub(step) {
+                Some(new_index) if new_index >= checkpoint_height => index = new_index,
+                Some(_) if index > checkpoint_height => index = checkpoint_height,
+                _ => break,
             }
         }
 
@@ -433,11 +530,28 @@ impl ChainStore<Full> {
             );
         }
 
-        // Delete full blocks overriden by snapshot
-        let from_key = StoreEntry::Block(Some(snaphost.min_height)).get_key();
+        // Delete full blocks overriden by snapshot. The first header of the snapshot is the last
+        // one in common with our chain, so the block at that height is still valid
+        let fork_height = snaphost.min_height + 1;
+        let from_key = StoreEntry::Block(Some(fork_height)).get_key();
         let to_key = StoreEntry::Block(Some(usize::MAX)).get_key();
         batch.delete_range(&from_key, &to_key);
 
+        // Remember where the chains diverged so that the filters and the wallet can be rewound.
+        // If the previous reorg hasn't been handled yet we keep the lowest of the two heights
+        if fork_height <= self.get_height()? {
+            let fork_height = match self.get_pending_reorg()? {
+                Some(pending) if pending < fork_height => pending,
+                _ => fork_height,
+            };
+
+            log::debug!("Reorg detected, fork at height {}", fork_height);
+            batch.put(
+                StoreEntry::PendingReorg.get_key(),
+                (fork_height as u32).serialize(),
+            );
+        }
+
         log::debug!("Copying over new items");
         for (k, v) in read_store.iterator_cf(snapshot_cf_handle, IteratorMode::Start) {
             batch.put_cf(cf_handle, k, v);
@@ -454,6 +568,25 @@ impl ChainStore<Full> {
         Ok(())
     }
 
+    /// Return the first height replaced by the last reorg, if it hasn't been handled yet
+    pub fn get_pending_reorg(&self) -> Result<Option<usize>, CompactFiltersError> {
+        let read_store = self.store.read().unwrap();
+
+        read_store
+            .get_pinned(StoreEntry::PendingReorg.get_key())?
+            .map(|data| Ok(u32::deserialize(&data)? as usize))
+            .transpose()
+    }
+
+    pub fn clear_pending_reorg(&self) -> Result<(), CompactFiltersError> {
+        self.store
+            .read()
+            .unwrap()
+            .delete(StoreEntry::PendingReorg.get_key())?;
+
+        Ok(())
+    }
+
     pub fn get_height_for(
         &self,
         block_hash: &BlockHash,
@@ -489,6 +622,52 @@ impl ChainStore<Full> {
             .transpose()?)
     }
 
+    pub fn get_block_time(&self, height: usize) -> Result<Option<u32>, CompactFiltersError> {
+        let read_store = self.store.read().unwrap();
+        let cf_handle = read_store.cf_handle(&self.cf_name).unwrap();
+
+        let key = StoreEntry::BlockHeader(Some(height)).get_key();
+        let data = read_store.get_pinned_cf(cf_handle, key)?;
+        Ok(data
+            .map(|data| {
+                let (header, _): (BlockHeader, Uint256) =
+                    deserialize(&data).map_err(|_| CompactFiltersError::DataCorruption)?;
+                Ok::<_, CompactFiltersError>(header.time)
+            })
+            .transpose()?)
+    }
+
+    /// Return the height of the first block that may contain transactions of a wallet with the
+    /// given `birthday`
+    ///
+    /// Timestamps are looked up in the headers we have: if none of them is recent enough, the
+    /// height after our tip is returned.
+    pub fn get_birthday_height(
+        &self,
+        birthday: WalletBirthday,
+    ) -> Result<usize, CompactFiltersError> {
+        if let WalletBirthday::Height(height) = birthday {
+            return Ok(height as usize);
+        }
+
+        let mut low = self.get_checkpoint_height()?;
+        let mut high = self.get_height()? + 1;
+        while low < high {
+            let mid = low + (high - low) / 2;
+            let time = self
+                .get_block_time(mid)?
+                .ok_or(CompactFiltersError::DataCorruption)?;
+
+            if birthday.covers(mid as u32, time as u64) {
+                high = mid;
+            } else {
+                low = mid + 1;
+            }
+        }
+
+        Ok(low)
+    }
+
     pub fn save_full_block(&self, block: &Block, height: usize) -> Result<(), CompactFiltersError> {
         let key = StoreEntry::Block(Some(height)).get_key();
         self.store.read().unwrap().put(key, block.serialize())?;
@@ -520,6 +699,54 @@ impl ChainStore<Full> {
         Ok(())
     }
 
+    /// Save the average fee rate of the block at `height`, in sat/kvB
+    pub fn save_fee_sample(
+        &self,
+        height: usize,
+        block_hash: BlockHash,
+        fee_rate: u64,
+    ) -> Result<(), CompactFiltersError> {
+        let key = StoreEntry::FeeSample(Some(height)).get_key();
+        self.store
+            .read()
+            .unwrap()
+            .put(key, (block_hash, fee_rate).serialize())?;
+
+        Ok(())
+    }
+
+    /// Return the average fee rate of the block at `height`, if it has been saved and the block
+    /// is still part of our chain
+    pub fn get_fee_sample(&self, height: usize) -> Result<Option<u64>, CompactFiltersError> {
+        let key = StoreEntry::FeeSample(Some(height)).get_key();
+        let sample = self
+            .store
+            .read()
+            .unwrap()
+            .get_pinned(key)?
+            .map(|data| SerializeDb::deserialize(&data))
+            .transpose()?;
+
+        match sample {
+            Some((block_hash, fee_rate)) if Some(block_hash) == self.get_block_hash(height)? => {
+                Ok(Some(fee_rate))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    pub fn delete_fee_samples_until(&self, height: usize) -> Result<(), CompactFiltersError> {
+        let from_key = StoreEntry::FeeSample(Some(0)).get_key();
+        let to_key = StoreEntry::FeeSample(Some(height)).get_key();
+
+        let mut batch = WriteBatch::default();
+        batch.delete_range(&from_key, &to_key);
+
+        self.store.read().unwrap().write(batch)?;
+
+        Ok(())
+    }
+
     pub fn iter_full_blocks(&self) -> Result<Vec<(usize, Block)>, CompactFiltersError> {
         let read_store = self.store.read().unwrap();
 
@@ -545,6 +772,10 @@ impl ChainStore<Full> {
 }
 
 impl<T: StoreType> ChainStore<T> {
+    pub fn get_min_height(&self) -> usize {
+        self.min_height
+    }
+
     pub fn work(&self) -> Result<Uint256, CompactFiltersError> {
         let read_store = self.store.read().unwrap();
         let cf_handle = read_store.cf_handle(&self.cf_name).unwrap();
@@ -671,11 +902,32 @@ pub struct FilterHeader {
 }
 
 impl FilterHeader {
-    fn header_hash(&self) -> FilterHeaderHash {
+    pub fn header_hash(&self) -> FilterHeaderHash {
         let mut hash_data = self.filter_hash.into_inner().to_vec();
         hash_data.extend_from_slice(&self.prev_header_hash);
         sha256d::Hash::hash(&hash_data).into()
     }
+
+    pub fn filter_hash(&self) -> &FilterHash {
+        &self.filter_hash
+    }
+
+    /// Build the chain of filter headers starting from `prev_header_hash`
+    pub fn chain(prev_header_hash: FilterHeaderHash, filter_hashes: Vec<FilterHash>) -> Vec<Self> {
+        let mut last_hash = prev_header_hash;
+        filter_hashes
+            .into_iter()
+            .map(|filter_hash| {
+                let filter_header = FilterHeader {
+                    prev_header_hash: last_hash,
+                    filter_hash,
+                };
+                last_hash = filter_header.header_hash();
+
+                filter_header
+            })
+            .collect()
+    }
 }
 
 pub enum BundleStatus {
@@ -693,6 +945,8 @@ pub struct CFStore {
 }
 
 type BundleEntry = (BundleStatus, FilterHeaderHash);
+/// Last height scanned and scripts used to scan the filters
+pub type SyncState = (usize, Vec<Vec<u8>>);
 
 impl CFStore {
     pub fn new(
@@ -747,6 +1001,20 @@ impl CFStore {
             .collect::<Result<_, _>>()
     }
 
+    pub fn get_checkpoint(
+        &self,
+        bundle: usize,
+    ) -> Result<Option<FilterHeaderHash>, CompactFiltersError> {
+        let key = StoreEntry::CFilterTable((self.filter_type, Some(bundle))).get_key();
+        let read_store = self.store.read().unwrap();
+
+        Ok(read_store
+            .get_pinned(&key)?
+            .map(|data| BundleEntry::deserialize(&data))
+            .transpose()?
+            .map(|(_, checkpoint)| checkpoint))
+    }
+
     pub fn get_checkpoints(&self) -> Result<Vec<FilterHash>, CompactFiltersError> {
         let read_store = self.store.read().unwrap();
 
@@ -787,11 +1055,16 @@ impl CFStore {
         for (index, filter_hash) in checkpoints.iter().enumerate().skip(equal_bundles) {
             let key = StoreEntry::CFilterTable((self.filter_type, Some(index + 1))).get_key(); // +1 to skip the genesis' filter
 
-            if let Some((BundleStatus::Tip { .. }, _)) = read_store
+            if let Some((BundleStatus::Tip { .. }, stored_hash)) = read_store
                 .get_pinned(&key)?
                 .map(|data| BundleEntry::deserialize(&data))
                 .transpose()?
             {
+                if stored_hash != *filter_hash {
+                    batch.put(&key, (BundleStatus::Init, *filter_hash).serialize());
+                    continue;
+                }
+
                 println!("Keeping bundle #{} as Tip", index);
             } else {
                 batch.put(&key, (BundleStatus::Init, *filter_hash).serialize());
@@ -809,19 +1082,11 @@ impl CFStore {
         checkpoint_hash: FilterHeaderHash,
         filter_headers: Vec<FilterHash>,
     ) -> Result<BundleStatus, CompactFiltersError> {
-        let mut last_hash = checkpoint_hash;
-        let cf_headers = filter_headers
-            .into_iter()
-            .map(|filter_hash| {
-                let filter_header = FilterHeader {
-                    prev_header_hash: last_hash,
-                    filter_hash,
-                };
-                last_hash = filter_header.header_hash();
-
-                filter_header
-            })
-            .collect();
+        let cf_headers = FilterHeader::chain(checkpoint_hash, filter_headers);
+        let last_hash = cf_headers
+            .last()
+            .map(FilterHeader::header_hash)
+            .unwrap_or(checkpoint_hash);
 
         let read_store = self.store.read().unwrap();
 
@@ -887,6 +1152,110 @@ impl CFStore {
         Ok(value.0)
     }
 
+    pub fn reset_bundle(
+        &self,
+        bundle: usize,
+        checkpoint_hash: FilterHeaderHash,
+    ) -> Result<BundleStatus, CompactFiltersError> {
+        let key = StoreEntry::CFilterTable((self.filter_type, Some(bundle))).get_key();
+        let value = (BundleStatus::Init, checkpoint_hash);
+
+        let read_store = self.store.read().unwrap();
+        read_store.put(key, value.serialize())?;
+
+        Ok(value.0)
+    }
+
+    /// Reset every pruned or tip bundle starting from `from_bundle` to [`BundleStatus::Init`], so
+    /// that their filters are downloaded and scanned again
+    pub fn reset_scanned_bundles(&self, from_bundle: usize) -> Result<usize, CompactFiltersError> {
+        let read_store = self.store.read().unwrap();
+
+        let prefix = StoreEntry::CFilterTable((self.filter_type, None)).get_key();
+        let mut batch = WriteBatch::default();
+        let mut count = 0;
+
+        // FIXME: we have to filter manually because rocksdb sometimes returns stuff that doesn't
+        // have the right prefix
+        for (key, data) in read_store
+            .prefix_iterator(&prefix)
+            .filter(|(k, _)| k.starts_with(&prefix))
+            .skip(from_bundle)
+        {
+            match BundleEntry::deserialize(&data)? {
+                (BundleStatus::Pruned, checkpoint) | (BundleStatus::Tip { .. }, checkpoint) => {
+                    batch.put(key, (BundleStatus::Init, checkpoint).serialize());
+                    count += 1;
+                }
+                _ => {}
+            }
+        }
+
+        read_store.write(batch)?;
+
+        Ok(count)
+    }
+
+    /// Reset every bundle that contains filters for blocks at or above `fork_height`, and make
+    /// sure that the sync state doesn't include them
+    pub fn rewind(&self, fork_height: usize) -> Result<(), CompactFiltersError> {
+        let first_bundle = fork_height.saturating_sub(1) / 1000;
+
+        {
+            let read_store = self.store.read().unwrap();
+
+            let prefix = StoreEntry::CFilterTable((self.filter_type, None)).get_key();
+            let mut batch = WriteBatch::default();
+
+            // FIXME: we have to filter manually because rocksdb sometimes returns stuff that doesn't
+            // have the right prefix
+            for (key, data) in read_store
+                .prefix_iterator(&prefix)
+                .filter(|(k, _)| k.starts_with(&prefix))
+                .skip(first_bundle)
+            {
+                let (_, checkpoint) = BundleEntry::deserialize(&data)?;
+                batch.put(key, (BundleStatus::Init, checkpoint).serialize());
+            }
+
+            read_store.write(batch)?;
+        }
+
+        match self.get_sync_state()? {
+            Some((height, scripts)) if height >= fork_height => {
+                self.set_sync_state(fork_height - 1, scripts)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Return the height up to which the filters have been scanned and the scripts used to scan
+    /// them, saved at the end of the last sync
+    pub fn get_sync_state(&self) -> Result<Option<SyncState>, CompactFiltersError> {
+        let key = StoreEntry::SyncState(self.filter_type).get_key();
+        let read_store = self.store.read().unwrap();
+
+        read_store
+            .get_pinned(key)?
+            .map(|data| {
+                let (height, scripts): (u32, Vec<Vec<u8>>) = SerializeDb::deserialize(&data)?;
+                Ok((height as usize, scripts))
+            })
+            .transpose()
+    }
+
+    pub fn set_sync_state(
+        &self,
+        height: usize,
+        scripts: Vec<Vec<u8>>,
+    ) -> Result<(), CompactFiltersError> {
+        let key = StoreEntry::SyncState(self.filter_type).get_key();
+        let read_store = self.store.read().unwrap();
+        read_store.put(key, (height as u32, scripts).serialize())?;
+
+        Ok(())
+    }
+
     pub fn mark_as_tip(
         &self,
         bundle: usize,
@@ -902,3 +1271,401 @@ impl CFStore {
         Ok(value.0)
     }
 }
+
+/// Entry of the address book
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AddressEntry {
+    pub address: String,
+    pub services: ServiceFlags,
+    pub last_seen: u64,
+    pub last_tried: u64,
+    pub failures: u32,
+}
+
+impl AddressEntry {
+    pub fn new(address: String, services: ServiceFlags, last_seen: u64) -> Self {
+        AddressEntry {
+            address,
+            services,
+            last_seen,
+            last_tried: 0,
+            failures: 0,
+        }
+    }
+}
+
+pub struct PeerStore {
+    store: Arc<RwLock<DB>>,
+}
+
+impl PeerStore {
+    pub fn new(headers_store: &ChainStore<Full>) -> Self {
+        PeerStore {
+            store: Arc::clone(&headers_store.store),
+        }
+    }
+
+    pub fn ban_peer(&self, address: &str, duration: Duration) -> Result<(), CompactFiltersError> {
+        let until = SystemTime::now().duration_since(UNIX_EPOCH)? + duration;
+
+        let key = StoreEntry::BannedPeer(Some(address.to_string())).get_key();
+        let read_store = self.store.read().unwrap();
+        read_store.put(key, until.as_secs().to_be_bytes())?;
+
+        Ok(())
+    }
+
+    pub fn is_banned(&self, address: &str) -> Result<bool, CompactFiltersError> {
+        let key = StoreEntry::BannedPeer(Some(address.to_string())).get_key();
+        let read_store = self.store.read().unwrap();
+
+        let until = match read_store.get_pinned(key)? {
+            None => return Ok(false),
+            Some(data) => u64::from_be_bytes(
+                data.as_ref()
+                    .try_into()
+                    .map_err(|_| CompactFiltersError::DataCorruption)?,
+            ),
+        };
+
+        Ok(SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() < until)
+    }
+
+    pub fn get_address(&self, address: &str) -> Result<Option<AddressEntry>, CompactFiltersError> {
+        let key = StoreEntry::PeerAddress(Some(address.to_string())).get_key();
+        let read_store = self.store.read().unwrap();
+
+        read_store
+            .get_pinned(key)?
+            .map(|data| {
+                let (services, last_seen, last_tried, failures): (u64, u64, u64, u32) =
+                    SerializeDb::deserialize(&data)?;
+
+                Ok(AddressEntry {
+                    address: address.to_string(),
+                    services: services.into(),
+                    last_seen,
+                    last_tried,
+                    failures,
+                })
+            })
+            .transpose()
+    }
+
+    pub fn put_address(&self, entry: &AddressEntry) -> Result<(), CompactFiltersError> {
+        let key = StoreEntry::PeerAddress(Some(entry.address.clone())).get_key();
+        let value = (
+            entry.services.as_u64(),
+            entry.last_seen,
+            entry.last_tried,
+            entry.failures,
+        );
+
+        let read_store = self.store.read().unwrap();
+        read_store.put(key, value.serialize())?;
+
+        Ok(())
+    }
+
+    pub fn del_address(&self, address: &str) -> Result<(), CompactFiltersError> {
+        let key = StoreEntry::PeerAddress(Some(address.to_string())).get_key();
+        let read_store = self.store.read().unwrap();
+        read_store.delete(key)?;
+
+        Ok(())
+    }
+
+    pub fn iter_addresses(&self) -> Result<Vec<AddressEntry>, CompactFiltersError> {
+        let read_store = self.store.read().unwrap();
+
+        let prefix = StoreEntry::PeerAddress(None).get_key();
+        let iterator = read_store.prefix_iterator(&prefix);
+
+        // FIXME: we have to filter manually because rocksdb sometimes returns stuff that doesn't
+        // have the right prefix
+        iterator
+            .filter(|(k, _)| k.starts_with(&prefix))
+            .map(|(k, v)| {
+                let address = String::from_utf8(k[prefix.len()..].to_vec())
+                    .map_err(|_| CompactFiltersError::DataCorruption)?;
+                let (services, last_seen, last_tried, failures): (u64, u64, u64, u32) =
+                    SerializeDb::deserialize(&v)?;
+
+                Ok(AddressEntry {
+                    address,
+                    services: services.into(),
+                    last_seen,
+                    last_tried,
+                    failures,
+                })
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    use bitcoin::blockdata::constants::genesis_block;
+    use bitcoin::Network;
+    use rand::{thread_rng, Rng};
+    use rocksdb::{Options, DB};
+
+    use super::*;
+
+    /// Return a new, unique directory to store the database in
+    pub fn get_storage_dir() -> std::path::PathBuf {
+        let mut dir = std::env::temp_dir();
+        dir.push(format!(
+            "bdk_cf_{}_{}",
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_nanos(),
+            thread_rng().gen::<u32>()
+        ));
+
+        dir
+    }
+
+    pub fn get_chain_store(network: Network) -> ChainStore<Full> {
+        let mut opts = Options::default();
+        opts.create_if_missing(true);
+        let db = DB::open_cf(&opts, get_storage_dir(), &["default"]).unwrap();
+
+        ChainStore::new(db, network).unwrap()
+    }
+
+    #[test]
+    fn test_ban_expiry() {
+        let headers = get_chain_store(Network::Regtest);
+        let peer_store = PeerStore::new(&headers);
+
+        assert!(!peer_store.is_banned("10.0.0.1:8333").unwrap());
+
+        peer_store
+            .ban_peer("10.0.0.1:8333", Duration::from_secs(60))
+            .unwrap();
+        assert!(peer_store.is_banned("10.0.0.1:8333").unwrap());
+        assert!(!peer_store.is_banned("10.0.0.2:8333").unwrap());
+
+        // a ban that has already expired
+        peer_store
+            .ban_peer("10.0.0.1:8333", Duration::from_secs(0))
+            .unwrap();
+        assert!(!peer_store.is_banned("10.0.0.1:8333").unwrap());
+    }
+
+    #[test]
+    fn test_reset_scanned_bundles() {
+        let headers = get_chain_store(Network::Regtest);
+        let cf_store = CFStore::new(&headers, 0x00).unwrap();
+
+        let checkpoints = vec![
+            FilterHash::hash(&[1]),
+            FilterHash::hash(&[2]),
+            FilterHash::hash(&[3]),
+        ];
+        cf_store.replace_checkpoints(checkpoints.clone()).unwrap();
+        for (index, checkpoint) in checkpoints.iter().enumerate().take(3) {
+            cf_store.prune_filters(index, *checkpoint).unwrap();
+        }
+
+        cf_store
+            .mark_as_tip(3, vec![vec![0x42]], FilterHash::default())
+            .unwrap();
+
+        assert_eq!(cf_store.reset_scanned_bundles(1).unwrap(), 3);
+
+        let statuses = cf_store
+            .get_bundles()
+            .unwrap()
+            .into_iter()
+            .map(|(status, _)| match status {
+                BundleStatus::Init => "init",
+                BundleStatus::Pruned => "pruned",
+                _ => "other",
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(statuses, vec!["pruned", "init", "init", "init"]);
+    }
+
+    fn build_headers(prev_blockhash: BlockHash, nonce: u32, count: usize) -> Vec<BlockHeader> {
+        let genesis = genesis_block(Network::Regtest);
+
+        let mut headers: Vec<BlockHeader> = Vec::with_capacity(count);
+        for _ in 0..count {
+            let header = BlockHeader {
+                prev_blockhash: headers
+                    .last()
+                    .map(BlockHeader::block_hash)
+                    .unwrap_or(prev_blockhash),
+                nonce,
+                ..genesis.header
+            };
+            headers.push(header);
+        }
+
+        headers
+    }
+
+    #[test]
+    fn test_apply_snapshot_reorg() {
+        let mut headers = get_chain_store(Network::Regtest);
+        let genesis = genesis_block(Network::Regtest);
+
+        headers
+            .apply(0, build_headers(genesis.block_hash(), 1, 3))
+            .unwrap();
+        for height in 1..=3 {
+            headers.save_full_block(&genesis, height).unwrap();
+        }
+        assert_eq!(headers.get_pending_reorg().unwrap(), None);
+
+        // replace the last two blocks with a longer branch
+        let fork_hash = headers.get_block_hash(1).unwrap().unwrap();
+        let mut snapshot = headers.start_snapshot(1).unwrap();
+        snapshot.apply(1, build_headers(fork_hash, 2, 3)).unwrap();
+        headers.apply_snapshot(snapshot).unwrap();
+
+        assert_eq!(headers.get_height().unwrap(), 4);
+        assert_eq!(headers.get_block_hash(1).unwrap(), Some(fork_hash));
+        assert_eq!(headers.get_pending_reorg().unwrap(), Some(2));
+        assert_eq!(
+            headers
+                .iter_full_blocks()
+                .unwrap()
+                .into_iter()
+                .map(|(height, _)| height)
+                .collect::<Vec<_>>(),
+            vec![1]
+        );
+
+        headers.clear_pending_reorg().unwrap();
+        assert_eq!(headers.get_pending_reorg().unwrap(), None);
+    }
+
+    #[test]
+    fn test_rewind() {
+        let headers = get_chain_store(Network::Regtest);
+        let cf_store = CFStore::new(&headers, 0x00).unwrap();
+
+        let checkpoints = vec![FilterHash::hash(&[1]), FilterHash::hash(&[2])];
+        cf_store.replace_checkpoints(checkpoints.clone()).unwrap();
+        cf_store.prune_filters(0, FilterHash::default()).unwrap();
+        cf_store.prune_filters(1, checkpoints[0]).unwrap();
+        cf_store.mark_as_tip(2, vec![], checkpoints[1]).unwrap();
+        cf_store.set_sync_state(2500, vec![]).unwrap();
+
+        cf_store.rewind(1500).unwrap();
+
+        let bundles = cf_store.get_bundles().unwrap();
+        assert!(matches!(bundles[0], (BundleStatus::Pruned, _)));
+        assert!(matches!(bundles[1], (BundleStatus::Init, hash) if hash == checkpoints[0]));
+        assert!(matches!(bundles[2], (BundleStatus::Init, hash) if hash == checkpoints[1]));
+        assert_eq!(cf_store.get_sync_state().unwrap(), Some((1499, vec![])));
+    }
+
+    #[test]
+    fn test_sync_state() {
+        let headers = get_chain_store(Network::Regtest);
+        let cf_store = CFStore::new(&headers, 0x00).unwrap();
+
+        assert!(cf_store.get_sync_state().unwrap().is_none());
+
+        let scripts = vec![vec![0x00, 0x14, 0x42], vec![0x51]];
+        cf_store.set_sync_state(1234, scripts.clone()).unwrap();
+        assert_eq!(cf_store.get_sync_state().unwrap(), Some((1234, scripts)));
+    }
+
+    #[test]
+    fn test_start_from_checkpoint() {
+        let mut headers = get_chain_store(Network::Regtest);
+        let genesis = genesis_block(Network::Regtest);
+
+        let checkpoint_header = build_headers(genesis.block_hash(), 1, 1)[0];
+        let checkpoint = HeaderCheckpoint {
+            height: 5_000,
+            hash: checkpoint_header.block_hash(),
+            chain_work: genesis.header.work().mul_u32(5_001),
+            filter_header: Some(FilterHash::hash(&[1])),
+        };
+
+        // the header must match the checkpoint
+        assert!(headers
+            .start_from_checkpoint(&checkpoint, genesis.header)
+            .is_err());
+        headers
+            .start_from_checkpoint(&checkpoint, checkpoint_header)
+            .unwrap();
+
+        assert_eq!(headers.get_height().unwrap(), 5_000);
+        assert_eq!(headers.get_checkpoint_height().unwrap(), 5_000);
+        assert_eq!(
+            headers.get_checkpoint_filter_header().unwrap(),
+            checkpoint.filter_header
+        );
+        assert_eq!(headers.work().unwrap(), checkpoint.chain_work);
+
+        // new headers must chain onto the checkpoint
+        assert!(headers
+            .apply(5_000, build_headers(genesis.block_hash(), 2, 20))
+            .is_err());
+        headers
+            .apply(5_000, build_headers(checkpoint.hash, 2, 20))
+            .unwrap();
+        assert_eq!(headers.get_height().unwrap(), 5_020);
+        assert_eq!(
+            headers.work().unwrap(),
+            genesis.header.work().mul_u32(5_021)
+        );
+
+        // the locators never go past the checkpoint
+        let locators = headers.get_locators().unwrap();
+        assert_eq!(locators.first().unwrap().1, 5_020);
+        assert_eq!(locators.last().unwrap(), &(checkpoint.hash, 5_000));
+        assert!(locators.iter().all(|(_, height)| *height >= 5_000));
+
+        // it can only be done on an empty chain
+        assert!(headers
+            .start_from_checkpoint(&checkpoint, checkpoint_header)
+            .is_err());
+    }
+
+    #[test]
+    fn test_birthday_height() {
+        let mut headers = get_chain_store(Network::Regtest);
+        let genesis = genesis_block(Network::Regtest);
+
+        // one block every ten minutes after the genesis
+        let mut new_headers: Vec<BlockHeader> = Vec::new();
+        for height in 1..=50 {
+            new_headers.push(BlockHeader {
+                prev_blockhash: new_headers
+                    .last()
+                    .map(BlockHeader::block_hash)
+                    .unwrap_or_else(|| genesis.block_hash()),
+                time: genesis.header.time + height * 600,
+                ..genesis.header
+            });
+        }
+        headers.apply(0, new_headers).unwrap();
+
+        let timestamp = |height: u32| (genesis.header.time + height * 600) as u64;
+        let birthday_height = |birthday| headers.get_birthday_height(birthday).unwrap();
+
+        assert_eq!(birthday_height(WalletBirthday::Height(1234)), 1234);
+        // two hours of margin
+        assert_eq!(
+            birthday_height(WalletBirthday::Timestamp(timestamp(30))),
+            18
+        );
+        assert_eq!(birthday_height(WalletBirthday::Timestamp(0)), 0);
+        // none of the blocks is recent enough
+        assert_eq!(
+            birthday_height(WalletBirthday::Timestamp(timestamp(100))),
+            51
+        );
+    }
+}
@@ -27,9 +27,11 @@ use std::sync::{Arc, Mutex};
 use std::time::Duration;
 
 use bitcoin::hash_types::{BlockHash, FilterHash};
+use bitcoin::hashes::{sha256d, Hash};
 use bitcoin::network::message::NetworkMessage;
 use bitcoin::network::message_blockdata::GetHeadersMessage;
 use bitcoin::util::bip158::BlockFilter;
+use bitcoin::Block;
 
 use super::peer::*;
 use super::store::*;
@@ -37,10 +39,14 @@ use super::CompactFiltersError;
 use crate::error::Error;
 
 pub(crate) const BURIED_CONFIRMATIONS: usize = 100;
+pub(crate) const BAN_DURATION: Duration = Duration::from_secs(24 * 60 * 60);
+
+type PeerGroup = (Vec<FilterHash>, Vec<Arc<Peer>>);
 
 pub struct CFSync {
     headers_store: Arc<ChainStore<Full>>,
     cf_store: Arc<CFStore>,
+    peer_store: PeerStore,
     skip_blocks: usize,
     bundles: Mutex<VecDeque<(BundleStatus, FilterHash, usize)>>,
 }
@@ -52,10 +58,12 @@ impl CFSync {
         filter_type: u8,
     ) -> Result<Self, CompactFiltersError> {
         let cf_store = Arc::new(CFStore::new(&headers_store, filter_type)?);
+        let peer_store = PeerStore::new(&headers_store);
 
         Ok(CFSync {
             headers_store,
             cf_store,
+            peer_store,
             skip_blocks,
             bundles: Mutex::new(VecDeque::new()),
         })
@@ -73,23 +81,339 @@ impl CFSync {
             }))
     }
 
-    pub fn prepare_sync(&self, peer: Arc<Peer>) -> Result<(), CompactFiltersError> {
+    /// Reset the scanned bundles that are not entirely skipped, so that the history can be
+    /// rescanned for scripts that weren't known during the previous syncs
+    pub fn reset_scanned_bundles(&self) -> Result<usize, CompactFiltersError> {
+        // bundles that start before `skip_blocks` would be pruned right away
+        let first_bundle = (self.skip_blocks.saturating_sub(1) + 999) / 1000;
+        self.cf_store.reset_scanned_bundles(first_bundle)
+    }
+
+    /// Discard the filters and the sync progress for the blocks replaced by a reorg
+    pub fn rewind(&self, fork_height: usize) -> Result<(), CompactFiltersError> {
+        self.cf_store.rewind(fork_height)
+    }
+
+    pub fn get_sync_state(&self) -> Result<Option<SyncState>, CompactFiltersError> {
+        self.cf_store.get_sync_state()
+    }
+
+    pub fn set_sync_state(
+        &self,
+        height: usize,
+        scripts: Vec<Vec<u8>>,
+    ) -> Result<(), CompactFiltersError> {
+        self.cf_store.set_sync_state(height, scripts)
+    }
+
+    pub fn remaining_bundles(&self) -> usize {
+        self.bundles.lock().unwrap().len()
+    }
+
+    /// Disconnect a peer that sent us invalid data and refuse to connect to it again for a while
+    pub fn ban_peer(&self, peer: &Peer) -> Result<(), CompactFiltersError> {
+        log::warn!("Banning misbehaving peer {}", peer.get_address());
+
+        self.peer_store.ban_peer(peer.get_address(), BAN_DURATION)?;
+        peer.disconnect();
+
+        Ok(())
+    }
+
+    /// Ask every peer for the filter checkpoints and only keep the ones that are consistent with
+    /// each other, banning the peers that are caught lying
+    ///
+    /// When there's no evidence against any of them the majority wins, and the other peers are
+    /// disconnected without a ban. If no majority can be found either, every peer involved is
+    /// disconnected and [`CompactFiltersError::PeersDisagree`] is returned.
+    pub fn prepare_sync(&self, peers: &[Arc<Peer>]) -> Result<(), CompactFiltersError> {
         let mut bundles_lock = self.bundles.lock().unwrap();
 
-        let resp = peer.get_cf_checkpt(
-            self.cf_store.get_filter_type(),
-            self.headers_store.get_tip_hash()?.unwrap(),
-        )?;
-        self.cf_store.replace_checkpoints(resp.filter_headers)?;
+        let filter_type = self.cf_store.get_filter_type();
+        let tip_hash = self.headers_store.get_tip_hash()?.unwrap();
+        let expected_checkpoints = self.headers_store.get_height()? / 1000;
+
+        // The filter header of the checkpoint our chain was started from can be compared with
+        // the ones of our peers when it's at the end of a bundle
+        let checkpoint_height = self.headers_store.get_checkpoint_height()?;
+        let known_filter_header = match self.headers_store.get_checkpoint_filter_header()? {
+            Some(filter_header) if checkpoint_height > 0 && checkpoint_height % 1000 == 0 => {
+                Some((checkpoint_height / 1000 - 1, filter_header))
+            }
+            _ => None,
+        };
+        let contradicts_checkpoint = |filter_headers: &[FilterHash]| match known_filter_header {
+            Some((index, filter_header)) => filter_headers
+                .get(index)
+                .map(|their| *their != filter_header)
+                .unwrap_or(false),
+            None => false,
+        };
+
+        let mut groups: Vec<PeerGroup> = Vec::new();
+        for peer in peers.iter().filter(|p| p.is_connected()) {
+            let checkpoints = match peer.get_cf_checkpt(filter_type, tip_hash) {
+                Ok(resp) if contradicts_checkpoint(&resp.filter_headers) => {
+                    log::warn!(
+                        "Peer {} returned a filter header that doesn't match our checkpoint",
+                        peer.get_address()
+                    );
+                    self.ban_peer(peer)?;
+                    continue;
+                }
+                Ok(resp) if resp.filter_headers.len() == expected_checkpoints => {
+                    resp.filter_headers
+                }
+                // A peer that is still catching up with our tip doesn't have all the checkpoints
+                // yet, which doesn't make it a liar
+                Ok(resp) if resp.filter_headers.len() < expected_checkpoints => {
+                    log::info!(
+                        "Skipping peer {}, which is behind our tip",
+                        peer.get_address()
+                    );
+                    continue;
+                }
+                Ok(_) => {
+                    self.ban_peer(peer)?;
+                    continue;
+                }
+                Err(e) if e.is_misbehavior() => {
+                    self.ban_peer(peer)?;
+                    continue;
+                }
+                Err(e) => {
+                    log::warn!("Skipping peer {}: {:?}", peer.get_address(), e);
+                    peer.disconnect();
+                    continue;
+                }
+            };
+
+            match groups.iter_mut().find(|(c, _)| c == &checkpoints) {
+                Some((_, group)) => group.push(Arc::clone(peer)),
+                None => groups.push((checkpoints, vec![Arc::clone(peer)])),
+            }
+        }
+
+        let mut honest: Option<PeerGroup> = None;
+        let mut undecided = false;
+        for (checkpoints, group) in groups {
+            let (honest_checkpoints, honest_group) = match honest.take() {
+                None => {
+                    honest = Some((checkpoints, group));
+                    continue;
+                }
+                Some(x) => x,
+            };
+
+            log::info!(
+                "Peers {} and {} returned different checkpoints",
+                honest_group[0].get_address(),
+                group[0].get_address()
+            );
+
+            // Some peers may not reply, so we try with every pair until one of them tells us
+            // something
+            let mut verdict = (true, true);
+            for (honest_peer, peer) in honest_group.iter().zip(group.iter()) {
+                verdict =
+                    self.check_conflict((&honest_checkpoints, honest_peer), (&checkpoints, peer))?;
+                if verdict != (true, true) {
+                    break;
+                }
+            }
+
+            let (keep_honest, keep_other) = match verdict {
+                // We couldn't find anything wrong with either of them and there's no majority
+                // to follow: we can't trust any of them, but we can't ban them either
+                (true, true) if honest_group.len() == group.len() => {
+                    log::warn!(
+                        "Unable to tell which peers are lying, disconnecting from all of them"
+                    );
+                    for peer in honest_group.iter().chain(group.iter()) {
+                        peer.disconnect();
+                    }
+
+                    undecided = true;
+                    continue;
+                }
+                // We couldn't find anything wrong with either of them, so we fall back to the
+                // choice of the majority. The others may just be on a different chain, so they
+                // are disconnected but not banned
+                (true, true) => {
+                    let (majority, minority) = if honest_group.len() > group.len() {
+                        ((honest_checkpoints, honest_group), group)
+                    } else {
+                        ((checkpoints, group), honest_group)
+                    };
+                    for peer in &minority {
+                        log::info!("Disconnecting from peer {}", peer.get_address());
+                        peer.disconnect();
+                    }
+
+                    honest = Some(majority);
+                    continue;
+                }
+                x => x,
+            };
 
+            if keep_honest {
+                honest = Some((honest_checkpoints, honest_group));
+            } else {
+                for peer in &honest_group {
+                    self.ban_peer(peer)?;
+                }
+            }
+            if keep_other {
+                honest = Some((checkpoints, group));
+            } else {
+                for peer in &group {
+                    self.ban_peer(peer)?;
+                }
+            }
+        }
+
+        let (checkpoints, _) = match honest {
+            Some(x) => x,
+            None if undecided => return Err(CompactFiltersError::PeersDisagree),
+            None => return Err(CompactFiltersError::NoPeers),
+        };
+        self.cf_store.replace_checkpoints(checkpoints)?;
+
+        // We don't have the headers of the blocks before the checkpoint our chain was started
+        // from, so the bundles that contain them can't be synced
         bundles_lock.clear();
-        for (index, (status, checkpoint)) in self.cf_store.get_bundles()?.into_iter().enumerate() {
+        for (index, (mut status, checkpoint)) in
+            self.cf_store.get_bundles()?.into_iter().enumerate()
+        {
+            if index * 1000 + 1 < checkpoint_height && !matches!(status, BundleStatus::Pruned) {
+                status = self.cf_store.prune_filters(index, checkpoint)?;
+            }
+
             bundles_lock.push_back((status, checkpoint, index));
         }
 
         Ok(())
     }
 
+    /// Given two peers that returned different checkpoints, download the first filter on which
+    /// they disagree and check it against the content of the block to find out which one is lying
+    ///
+    /// Returns whether the data sent by each peer looks valid. Peers that don't reply can't be
+    /// caught lying, so their data is considered valid.
+    fn check_conflict(
+        &self,
+        a: (&[FilterHash], &Peer),
+        b: (&[FilterHash], &Peer),
+    ) -> Result<(bool, bool), CompactFiltersError> {
+        let filter_type = self.cf_store.get_filter_type();
+
+        let index =
+            a.0.iter()
+                .zip(b.0.iter())
+                .position(|(a, b)| a != b)
+                .ok_or(CompactFiltersError::InvalidFilterHeader)?;
+        let previous_checkpoint = match index {
+            0 => self
+                .cf_store
+                .get_checkpoint(0)?
+                .ok_or(CompactFiltersError::DataCorruption)?,
+            i => a.0[i - 1],
+        };
+
+        // The headers before the checkpoint our chain starts from are missing, so we can't tell
+        // which peer is lying about these filters
+        let start_height = index * 1000 + 1;
+        if start_height <= self.headers_store.get_checkpoint_height()? {
+            return Ok((true, true));
+        }
+        let stop_hash = self
+            .headers_store
+            .get_block_hash((index + 1) * 1000)?
+            .ok_or(CompactFiltersError::DataCorruption)?;
+
+        // The headers must start from the last checkpoint both peers agree on and end with the
+        // checkpoint returned by the peer
+        // Returns `Ok(None)` if the headers are invalid and an error if the peer didn't reply
+        let fetch_headers = |(checkpoints, peer): (&[FilterHash], &Peer)| {
+            let resp = match peer.get_cf_headers(filter_type, start_height as u32, stop_hash) {
+                Ok(resp) => resp,
+                Err(e) => {
+                    log::debug!("Peer {} failed: {:?}", peer.get_address(), e);
+                    return match e.is_misbehavior() {
+                        true => Ok(None),
+                        false => Err(e),
+                    };
+                }
+            };
+            if resp.previous_filter != previous_checkpoint || resp.filter_hashes.len() != 1000 {
+                return Ok(None);
+            }
+
+            let headers = FilterHeader::chain(previous_checkpoint, resp.filter_hashes);
+            match headers.last().map(FilterHeader::header_hash) {
+                Some(last) if last == checkpoints[index] => Ok(Some(headers)),
+                _ => Ok(None),
+            }
+        };
+        let (headers_a, headers_b) = match (fetch_headers(a), fetch_headers(b)) {
+            (Ok(Some(headers_a)), Ok(Some(headers_b))) => (headers_a, headers_b),
+            (headers_a, headers_b) => {
+                return Ok((
+                    !matches!(headers_a, Ok(None)),
+                    !matches!(headers_b, Ok(None)),
+                ))
+            }
+        };
+
+        let position = headers_a
+            .iter()
+            .zip(headers_b.iter())
+            .position(|(a, b)| a.filter_hash() != b.filter_hash())
+            .ok_or(CompactFiltersError::InvalidFilterHeader)?;
+        let height = start_height + position;
+        let block_hash = self
+            .headers_store
+            .get_block_hash(height)?
+            .ok_or(CompactFiltersError::DataCorruption)?;
+
+        let block = match [a.1, b.1]
+            .iter()
+            .filter_map(|peer| peer.get_block(block_hash).ok().flatten())
+            .find(|block| block.block_hash() == block_hash && block.check_merkle_root())
+        {
+            Some(block) => block,
+            None => return Ok((true, true)),
+        };
+
+        let check_filter = |peer: &Peer, header: &FilterHeader| {
+            let filter = match peer
+                .get_cf_filters(filter_type, height as u32, block_hash)
+                .and_then(|_| peer.pop_cf_filter_resp())
+            {
+                Ok(filter) => filter,
+                Err(e) => {
+                    log::debug!("Peer {} failed: {:?}", peer.get_address(), e);
+                    return !e.is_misbehavior();
+                }
+            };
+
+            filter.filter_type == filter_type
+                && filter.block_hash == block_hash
+                && FilterHash::from(sha256d::Hash::hash(&filter.filter)) == *header.filter_hash()
+                && filter_matches_block(&BlockFilter::new(&filter.filter), &block)
+        };
+
+        Ok((
+            check_filter(a.1, &headers_a[position]),
+            check_filter(b.1, &headers_b[position]),
+        ))
+    }
+
+    /// Download and process the bundles until there are none left
+    ///
+    /// If the peer sends invalid data it is banned, the bundle is put back in the queue to be
+    /// downloaded from a different peer and the function returns early. The same happens without
+    /// the ban if the peer stops responding.
     pub fn capture_thread_for_sync<F, Q>(
         &self,
         peer: Arc<Peer>,
@@ -103,157 +427,231 @@ impl CFSync {
         let current_height = self.headers_store.get_height()?; // TODO: we should update it in case headers_store is also updated
 
         loop {
-            let (mut status, checkpoint, index) = match self.bundles.lock().unwrap().pop_front() {
+            let (status, checkpoint, index) = match self.bundles.lock().unwrap().pop_front() {
                 None => break,
                 Some(x) => x,
             };
 
-            log::debug!(
-                "Processing bundle #{} - height {} to {}",
-                index,
-                index * 1000 + 1,
-                (index + 1) * 1000
-            );
+            if let Err(e) = self.process_bundle(
+                &peer,
+                &process,
+                &completed_bundle,
+                current_height,
+                (status, checkpoint, index),
+            ) {
+                if e.is_misbehavior() {
+                    self.ban_peer(&peer)?;
+                } else if e.is_connection_error() {
+                    log::warn!("Disconnecting from peer {}: {:?}", peer.get_address(), e);
+                    peer.disconnect();
+                } else {
+                    return Err(e);
+                }
 
-            let process_received_filters =
-                |expected_filters| -> Result<BTreeMap<usize, Vec<u8>>, CompactFiltersError> {
-                    let mut filters_map = BTreeMap::new();
-                    for _ in 0..expected_filters {
-                        let filter = peer.pop_cf_filter_resp()?;
-                        if filter.filter_type != self.cf_store.get_filter_type() {
-                            return Err(CompactFiltersError::InvalidResponse);
-                        }
-
-                        match self.headers_store.get_height_for(&filter.block_hash)? {
-                            Some(height) => filters_map.insert(height, filter.filter),
-                            None => return Err(CompactFiltersError::InvalidFilter),
-                        };
+                let status = self.cf_store.reset_bundle(index, checkpoint)?;
+                self.bundles
+                    .lock()
+                    .unwrap()
+                    .push_back((status, checkpoint, index));
+
+                break;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn process_bundle<F, Q>(
+        &self,
+        peer: &Peer,
+        process: &F,
+        completed_bundle: &Q,
+        current_height: usize,
+        (mut status, checkpoint, index): (BundleStatus, FilterHash, usize),
+    ) -> Result<(), CompactFiltersError>
+    where
+        F: Fn(&BlockHash, &BlockFilter) -> Result<bool, CompactFiltersError>,
+        Q: Fn(usize) -> Result<(), Error>,
+    {
+        log::debug!(
+            "Processing bundle #{} - height {} to {}",
+            index,
+            index * 1000 + 1,
+            (index + 1) * 1000
+        );
+
+        let process_received_filters =
+            |expected_filters| -> Result<BTreeMap<usize, Vec<u8>>, CompactFiltersError> {
+                let mut filters_map = BTreeMap::new();
+                for _ in 0..expected_filters {
+                    let filter = peer.pop_cf_filter_resp()?;
+                    if filter.filter_type != self.cf_store.get_filter_type() {
+                        return Err(CompactFiltersError::InvalidResponse);
                     }
 
-                    Ok(filters_map)
-                };
+                    match self.headers_store.get_height_for(&filter.block_hash)? {
+                        Some(height) => filters_map.insert(height, filter.filter),
+                        None => return Err(CompactFiltersError::InvalidFilter),
+                    };
+                }
 
-            let start_height = index * 1000 + 1;
-            let mut already_processed = 0;
+                Ok(filters_map)
+            };
 
-            if start_height < self.skip_blocks {
-                status = self.cf_store.prune_filters(index, checkpoint)?;
-            }
+        let start_height = index * 1000 + 1;
+        let mut already_processed = 0;
 
-            let stop_height = std::cmp::min(current_height, start_height + 999);
-            let stop_hash = self.headers_store.get_block_hash(stop_height)?.unwrap();
+        if start_height < self.skip_blocks {
+            status = self.cf_store.prune_filters(index, checkpoint)?;
+        }
+        if let BundleStatus::Pruned = status {
+            log::trace!("status: Pruned");
+            return Ok(());
+        }
+        // The last bundle is empty when the tip is at the end of the previous one
+        if start_height > current_height {
+            return Ok(());
+        }
 
-            if let BundleStatus::Init = status {
-                log::trace!("status: Init");
+        let stop_height = std::cmp::min(current_height, start_height + 999);
+        let stop_hash = self
+            .headers_store
+            .get_block_hash(stop_height)?
+            .ok_or(CompactFiltersError::DataCorruption)?;
 
-                let resp = peer.get_cf_headers(0x00, start_height as u32, stop_hash)?;
+        if let BundleStatus::Init = status {
+            log::trace!("status: Init");
 
-                assert!(resp.previous_filter == checkpoint);
-                status =
-                    self.cf_store
-                        .advance_to_cf_headers(index, checkpoint, resp.filter_hashes)?;
-            }
-            if let BundleStatus::Tip { cf_filters } = status {
-                log::trace!("status: Tip (beginning) ");
-
-                already_processed = cf_filters.len();
-                let headers_resp = peer.get_cf_headers(0x00, start_height as u32, stop_hash)?;
-
-                let cf_headers = match self.cf_store.advance_to_cf_headers(
-                    index,
-                    checkpoint,
-                    headers_resp.filter_hashes,
-                )? {
-                    BundleStatus::CFHeaders { cf_headers } => cf_headers,
-                    _ => return Err(CompactFiltersError::InvalidResponse),
-                };
-
-                peer.get_cf_filters(
-                    self.cf_store.get_filter_type(),
-                    (start_height + cf_filters.len()) as u32,
-                    stop_hash,
-                )?;
-                let expected_filters = stop_height - start_height + 1 - cf_filters.len();
-                let filters_map = process_received_filters(expected_filters)?;
-                let filters = cf_filters
-                    .into_iter()
-                    .enumerate()
-                    .chain(filters_map.into_iter())
-                    .collect();
-                status = self
-                    .cf_store
-                    .advance_to_cf_filters(index, checkpoint, cf_headers, filters)?;
-            }
-            if let BundleStatus::CFHeaders { cf_headers } = status {
-                log::trace!("status: CFHeaders");
-
-                peer.get_cf_filters(
-                    self.cf_store.get_filter_type(),
-                    start_height as u32,
-                    stop_hash,
-                )?;
-                let expected_filters = stop_height - start_height + 1;
-                let filters_map = process_received_filters(expected_filters)?;
-                status = self.cf_store.advance_to_cf_filters(
-                    index,
-                    checkpoint,
-                    cf_headers,
-                    filters_map.into_iter().collect(),
-                )?;
+            let resp = peer.get_cf_headers(0x00, start_height as u32, stop_hash)?;
+
+            if resp.previous_filter != checkpoint {
+                return Err(CompactFiltersError::InvalidFilterHeader);
             }
-            if let BundleStatus::CFilters { cf_filters } = status {
-                log::trace!("status: CFilters");
+            status = self
+                .cf_store
+                .advance_to_cf_headers(index, checkpoint, resp.filter_hashes)?;
+        }
+        if let BundleStatus::Tip { cf_filters } = status {
+            log::trace!("status: Tip (beginning) ");
 
-                let last_sync_buried_height = (start_height + already_processed)
-                    .checked_sub(BURIED_CONFIRMATIONS)
-                    .unwrap_or(0);
+            already_processed = cf_filters.len();
+            let headers_resp = peer.get_cf_headers(0x00, start_height as u32, stop_hash)?;
 
-                for (filter_index, filter) in cf_filters.iter().enumerate() {
-                    let height = filter_index + start_height;
+            let cf_headers = match self.cf_store.advance_to_cf_headers(
+                index,
+                checkpoint,
+                headers_resp.filter_hashes,
+            )? {
+                BundleStatus::CFHeaders { cf_headers } => cf_headers,
+                _ => return Err(CompactFiltersError::InvalidResponse),
+            };
 
-                    // do not download blocks that were already "buried" since the last sync
-                    if height < last_sync_buried_height {
-                        continue;
-                    }
+            peer.get_cf_filters(
+                self.cf_store.get_filter_type(),
+                (start_height + cf_filters.len()) as u32,
+                stop_hash,
+            )?;
+            let expected_filters = stop_height - start_height + 1 - cf_filters.len();
+            let filters_map = process_received_filters(expected_filters)?;
+            let filters = cf_filters
+                .into_iter()
+                .enumerate()
+                .chain(filters_map.into_iter())
+                .collect();
+            status = self
+                .cf_store
+                .advance_to_cf_filters(index, checkpoint, cf_headers, filters)?;
+        }
+        if let BundleStatus::CFHeaders { cf_headers } = status {
+            log::trace!("status: CFHeaders");
+
+            peer.get_cf_filters(
+                self.cf_store.get_filter_type(),
+                start_height as u32,
+                stop_hash,
+            )?;
+            let expected_filters = stop_height - start_height + 1;
+            let filters_map = process_received_filters(expected_filters)?;
+            status = self.cf_store.advance_to_cf_filters(
+                index,
+                checkpoint,
+                cf_headers,
+                filters_map.into_iter().collect(),
+            )?;
+        }
+        if let BundleStatus::CFilters { cf_filters } = status {
+            log::trace!("status: CFilters");
 
-                    let block_hash = self.headers_store.get_block_hash(height)?.unwrap();
+            let last_sync_buried_height = (start_height + already_processed)
+                .checked_sub(BURIED_CONFIRMATIONS)
+                .unwrap_or(0);
 
-                    // TODO: also download random blocks?
-                    if process(&block_hash, &BlockFilter::new(&filter))? {
-                        log::debug!("Downloading block {}", block_hash);
+            for (filter_index, filter) in cf_filters.iter().enumerate() {
+                let height = filter_index + start_height;
 
-                        let block = peer
-                            .get_block(block_hash)?
-                            .ok_or(CompactFiltersError::MissingBlock)?;
-                        self.headers_store.save_full_block(&block, height)?;
-                    }
+                // do not download blocks that were already "buried" since the last sync
+                if height < last_sync_buried_height {
+                    continue;
                 }
 
-                status = BundleStatus::Processed { cf_filters };
-            }
-            if let BundleStatus::Processed { cf_filters } = status {
-                log::trace!("status: Processed");
+                let block_hash = self
+                    .headers_store
+                    .get_block_hash(height)?
+                    .ok_or(CompactFiltersError::DataCorruption)?;
 
-                if current_height - stop_height > 1000 {
-                    status = self.cf_store.prune_filters(index, checkpoint)?;
-                } else {
-                    status = self.cf_store.mark_as_tip(index, cf_filters, checkpoint)?;
-                }
+                // TODO: also download random blocks?
+                if process(&block_hash, &BlockFilter::new(&filter))? {
+                    log::debug!("Downloading block {}", block_hash);
 
-                completed_bundle(index)?;
-            }
-            if let BundleStatus::Pruned = status {
-                log::trace!("status: Pruned");
+                    let block = peer
+                        .get_block(block_hash)?
+                        .ok_or(CompactFiltersError::MissingBlock)?;
+                    self.headers_store.save_full_block(&block, height)?;
+                }
             }
-            if let BundleStatus::Tip { .. } = status {
-                log::trace!("status: Tip");
+
+            status = BundleStatus::Processed { cf_filters };
+        }
+        if let BundleStatus::Processed { cf_filters } = status {
+            log::trace!("status: Processed");
+
+            if current_height - stop_height > 1000 {
+                status = self.cf_store.prune_filters(index, checkpoint)?;
+            } else {
+                status = self.cf_store.mark_as_tip(index, cf_filters, checkpoint)?;
             }
+
+            completed_bundle(index)?;
+        }
+        if let BundleStatus::Pruned = status {
+            log::trace!("status: Pruned");
+        }
+        if let BundleStatus::Tip { .. } = status {
+            log::trace!("status: Tip");
         }
 
         Ok(())
     }
 }
 
+/// Check that a filter contains all the output scripts of a block
+///
+/// This is the only part of a basic filter that can be verified without knowing the previous
+/// outputs spent by the block, which is enough to catch a peer that is hiding transactions.
+pub(crate) fn filter_matches_block(filter: &BlockFilter, block: &Block) -> bool {
+    let mut scripts = block
+        .txdata
+        .iter()
+        .flat_map(|tx| tx.output.iter())
+        .map(|output| &output.script_pubkey)
+        .filter(|script| !script.is_empty() && !script.is_op_return())
+        .map(|script| script.as_bytes());
+
+    filter
+        .match_all(&block.block_hash(), &mut scripts)
+        .unwrap_or(false)
+}
+
 pub fn sync_headers<F>(
     peer: Arc<Peer>,
     store: Arc<ChainStore<Full>>,
@@ -289,7 +687,9 @@ where
         return Err(CompactFiltersError::InvalidResponse);
     };
 
-    let mut sync_height = store.get_height()?;
+    // Start from the last block in common with the peer, which is lower than our tip if the peer
+    // is on a different branch
+    let mut sync_height = snapshot.get_min_height();
     while sync_height < peer.get_version().start_height as usize {
         peer.send(NetworkMessage::GetHeaders(GetHeadersMessage::new(
             vec![last_hash],
@@ -311,3 +711,312 @@ where
 
     Ok(Some(snapshot))
 }
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use bitcoin::blockdata::constants::genesis_block;
+    use bitcoin::hash_types::FilterHash;
+    use bitcoin::util::bip158::BlockFilter;
+    use bitcoin::{Network, Script, TxOut};
+
+    use super::super::checkpoints::HeaderCheckpoint;
+    use super::super::peer::{CompactFiltersPeer, Mempool};
+    use super::super::store::test::get_chain_store;
+    use super::super::store::BundleStatus;
+    use super::super::test_node::{TestChain, TestNode};
+    use super::super::CompactFiltersError;
+    use super::{filter_matches_block, CFSync};
+
+    fn get_cf_sync(chain: &TestChain) -> CFSync {
+        let mut headers = get_chain_store(Network::Regtest);
+        headers.apply(0, chain.headers(1)).unwrap();
+
+        CFSync::new(Arc::new(headers), 0, 0x00).unwrap()
+    }
+
+    fn get_chain() -> TestChain {
+        let mut chain = TestChain::new();
+        chain.mine_empty(3000);
+
+        chain
+    }
+
+    /// Return a [`CFSync`] whose headers start from the block at `height` of `chain`
+    fn get_cf_sync_from_checkpoint(
+        chain: &TestChain,
+        height: usize,
+        filter_header: Option<FilterHash>,
+        skip_blocks: usize,
+    ) -> CFSync {
+        let mut headers = get_chain_store(Network::Regtest);
+        let checkpoint = HeaderCheckpoint {
+            height,
+            hash: chain.block(height).block_hash(),
+            chain_work: Default::default(),
+            filter_header,
+        };
+        headers
+            .start_from_checkpoint(&checkpoint, chain.block(height).header)
+            .unwrap();
+        headers.apply(height, chain.headers(height + 1)).unwrap();
+
+        CFSync::new(Arc::new(headers), skip_blocks, 0x00).unwrap()
+    }
+
+    #[test]
+    fn test_check_conflict() {
+        let chain = get_chain();
+        let mut lying_chain = chain.clone();
+        lying_chain.hide_outputs(1500);
+
+        let cf_sync = get_cf_sync(&chain);
+        let tip_hash = chain.block(3000).block_hash();
+        let mempool = Arc::new(Mempool::default());
+        let honest = TestNode::new(chain).connect(Arc::clone(&mempool));
+        let liar = TestNode::new(lying_chain).connect(mempool);
+
+        let honest_checkpoints = honest
+            .get_cf_checkpt(0x00, tip_hash)
+            .unwrap()
+            .filter_headers;
+        let lying_checkpoints = liar.get_cf_checkpt(0x00, tip_hash).unwrap().filter_headers;
+        assert_eq!(honest_checkpoints[0], lying_checkpoints[0]);
+        assert_ne!(honest_checkpoints[1], lying_checkpoints[1]);
+
+        assert_eq!(
+            cf_sync
+                .check_conflict((&honest_checkpoints, &honest), (&lying_checkpoints, &liar))
+                .unwrap(),
+            (true, false)
+        );
+        assert_eq!(
+            cf_sync
+                .check_conflict((&lying_checkpoints, &liar), (&honest_checkpoints, &honest))
+                .unwrap(),
+            (false, true)
+        );
+    }
+
+    #[test]
+    fn test_prepare_sync_bans_liars() {
+        let chain = get_chain();
+        let mut lying_chain = chain.clone();
+        lying_chain.hide_outputs(1500);
+
+        let cf_sync = get_cf_sync(&chain);
+        let mempool = Arc::new(Mempool::default());
+        let liar = Arc::new(TestNode::new(lying_chain).connect(Arc::clone(&mempool)));
+        let honest = Arc::new(TestNode::new(chain).connect(mempool));
+
+        cf_sync
+            .prepare_sync(&[Arc::clone(&liar), Arc::clone(&honest)])
+            .unwrap();
+
+        assert!(cf_sync.peer_store.is_banned(liar.get_address()).unwrap());
+        assert!(!liar.is_connected());
+        assert!(!cf_sync.peer_store.is_banned(honest.get_address()).unwrap());
+        assert!(honest.is_connected());
+        assert_eq!(cf_sync.remaining_bundles(), 4);
+    }
+
+    #[test]
+    fn test_prepare_sync_peer_behind() {
+        let chain = get_chain();
+        let behind_chain = chain.truncated(1500);
+
+        let cf_sync = get_cf_sync(&chain);
+        let mempool = Arc::new(Mempool::default());
+        let behind = Arc::new(TestNode::new(behind_chain).connect(Arc::clone(&mempool)));
+        let honest = Arc::new(TestNode::new(chain).connect(mempool));
+
+        cf_sync
+            .prepare_sync(&[Arc::clone(&behind), Arc::clone(&honest)])
+            .unwrap();
+
+        assert!(!cf_sync.peer_store.is_banned(behind.get_address()).unwrap());
+        assert!(!cf_sync.peer_store.is_banned(honest.get_address()).unwrap());
+        assert_eq!(cf_sync.remaining_bundles(), 4);
+
+        // with only the peer that is behind there's nothing to sync from
+        assert!(matches!(
+            cf_sync.prepare_sync(&[behind]),
+            Err(CompactFiltersError::NoPeers)
+        ));
+    }
+
+    #[test]
+    fn test_prepare_sync_undecided() {
+        let chain = get_chain();
+        let mut lying_chain = chain.clone();
+        lying_chain.hide_outputs(1500);
+        // without the blocks the lie can't be proven
+        let mut chain = chain;
+        chain.prune();
+        lying_chain.prune();
+
+        let cf_sync = get_cf_sync(&chain);
+        let mempool = Arc::new(Mempool::default());
+        let liar = Arc::new(TestNode::new(lying_chain).connect(Arc::clone(&mempool)));
+        let honest = Arc::new(TestNode::new(chain).connect(mempool));
+
+        assert!(matches!(
+            cf_sync.prepare_sync(&[Arc::clone(&liar), Arc::clone(&honest)]),
+            Err(CompactFiltersError::PeersDisagree)
+        ));
+
+        // neither of them can be blamed, but we don't trust them either
+        for peer in &[liar, honest] {
+            assert!(!cf_sync.peer_store.is_banned(peer.get_address()).unwrap());
+            assert!(!peer.is_connected());
+        }
+    }
+
+    #[test]
+    fn test_prepare_sync_majority() {
+        let mut chain = get_chain();
+        let mut lying_chain = chain.clone();
+        lying_chain.hide_outputs(1500);
+        chain.prune();
+        lying_chain.prune();
+
+        let cf_sync = get_cf_sync(&chain);
+        let mempool = Arc::new(Mempool::default());
+        let liar = Arc::new(TestNode::new(lying_chain).connect(Arc::clone(&mempool)));
+        let honest = (0..2)
+            .map(|_| Arc::new(TestNode::new(chain.clone()).connect(Arc::clone(&mempool))))
+            .collect::<Vec<_>>();
+
+        let peers = honest
+            .iter()
+            .chain(std::iter::once(&liar))
+            .cloned()
+            .collect::<Vec<_>>();
+        cf_sync.prepare_sync(&peers).unwrap();
+        assert_eq!(cf_sync.remaining_bundles(), 4);
+
+        // there's no evidence against the minority, which is disconnected without a ban
+        assert!(!cf_sync.peer_store.is_banned(liar.get_address()).unwrap());
+        assert!(!liar.is_connected());
+        for peer in &honest {
+            assert!(!cf_sync.peer_store.is_banned(peer.get_address()).unwrap());
+            assert!(peer.is_connected());
+        }
+    }
+
+    #[test]
+    fn test_prepare_sync_checkpoint_undecided() {
+        let chain = get_chain();
+        // the lie is in the blocks before the checkpoint our chain starts from, which can't be
+        // checked
+        let mut lying_chain = chain.clone();
+        lying_chain.hide_outputs(1500);
+
+        let cf_sync = get_cf_sync_from_checkpoint(&chain, 2000, None, 2000);
+        let mempool = Arc::new(Mempool::default());
+        let liar = Arc::new(TestNode::new(lying_chain).connect(Arc::clone(&mempool)));
+        let honest = Arc::new(TestNode::new(chain).connect(mempool));
+
+        assert!(matches!(
+            cf_sync.prepare_sync(&[Arc::clone(&liar), Arc::clone(&honest)]),
+            Err(CompactFiltersError::PeersDisagree)
+        ));
+
+        for peer in &[liar, honest] {
+            assert!(!cf_sync.peer_store.is_banned(peer.get_address()).unwrap());
+            assert!(!peer.is_connected());
+        }
+    }
+
+    #[test]
+    fn test_prepare_sync_checkpoint_filter_header() {
+        let chain = get_chain();
+        let mut lying_chain = chain.clone();
+        lying_chain.hide_outputs(1500);
+
+        // unlike `test_prepare_sync_checkpoint_undecided`, the filter header of the checkpoint is known
+        let cf_sync =
+            get_cf_sync_from_checkpoint(&chain, 2000, Some(chain.filter_header(2000)), 2000);
+        let mempool = Arc::new(Mempool::default());
+        let liar = Arc::new(TestNode::new(lying_chain).connect(Arc::clone(&mempool)));
+        let honest = Arc::new(TestNode::new(chain).connect(mempool));
+
+        cf_sync
+            .prepare_sync(&[Arc::clone(&liar), Arc::clone(&honest)])
+            .unwrap();
+
+        assert!(cf_sync.peer_store.is_banned(liar.get_address()).unwrap());
+        assert!(!cf_sync.peer_store.is_banned(honest.get_address()).unwrap());
+        assert!(honest.is_connected());
+    }
+
+    #[test]
+    fn test_sync_from_checkpoint() {
+        let chain = get_chain();
+        // even without skipping them, the bundles before the checkpoint can't be synced
+        let cf_sync = get_cf_sync_from_checkpoint(&chain, 1500, None, 0);
+        let peer = Arc::new(TestNode::new(chain).connect(Arc::new(Mempool::default())));
+
+        cf_sync.prepare_sync(&[Arc::clone(&peer)]).unwrap();
+        cf_sync
+            .capture_thread_for_sync(peer, |_, _| Ok(false), |_| Ok(()))
+            .unwrap();
+
+        let statuses = cf_sync
+            .cf_store
+            .get_bundles()
+            .unwrap()
+            .into_iter()
+            .map(|(status, _)| match status {
+                BundleStatus::Pruned => "pruned",
+                BundleStatus::Tip { .. } => "tip",
+                _ => "other",
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(statuses[..3], ["pruned", "pruned", "tip"]);
+    }
+
+    #[test]
+    fn test_filter_matches_block() {
+        let block = genesis_block(Network::Bitcoin);
+        let filter = BlockFilter::new_script_filter(&block, |utxo| {
+            Err(bitcoin::util::bip158::Error::UtxoMissing(*utxo))
+        })
+        .unwrap();
+
+        assert!(filter_matches_block(&filter, &block));
+    }
+
+    #[test]
+    fn test_filter_missing_output() {
+        let mut block = genesis_block(Network::Bitcoin);
+        let filter = BlockFilter::new_script_filter(&block, |utxo| {
+            Err(bitcoin::util::bip158::Error::UtxoMissing(*utxo))
+        })
+        .unwrap();
+
+        block.txdata[0].output.push(TxOut {
+            value: 0,
+            script_pubkey: Script::from(vec![0x00, 0x14, 0x42]),
+        });
+
+        assert!(!filter_matches_block(&filter, &block));
+    }
+
+    #[test]
+    fn test_filter_ignores_op_return() {
+        let mut block = genesis_block(Network::Bitcoin);
+        let filter = BlockFilter::new_script_filter(&block, |utxo| {
+            Err(bitcoin::util::bip158::Error::UtxoMissing(*utxo))
+        })
+        .unwrap();
+
+        block.txdata[0].output.push(TxOut {
+            value: 0,
+            script_pubkey: Script::new_op_return(&[0x42; 32]),
+        });
+
+        assert!(filter_matches_block(&filter, &block));
+    }
+}
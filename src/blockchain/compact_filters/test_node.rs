@@ -0,0 +1,374 @@
+// Magical Bitcoin Library
+// Written in 2020 by
+//     Alekos Filini <alekos.filini@gmail.com>
+//
+// Copyright (c) 2020 Magical Bitcoin
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! Fake regtest node serving blocks and compact filters, used to test the sync end to end
+//! without hitting the network
+
+use std::collections::HashMap;
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::{Arc, RwLock};
+use std::thread;
+
+use bitcoin::blockdata::constants::genesis_block;
+use bitcoin::blockdata::script::Builder;
+use bitcoin::consensus::Encodable;
+use bitcoin::hash_types::{BlockHash, FilterHash};
+use bitcoin::hashes::Hash;
+use bitcoin::network::constants::ServiceFlags;
+use bitcoin::network::message::{NetworkMessage, RawNetworkMessage};
+use bitcoin::network::message_blockdata::Inventory;
+use bitcoin::network::message_filter::{CFCheckpt, CFHeaders, CFilter};
+use bitcoin::network::message_network::VersionMessage;
+use bitcoin::network::Address;
+use bitcoin::util::bip158::{self, BlockFilter};
+use bitcoin::{Block, BlockHeader, Network, OutPoint, Script, Transaction, TxIn, TxOut};
+
+use super::peer::*;
+
+/// A regtest chain with the basic filter of every block
+#[derive(Debug, Clone)]
+pub(crate) struct TestChain {
+    blocks: Vec<Block>,
+    heights: HashMap<BlockHash, usize>,
+    filters: Vec<Vec<u8>>,
+    filter_headers: Vec<FilterHash>,
+    outputs: HashMap<OutPoint, TxOut>,
+    pruned: bool,
+}
+
+impl TestChain {
+    /// Create a chain that only contains the genesis block
+    pub fn new() -> Self {
+        let mut chain = TestChain {
+            blocks: vec![],
+            heights: HashMap::new(),
+            filters: vec![],
+            filter_headers: vec![],
+            outputs: HashMap::new(),
+            pruned: false,
+        };
+        chain.push(genesis_block(Network::Regtest));
+
+        chain
+    }
+
+    /// Mine a block containing `txdata` after the coinbase, returning its height
+    ///
+    /// The transactions can only spend outputs created in the previous blocks.
+    pub fn mine(&mut self, txdata: Vec<Transaction>) -> usize {
+        let height = self.blocks.len();
+        let genesis = &self.blocks[0];
+
+        let coinbase = Transaction {
+            version: 1,
+            lock_time: 0,
+            input: vec![TxIn {
+                previous_output: OutPoint::null(),
+                script_sig: Builder::new().push_int(height as i64).into_script(),
+                sequence: 0xFFFF_FFFF,
+                witness: vec![],
+            }],
+            output: vec![TxOut {
+                value: (50 * 100_000_000) >> (height / 150),
+                script_pubkey: Script::from(vec![0x51]),
+            }],
+        };
+        let mut block = Block {
+            header: BlockHeader {
+                prev_blockhash: self.blocks[height - 1].block_hash(),
+                time: genesis.header.time + height as u32 * 600,
+                ..genesis.header
+            },
+            txdata: vec![coinbase].into_iter().chain(txdata).collect(),
+        };
+        block.header.merkle_root = block.merkle_root();
+        self.push(block);
+
+        height
+    }
+
+    /// Mine `count` blocks without transactions
+    pub fn mine_empty(&mut self, count: usize) {
+        for _ in 0..count {
+            self.mine(vec![]);
+        }
+    }
+
+    /// Replace the filter of the block at `height` with one that doesn't contain its outputs
+    ///
+    /// The filter headers are updated accordingly, like a peer that lies consistently would do.
+    pub fn hide_outputs(&mut self, height: usize) {
+        let block = Block {
+            header: self.blocks[height].header,
+            txdata: vec![],
+        };
+        self.filters[height] = BlockFilter::new_script_filter(&block, |_| unreachable!())
+            .unwrap()
+            .content;
+
+        for height in height..self.blocks.len() {
+            let previous = match height {
+                0 => FilterHash::default(),
+                h => self.filter_headers[h - 1],
+            };
+            self.filter_headers[height] =
+                BlockFilter::new(&self.filters[height]).filter_id(&previous);
+        }
+    }
+
+    /// Stop serving blocks, like a pruned node
+    pub fn prune(&mut self) {
+        self.pruned = true;
+    }
+
+    /// Return a copy of the chain that stops at `height`
+    pub fn truncated(&self, height: usize) -> Self {
+        let mut chain = self.clone();
+        for block in chain.blocks.drain(height + 1..) {
+            chain.heights.remove(&block.block_hash());
+        }
+        chain.filters.truncate(height + 1);
+        chain.filter_headers.truncate(height + 1);
+
+        chain
+    }
+
+    pub fn height(&self) -> usize {
+        self.blocks.len() - 1
+    }
+
+    pub fn block(&self, height: usize) -> &Block {
+        &self.blocks[height]
+    }
+
+    pub fn filter_header(&self, height: usize) -> FilterHash {
+        self.filter_headers[height]
+    }
+
+    /// Return the headers of the blocks from `from` to the tip, both included
+    pub fn headers(&self, from: usize) -> Vec<BlockHeader> {
+        self.blocks[from..]
+            .iter()
+            .map(|block| block.header)
+            .collect()
+    }
+
+    fn push(&mut self, block: Block) {
+        let height = self.blocks.len();
+
+        for tx in &block.txdata {
+            for (vout, output) in tx.output.iter().enumerate() {
+                self.outputs
+                    .insert(OutPoint::new(tx.txid(), vout as u32), output.clone());
+            }
+        }
+
+        let outputs = &self.outputs;
+        let filter = BlockFilter::new_script_filter(&block, |outpoint| {
+            outputs
+                .get(outpoint)
+                .map(|output| output.script_pubkey.clone())
+                .ok_or(bip158::Error::UtxoMissing(*outpoint))
+        })
+        .unwrap();
+        let previous = self.filter_headers.last().cloned().unwrap_or_default();
+
+        self.filter_headers.push(filter.filter_id(&previous));
+        self.filters.push(filter.content);
+        self.heights.insert(block.block_hash(), height);
+        self.blocks.push(block);
+    }
+}
+
+/// A node listening on localhost that serves a [`TestChain`] to our [`Peer`]s
+pub(crate) struct TestNode {
+    address: SocketAddr,
+    chain: Arc<RwLock<TestChain>>,
+}
+
+impl TestNode {
+    pub fn new(chain: TestChain) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let chain = Arc::new(RwLock::new(chain));
+
+        let listener_chain = Arc::clone(&chain);
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let stream = match stream {
+                    Ok(stream) => stream,
+                    Err(_) => break,
+                };
+                let chain = Arc::clone(&listener_chain);
+                thread::spawn(move || serve(stream, chain));
+            }
+        });
+
+        TestNode { address, chain }
+    }
+
+    pub fn address(&self) -> SocketAddr {
+        self.address
+    }
+
+    /// Connect a new [`Peer`] to the node
+    pub fn connect(&self, mempool: Arc<Mempool>) -> Peer {
+        Peer::connect(self.address, mempool, Network::Regtest).unwrap()
+    }
+
+    /// Mine a new block, see [`TestChain::mine`]
+    pub fn mine(&self, txdata: Vec<Transaction>) -> usize {
+        self.chain.write().unwrap().mine(txdata)
+    }
+
+    pub fn mine_empty(&self, count: usize) {
+        self.chain.write().unwrap().mine_empty(count)
+    }
+}
+
+/// Reply to the messages sent by a peer until it disconnects
+fn serve(mut stream: TcpStream, chain: Arc<RwLock<TestChain>>) {
+    let mut writer = stream.try_clone().unwrap();
+    let mut send = |payload: NetworkMessage| {
+        RawNetworkMessage {
+            magic: Network::Regtest.magic(),
+            payload,
+        }
+        .consensus_encode(&mut writer)
+        .is_ok()
+    };
+
+    loop {
+        let message = match read_message(&mut stream) {
+            Ok((_, PeerMessage::Known(message))) => message,
+            Ok(_) => continue,
+            Err(_) => break,
+        };
+        let chain = chain.read().unwrap();
+
+        let replies = match message {
+            NetworkMessage::Version(_) => {
+                let services =
+                    ServiceFlags::NETWORK | ServiceFlags::WITNESS | ServiceFlags::COMPACT_FILTERS;
+                let address = Address::new(&stream.local_addr().unwrap(), services);
+                let version = VersionMessage::new(
+                    services,
+                    0,
+                    address.clone(),
+                    address,
+                    0,
+                    "TestNode".into(),
+                    chain.height() as i32,
+                );
+
+                vec![NetworkMessage::Version(version), NetworkMessage::Verack]
+            }
+            NetworkMessage::Ping(nonce) => vec![NetworkMessage::Pong(nonce)],
+            NetworkMessage::GetHeaders(request) => {
+                let from = request
+                    .locator_hashes
+                    .iter()
+                    .find_map(|hash| chain.heights.get(hash))
+                    .cloned()
+                    .unwrap_or(0);
+                let headers = chain.headers(from + 1).into_iter().take(2000).collect();
+
+                vec![NetworkMessage::Headers(headers)]
+            }
+            // Like a peer that is behind, reply with the checkpoints we have if we don't know
+            // the block requested
+            NetworkMessage::GetCFCheckpt(request) => {
+                let stop_height = chain
+                    .heights
+                    .get(&request.stop_hash)
+                    .cloned()
+                    .unwrap_or_else(|| chain.height());
+                let filter_headers = (1..=stop_height / 1000)
+                    .map(|i| chain.filter_headers[i * 1000])
+                    .collect();
+
+                vec![NetworkMessage::CFCheckpt(CFCheckpt {
+                    filter_type: request.filter_type,
+                    stop_hash: request.stop_hash,
+                    filter_headers,
+                })]
+            }
+            NetworkMessage::GetCFHeaders(request) => {
+                let start_height = request.start_height as usize;
+                match chain.heights.get(&request.stop_hash) {
+                    Some(&stop_height) if start_height > 0 && start_height <= stop_height => {
+                        vec![NetworkMessage::CFHeaders(CFHeaders {
+                            filter_type: request.filter_type,
+                            stop_hash: request.stop_hash,
+                            previous_filter: chain.filter_headers[start_height - 1],
+                            filter_hashes: chain.filters[start_height..=stop_height]
+                                .iter()
+                                .map(|filter| FilterHash::hash(filter))
+                                .collect(),
+                        })]
+                    }
+                    _ => vec![],
+                }
+            }
+            NetworkMessage::GetCFilters(request) => {
+                let start_height = request.start_height as usize;
+                match chain.heights.get(&request.stop_hash) {
+                    Some(&stop_height) if start_height <= stop_height => (start_height
+                        ..=stop_height)
+                        .map(|height| {
+                            NetworkMessage::CFilter(CFilter {
+                                filter_type: request.filter_type,
+                                block_hash: chain.blocks[height].block_hash(),
+                                filter: chain.filters[height].clone(),
+                            })
+                        })
+                        .collect(),
+                    _ => vec![],
+                }
+            }
+            NetworkMessage::GetData(inventory) => inventory
+                .into_iter()
+                .map(|item| match item {
+                    Inventory::Block(hash) | Inventory::WitnessBlock(hash) => {
+                        match chain.heights.get(&hash) {
+                            Some(&height) if !chain.pruned => {
+                                NetworkMessage::Block(chain.blocks[height].clone())
+                            }
+                            _ => NetworkMessage::NotFound(vec![item]),
+                        }
+                    }
+                    item => NetworkMessage::NotFound(vec![item]),
+                })
+                .collect(),
+            _ => vec![],
+        };
+
+        std::mem::drop(chain);
+        for reply in replies {
+            if !send(reply) {
+                return;
+            }
+        }
+    }
+}
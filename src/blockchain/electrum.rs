@@ -36,15 +36,32 @@
 //! let blockchain = ElectrumBlockchain::from(client);
 //! # Ok::<(), bdk::Error>(())
 //! ```
+//!
+//! ## Subscriptions
+//!
+//! A long-lived [`ElectrumBlockchain`] created with [`ElectrumBlockchain::with_subscriptions`]
+//! subscribes to every script of the wallet and to the new block headers. After the first full
+//! sync, every following sync only re-queries the history of the scripts whose status has changed
+//! since the previous one, based on the notifications sent by the server.
+//!
+//! ```no_run
+//! # use bdk::blockchain::electrum::ElectrumBlockchain;
+//! let client = electrum_client::Client::new("ssl://electrum.blockstream.info:50002")?;
+//! let blockchain = ElectrumBlockchain::with_subscriptions(client);
+//! # Ok::<(), bdk::Error>(())
+//! ```
 
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
 
 #[allow(unused_imports)]
 use log::{debug, error, info, trace};
 
 use bitcoin::{BlockHeader, Script, Transaction, Txid};
 
-use electrum_client::{Client, ConfigBuilder, ElectrumApi, Socks5Config};
+use electrum_client::{Client, ConfigBuilder, ElectrumApi, ScriptStatus, Socks5Config};
 
 use self::utils::{ELSGetHistoryRes, ElectrumLikeSync};
 use super::*;
@@ -52,11 +69,195 @@ use crate::database::BatchDatabase;
 use crate::error::Error;
 use crate::FeeRate;
 
+/// Maximum number of threads subscribing to the scripts of the wallet at the same time
+const SUBSCRIBE_THREADS: usize = 8;
+
 /// Wrapper over an Electrum Client that implements the required blockchain traits
 ///
 /// ## Example
 /// See the [`blockchain::electrum`](crate::blockchain::electrum) module for a usage example.
-pub struct ElectrumBlockchain(Client);
+pub struct ElectrumBlockchain {
+    client: Arc<Client>,
+    subscriptions: Option<Mutex<Subscriptions>>,
+}
+
+/// Calls used to keep the [`Subscriptions`] of an [`ElectrumBlockchain`] up to date
+trait ElectrumSubscribe: ElectrumLikeSync {
+    /// Subscribe to every script in `scripts`, returning their current status or the error
+    /// encountered for each one of them
+    fn els_batch_script_subscribe(
+        client: &Arc<Self>,
+        scripts: &[Script],
+    ) -> Vec<Result<Option<ScriptStatus>, Error>>;
+
+    /// Pop the oldest status notified for `script` that hasn't been processed yet
+    fn els_script_pop(&self, script: &Script) -> Result<Option<ScriptStatus>, Error>;
+
+    /// Subscribe to the new headers, returning the height of the tip
+    fn els_block_headers_subscribe(&self) -> Result<u32, Error>;
+
+    /// Pop the oldest header notification that hasn't been processed yet, returning its height
+    fn els_block_headers_pop(&self) -> Result<Option<u32>, Error>;
+
+    /// Read the notifications sent by the server since the last request
+    fn els_ping(&self) -> Result<(), Error>;
+}
+
+/// State of the subscriptions of an [`ElectrumBlockchain`]
+#[derive(Debug, Default)]
+struct Subscriptions {
+    /// Last status received for each of the scripts we are subscribed to
+    statuses: HashMap<Script, Option<ScriptStatus>>,
+    /// Scripts whose status has changed since the last sync
+    dirty: HashSet<Script>,
+    /// Whether the full sync has been completed after subscribing
+    synced: bool,
+    /// Height of the last header notified by the server
+    tip: Option<u32>,
+}
+
+impl Subscriptions {
+    /// Subscribe to the scripts of `database` we don't know yet, `chunk_size` at a time, and
+    /// process the notifications received for the other ones, marking as dirty the scripts whose
+    /// status has changed
+    fn update_statuses<C: ElectrumSubscribe, D: BatchDatabase>(
+        &mut self,
+        client: &Arc<C>,
+        chunk_size: usize,
+        database: &D,
+    ) -> Result<(), Error> {
+        // notifications are only read from the socket while making a request
+        client.els_ping()?;
+
+        let mut new_scripts = vec![];
+        for script in database.iter_script_pubkeys(None)? {
+            if !self.statuses.contains_key(&script) {
+                new_scripts.push(script);
+                continue;
+            }
+
+            while let Some(status) = client.els_script_pop(&script)? {
+                if self.statuses.insert(script.clone(), Some(status)) != Some(Some(status)) {
+                    self.dirty.insert(script.clone());
+                }
+            }
+        }
+
+        for chunk in new_scripts.chunks(chunk_size.max(1)) {
+            // keep the subscriptions that went through even if some failed, the client would
+            // refuse to subscribe to them again
+            let mut error = None;
+            for (script, status) in chunk
+                .iter()
+                .zip(C::els_batch_script_subscribe(client, chunk))
+            {
+                match status {
+                    Ok(status) => {
+                        if status.is_some() {
+                            self.dirty.insert(script.clone());
+                        }
+                        self.statuses.insert(script.clone(), status);
+                    }
+                    Err(e) => error = error.or(Some(e)),
+                }
+            }
+
+            if let Some(e) = error {
+                return Err(e);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Process the header notifications and return the height of the tip
+    fn update_tip<C: ElectrumSubscribe>(&mut self, client: &C) -> Result<u32, Error> {
+        match self.tip {
+            None => self.tip = Some(client.els_block_headers_subscribe()?),
+            Some(_) => {
+                client.els_ping()?;
+                while let Some(height) = client.els_block_headers_pop()? {
+                    self.tip = Some(height);
+                }
+            }
+        }
+
+        Ok(self.tip.unwrap())
+    }
+
+    /// Update `database` with a full sync the first time, and then only with the history of the
+    /// scripts whose status has changed
+    fn sync<C: ElectrumSubscribe, D: BatchDatabase, P: Progress>(
+        &mut self,
+        client: &Arc<C>,
+        stop_gap: Option<usize>,
+        database: &mut D,
+        progress_update: P,
+    ) -> Result<(), Error> {
+        let chunk_size = stop_gap.unwrap_or(20);
+
+        // subscribe before the full sync, so that nothing happening during it can be missed
+        self.update_statuses(client, chunk_size, database)?;
+
+        if !self.synced {
+            client.electrum_like_setup(stop_gap, database, progress_update)?;
+            self.synced = true;
+        } else if !self.dirty.is_empty() {
+            let scripts = self.dirty.iter().cloned().collect::<Vec<_>>();
+            client.electrum_like_update(&scripts, chunk_size, database)?;
+        }
+        self.dirty.clear();
+
+        Ok(())
+    }
+}
+
+/// Run `f` on the `subscriptions`, starting over with a full sync if it fails because the client
+/// has lost them
+fn update_subscriptions<T, F>(subscriptions: &Mutex<Subscriptions>, f: F) -> Result<T, Error>
+where
+    F: FnOnce(&mut Subscriptions) -> Result<T, Error>,
+{
+    let mut subscriptions = subscriptions.lock().unwrap();
+    let result = f(&mut subscriptions);
+    if matches!(&result, Err(e) if lost_subscriptions(e)) {
+        *subscriptions = Subscriptions::default();
+    }
+
+    result
+}
+
+/// Return whether `error` means the client has lost its subscriptions, which happens when it
+/// reconnects to the server
+fn lost_subscriptions(error: &Error) -> bool {
+    fn is_not_subscribed(error: &electrum_client::Error) -> bool {
+        match error {
+            electrum_client::Error::NotSubscribed(_) => true,
+            electrum_client::Error::AllAttemptsErrored(errors) => {
+                errors.iter().any(is_not_subscribed)
+            }
+            _ => false,
+        }
+    }
+
+    match error {
+        Error::Electrum(e) => is_not_subscribed(e),
+        _ => false,
+    }
+}
+
+impl ElectrumBlockchain {
+    /// Create a long-lived [`ElectrumBlockchain`] that subscribes to the scripts of the wallet and
+    /// only re-queries the ones whose status has changed when syncing
+    ///
+    /// See the [`blockchain::electrum`](crate::blockchain::electrum) module for more details.
+    pub fn with_subscriptions(client: Client) -> Self {
+        ElectrumBlockchain {
+            client: Arc::new(client),
+            subscriptions: Some(Mutex::new(Subscriptions::default())),
+        }
+    }
+}
 
 #[cfg(test)]
 #[cfg(feature = "test-electrum")]
@@ -67,7 +268,10 @@ fn local_electrs() -> ElectrumBlockchain {
 
 impl std::convert::From<Client> for ElectrumBlockchain {
     fn from(client: Client) -> Self {
-        ElectrumBlockchain(client)
+        ElectrumBlockchain {
+            client: Arc::new(client),
+            subscriptions: None,
+        }
     }
 }
 
@@ -88,30 +292,42 @@ impl Blockchain for ElectrumBlockchain {
         database: &mut D,
         progress_update: P,
     ) -> Result<(), Error> {
-        self.0
-            .electrum_like_setup(stop_gap, database, progress_update)
+        match &self.subscriptions {
+            Some(subscriptions) => update_subscriptions(subscriptions, |subscriptions| {
+                subscriptions.sync(&self.client, stop_gap, database, progress_update)
+            }),
+            None => self
+                .client
+                .electrum_like_setup(stop_gap, database, progress_update),
+        }
     }
 
     fn get_tx(&self, txid: &Txid) -> Result<Option<Transaction>, Error> {
-        Ok(self.0.transaction_get(txid).map(Option::Some)?)
+        Ok(self.client.transaction_get(txid).map(Option::Some)?)
     }
 
     fn broadcast(&self, tx: &Transaction) -> Result<(), Error> {
-        Ok(self.0.transaction_broadcast(tx).map(|_| ())?)
+        Ok(self.client.transaction_broadcast(tx).map(|_| ())?)
     }
 
     fn get_height(&self) -> Result<u32, Error> {
+        if let Some(subscriptions) = &self.subscriptions {
+            return update_subscriptions(subscriptions, |subscriptions| {
+                subscriptions.update_tip(self.client.as_ref())
+            });
+        }
+
         // TODO: unsubscribe when added to the client, or is there a better call to use here?
 
         Ok(self
-            .0
+            .client
             .block_headers_subscribe()
             .map(|data| data.height as u32)?)
     }
 
     fn estimate_fee(&self, target: usize) -> Result<FeeRate, Error> {
         Ok(FeeRate::from_btc_per_kvb(
-            self.0.estimate_fee(target)? as f32
+            self.client.estimate_fee(target)? as f32
         ))
     }
 }
@@ -156,6 +372,90 @@ impl ElectrumLikeSync for Client {
     }
 }
 
+impl ElectrumSubscribe for Client {
+    fn els_batch_script_subscribe(
+        client: &Arc<Self>,
+        scripts: &[Script],
+    ) -> Vec<Result<Option<ScriptStatus>, Error>> {
+        // the client can't batch subscriptions, but it can have many requests in flight at once on
+        // the same connection, so a few threads are enough to keep it busy
+        let client = Arc::clone(client);
+        parallel_map(scripts.to_vec(), SUBSCRIBE_THREADS, move |script| {
+            client.script_subscribe(script)
+        })
+        .into_iter()
+        .map(|result| match result {
+            Some(result) => result.map_err(Error::Electrum),
+            None => Err(Error::Generic(
+                "The thread subscribing to a script panicked".into(),
+            )),
+        })
+        .collect()
+    }
+
+    fn els_script_pop(&self, script: &Script) -> Result<Option<ScriptStatus>, Error> {
+        self.script_pop(script).map_err(Error::Electrum)
+    }
+
+    fn els_block_headers_subscribe(&self) -> Result<u32, Error> {
+        self.block_headers_subscribe()
+            .map(|data| data.height as u32)
+            .map_err(Error::Electrum)
+    }
+
+    fn els_block_headers_pop(&self) -> Result<Option<u32>, Error> {
+        self.block_headers_pop()
+            .map(|data| data.map(|data| data.height as u32))
+            .map_err(Error::Electrum)
+    }
+
+    fn els_ping(&self) -> Result<(), Error> {
+        self.ping().map_err(Error::Electrum)
+    }
+}
+
+/// Call `f` on every item, from at most `threads` threads at once
+///
+/// The results are returned in the same order as the items, with `None` for the items processed
+/// by a thread that panicked.
+fn parallel_map<T, R, F>(items: Vec<T>, threads: usize, f: F) -> Vec<Option<R>>
+where
+    T: Send + Sync + 'static,
+    R: Send + 'static,
+    F: Fn(&T) -> R + Send + Sync + 'static,
+{
+    let items = Arc::new(items);
+    let f = Arc::new(f);
+    let next = Arc::new(AtomicUsize::new(0));
+    let results = Arc::new(Mutex::new(items.iter().map(|_| None).collect::<Vec<_>>()));
+
+    let handles = (0..threads.max(1).min(items.len()))
+        .map(|_| {
+            let items = Arc::clone(&items);
+            let f = Arc::clone(&f);
+            let next = Arc::clone(&next);
+            let results = Arc::clone(&results);
+            thread::spawn(move || loop {
+                let index = next.fetch_add(1, Ordering::SeqCst);
+                match items.get(index) {
+                    Some(item) => {
+                        let result = f(item);
+                        results.lock().unwrap()[index] = Some(result);
+                    }
+                    None => break,
+                }
+            })
+        })
+        .collect::<Vec<_>>();
+    for handle in handles {
+        // the items left by a thread that panicked are processed by the other ones
+        let _ = handle.join();
+    }
+
+    let mut results = results.lock().unwrap();
+    std::mem::take(&mut *results)
+}
+
 /// Configuration for an [`ElectrumBlockchain`]
 #[derive(Debug, serde::Deserialize, serde::Serialize)]
 pub struct ElectrumBlockchainConfig {
@@ -169,6 +469,10 @@ pub struct ElectrumBlockchainConfig {
     pub retry: u8,
     /// Request timeout (seconds)
     pub timeout: u8,
+    /// Subscribe to the scripts of the wallet and only re-query the ones that have changed when
+    /// syncing, see [`ElectrumBlockchain::with_subscriptions`]
+    #[serde(default)]
+    pub subscribe: bool,
 }
 
 impl ConfigurableBlockchain for ElectrumBlockchain {
@@ -182,9 +486,239 @@ impl ConfigurableBlockchain for ElectrumBlockchain {
             .timeout(config.timeout)?
             .build();
 
-        Ok(ElectrumBlockchain(Client::from_config(
-            config.url.as_str(),
-            electrum_config,
-        )?))
+        let client = Client::from_config(config.url.as_str(), electrum_config)?;
+
+        Ok(if config.subscribe {
+            ElectrumBlockchain::with_subscriptions(client)
+        } else {
+            ElectrumBlockchain::from(client)
+        })
+    }
+}
+
+#[cfg(test)]
+#[cfg(not(feature = "async-interface"))]
+mod test {
+    use std::collections::VecDeque;
+
+    use bitcoin::hashes::{sha256, Hash};
+    use electrum_client::ToElectrumScriptHash;
+
+    use super::*;
+    use crate::blockchain::noop_progress;
+    use crate::blockchain::utils::test::{script, tx, MockServer};
+    use crate::database::{BatchOperations, Database, MemoryDatabase};
+    use crate::types::ScriptType;
+
+    fn to_status(hash: sha256::Hash) -> ScriptStatus {
+        ScriptStatus::from(hash.into_inner())
+    }
+
+    impl ElectrumSubscribe for MockServer {
+        fn els_batch_script_subscribe(
+            client: &Arc<Self>,
+            scripts: &[Script],
+        ) -> Vec<Result<Option<ScriptStatus>, Error>> {
+            client.subscribe_batches.lock().unwrap().push(scripts.len());
+
+            scripts
+                .iter()
+                .map(|script| {
+                    let mut notifications = client.notifications.lock().unwrap();
+                    if notifications.contains_key(script) {
+                        return Err(Error::Electrum(electrum_client::Error::AlreadySubscribed(
+                            script.to_electrum_scripthash(),
+                        )));
+                    }
+                    notifications.insert(script.clone(), VecDeque::new());
+
+                    Ok(client.status(script).map(to_status))
+                })
+                .collect()
+        }
+
+        fn els_script_pop(&self, script: &Script) -> Result<Option<ScriptStatus>, Error> {
+            match self.notifications.lock().unwrap().get_mut(script) {
+                Some(queue) => Ok(queue.pop_front().map(to_status)),
+                None => Err(Error::Electrum(electrum_client::Error::NotSubscribed(
+                    script.to_electrum_scripthash(),
+                ))),
+            }
+        }
+
+        fn els_block_headers_subscribe(&self) -> Result<u32, Error> {
+            Ok(0)
+        }
+
+        fn els_block_headers_pop(&self) -> Result<Option<u32>, Error> {
+            Ok(None)
+        }
+
+        fn els_ping(&self) -> Result<(), Error> {
+            Ok(())
+        }
+    }
+
+    fn get_test_db(scripts: u8) -> MemoryDatabase {
+        let mut db = MemoryDatabase::new();
+        for i in 0..scripts {
+            db.set_script_pubkey(&script(i), ScriptType::External, i as u32)
+                .unwrap();
+        }
+
+        db
+    }
+
+    #[test]
+    fn test_subscriptions_dirty_scripts() {
+        let mut db = get_test_db(3);
+        let mut server = MockServer::default();
+        let tx_a = tx(&[], &[script(0)]);
+        server.add_tx(tx_a.clone(), 100);
+        let mut server = Arc::new(server);
+
+        let mut subscriptions = Subscriptions::default();
+        subscriptions
+            .sync(&server, None, &mut db, noop_progress())
+            .unwrap();
+        assert!(subscriptions.synced);
+        assert!(subscriptions.dirty.is_empty());
+        assert_eq!(subscriptions.statuses.len(), 3);
+        assert!(db.get_tx(&tx_a.txid(), false).unwrap().is_some());
+
+        // a notification that doesn't change the status is ignored
+        let status = server.status(&script(0)).unwrap();
+        server
+            .notifications
+            .lock()
+            .unwrap()
+            .get_mut(&script(0))
+            .unwrap()
+            .push_back(status);
+        subscriptions.update_statuses(&server, 20, &db).unwrap();
+        assert!(subscriptions.dirty.is_empty());
+
+        let tx_b = tx(&[], &[script(1)]);
+        Arc::get_mut(&mut server).unwrap().add_tx(tx_b.clone(), 0);
+        subscriptions.update_statuses(&server, 20, &db).unwrap();
+        assert_eq!(
+            subscriptions.dirty,
+            vec![script(1)].into_iter().collect::<HashSet<_>>()
+        );
+
+        server.queried.lock().unwrap().clear();
+        subscriptions
+            .sync(&server, None, &mut db, noop_progress())
+            .unwrap();
+        assert_eq!(*server.queried.lock().unwrap(), vec![script(1)]);
+        assert!(subscriptions.dirty.is_empty());
+        assert!(db.get_tx(&tx_b.txid(), false).unwrap().is_some());
+
+        // a new script of the wallet with some history is subscribed to and queried
+        let tx_c = tx(&[], &[script(3)]);
+        Arc::get_mut(&mut server).unwrap().add_tx(tx_c.clone(), 0);
+        db.set_script_pubkey(&script(3), ScriptType::External, 3)
+            .unwrap();
+
+        server.queried.lock().unwrap().clear();
+        subscriptions
+            .sync(&server, None, &mut db, noop_progress())
+            .unwrap();
+        assert_eq!(*server.queried.lock().unwrap(), vec![script(3)]);
+        assert!(db.get_tx(&tx_c.txid(), false).unwrap().is_some());
+    }
+
+    #[test]
+    fn test_subscriptions_batched() {
+        let mut db = get_test_db(5);
+        let server = Arc::new(MockServer::default());
+
+        let mut subscriptions = Subscriptions::default();
+        subscriptions
+            .sync(&server, Some(2), &mut db, noop_progress())
+            .unwrap();
+
+        assert_eq!(*server.subscribe_batches.lock().unwrap(), vec![2, 2, 1]);
+        assert_eq!(subscriptions.statuses.len(), 5);
+    }
+
+    #[test]
+    fn test_subscriptions_lost() {
+        let mut db = get_test_db(3);
+        let mut server = MockServer::default();
+        server.add_tx(tx(&[], &[script(0)]), 100);
+        let server = Arc::new(server);
+
+        let subscriptions = Mutex::new(Subscriptions::default());
+        update_subscriptions(&subscriptions, |subscriptions| {
+            subscriptions.sync(&server, None, &mut db, noop_progress())
+        })
+        .unwrap();
+
+        // the client has reconnected, so the next sync fails and starts over
+        server.reconnect();
+        let result = update_subscriptions(&subscriptions, |subscriptions| {
+            subscriptions.sync(&server, None, &mut db, noop_progress())
+        });
+        assert!(matches!(&result, Err(e) if lost_subscriptions(e)));
+        {
+            let subscriptions = subscriptions.lock().unwrap();
+            assert!(!subscriptions.synced);
+            assert!(subscriptions.statuses.is_empty());
+        }
+
+        server.queried.lock().unwrap().clear();
+        update_subscriptions(&subscriptions, |subscriptions| {
+            subscriptions.sync(&server, None, &mut db, noop_progress())
+        })
+        .unwrap();
+        assert!(subscriptions.lock().unwrap().synced);
+        assert_eq!(server.notifications.lock().unwrap().len(), 3);
+        // a full sync queries every script again
+        assert!(server.queried.lock().unwrap().len() >= 3);
+    }
+
+    #[test]
+    fn test_lost_subscriptions() {
+        let not_subscribed =
+            || electrum_client::Error::NotSubscribed(script(0).to_electrum_scripthash());
+
+        assert!(lost_subscriptions(&Error::Electrum(not_subscribed())));
+        assert!(lost_subscriptions(&Error::Electrum(
+            electrum_client::Error::AllAttemptsErrored(vec![
+                electrum_client::Error::Message("timeout".into()),
+                not_subscribed(),
+            ])
+        )));
+        assert!(!lost_subscriptions(&Error::Electrum(
+            electrum_client::Error::Message("timeout".into())
+        )));
+        assert!(!lost_subscriptions(&Error::TransactionNotFound));
+    }
+
+    #[test]
+    fn test_parallel_map() {
+        let running = Arc::new(AtomicUsize::new(0));
+        let max_running = Arc::new(AtomicUsize::new(0));
+
+        let (running_f, max_running_f) = (Arc::clone(&running), Arc::clone(&max_running));
+        let results = parallel_map((0..50).collect(), 4, move |item: &usize| {
+            let now_running = running_f.fetch_add(1, Ordering::SeqCst) + 1;
+            max_running_f.fetch_max(now_running, Ordering::SeqCst);
+            thread::sleep(std::time::Duration::from_millis(1));
+            running_f.fetch_sub(1, Ordering::SeqCst);
+
+            if *item == 7 {
+                panic!("item 7");
+            }
+            item * 2
+        });
+
+        assert!(max_running.load(Ordering::SeqCst) <= 4);
+        // only the item that made its thread panic is missing
+        assert_eq!(results.iter().filter(|result| result.is_none()).count(), 1);
+        assert_eq!(results[7], None);
+        assert_eq!(results[8], Some(16));
+        assert_eq!(results[49], Some(98));
     }
 }
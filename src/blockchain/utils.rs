@@ -34,7 +34,7 @@ use bitcoin::{BlockHeader, OutPoint, Script, Transaction, Txid};
 use super::*;
 use crate::database::{BatchDatabase, BatchOperations, DatabaseUtils};
 use crate::error::Error;
-use crate::types::{ScriptType, TransactionDetails, UTXO};
+use crate::types::{ScriptType, TransactionDetails, WalletBirthday, UTXO};
 use crate::wallet::time::Instant;
 use crate::wallet::utils::ChunksIterator;
 
@@ -82,9 +82,10 @@ pub trait ElectrumLikeSync {
         let mut max_indexes = HashMap::new();
 
         let mut wallet_chains = vec![ScriptType::Internal, ScriptType::External];
+        wallet_chains.extend(db.iter_keychains()?);
         // shuffling improve privacy, the server doesn't know my first request is from my internal or external addresses
         wallet_chains.shuffle(&mut thread_rng());
-        // download history of our internal and external script_pubkeys
+        // download history of our internal, external and keychains' script_pubkeys
         for script_type in wallet_chains.iter() {
             let script_iter = db.iter_script_pubkeys(Some(*script_type))?.into_iter();
 
@@ -129,12 +130,138 @@ pub trait ElectrumLikeSync {
             }
         }
 
+        maybe_await!(self.save_history(history_txs_id, txid_height, None, chunk_size, db))?;
+        info!("finish setup, elapsed {:?}ms", start.elapsed().as_millis());
+
+        Ok(())
+    }
+
+    /// Update the database with the history of `scripts` only, leaving the transactions that
+    /// don't involve them untouched
+    #[cfg(any(feature = "electrum", test))]
+    fn electrum_like_update<D: BatchDatabase>(
+        &self,
+        scripts: &[Script],
+        chunk_size: usize,
+        db: &mut D,
+    ) -> Result<(), Error> {
+        let start = Instant::new();
+        debug!("start update of {} scripts", scripts.len());
+
+        let mut history_txs_id = HashSet::new();
+        let mut txid_height = HashMap::new();
+        let mut max_indexes = HashMap::new();
+
+        for chunk in ChunksIterator::new(scripts.iter(), chunk_size) {
+            let call_result: Vec<Vec<ELSGetHistoryRes>> =
+                maybe_await!(self.els_batch_script_get_history(chunk.iter().cloned()))?;
+            for (script, history) in chunk.into_iter().zip(call_result) {
+                if history.is_empty() {
+                    continue;
+                }
+                if let Some((script_type, child)) = db.get_path_from_script_pubkey(script)? {
+                    let max_index = max_indexes.entry(script_type).or_insert(child);
+                    *max_index = std::cmp::max(*max_index, child);
+                }
+
+                for el in history {
+                    if el.height <= 0 {
+                        txid_height.insert(el.tx_hash, None);
+                    } else {
+                        txid_height.insert(el.tx_hash, Some(el.height as u32));
+                    }
+                    history_txs_id.insert(el.tx_hash);
+                }
+            }
+        }
+
+        // the indexes can only grow, since we only see part of the wallet
+        for (script_type, index) in max_indexes {
+            if db.get_last_index(script_type)?.unwrap_or(0) < index {
+                db.set_last_index(script_type, index)?;
+            }
+        }
+
+        let scripts = scripts.iter().cloned().collect();
+        maybe_await!(self.save_history(
+            history_txs_id,
+            txid_height,
+            Some(&scripts),
+            chunk_size,
+            db
+        ))?;
+        info!("finish update, elapsed {:?}ms", start.elapsed().as_millis());
+
+        Ok(())
+    }
+
+    /// Save the transactions in `history_txs_id` and remove the ones that are not part of the
+    /// history anymore
+    ///
+    /// If `scripts` is set, the history only covers those scripts and the transactions that don't
+    /// involve them are kept.
+    fn save_history<D: BatchDatabase>(
+        &self,
+        mut history_txs_id: HashSet<Txid>,
+        mut txid_height: HashMap<Txid, Option<u32>>,
+        scripts: Option<&HashSet<Script>>,
+        chunk_size: usize,
+        db: &mut D,
+    ) -> Result<(), Error> {
         // get db status
         let txs_details_in_db: HashMap<Txid, TransactionDetails> = db
             .iter_txs(false)?
             .into_iter()
             .map(|tx| (tx.txid, tx))
             .collect();
+        // the timestamps of the blocks that still confirm the txs we have already saved don't
+        // have to be downloaded again
+        let mut height_timestamp: HashMap<u32, u64> = txs_details_in_db
+            .values()
+            .filter(|details| details.timestamp > 0)
+            .filter_map(|details| match txid_height.get(&details.txid) {
+                Some(Some(height)) if details.height == Some(*height) => {
+                    Some((*height, details.timestamp))
+                }
+                _ => None,
+            })
+            .collect();
+
+        // ignore the transactions confirmed before the birthday of the wallet
+        if let Some(birthday) = db.get_birthday()? {
+            if let WalletBirthday::Timestamp(_) = birthday {
+                let missing_heights = txid_height
+                    .values()
+                    .filter_map(|height| *height)
+                    .filter(|height| !height_timestamp.contains_key(height))
+                    .collect::<HashSet<_>>();
+                if !missing_heights.is_empty() {
+                    height_timestamp.extend(maybe_await!(
+                        self.download_headers_timestamp(missing_heights, chunk_size)
+                    )?);
+                }
+            }
+
+            let before_birthday = txid_height
+                .iter()
+                .filter_map(|(txid, height)| height.map(|height| (txid, height)))
+                .filter(|(_, height)| {
+                    let timestamp = height_timestamp.get(height).cloned().unwrap_or(0);
+                    !birthday.covers(*height, timestamp)
+                })
+                .map(|(txid, _)| *txid)
+                .collect::<Vec<_>>();
+            debug!(
+                "Ignoring {} txs confirmed before the birthday {:?}",
+                before_birthday.len(),
+                birthday
+            );
+            for txid in before_birthday {
+                txid_height.remove(&txid);
+                history_txs_id.remove(&txid);
+            }
+        }
+
         let txs_raw_in_db: HashMap<Txid, Transaction> = db
             .iter_raw_txs()?
             .into_iter()
@@ -152,6 +279,7 @@ pub trait ElectrumLikeSync {
         let new_timestamps = maybe_await!(self.download_needed_headers(
             &txid_height,
             &txs_details_in_db,
+            &mut height_timestamp,
             chunk_size
         ))?;
 
@@ -181,9 +309,15 @@ pub trait ElectrumLikeSync {
             }
         }
 
-        // remove any tx details in db but not in history_txs_id
+        // remove any tx details in db but not in history_txs_id, only looking at the txs that
+        // involve `scripts` if the history doesn't cover the whole wallet
         for txid in txs_details_in_db.keys() {
-            if !history_txs_id.contains(txid) {
+            let in_scope = match (scripts, txs_raw_in_db.get(txid)) {
+                (None, _) => true,
+                (Some(scripts), Some(tx)) => tx_involves_scripts(tx, &txs_raw_in_db, scripts),
+                (Some(_), None) => false,
+            };
+            if in_scope && !history_txs_id.contains(txid) {
                 batch.del_tx(&txid, false)?;
             }
         }
@@ -196,7 +330,6 @@ pub trait ElectrumLikeSync {
         }
 
         db.commit_batch(batch)?;
-        info!("finish setup, elapsed {:?}ms", start.elapsed().as_millis());
 
         Ok(())
     }
@@ -243,11 +376,13 @@ pub trait ElectrumLikeSync {
         Ok(txs_downloaded)
     }
 
-    /// download headers at heights in `txid_height` if tx details not already present, returns a map Txid -> timestamp
+    /// download headers at heights in `txid_height` if tx details not already present and the
+    /// timestamp isn't in `height_timestamp` yet, returns a map Txid -> timestamp
     fn download_needed_headers(
         &self,
         txid_height: &HashMap<Txid, Option<u32>>,
         txs_details_in_db: &HashMap<Txid, TransactionDetails>,
+        height_timestamp: &mut HashMap<u32, u64>,
         chunk_size: usize,
     ) -> Result<HashMap<Txid, u64>, Error> {
         let mut txid_timestamp = HashMap::new();
@@ -256,30 +391,47 @@ pub trait ElectrumLikeSync {
             .filter(|(t, _)| txs_details_in_db.get(*t).is_none())
             .filter_map(|(t, o)| o.map(|h| (t, h)))
             .collect();
-        let needed_heights: HashSet<u32> = needed_txid_height.values().cloned().collect();
+        let needed_heights: HashSet<u32> = needed_txid_height
+            .values()
+            .filter(|h| !height_timestamp.contains_key(h))
+            .cloned()
+            .collect();
         if !needed_heights.is_empty() {
-            info!("{} headers to download for timestamp", needed_heights.len());
-            let mut height_timestamp: HashMap<u32, u64> = HashMap::new();
-            for chunk in ChunksIterator::new(needed_heights.into_iter(), chunk_size) {
-                let call_result: Vec<BlockHeader> =
-                    maybe_await!(self.els_batch_block_header(chunk.clone()))?;
-                height_timestamp.extend(
-                    chunk
-                        .into_iter()
-                        .zip(call_result.iter().map(|h| h.time as u64)),
-                );
-            }
-            for (txid, height) in needed_txid_height {
-                let timestamp = height_timestamp
-                    .get(&height)
-                    .ok_or_else(|| Error::Generic("timestamp missing".to_string()))?;
-                txid_timestamp.insert(*txid, *timestamp);
-            }
+            height_timestamp.extend(maybe_await!(
+                self.download_headers_timestamp(needed_heights, chunk_size)
+            )?);
+        }
+        for (txid, height) in needed_txid_height {
+            let timestamp = height_timestamp
+                .get(&height)
+                .ok_or_else(|| Error::Generic("timestamp missing".to_string()))?;
+            txid_timestamp.insert(*txid, *timestamp);
         }
 
         Ok(txid_timestamp)
     }
 
+    /// download headers at `heights`, returns a map height -> timestamp
+    fn download_headers_timestamp(
+        &self,
+        heights: HashSet<u32>,
+        chunk_size: usize,
+    ) -> Result<HashMap<u32, u64>, Error> {
+        info!("{} headers to download for timestamp", heights.len());
+        let mut height_timestamp: HashMap<u32, u64> = HashMap::new();
+        for chunk in ChunksIterator::new(heights.into_iter(), chunk_size) {
+            let call_result: Vec<BlockHeader> =
+                maybe_await!(self.els_batch_block_header(chunk.clone()))?;
+            height_timestamp.extend(
+                chunk
+                    .into_iter()
+                    .zip(call_result.iter().map(|h| h.time as u64)),
+            );
+        }
+
+        Ok(height_timestamp)
+    }
+
     fn download_and_save_in_chunks<D: BatchDatabase>(
         &self,
         to_download: Vec<&Txid>,
@@ -379,6 +531,24 @@ fn save_transaction_details_and_utxos<D: BatchDatabase>(
     Ok(())
 }
 
+/// Return whether `tx` spends from or sends to one of `scripts`
+fn tx_involves_scripts(
+    tx: &Transaction,
+    txs_raw_in_db: &HashMap<Txid, Transaction>,
+    scripts: &HashSet<Script>,
+) -> bool {
+    tx.output
+        .iter()
+        .any(|output| scripts.contains(&output.script_pubkey))
+        || tx.input.iter().any(|input| {
+            txs_raw_in_db
+                .get(&input.previous_output.txid)
+                .and_then(|prev_tx| prev_tx.output.get(input.previous_output.vout as usize))
+                .map(|prev_output| scripts.contains(&prev_output.script_pubkey))
+                .unwrap_or(false)
+        })
+}
+
 /// returns utxo dependency as the inputs needed for the utxo to exist
 /// `tx_raw_in_db` must contains utxo's generating txs or errors witt [crate::Error::TransactionNotFound]
 fn utxos_deps<D: BatchDatabase>(
@@ -397,3 +567,308 @@ fn utxos_deps<D: BatchDatabase>(
     }
     Ok(utxos_deps)
 }
+
+#[cfg(test)]
+#[cfg(not(feature = "async-interface"))]
+pub(crate) mod test {
+    use std::collections::VecDeque;
+    use std::sync::Mutex;
+
+    use bitcoin::blockdata::constants::genesis_block;
+    use bitcoin::hashes::{sha256, Hash};
+    use bitcoin::{Network, TxIn, TxOut};
+
+    use super::*;
+    use crate::database::{Database, MemoryDatabase};
+    use crate::types::TIMESTAMP_WINDOW;
+
+    /// An Electrum-like server that keeps its state in memory and records the scripts it is
+    /// queried for
+    #[derive(Default)]
+    pub(crate) struct MockServer {
+        pub history: HashMap<Script, Vec<(Txid, i32)>>,
+        pub txs: HashMap<Txid, Transaction>,
+        pub queried: Mutex<Vec<Script>>,
+        /// Statuses notified to the subscribed scripts and not popped yet
+        pub notifications: Mutex<HashMap<Script, VecDeque<sha256::Hash>>>,
+        /// Number of scripts in each batch of subscriptions
+        pub subscribe_batches: Mutex<Vec<usize>>,
+        /// Heights of the headers downloaded
+        pub headers: Mutex<Vec<u32>>,
+    }
+
+    impl MockServer {
+        /// Add a transaction confirmed at `height`, or unconfirmed if it's 0, to the history of
+        /// the scripts it spends from or sends to
+        pub fn add_tx(&mut self, tx: Transaction, height: i32) {
+            let txid = tx.txid();
+            let mut scripts = tx
+                .output
+                .iter()
+                .map(|output| output.script_pubkey.clone())
+                .collect::<HashSet<_>>();
+            for input in &tx.input {
+                if let Some(prev_output) = self
+                    .txs
+                    .get(&input.previous_output.txid)
+                    .and_then(|prev_tx| prev_tx.output.get(input.previous_output.vout as usize))
+                {
+                    scripts.insert(prev_output.script_pubkey.clone());
+                }
+            }
+
+            self.txs.insert(txid, tx);
+            for script in scripts {
+                self.history
+                    .entry(script.clone())
+                    .or_default()
+                    .push((txid, height));
+                self.notify(&script);
+            }
+        }
+
+        /// Return the status of `script`, the hash of its history, or `None` if it's empty
+        pub fn status(&self, script: &Script) -> Option<sha256::Hash> {
+            let history = self
+                .history
+                .get(script)
+                .filter(|history| !history.is_empty())?;
+            let status = history
+                .iter()
+                .map(|(txid, height)| format!("{}:{}:", txid, height))
+                .collect::<String>();
+
+            Some(sha256::Hash::hash(status.as_bytes()))
+        }
+
+        /// Notify the current status of `script`, if it's subscribed
+        fn notify(&self, script: &Script) {
+            if let Some(queue) = self.notifications.lock().unwrap().get_mut(script) {
+                queue.extend(self.status(script));
+            }
+        }
+
+        /// Drop all the subscriptions, like a server does when the client reconnects
+        pub fn reconnect(&self) {
+            self.notifications.lock().unwrap().clear();
+        }
+    }
+
+    impl ElectrumLikeSync for MockServer {
+        fn els_batch_script_get_history<'s, I: IntoIterator<Item = &'s Script> + Clone>(
+            &self,
+            scripts: I,
+        ) -> Result<Vec<Vec<ELSGetHistoryRes>>, Error> {
+            Ok(scripts
+                .into_iter()
+                .map(|script| {
+                    self.queried.lock().unwrap().push(script.clone());
+                    self.history
+                        .get(script)
+                        .into_iter()
+                        .flatten()
+                        .map(|(tx_hash, height)| ELSGetHistoryRes {
+                            tx_hash: *tx_hash,
+                            height: *height,
+                        })
+                        .collect()
+                })
+                .collect())
+        }
+
+        fn els_batch_transaction_get<'s, I: IntoIterator<Item = &'s Txid> + Clone>(
+            &self,
+            txids: I,
+        ) -> Result<Vec<Transaction>, Error> {
+            txids
+                .into_iter()
+                .map(|txid| {
+                    self.txs
+                        .get(txid)
+                        .cloned()
+                        .ok_or(Error::TransactionNotFound)
+                })
+                .collect()
+        }
+
+        fn els_batch_block_header<I: IntoIterator<Item = u32> + Clone>(
+            &self,
+            heights: I,
+        ) -> Result<Vec<BlockHeader>, Error> {
+            Ok(heights
+                .into_iter()
+                .inspect(|height| self.headers.lock().unwrap().push(*height))
+                .map(|height| BlockHeader {
+                    time: height,
+                    ..genesis_block(Network::Regtest).header
+                })
+                .collect())
+        }
+    }
+
+    pub(crate) fn script(n: u8) -> Script {
+        Script::from(vec![0x00, 0x14, n])
+    }
+
+    /// Return a transaction spending `inputs` and sending 50'000 satoshi to each of `outputs`
+    pub(crate) fn tx(inputs: &[OutPoint], outputs: &[Script]) -> Transaction {
+        let input = match inputs {
+            // a coinbase, so that there's no previous tx to download
+            [] => vec![TxIn::default()],
+            inputs => inputs
+                .iter()
+                .map(|previous_output| TxIn {
+                    previous_output: *previous_output,
+                    ..Default::default()
+                })
+                .collect(),
+        };
+
+        Transaction {
+            version: 1,
+            // make every tx unique
+            lock_time: rand::random(),
+            input,
+            output: outputs
+                .iter()
+                .map(|script_pubkey| TxOut {
+                    value: 50_000,
+                    script_pubkey: script_pubkey.clone(),
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn test_setup_keychains_with_gaps() {
+        let mut db = MemoryDatabase::new();
+        db.set_script_pubkey(&script(0), ScriptType::External, 0)
+            .unwrap();
+        // the wallet has added a single keychain, which isn't the first one
+        db.check_descriptor_checksum(ScriptType::Keychain(1), b"checksum")
+            .unwrap();
+        db.set_script_pubkey(&script(1), ScriptType::Keychain(1), 0)
+            .unwrap();
+
+        let mut server = MockServer::default();
+        let funding = tx(&[], &[script(1)]);
+        server.add_tx(funding.clone(), 100);
+
+        server
+            .electrum_like_setup(None, &mut db, crate::blockchain::noop_progress())
+            .unwrap();
+
+        assert!(db.get_tx(&funding.txid(), false).unwrap().is_some());
+        assert_eq!(db.get_last_index(ScriptType::Keychain(1)).unwrap(), Some(0));
+    }
+
+    #[test]
+    fn test_tx_involves_scripts() {
+        let funding = tx(&[], &[script(0), script(1)]);
+        let spending = tx(&[OutPoint::new(funding.txid(), 1)], &[script(2)]);
+        let mut txs_raw_in_db = HashMap::new();
+        txs_raw_in_db.insert(funding.txid(), funding.clone());
+        let scripts = |scripts: &[Script]| scripts.iter().cloned().collect::<HashSet<_>>();
+
+        assert!(tx_involves_scripts(
+            &funding,
+            &txs_raw_in_db,
+            &scripts(&[script(0)])
+        ));
+        assert!(tx_involves_scripts(
+            &spending,
+            &txs_raw_in_db,
+            &scripts(&[script(1)])
+        ));
+        assert!(!tx_involves_scripts(
+            &spending,
+            &txs_raw_in_db,
+            &scripts(&[script(0)])
+        ));
+        // the script of an input can't be found without its previous tx
+        assert!(!tx_involves_scripts(
+            &spending,
+            &HashMap::new(),
+            &scripts(&[script(1)])
+        ));
+    }
+
+    #[test]
+    fn test_update_scripts() {
+        let mut db = MemoryDatabase::new();
+        db.set_script_pubkey(&script(0), ScriptType::External, 0)
+            .unwrap();
+        db.set_script_pubkey(&script(1), ScriptType::External, 1)
+            .unwrap();
+
+        let mut server = MockServer::default();
+        let tx_a = tx(&[], &[script(0)]);
+        let tx_b = tx(&[], &[script(1)]);
+        server.add_tx(tx_a.clone(), 100);
+        server.add_tx(tx_b.clone(), 101);
+        server
+            .electrum_like_setup(None, &mut db, crate::blockchain::noop_progress())
+            .unwrap();
+        assert_eq!(db.get_last_index(ScriptType::External).unwrap(), Some(1));
+
+        // tx_b is replaced, and the server forgets about tx_a too
+        server.history.clear();
+        let tx_c = tx(&[], &[script(1)]);
+        server.add_tx(tx_c.clone(), 0);
+        server.queried.lock().unwrap().clear();
+
+        server
+            .electrum_like_update(&[script(1)], 20, &mut db)
+            .unwrap();
+
+        assert_eq!(*server.queried.lock().unwrap(), vec![script(1)]);
+        // the txs of the other scripts are left untouched
+        assert!(db.get_tx(&tx_a.txid(), false).unwrap().is_some());
+        assert!(db.get_tx(&tx_b.txid(), false).unwrap().is_none());
+        assert!(db.get_tx(&tx_c.txid(), false).unwrap().is_some());
+
+        // the last index isn't lowered by an update that only sees the first script
+        server.add_tx(tx(&[], &[script(0)]), 0);
+        server
+            .electrum_like_update(&[script(0)], 20, &mut db)
+            .unwrap();
+        assert_eq!(db.get_last_index(ScriptType::External).unwrap(), Some(1));
+    }
+
+    #[test]
+    fn test_birthday_timestamp_headers() {
+        let mut db = MemoryDatabase::new();
+        db.set_script_pubkey(&script(0), ScriptType::External, 0)
+            .unwrap();
+        // the mock headers have the height as timestamp, the birthday covers the blocks after
+        // height 20'000
+        db.set_birthday(WalletBirthday::Timestamp(20_000 + TIMESTAMP_WINDOW))
+            .unwrap();
+
+        let mut server = MockServer::default();
+        let tx_old = tx(&[], &[script(0)]);
+        let tx_new = tx(&[OutPoint::new(tx_old.txid(), 0)], &[script(0)]);
+        server.add_tx(tx_old.clone(), 10_000);
+        server.add_tx(tx_new.clone(), 30_000);
+
+        server
+            .electrum_like_setup(None, &mut db, crate::blockchain::noop_progress())
+            .unwrap();
+        assert!(db.get_tx(&tx_old.txid(), false).unwrap().is_none());
+        assert_eq!(
+            db.get_tx(&tx_new.txid(), false).unwrap().unwrap().timestamp,
+            30_000
+        );
+        // every header is only downloaded once
+        let mut headers = server.headers.lock().unwrap().clone();
+        headers.sort();
+        assert_eq!(headers, vec![10_000, 30_000]);
+
+        // the timestamp of the tx we saved is reused
+        server.headers.lock().unwrap().clear();
+        server
+            .electrum_like_setup(None, &mut db, crate::blockchain::noop_progress())
+            .unwrap();
+        assert_eq!(*server.headers.lock().unwrap(), vec![10_000]);
+    }
+}
@@ -43,7 +43,7 @@
 //! # use bdk::cli::{self, WalletOpt, WalletSubCommand};
 //! # use bdk::database::MemoryDatabase;
 //! # use bdk::Wallet;
-//! # use bitcoin::hashes::core::str::FromStr;
+//! # use std::str::FromStr;
 //! # use std::sync::Arc;
 //! # use structopt::StructOpt;
 //!
@@ -72,6 +72,7 @@
 //!         socks5: cli_opt.proxy,
 //!         retry: 3,
 //!         timeout: 5,
+//!         subscribe: false,
 //!     }),
 //! };
 //!
@@ -99,12 +100,16 @@ use log::{debug, error, info, trace, LevelFilter};
 
 use bitcoin::consensus::encode::{deserialize, serialize, serialize_hex};
 use bitcoin::hashes::hex::FromHex;
+use bitcoin::util::amount::{Amount, Denomination};
 use bitcoin::util::psbt::PartiallySignedTransaction;
-use bitcoin::{Address, OutPoint, Script, Txid};
+use bitcoin::{Address, OutPoint, Txid};
 
 use crate::blockchain::log_progress;
 use crate::error::Error;
 use crate::types::ScriptType;
+use crate::wallet::bip21::{BIP21Error, PaymentURI};
+use crate::wallet::signer::SignOptions;
+use crate::wallet::utils::is_network_compatible;
 use crate::{FeeRate, TxBuilder, Wallet};
 
 /// Wallet global options and sub-command
@@ -276,16 +281,28 @@ pub enum WalletSubCommand {
         max_addresses: Option<u32>,
     },
     /// Lists the available spendable UTXOs
-    ListUnspent,
+    ListUnspent {
+        /// Unit used to display the amounts (btc, mbtc, ubtc, bits, sat, msat)
+        #[structopt(name = "UNIT", long = "unit", parse(try_from_str = parse_denomination))]
+        unit: Option<Denomination>,
+    },
     /// Lists all the incoming and outgoing transactions of the wallet
-    ListTransactions,
+    ListTransactions {
+        /// Unit used to display the amounts (btc, mbtc, ubtc, bits, sat, msat)
+        #[structopt(name = "UNIT", long = "unit", parse(try_from_str = parse_denomination))]
+        unit: Option<Denomination>,
+    },
     /// Returns the current wallet balance
-    GetBalance,
+    GetBalance {
+        /// Also display the balance in this unit (btc, mbtc, ubtc, bits, sat, msat)
+        #[structopt(name = "UNIT", long = "unit", parse(try_from_str = parse_denomination))]
+        unit: Option<Denomination>,
+    },
     /// Creates a new unsigned transaction
     CreateTx {
-        /// Adds a recipient to the transaction
-        #[structopt(name = "ADDRESS:SAT", long = "to", required = true, parse(try_from_str = parse_recipient))]
-        recipients: Vec<(Script, u64)>,
+        /// Adds a recipient to the transaction. The amount is in satoshi unless a unit is specified, like `0.001btc` or `1.5mbtc`. A BIP21 `bitcoin:` URI with an amount is also accepted
+        #[structopt(name = "ADDRESS:AMOUNT", long = "to", required = true, parse(try_from_str = parse_recipient))]
+        recipients: Vec<(Address, u64)>,
         /// Sends all the funds (or all the selected utxos). Requires only one recipients of value 0
         #[structopt(short = "all", long = "send_all")]
         send_all: bool,
@@ -390,7 +407,15 @@ pub enum WalletSubCommand {
     Other(Vec<String>),
 }
 
-fn parse_recipient(s: &str) -> Result<(Script, u64), String> {
+fn parse_recipient(s: &str) -> Result<(Address, u64), String> {
+    if s.to_lowercase().starts_with("bitcoin:") {
+        let uri = PaymentURI::from_str(s).map_err(|e| format!("{:?}", e))?;
+        let amount = uri
+            .amount
+            .ok_or_else(|| format!("{:?}", BIP21Error::MissingAmount))?;
+        return Ok((uri.address, amount.as_sat()));
+    }
+
     let parts: Vec<_> = s.split(':').collect();
     if parts.len() != 2 {
         return Err("Invalid format".to_string());
@@ -400,12 +425,41 @@ fn parse_recipient(s: &str) -> Result<(Script, u64), String> {
     if let Err(e) = addr {
         return Err(format!("{:?}", e));
     }
-    let val = u64::from_str(&parts[1]);
-    if let Err(e) = val {
-        return Err(format!("{:?}", e));
+    let val = parse_amount(parts[1])?;
+
+    Ok((addr.unwrap(), val.as_sat()))
+}
+
+/// Parse an amount with an optional unit suffix, like `0.001btc`, `100000sat` or `1.5mbtc`
+///
+/// Amounts without a unit are interpreted as satoshi.
+fn parse_amount(s: &str) -> Result<Amount, String> {
+    let s = s.trim();
+    let unit_start = s.find(|c: char| c.is_ascii_alphabetic()).unwrap_or(s.len());
+    let (value, unit) = s.split_at(unit_start);
+
+    let denomination = match unit {
+        "" => Denomination::Satoshi,
+        unit => parse_denomination(unit)?,
+    };
+
+    Amount::from_str_in(value.trim(), denomination).map_err(|e| format!("{:?}", e))
+}
+
+fn parse_denomination(s: &str) -> Result<Denomination, String> {
+    match s.to_lowercase().as_str() {
+        "btc" => Ok(Denomination::Bitcoin),
+        "mbtc" => Ok(Denomination::MilliBitcoin),
+        "ubtc" => Ok(Denomination::MicroBitcoin),
+        "bit" | "bits" => Ok(Denomination::Bit),
+        "sat" | "sats" | "satoshi" | "satoshis" => Ok(Denomination::Satoshi),
+        "msat" => Ok(Denomination::MilliSatoshi),
+        _ => Err(format!("Unknown unit `{}`", s)),
     }
+}
 
-    Ok((addr.unwrap().script_pubkey(), val.unwrap()))
+fn format_amount(amount: Amount, unit: Denomination) -> serde_json::Value {
+    json!(amount.to_string_with_denomination(unit))
 }
 
 fn parse_outpoint(s: &str) -> Result<OutPoint, String> {
@@ -430,11 +484,49 @@ where
             maybe_await!(wallet.sync(log_progress(), max_addresses))?;
             Ok(json!({}))
         }
-        WalletSubCommand::ListUnspent => Ok(serde_json::to_value(&wallet.list_unspent()?)?),
-        WalletSubCommand::ListTransactions => {
-            Ok(serde_json::to_value(&wallet.list_transactions(false)?)?)
+        WalletSubCommand::ListUnspent { unit } => {
+            let utxos = wallet.list_unspent()?;
+            match unit {
+                None => Ok(serde_json::to_value(&utxos)?),
+                Some(unit) => Ok(serde_json::Value::Array(
+                    utxos
+                        .iter()
+                        .map(|utxo| {
+                            let mut value = serde_json::to_value(utxo)?;
+                            value["txout"]["value"] = format_amount(utxo.amount(), unit);
+                            Ok(value)
+                        })
+                        .collect::<Result<_, Error>>()?,
+                )),
+            }
+        }
+        WalletSubCommand::ListTransactions { unit } => {
+            let txs = wallet.list_transactions(false)?;
+            match unit {
+                None => Ok(serde_json::to_value(&txs)?),
+                Some(unit) => Ok(serde_json::Value::Array(
+                    txs.iter()
+                        .map(|tx| {
+                            let mut value = serde_json::to_value(tx)?;
+                            value["received"] = format_amount(tx.received_amount(), unit);
+                            value["sent"] = format_amount(tx.sent_amount(), unit);
+                            value["fees"] = format_amount(tx.fees_amount(), unit);
+                            Ok(value)
+                        })
+                        .collect::<Result<_, Error>>()?,
+                )),
+            }
+        }
+        WalletSubCommand::GetBalance { unit } => {
+            let balance = wallet.get_balance()?;
+            match unit {
+                None => Ok(json!({ "satoshi": balance })),
+                Some(unit) => Ok(json!({
+                    "satoshi": balance,
+                    "balance": format_amount(Amount::from_sat(balance), unit)
+                })),
+            }
         }
-        WalletSubCommand::GetBalance => Ok(json!({"satoshi": wallet.get_balance()?})),
         WalletSubCommand::CreateTx {
             recipients,
             send_all,
@@ -446,6 +538,18 @@ where
             external_policy,
             internal_policy,
         } => {
+            if let Some((address, _)) = recipients
+                .iter()
+                .find(|(address, _)| !is_network_compatible(address, wallet.network()))
+            {
+                return Err(Error::InvalidAddressNetwork(address.clone()));
+            }
+
+            let recipients = recipients
+                .into_iter()
+                .map(|(address, amount)| (address.script_pubkey(), amount))
+                .collect::<Vec<_>>();
+
             let mut tx_builder = TxBuilder::new();
 
             if send_all {
@@ -539,7 +643,11 @@ where
         } => {
             let psbt = base64::decode(&psbt).unwrap();
             let psbt: PartiallySignedTransaction = deserialize(&psbt).unwrap();
-            let (psbt, finalized) = wallet.sign(psbt, assume_height)?;
+            let sign_options = SignOptions {
+                assume_height,
+                ..Default::default()
+            };
+            let (psbt, finalized) = wallet.sign(psbt, sign_options)?;
             Ok(json!({"psbt": base64::encode(&serialize(&psbt)),"is_finalized": finalized,}))
         }
         WalletSubCommand::Broadcast { psbt, tx } => {
@@ -601,9 +709,10 @@ where
 
 #[cfg(test)]
 mod test {
-    use super::{WalletOpt, WalletSubCommand};
-    use bitcoin::hashes::core::str::FromStr;
+    use super::{parse_amount, WalletOpt, WalletSubCommand};
+    use bitcoin::util::amount::Denomination;
     use bitcoin::{Address, OutPoint};
+    use std::str::FromStr;
     use structopt::StructOpt;
 
     #[test]
@@ -675,12 +784,8 @@ mod test {
 
         let wallet_opt = WalletOpt::from_iter(&cli_args);
 
-        let script1 = Address::from_str("n2Z3YNXtceeJhFkTknVaNjT1mnCGWesykJ")
-            .unwrap()
-            .script_pubkey();
-        let script2 = Address::from_str("mjDZ34icH4V2k9GmC8niCrhzVuR3z8Mgkf")
-            .unwrap()
-            .script_pubkey();
+        let addr1 = Address::from_str("n2Z3YNXtceeJhFkTknVaNjT1mnCGWesykJ").unwrap();
+        let addr2 = Address::from_str("mjDZ34icH4V2k9GmC8niCrhzVuR3z8Mgkf").unwrap();
         let outpoint1 = OutPoint::from_str(
             "87345e46bfd702d24d54890cc094d08a005f773b27c8f965dfe0eb1e23eef88e:1",
         )
@@ -703,7 +808,7 @@ mod test {
             esplora_concurrency: 4,
             electrum: "ssl://electrum.blockstream.info:50002".to_string(),
             subcommand: WalletSubCommand::CreateTx {
-                recipients: vec![(script1, 123456), (script2, 78910)],
+                recipients: vec![(addr1, 123456), (addr2, 78910)],
                 send_all: false,
                 enable_rbf: false,
                 offline_signer: false,
@@ -747,4 +852,68 @@ mod test {
 
         assert_eq!(expected_wallet_opt, wallet_opt);
     }
+
+    #[test]
+    fn test_parse_amount() {
+        assert_eq!(parse_amount("123456").unwrap().as_sat(), 123456);
+        assert_eq!(parse_amount("100000sat").unwrap().as_sat(), 100000);
+        assert_eq!(parse_amount("0.001btc").unwrap().as_sat(), 100000);
+        assert_eq!(parse_amount("0.001BTC").unwrap().as_sat(), 100000);
+        assert_eq!(parse_amount("1.5mbtc").unwrap().as_sat(), 150000);
+        assert_eq!(parse_amount("10bits").unwrap().as_sat(), 1000);
+
+        assert!(parse_amount("0.5sat").is_err());
+        assert!(parse_amount("-1btc").is_err());
+        assert!(parse_amount("1xyz").is_err());
+    }
+
+    #[test]
+    fn test_create_tx_with_units() {
+        let cli_args = vec!["repl", "--network", "testnet",
+                            "--descriptor", "wpkh(tpubDEnoLuPdBep9bzw5LoGYpsxUQYheRQ9gcgrJhJEcdKFB9cWQRyYmkCyRoTqeD4tJYiVVgt6A3rN6rWn9RYhR9sBsGxji29LYWHuKKbdb1ev/0/*)",
+                            "create_tx", "--to", "n2Z3YNXtceeJhFkTknVaNjT1mnCGWesykJ:0.001btc","mjDZ34icH4V2k9GmC8niCrhzVuR3z8Mgkf:1.5mbtc"];
+
+        let wallet_opt = WalletOpt::from_iter(&cli_args);
+
+        match wallet_opt.subcommand {
+            WalletSubCommand::CreateTx { recipients, .. } => {
+                let values: Vec<_> = recipients.iter().map(|(_, v)| *v).collect();
+                assert_eq!(values, vec![100_000, 150_000]);
+            }
+            _ => panic!("unexpected subcommand"),
+        }
+    }
+
+    #[test]
+    fn test_get_balance_unit() {
+        let cli_args = vec!["repl", "--network", "testnet",
+                            "--descriptor", "wpkh(tpubDEnoLuPdBep9bzw5LoGYpsxUQYheRQ9gcgrJhJEcdKFB9cWQRyYmkCyRoTqeD4tJYiVVgt6A3rN6rWn9RYhR9sBsGxji29LYWHuKKbdb1ev/0/*)",
+                            "get_balance", "--unit", "mBTC"];
+
+        let wallet_opt = WalletOpt::from_iter(&cli_args);
+
+        assert_eq!(
+            wallet_opt.subcommand,
+            WalletSubCommand::GetBalance {
+                unit: Some(Denomination::MilliBitcoin)
+            }
+        );
+    }
+
+    #[test]
+    fn test_create_tx_bip21() {
+        let cli_args = vec!["repl", "--network", "testnet",
+                            "--descriptor", "wpkh(tpubDEnoLuPdBep9bzw5LoGYpsxUQYheRQ9gcgrJhJEcdKFB9cWQRyYmkCyRoTqeD4tJYiVVgt6A3rN6rWn9RYhR9sBsGxji29LYWHuKKbdb1ev/0/*)",
+                            "create_tx", "--to", "bitcoin:n2Z3YNXtceeJhFkTknVaNjT1mnCGWesykJ?amount=0.001&label=Coffee"];
+
+        let wallet_opt = WalletOpt::from_iter(&cli_args);
+
+        match wallet_opt.subcommand {
+            WalletSubCommand::CreateTx { recipients, .. } => {
+                let addr = Address::from_str("n2Z3YNXtceeJhFkTknVaNjT1mnCGWesykJ").unwrap();
+                assert_eq!(recipients, vec![(addr, 100_000)]);
+            }
+            _ => panic!("unexpected subcommand"),
+        }
+    }
 }
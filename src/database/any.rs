@@ -145,6 +145,12 @@ impl BatchOperations for AnyDatabase {
     fn set_last_index(&mut self, script_type: ScriptType, value: u32) -> Result<(), Error> {
         impl_inner_method!(AnyDatabase, self, set_last_index, script_type, value)
     }
+    fn set_marked_used(&mut self, script_type: ScriptType, child: u32) -> Result<(), Error> {
+        impl_inner_method!(AnyDatabase, self, set_marked_used, script_type, child)
+    }
+    fn set_birthday(&mut self, birthday: WalletBirthday) -> Result<(), Error> {
+        impl_inner_method!(AnyDatabase, self, set_birthday, birthday)
+    }
 
     fn del_script_pubkey_from_path(
         &mut self,
@@ -181,6 +187,12 @@ impl BatchOperations for AnyDatabase {
     fn del_last_index(&mut self, script_type: ScriptType) -> Result<Option<u32>, Error> {
         impl_inner_method!(AnyDatabase, self, del_last_index, script_type)
     }
+    fn del_marked_used(&mut self, script_type: ScriptType, child: u32) -> Result<bool, Error> {
+        impl_inner_method!(AnyDatabase, self, del_marked_used, script_type, child)
+    }
+    fn del_birthday(&mut self) -> Result<Option<WalletBirthday>, Error> {
+        impl_inner_method!(AnyDatabase, self, del_birthday)
+    }
 }
 
 impl Database for AnyDatabase {
@@ -201,6 +213,9 @@ impl Database for AnyDatabase {
     fn iter_script_pubkeys(&self, script_type: Option<ScriptType>) -> Result<Vec<Script>, Error> {
         impl_inner_method!(AnyDatabase, self, iter_script_pubkeys, script_type)
     }
+    fn iter_keychains(&self) -> Result<Vec<ScriptType>, Error> {
+        impl_inner_method!(AnyDatabase, self, iter_keychains)
+    }
     fn iter_utxos(&self) -> Result<Vec<UTXO>, Error> {
         impl_inner_method!(AnyDatabase, self, iter_utxos)
     }
@@ -242,6 +257,12 @@ impl Database for AnyDatabase {
     fn get_last_index(&self, script_type: ScriptType) -> Result<Option<u32>, Error> {
         impl_inner_method!(AnyDatabase, self, get_last_index, script_type)
     }
+    fn is_marked_used(&self, script_type: ScriptType, child: u32) -> Result<bool, Error> {
+        impl_inner_method!(AnyDatabase, self, is_marked_used, script_type, child)
+    }
+    fn get_birthday(&self) -> Result<Option<WalletBirthday>, Error> {
+        impl_inner_method!(AnyDatabase, self, get_birthday)
+    }
 
     fn increment_last_index(&mut self, script_type: ScriptType) -> Result<u32, Error> {
         impl_inner_method!(AnyDatabase, self, increment_last_index, script_type)
@@ -276,6 +297,12 @@ impl BatchOperations for AnyBatch {
     fn set_last_index(&mut self, script_type: ScriptType, value: u32) -> Result<(), Error> {
         impl_inner_method!(AnyBatch, self, set_last_index, script_type, value)
     }
+    fn set_marked_used(&mut self, script_type: ScriptType, child: u32) -> Result<(), Error> {
+        impl_inner_method!(AnyBatch, self, set_marked_used, script_type, child)
+    }
+    fn set_birthday(&mut self, birthday: WalletBirthday) -> Result<(), Error> {
+        impl_inner_method!(AnyBatch, self, set_birthday, birthday)
+    }
 
     fn del_script_pubkey_from_path(
         &mut self,
@@ -312,6 +339,12 @@ impl BatchOperations for AnyBatch {
     fn del_last_index(&mut self, script_type: ScriptType) -> Result<Option<u32>, Error> {
         impl_inner_method!(AnyBatch, self, del_last_index, script_type)
     }
+    fn del_marked_used(&mut self, script_type: ScriptType, child: u32) -> Result<bool, Error> {
+        impl_inner_method!(AnyBatch, self, del_marked_used, script_type, child)
+    }
+    fn del_birthday(&mut self) -> Result<Option<WalletBirthday>, Error> {
+        impl_inner_method!(AnyBatch, self, del_birthday)
+    }
 }
 
 impl BatchDatabase for AnyDatabase {
@@ -95,6 +95,20 @@ macro_rules! impl_batch_operations {
             Ok(())
         }
 
+        fn set_marked_used(&mut self, script_type: ScriptType, child: u32) -> Result<(), Error> {
+            let key = MapKey::MarkedUsed((script_type, child)).as_map_key();
+            self.insert(key, &[])$($after_insert)*;
+
+            Ok(())
+        }
+
+        fn set_birthday(&mut self, birthday: WalletBirthday) -> Result<(), Error> {
+            let key = MapKey::Birthday.as_map_key();
+            self.insert(key, serde_json::to_vec(&birthday)?)$($after_insert)*;
+
+            Ok(())
+        }
+
         fn del_script_pubkey_from_path(&mut self, script_type: ScriptType, path: u32) -> Result<Option<Script>, Error> {
             let key = MapKey::Path((Some(script_type), Some(path))).as_map_key();
             let res = self.remove(key);
@@ -181,6 +195,22 @@ macro_rules! impl_batch_operations {
                 }
             }
         }
+
+        fn del_marked_used(&mut self, script_type: ScriptType, child: u32) -> Result<bool, Error> {
+            let key = MapKey::MarkedUsed((script_type, child)).as_map_key();
+            let res = self.remove(key);
+            let res = $process_delete!(res);
+
+            Ok(res.is_some())
+        }
+
+        fn del_birthday(&mut self) -> Result<Option<WalletBirthday>, Error> {
+            let key = MapKey::Birthday.as_map_key();
+            let res = self.remove(key);
+            let res = $process_delete!(res);
+
+            Ok(res.map(|b| serde_json::from_slice(&b)).transpose()?)
+        }
     }
 }
 
@@ -234,6 +264,16 @@ impl Database for Tree {
             .collect()
     }
 
+    fn iter_keychains(&self) -> Result<Vec<ScriptType>, Error> {
+        let key = MapKey::KeychainChecksums.as_map_key();
+        self.scan_prefix(&key)
+            .map(|x| -> Result<_, Error> {
+                let (k, _) = x?;
+                Ok(ScriptType::Keychain(k[key.len()]))
+            })
+            .collect()
+    }
+
     fn iter_utxos(&self) -> Result<Vec<UTXO>, Error> {
         let key = MapKey::UTXO(None).as_map_key();
         self.scan_prefix(key)
@@ -355,6 +395,19 @@ impl Database for Tree {
             .transpose()
     }
 
+    fn is_marked_used(&self, script_type: ScriptType, child: u32) -> Result<bool, Error> {
+        let key = MapKey::MarkedUsed((script_type, child)).as_map_key();
+        Ok(self.contains_key(key)?)
+    }
+
+    fn get_birthday(&self) -> Result<Option<WalletBirthday>, Error> {
+        let key = MapKey::Birthday.as_map_key();
+        Ok(self
+            .get(key)?
+            .map(|b| serde_json::from_slice(&b))
+            .transpose()?)
+    }
+
     // inserts 0 if not present
     fn increment_last_index(&mut self, script_type: ScriptType) -> Result<u32, Error> {
         let key = MapKey::LastIndex(script_type).as_map_key();
@@ -399,6 +452,7 @@ mod test {
     use std::sync::{Arc, Condvar, Mutex, Once};
     use std::time::{SystemTime, UNIX_EPOCH};
 
+    use lazy_static::lazy_static;
     use sled::{Db, Tree};
 
     static mut COUNT: usize = 0;
@@ -478,4 +532,24 @@ mod test {
     fn test_last_index() {
         crate::database::test::test_last_index(get_tree());
     }
+
+    #[test]
+    fn test_marked_used() {
+        crate::database::test::test_marked_used(get_tree());
+    }
+
+    #[test]
+    fn test_birthday() {
+        crate::database::test::test_birthday(get_tree());
+    }
+
+    #[test]
+    fn test_keychain_script_pubkey() {
+        crate::database::test::test_keychain_script_pubkey(get_tree());
+    }
+
+    #[test]
+    fn test_iter_keychains() {
+        crate::database::test::test_iter_keychains(get_tree());
+    }
 }
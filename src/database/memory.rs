@@ -44,7 +44,9 @@ use crate::types::*;
 // rawtx                r<txid> -> tx
 // transactions         t<txid> -> tx details
 // deriv indexes        c{i,e} -> u32
-// descriptor checksum  d{i,e} -> vec<u8>
+// descriptor checksum  d{i,e,k<index>} -> vec<u8>
+// marked used          m{i,e}<path> -> ()
+// birthday             b -> birthday
 
 pub(crate) enum MapKey<'a> {
     Path((Option<ScriptType>, Option<u32>)),
@@ -54,6 +56,9 @@ pub(crate) enum MapKey<'a> {
     Transaction(Option<&'a Txid>),
     LastIndex(ScriptType),
     DescriptorChecksum(ScriptType),
+    KeychainChecksums,
+    MarkedUsed((ScriptType, u32)),
+    Birthday,
 }
 
 impl MapKey<'_> {
@@ -62,7 +67,7 @@ impl MapKey<'_> {
             MapKey::Path((st, _)) => {
                 let mut v = b"p".to_vec();
                 if let Some(st) = st {
-                    v.push(st.as_byte());
+                    v.extend_from_slice(&st.as_bytes());
                 }
                 v
             }
@@ -70,14 +75,18 @@ impl MapKey<'_> {
             MapKey::UTXO(_) => b"u".to_vec(),
             MapKey::RawTx(_) => b"r".to_vec(),
             MapKey::Transaction(_) => b"t".to_vec(),
-            MapKey::LastIndex(st) => [b"c", st.as_ref()].concat(),
-            MapKey::DescriptorChecksum(st) => [b"d", st.as_ref()].concat(),
+            MapKey::LastIndex(st) => [b"c".to_vec(), st.as_bytes()].concat(),
+            MapKey::DescriptorChecksum(st) => [b"d".to_vec(), st.as_bytes()].concat(),
+            MapKey::KeychainChecksums => b"dk".to_vec(),
+            MapKey::MarkedUsed((st, _)) => [b"m".to_vec(), st.as_bytes()].concat(),
+            MapKey::Birthday => b"b".to_vec(),
         }
     }
 
     fn serialize_content(&self) -> Vec<u8> {
         match self {
             MapKey::Path((_, Some(child))) => child.to_be_bytes().to_vec(),
+            MapKey::MarkedUsed((_, child)) => child.to_be_bytes().to_vec(),
             MapKey::Script(Some(s)) => serialize(*s),
             MapKey::UTXO(Some(s)) => serialize(*s),
             MapKey::RawTx(Some(s)) => serialize(*s),
@@ -192,6 +201,18 @@ impl BatchOperations for MemoryDatabase {
 
         Ok(())
     }
+    fn set_marked_used(&mut self, script_type: ScriptType, child: u32) -> Result<(), Error> {
+        let key = MapKey::MarkedUsed((script_type, child)).as_map_key();
+        self.map.insert(key, Box::new(()));
+
+        Ok(())
+    }
+    fn set_birthday(&mut self, birthday: WalletBirthday) -> Result<(), Error> {
+        let key = MapKey::Birthday.as_map_key();
+        self.map.insert(key, Box::new(birthday));
+
+        Ok(())
+    }
 
     fn del_script_pubkey_from_path(
         &mut self,
@@ -277,6 +298,23 @@ impl BatchOperations for MemoryDatabase {
         let res = self.map.remove(&key);
         self.deleted_keys.push(key);
 
+        match res {
+            None => Ok(None),
+            Some(b) => Ok(Some(*b.downcast_ref().unwrap())),
+        }
+    }
+    fn del_marked_used(&mut self, script_type: ScriptType, child: u32) -> Result<bool, Error> {
+        let key = MapKey::MarkedUsed((script_type, child)).as_map_key();
+        let res = self.map.remove(&key);
+        self.deleted_keys.push(key);
+
+        Ok(res.is_some())
+    }
+    fn del_birthday(&mut self) -> Result<Option<WalletBirthday>, Error> {
+        let key = MapKey::Birthday.as_map_key();
+        let res = self.map.remove(&key);
+        self.deleted_keys.push(key);
+
         match res {
             None => Ok(None),
             Some(b) => Ok(Some(*b.downcast_ref().unwrap())),
@@ -316,6 +354,15 @@ impl Database for MemoryDatabase {
             .collect()
     }
 
+    fn iter_keychains(&self) -> Result<Vec<ScriptType>, Error> {
+        let key = MapKey::KeychainChecksums.as_map_key();
+        Ok(self
+            .map
+            .range::<Vec<u8>, _>((Included(&key), Excluded(&after(&key))))
+            .map(|(k, _)| ScriptType::Keychain(k[key.len()]))
+            .collect())
+    }
+
     fn iter_utxos(&self) -> Result<Vec<UTXO>, Error> {
         let key = MapKey::UTXO(None).as_map_key();
         self.map
@@ -419,6 +466,16 @@ impl Database for MemoryDatabase {
         Ok(self.map.get(&key).map(|b| *b.downcast_ref().unwrap()))
     }
 
+    fn is_marked_used(&self, script_type: ScriptType, child: u32) -> Result<bool, Error> {
+        let key = MapKey::MarkedUsed((script_type, child)).as_map_key();
+        Ok(self.map.contains_key(&key))
+    }
+
+    fn get_birthday(&self) -> Result<Option<WalletBirthday>, Error> {
+        let key = MapKey::Birthday.as_map_key();
+        Ok(self.map.get(&key).map(|b| *b.downcast_ref().unwrap()))
+    }
+
     // inserts 0 if not present
     fn increment_last_index(&mut self, script_type: ScriptType) -> Result<u32, Error> {
         let key = MapKey::LastIndex(script_type).as_map_key();
@@ -563,4 +620,24 @@ mod test {
     fn test_last_index() {
         crate::database::test::test_last_index(get_tree());
     }
+
+    #[test]
+    fn test_marked_used() {
+        crate::database::test::test_marked_used(get_tree());
+    }
+
+    #[test]
+    fn test_birthday() {
+        crate::database::test::test_birthday(get_tree());
+    }
+
+    #[test]
+    fn test_keychain_script_pubkey() {
+        crate::database::test::test_keychain_script_pubkey(get_tree());
+    }
+
+    #[test]
+    fn test_iter_keychains() {
+        crate::database::test::test_iter_keychains(get_tree());
+    }
 }
@@ -72,6 +72,10 @@ pub trait BatchOperations {
     fn set_tx(&mut self, transaction: &TransactionDetails) -> Result<(), Error>;
     /// Store the last derivation index for a given script type
     fn set_last_index(&mut self, script_type: ScriptType, value: u32) -> Result<(), Error>;
+    /// Mark the script_pubkey with the given script type and child number as used
+    fn set_marked_used(&mut self, script_type: ScriptType, child: u32) -> Result<(), Error>;
+    /// Store the birthday of the wallet
+    fn set_birthday(&mut self, birthday: WalletBirthday) -> Result<(), Error>;
 
     /// Delete a script_pubkey given the script type and its child number
     fn del_script_pubkey_from_path(
@@ -97,6 +101,11 @@ pub trait BatchOperations {
     ) -> Result<Option<TransactionDetails>, Error>;
     /// Delete the last derivation index for a script type
     fn del_last_index(&mut self, script_type: ScriptType) -> Result<Option<u32>, Error>;
+    /// Remove the "used" mark from a script_pubkey given the script type and its child number,
+    /// returning whether it was marked
+    fn del_marked_used(&mut self, script_type: ScriptType, child: u32) -> Result<bool, Error>;
+    /// Delete the birthday of the wallet
+    fn del_birthday(&mut self) -> Result<Option<WalletBirthday>, Error>;
 }
 
 /// Trait for reading data from a database
@@ -116,6 +125,11 @@ pub trait Database: BatchOperations {
 
     /// Return the list of script_pubkeys
     fn iter_script_pubkeys(&self, script_type: Option<ScriptType>) -> Result<Vec<Script>, Error>;
+    /// Return the list of additional keychains stored in the database
+    ///
+    /// A keychain is stored when the checksum of its descriptor is checked for the first time with
+    /// [`Database::check_descriptor_checksum`], even if none of its script_pubkeys are cached yet.
+    fn iter_keychains(&self) -> Result<Vec<ScriptType>, Error>;
     /// Return the list of [`UTXO`]s
     fn iter_utxos(&self) -> Result<Vec<UTXO>, Error>;
     /// Return the list of raw transactions
@@ -142,6 +156,11 @@ pub trait Database: BatchOperations {
     fn get_tx(&self, txid: &Txid, include_raw: bool) -> Result<Option<TransactionDetails>, Error>;
     /// Return the last defivation index for a script type
     fn get_last_index(&self, script_type: ScriptType) -> Result<Option<u32>, Error>;
+    /// Return whether the script_pubkey with the given script type and child number has been
+    /// marked as used
+    fn is_marked_used(&self, script_type: ScriptType, child: u32) -> Result<bool, Error>;
+    /// Return the birthday of the wallet
+    fn get_birthday(&self) -> Result<Option<WalletBirthday>, Error>;
 
     /// Increment the last derivation index for a script type and returns it
     ///
@@ -376,5 +395,88 @@ pub mod test {
         assert_eq!(tree.get_last_index(ScriptType::Internal).unwrap(), Some(0));
     }
 
+    pub fn test_marked_used<D: Database>(mut tree: D) {
+        tree.set_marked_used(ScriptType::External, 42).unwrap();
+
+        assert!(tree.is_marked_used(ScriptType::External, 42).unwrap());
+        assert!(!tree.is_marked_used(ScriptType::Internal, 42).unwrap());
+        assert!(!tree.is_marked_used(ScriptType::External, 43).unwrap());
+
+        assert!(tree.del_marked_used(ScriptType::External, 42).unwrap());
+        assert!(!tree.del_marked_used(ScriptType::External, 42).unwrap());
+        assert!(!tree.is_marked_used(ScriptType::External, 42).unwrap());
+    }
+
+    pub fn test_keychain_script_pubkey<D: Database>(mut tree: D) {
+        let script = Script::from(
+            Vec::<u8>::from_hex("76a91402306a7c23f3e8010de41e9e591348bb83f11daa88ac").unwrap(),
+        );
+        let path = 42;
+        let script_type = ScriptType::Keychain(1);
+
+        tree.set_script_pubkey(&script, script_type, path).unwrap();
+        tree.set_last_index(script_type, 42).unwrap();
+
+        assert_eq!(
+            tree.get_path_from_script_pubkey(&script).unwrap(),
+            Some((script_type, path))
+        );
+        assert_eq!(
+            tree.iter_script_pubkeys(Some(script_type)).unwrap().len(),
+            1
+        );
+        assert_eq!(
+            tree.iter_script_pubkeys(Some(ScriptType::Keychain(0)))
+                .unwrap()
+                .len(),
+            0
+        );
+        assert_eq!(
+            tree.iter_script_pubkeys(Some(ScriptType::External))
+                .unwrap()
+                .len(),
+            0
+        );
+        assert_eq!(tree.get_last_index(script_type).unwrap(), Some(42));
+        assert_eq!(tree.get_last_index(ScriptType::Keychain(0)).unwrap(), None);
+    }
+
+    pub fn test_iter_keychains<D: Database>(mut tree: D) {
+        tree.check_descriptor_checksum(ScriptType::External, b"ext")
+            .unwrap();
+        tree.check_descriptor_checksum(ScriptType::Keychain(2), b"kc2")
+            .unwrap();
+        tree.check_descriptor_checksum(ScriptType::Keychain(0), b"kc0")
+            .unwrap();
+
+        assert_eq!(
+            tree.iter_keychains().unwrap(),
+            vec![ScriptType::Keychain(0), ScriptType::Keychain(2)]
+        );
+    }
+
+    pub fn test_birthday<D: Database>(mut tree: D) {
+        assert_eq!(tree.get_birthday().unwrap(), None);
+
+        tree.set_birthday(WalletBirthday::Height(42)).unwrap();
+        assert_eq!(
+            tree.get_birthday().unwrap(),
+            Some(WalletBirthday::Height(42))
+        );
+
+        tree.set_birthday(WalletBirthday::Timestamp(1_600_000_000))
+            .unwrap();
+        assert_eq!(
+            tree.get_birthday().unwrap(),
+            Some(WalletBirthday::Timestamp(1_600_000_000))
+        );
+
+        assert_eq!(
+            tree.del_birthday().unwrap(),
+            Some(WalletBirthday::Timestamp(1_600_000_000))
+        );
+        assert_eq!(tree.get_birthday().unwrap(), None);
+    }
+
     // TODO: more tests...
 }
@@ -386,12 +386,10 @@ macro_rules! expand_make_bipxx {
                 derivation_path.push(bip32::ChildNumber::from_hardened_idx(0)?);
 
                 match script_type {
-                    ScriptType::External => {
-                        derivation_path.push(bip32::ChildNumber::from_normal_idx(0)?)
-                    }
                     ScriptType::Internal => {
                         derivation_path.push(bip32::ChildNumber::from_normal_idx(1)?)
                     }
+                    _ => derivation_path.push(bip32::ChildNumber::from_normal_idx(0)?),
                 };
 
                 let derivation_path: bip32::DerivationPath = derivation_path.into();
@@ -405,8 +403,8 @@ macro_rules! expand_make_bipxx {
                 script_type: ScriptType,
             ) -> Result<impl ToDescriptorKey<$ctx>, KeyError> {
                 let derivation_path: bip32::DerivationPath = match script_type {
-                    ScriptType::External => vec![bip32::ChildNumber::from_normal_idx(0)?].into(),
                     ScriptType::Internal => vec![bip32::ChildNumber::from_normal_idx(1)?].into(),
+                    _ => vec![bip32::ChildNumber::from_normal_idx(0)?].into(),
                 };
 
                 let mut source_path = Vec::with_capacity(3);
@@ -431,12 +429,12 @@ mod test {
     use super::*;
     use crate::descriptor::DescriptorMeta;
     use crate::keys::{KeyError, ValidNetworks};
-    use bitcoin::hashes::core::str::FromStr;
     use bitcoin::network::constants::Network::Regtest;
     use bitcoin::secp256k1::Secp256k1;
     use bitcoin::util::bip32::ChildNumber;
     use miniscript::descriptor::{DescriptorPublicKey, DescriptorPublicKeyCtx, KeyMap};
     use miniscript::Descriptor;
+    use std::str::FromStr;
 
     // verify template descriptor generates expected address(es)
     fn check(
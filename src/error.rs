@@ -25,7 +25,7 @@
 use std::fmt;
 
 use crate::{descriptor, wallet, wallet::address_validator};
-use bitcoin::OutPoint;
+use bitcoin::{Address, OutPoint};
 
 /// Errors that can be thrown by the [`Wallet`](crate::wallet::Wallet)
 #[derive(Debug)]
@@ -84,6 +84,8 @@ pub enum Error {
     ChecksumMismatch,
     /// Spending policy is not compatible with this [`ScriptType`](crate::types::ScriptType)
     SpendingPolicyRequired(crate::types::ScriptType),
+    /// The wallet doesn't have a keychain for this [`ScriptType`](crate::types::ScriptType)
+    UnknownKeychain(crate::types::ScriptType),
     #[allow(missing_docs)]
     InvalidPolicyPathError(crate::descriptor::policy::PolicyError),
     #[allow(missing_docs)]
@@ -100,6 +102,16 @@ pub enum Error {
     ProgressUpdateError,
     /// Requested outpoint doesn't exist in the tx (vout greater than available outputs)
     InvalidOutpoint(OutPoint),
+    /// The PSBT input of a foreign utxo doesn't contain the output it spends, or it doesn't match
+    /// its outpoint, or the utxo belongs to the wallet
+    InvalidForeignUtxo(OutPoint),
+    /// The address is not valid for the network used by the wallet
+    InvalidAddressNetwork(Address),
+    /// Trying to reveal too many addresses at once
+    AddressIndexTooHigh {
+        /// Highest index that can be revealed
+        max: u32,
+    },
 
     #[allow(missing_docs)]
     Descriptor(crate::descriptor::error::Error),
@@ -119,10 +131,13 @@ pub enum Error {
     Hex(bitcoin::hashes::hex::Error),
     #[allow(missing_docs)]
     PSBT(bitcoin::util::psbt::Error),
+    #[allow(missing_docs)]
+    BIP21(crate::wallet::bip21::BIP21Error),
+    #[allow(missing_docs)]
+    Payjoin(crate::wallet::payjoin::PayjoinError),
 
     //KeyMismatch(bitcoin::secp256k1::PublicKey, bitcoin::secp256k1::PublicKey),
     //MissingInputUTXO(usize),
-    //InvalidAddressNetwork(Address),
     //DifferentTransactions,
     //DifferentDescriptorStructure,
     //Uncapable(crate::blockchain::Capability),
@@ -166,6 +181,8 @@ impl_error!(descriptor::error::Error, Descriptor);
 impl_error!(address_validator::AddressValidatorError, AddressValidator);
 impl_error!(descriptor::policy::PolicyError, InvalidPolicyPathError);
 impl_error!(wallet::signer::SignerError, Signer);
+impl_error!(wallet::bip21::BIP21Error, BIP21);
+impl_error!(wallet::payjoin::PayjoinError, Payjoin);
 
 impl From<crate::keys::KeyError> for Error {
     fn from(key_error: crate::keys::KeyError) -> Error {
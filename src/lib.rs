@@ -157,6 +157,7 @@
 //! use base64::decode;
 //! use bdk::{Wallet, OfflineWallet};
 //! use bdk::database::MemoryDatabase;
+//! use bdk::signer::SignOptions;
 //!
 //! use bitcoin::consensus::deserialize;
 //!
@@ -171,7 +172,7 @@
 //!     let psbt = "...";
 //!     let psbt = deserialize(&base64::decode(psbt).unwrap())?;
 //!
-//!     let (signed_psbt, finalized) = wallet.sign(psbt, None)?;
+//!     let (signed_psbt, finalized) = wallet.sign(psbt, SignOptions::default())?;
 //!
 //!     Ok(())
 //! }
@@ -41,7 +41,10 @@ impl PSBTUtils for PSBT {
             if let Some(wit_utxo) = &input.witness_utxo {
                 Some(wit_utxo.clone())
             } else if let Some(in_tx) = &input.non_witness_utxo {
-                Some(in_tx.output[tx.input[input_index].previous_output.vout as usize].clone())
+                in_tx
+                    .output
+                    .get(tx.input[input_index].previous_output.vout as usize)
+                    .cloned()
             } else {
                 None
             }
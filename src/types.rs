@@ -26,6 +26,8 @@ use std::convert::AsRef;
 
 use bitcoin::blockdata::transaction::{OutPoint, Transaction, TxOut};
 use bitcoin::hash_types::Txid;
+use bitcoin::util::amount::Amount;
+use bitcoin::Address;
 
 use serde::{Deserialize, Serialize};
 
@@ -33,25 +35,46 @@ use serde::{Deserialize, Serialize};
 #[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum ScriptType {
     /// External
-    External = 0,
+    External,
     /// Internal, usually used for change outputs
-    Internal = 1,
+    Internal,
+    /// Additional keychain, identified by the index assigned when it was added to the wallet
+    ///
+    /// See [`Wallet::add_keychain`](crate::wallet::Wallet::add_keychain).
+    Keychain(u8),
 }
 
 impl ScriptType {
+    /// Return the bytes used to identify this script type in the database keys
+    pub fn as_bytes(&self) -> Vec<u8> {
+        match self {
+            ScriptType::External => b"e".to_vec(),
+            ScriptType::Internal => b"i".to_vec(),
+            ScriptType::Keychain(index) => vec![b'k', *index],
+        }
+    }
+
+    /// Return the byte used to identify this script type in the database keys
+    ///
+    /// All the [`ScriptType::Keychain`]s share the same byte, `k`.
+    #[deprecated(note = "use `as_bytes`, which also identifies the additional keychains")]
     pub fn as_byte(&self) -> u8 {
         match self {
             ScriptType::External => b'e',
             ScriptType::Internal => b'i',
+            ScriptType::Keychain(_) => b'k',
         }
     }
 }
 
+/// All the [`ScriptType::Keychain`]s share the same bytes, `k`: [`ScriptType::as_bytes`] should be
+/// used to tell them apart.
 impl AsRef<[u8]> for ScriptType {
     fn as_ref(&self) -> &[u8] {
         match self {
             ScriptType::External => b"e",
             ScriptType::Internal => b"i",
+            ScriptType::Keychain(_) => b"k",
         }
     }
 }
@@ -97,6 +120,61 @@ pub struct UTXO {
     pub script_type: ScriptType,
 }
 
+impl UTXO {
+    /// Return the value of the output as an [`Amount`]
+    pub fn amount(&self) -> Amount {
+        Amount::from_sat(self.txout.value)
+    }
+}
+
+/// An address revealed by the wallet, along with its status
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct AddressInfo {
+    /// Child number of the address
+    pub index: u32,
+    /// The address itself
+    pub address: Address,
+    /// Whether the address has received funds or has been manually marked as used
+    pub used: bool,
+    /// Sum of the values of the unspent outputs sent to this address
+    pub balance: u64,
+}
+
+/// Margin left before a [`WalletBirthday::Timestamp`], since the timestamp of a block can be off by
+/// up to two hours
+pub(crate) const TIMESTAMP_WINDOW: u64 = 2 * 60 * 60;
+
+/// Earliest point in the chain that may contain transactions of the wallet
+///
+/// Blockchain backends don't look for the wallet's transactions in the blocks that come before
+/// it. See [`Wallet::new_offline_with_birthday`](crate::wallet::Wallet::new_offline_with_birthday).
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum WalletBirthday {
+    /// Height of the first block that may contain transactions of the wallet
+    Height(u32),
+    /// Timestamp, in seconds, of the creation of the wallet
+    Timestamp(u64),
+}
+
+impl WalletBirthday {
+    /// Create a birthday for a wallet created right now
+    pub fn now() -> Self {
+        WalletBirthday::Timestamp(crate::wallet::time::get_timestamp())
+    }
+
+    /// Return whether the block at `height`, with the given `time`, may contain transactions of
+    /// the wallet
+    ///
+    /// The `time` is only used for timestamp birthdays, and includes a margin of two hours.
+    pub(crate) fn covers(&self, height: u32, time: u64) -> bool {
+        match self {
+            WalletBirthday::Height(birthday) => height >= *birthday,
+            WalletBirthday::Timestamp(birthday) => time + TIMESTAMP_WINDOW >= *birthday,
+        }
+    }
+}
+
 /// A wallet transaction
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Default)]
 pub struct TransactionDetails {
@@ -108,3 +186,20 @@ pub struct TransactionDetails {
     pub fees: u64,
     pub height: Option<u32>,
 }
+
+impl TransactionDetails {
+    /// Return the amount received by the wallet as an [`Amount`]
+    pub fn received_amount(&self) -> Amount {
+        Amount::from_sat(self.received)
+    }
+
+    /// Return the amount sent by the wallet as an [`Amount`]
+    pub fn sent_amount(&self) -> Amount {
+        Amount::from_sat(self.sent)
+    }
+
+    /// Return the fees paid by the transaction as an [`Amount`]
+    pub fn fees_amount(&self) -> Amount {
+        Amount::from_sat(self.fees)
+    }
+}
@@ -0,0 +1,440 @@
+// Magical Bitcoin Library
+// Written in 2020 by
+//     Alekos Filini <alekos.filini@gmail.com>
+//
+// Copyright (c) 2020 Magical Bitcoin
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! BIP21 payment URIs
+//!
+//! This module implements parsing and generation of `bitcoin:` payment URIs, as described in
+//! [BIP21](https://github.com/bitcoin/bips/blob/master/bip-0021.mediawiki).
+//!
+//! ## Examples
+//!
+//! ### Pay a URI
+//!
+//! ```
+//! # use std::str::FromStr;
+//! # use bitcoin::*;
+//! # use bdk::wallet::bip21::*;
+//! # use bdk::wallet::tx_builder::CreateTx;
+//! # use bdk::*;
+//! let uri = PaymentURI::from_str("bitcoin:mjDZ34icH4V2k9GmC8niCrhzVuR3z8Mgkf?amount=0.001&label=Coffee")?;
+//! let (script_pubkey, amount) = uri.to_recipient(Network::Testnet)?;
+//! let builder = TxBuilder::new().add_recipient(script_pubkey, amount);
+//! # let builder: TxBuilder<bdk::database::MemoryDatabase, _, CreateTx> = builder;
+//! # Ok::<_, bdk::Error>(())
+//! ```
+//!
+//! ### Request a payment
+//!
+//! ```
+//! # use bitcoin::*;
+//! # use bitcoin::util::amount::Amount;
+//! # use bdk::database::*;
+//! # use bdk::wallet::bip21::*;
+//! # use bdk::*;
+//! let descriptor = "wpkh(tpubD6NzVbkrYhZ4Xferm7Pz4VnjdcDPFyjVu5K4iZXQ4pVN8Cks4pHVowTBXBKRhX64pkRyJZJN5xAKj4UDNnLPb5p2sSKXhewoYx5GbTdUFWq/*)";
+//! let wallet: OfflineWallet<_> = Wallet::new_offline(descriptor, None, Network::Testnet, MemoryDatabase::default())?;
+//!
+//! let mut uri = PaymentURI::new(wallet.get_new_address()?);
+//! uri.amount = Some(Amount::from_sat(100_000));
+//! uri.message = Some("Order #42".to_string());
+//!
+//! println!("Pay to: {}", uri);
+//! # Ok::<_, bdk::Error>(())
+//! ```
+
+use std::fmt;
+use std::str::FromStr;
+
+use bitcoin::util::address::{self, Address};
+use bitcoin::util::amount::{Amount, Denomination, ParseAmountError};
+use bitcoin::{Network, Script};
+
+use crate::error::Error;
+use crate::wallet::utils::is_network_compatible;
+
+const SCHEME: &str = "bitcoin:";
+
+/// Errors that can be thrown while parsing or using a [`PaymentURI`]
+#[derive(Debug, PartialEq)]
+pub enum BIP21Error {
+    /// The URI doesn't start with the `bitcoin:` scheme
+    InvalidScheme,
+    /// The address in the URI is not valid
+    Address(address::Error),
+    /// The `amount` parameter is not a valid amount of BTC
+    Amount(ParseAmountError),
+    /// A parameter is malformed or not correctly percent-encoded
+    InvalidParameter(String),
+    /// A parameter has been specified more than once
+    DuplicateParameter(String),
+    /// The URI contains a `req-` parameter that is not supported, which makes it invalid
+    UnknownRequiredParameter(String),
+    /// The URI doesn't specify an amount, which is required to build a recipient
+    MissingAmount,
+}
+
+impl fmt::Display for BIP21Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+impl std::error::Error for BIP21Error {}
+
+/// A BIP21 payment URI
+///
+/// It can be parsed from a string with [`FromStr`] and converted back to a URI with
+/// [`Display`](fmt::Display).
+#[derive(Debug, Clone, PartialEq)]
+pub struct PaymentURI {
+    /// Address to pay
+    pub address: Address,
+    /// Requested amount
+    pub amount: Option<Amount>,
+    /// Label for the address, like the name of the recipient
+    pub label: Option<String>,
+    /// Message describing the payment
+    pub message: Option<String>,
+    /// Other optional parameters, in the order they appear in the URI
+    pub params: Vec<(String, String)>,
+}
+
+impl PaymentURI {
+    /// Create a new URI for `address`, with no other parameter set
+    pub fn new(address: Address) -> Self {
+        PaymentURI {
+            address,
+            amount: None,
+            label: None,
+            message: None,
+            params: vec![],
+        }
+    }
+
+    /// Return the payjoin endpoint of the receiver, from the `pj` parameter
+    ///
+    /// See [`Wallet::send_payjoin`](crate::Wallet::send_payjoin).
+    pub fn payjoin_endpoint(&self) -> Option<&str> {
+        self.params
+            .iter()
+            .find(|(key, _)| key == "pj")
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Ensure that the address in the URI is valid for `network`
+    pub fn check_network(&self, network: Network) -> Result<(), Error> {
+        if is_network_compatible(&self.address, network) {
+            Ok(())
+        } else {
+            Err(Error::InvalidAddressNetwork(self.address.clone()))
+        }
+    }
+
+    /// Return the recipient described by this URI, in the format accepted by
+    /// [`TxBuilder::add_recipient`](crate::TxBuilder::add_recipient)
+    ///
+    /// `network` should be the [`Wallet::network`](crate::Wallet::network) paying the URI: an
+    /// error is returned if the address is not valid for it.
+    pub fn to_recipient(&self, network: Network) -> Result<(Script, u64), Error> {
+        self.check_network(network)?;
+        let amount = self.amount.ok_or(BIP21Error::MissingAmount)?;
+
+        Ok((self.address.script_pubkey(), amount.as_sat()))
+    }
+}
+
+impl From<Address> for PaymentURI {
+    fn from(address: Address) -> Self {
+        PaymentURI::new(address)
+    }
+}
+
+impl FromStr for PaymentURI {
+    type Err = BIP21Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.len() < SCHEME.len() || !s[..SCHEME.len()].eq_ignore_ascii_case(SCHEME) {
+            return Err(BIP21Error::InvalidScheme);
+        }
+
+        let mut parts = s[SCHEME.len()..].splitn(2, '?');
+        let address =
+            Address::from_str(parts.next().unwrap_or_default()).map_err(BIP21Error::Address)?;
+        let mut uri = PaymentURI::new(address);
+
+        for param in parts.next().unwrap_or_default().split('&') {
+            if param.is_empty() {
+                continue;
+            }
+
+            let mut param = param.splitn(2, '=');
+            let key = param.next().unwrap_or_default();
+            let value = param
+                .next()
+                .ok_or_else(|| BIP21Error::InvalidParameter(key.to_string()))?;
+            let value = percent_decode(value)
+                .ok_or_else(|| BIP21Error::InvalidParameter(key.to_string()))?;
+
+            let duplicate = match key {
+                "amount" => uri
+                    .amount
+                    .replace(
+                        Amount::from_str_in(&value, Denomination::Bitcoin)
+                            .map_err(BIP21Error::Amount)?,
+                    )
+                    .is_some(),
+                "label" => uri.label.replace(value).is_some(),
+                "message" => uri.message.replace(value).is_some(),
+                key if key.starts_with("req-") => {
+                    return Err(BIP21Error::UnknownRequiredParameter(key.to_string()))
+                }
+                key => {
+                    uri.params.push((key.to_string(), value));
+                    false
+                }
+            };
+
+            if duplicate {
+                return Err(BIP21Error::DuplicateParameter(key.to_string()));
+            }
+        }
+
+        Ok(uri)
+    }
+}
+
+impl fmt::Display for PaymentURI {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}{}", SCHEME, self.address)?;
+
+        let params = self
+            .amount
+            .map(|amount| ("amount", format_amount(amount)))
+            .into_iter()
+            .chain(self.label.as_ref().map(|l| ("label", percent_encode(l))))
+            .chain(
+                self.message
+                    .as_ref()
+                    .map(|m| ("message", percent_encode(m))),
+            )
+            .chain(
+                self.params
+                    .iter()
+                    .map(|(k, v)| (k.as_str(), percent_encode(v))),
+            );
+
+        for (i, (key, value)) in params.enumerate() {
+            let separator = if i == 0 { '?' } else { '&' };
+            write!(f, "{}{}={}", separator, key, value)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Format `amount` in BTC without the trailing zeros of the fractional part
+fn format_amount(amount: Amount) -> String {
+    if amount == Amount::ZERO {
+        return "0".to_string();
+    }
+
+    let amount = amount.to_string_in(Denomination::Bitcoin);
+    match amount.find('.') {
+        Some(_) => amount
+            .trim_end_matches('0')
+            .trim_end_matches('.')
+            .to_string(),
+        None => amount,
+    }
+}
+
+fn percent_encode(s: &str) -> String {
+    s.bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                (b as char).to_string()
+            }
+            b => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+pub(crate) fn percent_decode(s: &str) -> Option<String> {
+    let mut bytes = Vec::with_capacity(s.len());
+    let mut iter = s.bytes();
+
+    while let Some(b) = iter.next() {
+        if b == b'%' {
+            let hex = [iter.next()?, iter.next()?];
+            // `from_str_radix` alone would also accept a sign, like in `%+4`
+            if !hex.iter().all(u8::is_ascii_hexdigit) {
+                return None;
+            }
+            let hex = std::str::from_utf8(&hex).ok()?;
+            bytes.push(u8::from_str_radix(hex, 16).ok()?);
+        } else {
+            bytes.push(b);
+        }
+    }
+
+    String::from_utf8(bytes).ok()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_uri() {
+        let uri = PaymentURI::from_str("bitcoin:mjDZ34icH4V2k9GmC8niCrhzVuR3z8Mgkf?amount=0.001&label=Luke-Jr&message=Donation%20for%20project%20xyz&foo=bar").unwrap();
+
+        assert_eq!(
+            uri.address,
+            Address::from_str("mjDZ34icH4V2k9GmC8niCrhzVuR3z8Mgkf").unwrap()
+        );
+        assert_eq!(uri.amount, Some(Amount::from_sat(100_000)));
+        assert_eq!(uri.label, Some("Luke-Jr".to_string()));
+        assert_eq!(uri.message, Some("Donation for project xyz".to_string()));
+        assert_eq!(uri.params, vec![("foo".to_string(), "bar".to_string())]);
+        assert_eq!(
+            uri.to_recipient(Network::Testnet).unwrap(),
+            (uri.address.script_pubkey(), 100_000)
+        );
+    }
+
+    #[test]
+    fn test_payjoin_endpoint() {
+        let uri = PaymentURI::from_str("bitcoin:mjDZ34icH4V2k9GmC8niCrhzVuR3z8Mgkf?amount=0.001&pj=https://example.com/pj%3Fid%3D42").unwrap();
+        assert_eq!(uri.payjoin_endpoint(), Some("https://example.com/pj?id=42"));
+
+        let uri = PaymentURI::from_str("bitcoin:mjDZ34icH4V2k9GmC8niCrhzVuR3z8Mgkf").unwrap();
+        assert_eq!(uri.payjoin_endpoint(), None);
+    }
+
+    #[test]
+    fn test_parse_uri_only_address() {
+        let uri = PaymentURI::from_str("BITCOIN:mjDZ34icH4V2k9GmC8niCrhzVuR3z8Mgkf").unwrap();
+
+        assert_eq!(uri.amount, None);
+        assert!(matches!(
+            uri.to_recipient(Network::Testnet),
+            Err(Error::BIP21(BIP21Error::MissingAmount))
+        ));
+    }
+
+    #[test]
+    fn test_parse_uri_errors() {
+        assert_eq!(
+            PaymentURI::from_str("litecoin:mjDZ34icH4V2k9GmC8niCrhzVuR3z8Mgkf"),
+            Err(BIP21Error::InvalidScheme)
+        );
+        assert!(matches!(
+            PaymentURI::from_str("bitcoin:notanaddress"),
+            Err(BIP21Error::Address(_))
+        ));
+        assert!(matches!(
+            PaymentURI::from_str("bitcoin:mjDZ34icH4V2k9GmC8niCrhzVuR3z8Mgkf?amount=1,5"),
+            Err(BIP21Error::Amount(_))
+        ));
+        assert_eq!(
+            PaymentURI::from_str(
+                "bitcoin:mjDZ34icH4V2k9GmC8niCrhzVuR3z8Mgkf?req-somethingyoudontunderstand=50"
+            ),
+            Err(BIP21Error::UnknownRequiredParameter(
+                "req-somethingyoudontunderstand".to_string()
+            ))
+        );
+        assert_eq!(
+            PaymentURI::from_str("bitcoin:mjDZ34icH4V2k9GmC8niCrhzVuR3z8Mgkf?amount=1&amount=2"),
+            Err(BIP21Error::DuplicateParameter("amount".to_string()))
+        );
+        assert_eq!(
+            PaymentURI::from_str("bitcoin:mjDZ34icH4V2k9GmC8niCrhzVuR3z8Mgkf?label=%4"),
+            Err(BIP21Error::InvalidParameter("label".to_string()))
+        );
+        assert_eq!(
+            PaymentURI::from_str("bitcoin:mjDZ34icH4V2k9GmC8niCrhzVuR3z8Mgkf?label=%+4"),
+            Err(BIP21Error::InvalidParameter("label".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_uri_roundtrip() {
+        let mut uri = PaymentURI::new(
+            Address::from_str("tb1qw508d6qejxtdg4y5r3zarvary0c5xw7kxpjzsx").unwrap(),
+        );
+        uri.amount = Some(Amount::from_sat(150_000_000));
+        uri.message = Some("Order #42 & co".to_string());
+
+        let s = uri.to_string();
+        assert_eq!(
+            s,
+            "bitcoin:tb1qw508d6qejxtdg4y5r3zarvary0c5xw7kxpjzsx?amount=1.5&message=Order%20%2342%20%26%20co"
+        );
+        assert_eq!(PaymentURI::from_str(&s).unwrap(), uri);
+    }
+
+    #[test]
+    fn test_format_amount() {
+        let address = Address::from_str("mjDZ34icH4V2k9GmC8niCrhzVuR3z8Mgkf").unwrap();
+
+        for (sat, formatted) in &[(0, "0"), (1, "0.00000001"), (10 * 100_000_000, "10")] {
+            let mut uri = PaymentURI::new(address.clone());
+            uri.amount = Some(Amount::from_sat(*sat));
+
+            let s = uri.to_string();
+            assert_eq!(
+                s,
+                format!(
+                    "bitcoin:mjDZ34icH4V2k9GmC8niCrhzVuR3z8Mgkf?amount={}",
+                    formatted
+                )
+            );
+            assert_eq!(PaymentURI::from_str(&s).unwrap(), uri);
+        }
+    }
+
+    #[test]
+    fn test_check_network() {
+        let uri = PaymentURI::from_str("bitcoin:mjDZ34icH4V2k9GmC8niCrhzVuR3z8Mgkf").unwrap();
+
+        assert!(uri.check_network(Network::Testnet).is_ok());
+        assert!(uri.check_network(Network::Regtest).is_ok());
+        assert!(matches!(
+            uri.check_network(Network::Bitcoin),
+            Err(Error::InvalidAddressNetwork(_))
+        ));
+    }
+
+    #[test]
+    fn test_to_recipient_wrong_network() {
+        let uri = PaymentURI::from_str("bitcoin:mjDZ34icH4V2k9GmC8niCrhzVuR3z8Mgkf?amount=0.001")
+            .unwrap();
+
+        assert!(matches!(
+            uri.to_recipient(Network::Bitcoin),
+            Err(Error::InvalidAddressNetwork(_))
+        ));
+    }
+}
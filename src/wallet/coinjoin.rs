@@ -0,0 +1,92 @@
+// Magical Bitcoin Library
+// Written in 2020 by
+//     Alekos Filini <alekos.filini@gmail.com>
+//
+// Copyright (c) 2020 Magical Bitcoin
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! Collaborative transactions
+//!
+//! This module contains the types used to add our inputs and outputs to a transaction assembled
+//! by someone else, like the coordinator of a coinjoin, with
+//! [`Wallet::contribute_to_psbt`](super::Wallet::contribute_to_psbt).
+//!
+//! Our coins are chosen with a [`CoinSelectionAlgorithm`](super::coin_selection::CoinSelectionAlgorithm)
+//! to pay for our outputs and for our share of the fees, which only covers the weight of the
+//! inputs and outputs we add. The inputs already in the PSBT are never modified: once the
+//! coordinator has collected every contribution, each participant signs its own inputs with
+//! [`Wallet::sign`](super::Wallet::sign), disabling
+//! [`SignOptions::sign_unknown_inputs`](crate::signer::SignOptions::sign_unknown_inputs) so that
+//! the inputs that don't belong to the wallet are skipped.
+//!
+//! ## Example
+//!
+//! ```no_run
+//! # use bitcoin::*;
+//! # use bitcoin::util::psbt::PartiallySignedTransaction as PSBT;
+//! # use bdk::database::*;
+//! # use bdk::signer::SignOptions;
+//! # use bdk::wallet::coin_selection::DefaultCoinSelectionAlgorithm;
+//! # use bdk::*;
+//! # let descriptor = "wpkh(tpubD6NzVbkrYhZ4Xferm7Pz4VnjdcDPFyjVu5K4iZXQ4pVN8Cks4pHVowTBXBKRhX64pkRyJZJN5xAKj4UDNnLPb5p2sSKXhewoYx5GbTdUFWq/*)";
+//! # let wallet: OfflineWallet<_> = Wallet::new_offline(descriptor, None, Network::Testnet, MemoryDatabase::default())?;
+//! # let coordinator_psbt = PSBT::from_unsigned_tx(Transaction {
+//! #     version: 2,
+//! #     lock_time: 0,
+//! #     input: vec![],
+//! #     output: vec![],
+//! # })?;
+//! let mix_address = wallet.get_new_address()?;
+//! let contribution = wallet.contribute_to_psbt(
+//!     coordinator_psbt,
+//!     vec![(mix_address.script_pubkey(), 100_000)],
+//!     FeeRate::from_sat_per_vb(5.0),
+//!     DefaultCoinSelectionAlgorithm::default(),
+//! )?;
+//! println!("Foreign inputs: {:?}", contribution.foreign_inputs);
+//!
+//! // send `contribution.psbt` back to the coordinator, and later sign the complete transaction
+//! # let complete_psbt = contribution.psbt;
+//! let sign_options = SignOptions {
+//!     sign_unknown_inputs: false,
+//!     ..Default::default()
+//! };
+//! let (signed_psbt, finalized) = wallet.sign(complete_psbt, sign_options)?;
+//! # Ok::<(), bdk::Error>(())
+//! ```
+
+use bitcoin::util::psbt::PartiallySignedTransaction as PSBT;
+
+/// Result of [`Wallet::contribute_to_psbt`](super::Wallet::contribute_to_psbt)
+#[derive(Debug, Clone, PartialEq)]
+pub struct PsbtContribution {
+    /// The PSBT including our inputs and outputs
+    pub psbt: PSBT,
+    /// Indexes of the inputs we added
+    pub inputs: Vec<usize>,
+    /// Indexes of the outputs we added, including the change
+    pub outputs: Vec<usize>,
+    /// Index of our change output, if any
+    pub change: Option<usize>,
+    /// Indexes of the inputs that don't belong to the wallet, which are left untouched
+    pub foreign_inputs: Vec<usize>,
+    /// Our share of the fees, in satoshi
+    pub fee: u64,
+}
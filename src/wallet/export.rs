@@ -75,6 +75,7 @@ use miniscript::{Descriptor, DescriptorPublicKey, ScriptContext, Terminal};
 
 use crate::blockchain::BlockchainMarker;
 use crate::database::BatchDatabase;
+use crate::types::WalletBirthday;
 use crate::wallet::Wallet;
 
 /// Structure that contains the export of a wallet
@@ -110,8 +111,9 @@ impl WalletExport {
     /// supported by Bitcoin Core or don't follow the standard derivation paths defined by BIP44
     /// and others.
     ///
-    /// If `include_blockheight` is `true`, this function will look into the `wallet`'s database
-    /// for the oldest transaction it knows and use that as the earliest block to rescan.
+    /// If `include_blockheight` is `true`, this function will use the `wallet`'s birthday as the
+    /// earliest block to rescan, or look into its database for the oldest transaction it knows
+    /// when the birthday isn't a height.
     ///
     /// If the database is empty or `include_blockheight` is false, the `blockheight` field
     /// returned will be `0`.
@@ -125,18 +127,16 @@ impl WalletExport {
             .to_string_with_secret(&wallet.signers.as_key_map(wallet.secp_ctx()));
         Self::is_compatible_with_core(&descriptor)?;
 
-        let blockheight = match wallet.database.borrow().iter_txs(false) {
+        let database = wallet.database.borrow();
+        let blockheight = match (database.get_birthday(), database.iter_txs(false)) {
             _ if !include_blockheight => 0,
-            Err(_) => 0,
-            Ok(txs) => {
-                let mut heights = txs
-                    .into_iter()
-                    .map(|tx| tx.height.unwrap_or(0))
-                    .collect::<Vec<_>>();
-                heights.sort_unstable();
-
-                *heights.last().unwrap_or(&0)
-            }
+            (Ok(Some(WalletBirthday::Height(height))), _) => height,
+            (_, Err(_)) => 0,
+            (_, Ok(txs)) => txs
+                .into_iter()
+                .filter_map(|tx| tx.height)
+                .min()
+                .unwrap_or(0),
         };
 
         let export = WalletExport {
@@ -177,6 +177,16 @@ impl WalletExport {
         }
     }
 
+    /// Return the birthday of the exported wallet, or `None` if the `blockheight` wasn't included
+    ///
+    /// It can be passed to [`Wallet::new_offline_with_birthday`] when the wallet is imported.
+    pub fn birthday(&self) -> Option<WalletBirthday> {
+        match self.blockheight {
+            0 => None,
+            height => Some(WalletBirthday::Height(height)),
+        }
+    }
+
     /// Return the external descriptor
     pub fn descriptor(&self) -> String {
         self.descriptor.clone()
@@ -333,6 +343,93 @@ mod test {
         assert_eq!(export.descriptor(), descriptor);
         assert_eq!(export.change_descriptor(), Some(change_descriptor.into()));
         assert_eq!(export.blockheight, 5000);
+        assert_eq!(export.birthday(), Some(WalletBirthday::Height(5000)));
         assert_eq!(export.label, "Test Label");
     }
+
+    #[test]
+    fn test_export_oldest_blockheight() {
+        let descriptor = "wpkh(xprv9s21ZrQH143K4CTb63EaMxja1YiTnSEWKMbn23uoEnAzxjdUJRQkazCAtzxGm4LSoTSVTptoV9RbchnKPW9HxKtZumdyxyikZFDLhogJ5Uj/44'/0'/0'/0/*)";
+        let change_descriptor = "wpkh(xprv9s21ZrQH143K4CTb63EaMxja1YiTnSEWKMbn23uoEnAzxjdUJRQkazCAtzxGm4LSoTSVTptoV9RbchnKPW9HxKtZumdyxyikZFDLhogJ5Uj/44'/0'/0'/1/*)";
+
+        let mut db = get_test_db();
+        for (txid, height) in &[
+            (
+                "a8d9efe9a0f6d9d6d5c6fae0e4c5df1d2d2a73b6c4e0d0f3b9b52ee8d0bb9f34",
+                Some(7000),
+            ),
+            (
+                "b8d9efe9a0f6d9d6d5c6fae0e4c5df1d2d2a73b6c4e0d0f3b9b52ee8d0bb9f34",
+                None,
+            ),
+        ] {
+            db.set_tx(&TransactionDetails {
+                txid: Txid::from_str(txid).unwrap(),
+                height: *height,
+                ..Default::default()
+            })
+            .unwrap();
+        }
+
+        let wallet: OfflineWallet<_> =
+            Wallet::new_offline(descriptor, Some(change_descriptor), Network::Bitcoin, db).unwrap();
+
+        // the oldest confirmed transaction is used, unconfirmed ones are ignored
+        let export = WalletExport::export_wallet(&wallet, "Test Label", true).unwrap();
+        assert_eq!(export.blockheight, 5000);
+        assert_eq!(export.birthday(), Some(WalletBirthday::Height(5000)));
+
+        let export = WalletExport::export_wallet(&wallet, "Test Label", false).unwrap();
+        assert_eq!(export.blockheight, 0);
+        assert_eq!(export.birthday(), None);
+    }
+
+    #[test]
+    fn test_export_birthday() {
+        let descriptor = "wpkh(xprv9s21ZrQH143K4CTb63EaMxja1YiTnSEWKMbn23uoEnAzxjdUJRQkazCAtzxGm4LSoTSVTptoV9RbchnKPW9HxKtZumdyxyikZFDLhogJ5Uj/44'/0'/0'/0/*)";
+
+        let change_descriptor = "wpkh(xprv9s21ZrQH143K4CTb63EaMxja1YiTnSEWKMbn23uoEnAzxjdUJRQkazCAtzxGm4LSoTSVTptoV9RbchnKPW9HxKtZumdyxyikZFDLhogJ5Uj/44'/0'/0'/1/*)";
+
+        let wallet: OfflineWallet<_> = Wallet::new_offline(
+            descriptor,
+            Some(change_descriptor),
+            Network::Bitcoin,
+            get_test_db(),
+        )
+        .unwrap();
+
+        // a timestamp can't be exported, the oldest transaction is used instead
+        wallet
+            .set_birthday(WalletBirthday::Timestamp(1234))
+            .unwrap();
+        let export = WalletExport::export_wallet(&wallet, "Test Label", true).unwrap();
+        assert_eq!(export.blockheight, 5000);
+
+        wallet.set_birthday(WalletBirthday::Height(4000)).unwrap();
+        let export = WalletExport::export_wallet(&wallet, "Test Label", true).unwrap();
+        assert_eq!(export.blockheight, 4000);
+    }
+
+    #[test]
+    fn test_import_birthday() {
+        let descriptor = "wpkh(xprv9s21ZrQH143K4CTb63EaMxja1YiTnSEWKMbn23uoEnAzxjdUJRQkazCAtzxGm4LSoTSVTptoV9RbchnKPW9HxKtZumdyxyikZFDLhogJ5Uj/44'/0'/0'/0/*)";
+        let import_str = format!(
+            "{{\"descriptor\":\"{}\",\"blockheight\":5000,\"label\":\"Test Label\"}}",
+            descriptor
+        );
+
+        let export = WalletExport::from_str(&import_str).unwrap();
+        let wallet: OfflineWallet<_> = Wallet::new_offline_with_birthday(
+            &export.descriptor(),
+            None,
+            Network::Bitcoin,
+            MemoryDatabase::new(),
+            export.birthday().unwrap(),
+        )
+        .unwrap();
+        assert_eq!(
+            wallet.get_birthday().unwrap(),
+            Some(WalletBirthday::Height(5000))
+        );
+    }
 }
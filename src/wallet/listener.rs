@@ -0,0 +1,344 @@
+// Magical Bitcoin Library
+// Written in 2020 by
+//     Alekos Filini <alekos.filini@gmail.com>
+//
+// Copyright (c) 2020 Magical Bitcoin
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! Wallet listeners
+//!
+//! Listeners are notified every time a [`Wallet::sync`](super::Wallet::sync) completes, and they
+//! receive a [`SyncDiff`] that describes what changed in the wallet since the previous sync. This
+//! allows applications to react to incoming payments or confirmations without having to compare
+//! the content of the database themselves.
+//!
+//! A listener can be attached to a [`Wallet`](super::Wallet) by using the
+//! [`Wallet::add_listener`](super::Wallet::add_listener) method. All the attached listeners are
+//! notified in sequence, in the order they were added.
+//!
+//! ## Example
+//!
+//! ```
+//! # use std::sync::Arc;
+//! # use bitcoin::*;
+//! # use bdk::wallet::listener::*;
+//! # use bdk::database::*;
+//! # use bdk::*;
+//! struct PrintDeposits;
+//!
+//! impl WalletListener for PrintDeposits {
+//!     fn on_sync(&self, diff: &SyncDiff) {
+//!         for utxo in &diff.new_utxos {
+//!             println!("Received {} sat in {}", utxo.txout.value, utxo.outpoint);
+//!         }
+//!         for tx in &diff.confirmed_txs {
+//!             println!("Transaction {} confirmed at height {:?}", tx.txid, tx.height);
+//!         }
+//!     }
+//! }
+//!
+//! let descriptor = "wpkh(tpubD6NzVbkrYhZ4Xferm7Pz4VnjdcDPFyjVu5K4iZXQ4pVN8Cks4pHVowTBXBKRhX64pkRyJZJN5xAKj4UDNnLPb5p2sSKXhewoYx5GbTdUFWq/*)";
+//! let mut wallet: OfflineWallet<_> = Wallet::new_offline(descriptor, None, Network::Testnet, MemoryDatabase::default())?;
+//! wallet.add_listener(Arc::new(PrintDeposits));
+//! # Ok::<(), bdk::Error>(())
+//! ```
+
+use std::collections::{HashMap, HashSet};
+
+use bitcoin::{OutPoint, Txid};
+
+use crate::database::Database;
+use crate::error::Error;
+use crate::types::{TransactionDetails, UTXO};
+
+/// Changes to the wallet found during a sync
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SyncDiff {
+    /// Transactions that weren't in the database before
+    pub new_txs: Vec<TransactionDetails>,
+    /// Transactions that were unconfirmed before and are now confirmed
+    pub confirmed_txs: Vec<TransactionDetails>,
+    /// Transactions that were confirmed before and are now unconfirmed, confirmed at a different
+    /// height or not in the database anymore
+    pub reorged_txs: Vec<TransactionDetails>,
+    /// Outputs that weren't in the database before
+    pub new_utxos: Vec<UTXO>,
+    /// Outputs that were unspent before and are now spent
+    pub spent_utxos: Vec<UTXO>,
+}
+
+impl SyncDiff {
+    /// Return whether nothing has changed
+    pub fn is_empty(&self) -> bool {
+        self.new_txs.is_empty()
+            && self.confirmed_txs.is_empty()
+            && self.reorged_txs.is_empty()
+            && self.new_utxos.is_empty()
+            && self.spent_utxos.is_empty()
+    }
+}
+
+/// Trait to build wallet listeners
+///
+/// All the listeners attached to a wallet with [`Wallet::add_listener`](super::Wallet::add_listener)
+/// will be notified at the end of every successful sync.
+///
+/// For a usage example see [this module](crate::wallet::listener)'s documentation.
+pub trait WalletListener: Send + Sync {
+    /// Called after a sync with the changes found, even if nothing has changed
+    fn on_sync(&self, diff: &SyncDiff);
+}
+
+/// State of the database used to compute a [`SyncDiff`]
+pub(crate) struct Snapshot {
+    txs: HashMap<Txid, TransactionDetails>,
+    utxos: HashMap<OutPoint, UTXO>,
+}
+
+impl Snapshot {
+    pub(crate) fn new<D: Database>(database: &D) -> Result<Self, Error> {
+        Ok(Snapshot {
+            txs: database
+                .iter_txs(false)?
+                .into_iter()
+                .map(|tx| (tx.txid, tx))
+                .collect(),
+            utxos: database
+                .iter_utxos()?
+                .into_iter()
+                .map(|utxo| (utxo.outpoint, utxo))
+                .collect(),
+        })
+    }
+
+    /// Compute the changes from `self` to `after`
+    pub(crate) fn diff(mut self, after: &Snapshot) -> SyncDiff {
+        let mut diff = SyncDiff::default();
+
+        for (txid, tx) in &after.txs {
+            match self.txs.remove(txid) {
+                None => diff.new_txs.push(tx.clone()),
+                Some(prev) => match (prev.height, tx.height) {
+                    (None, Some(_)) => diff.confirmed_txs.push(tx.clone()),
+                    (Some(_), None) => diff.reorged_txs.push(tx.clone()),
+                    (Some(prev), Some(now)) if prev != now => diff.reorged_txs.push(tx.clone()),
+                    _ => {}
+                },
+            }
+        }
+        // whatever is left has been removed from the database
+        diff.reorged_txs.extend(self.txs.values().cloned());
+
+        let prev_utxos: HashSet<_> = self.utxos.keys().cloned().collect();
+        diff.new_utxos = after
+            .utxos
+            .values()
+            .filter(|utxo| !prev_utxos.contains(&utxo.outpoint))
+            .cloned()
+            .collect();
+        diff.spent_utxos = self
+            .utxos
+            .into_iter()
+            .filter(|(outpoint, _)| !after.utxos.contains_key(outpoint))
+            .map(|(_, utxo)| utxo)
+            .collect();
+
+        diff
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use bitcoin::hashes::Hash;
+    use bitcoin::{Script, TxOut};
+
+    use super::*;
+    use crate::database::{BatchOperations, MemoryDatabase};
+    use crate::types::ScriptType;
+
+    fn tx(n: u8, height: Option<u32>) -> TransactionDetails {
+        TransactionDetails {
+            txid: Txid::from_inner([n; 32]),
+            height,
+            ..Default::default()
+        }
+    }
+
+    fn utxo(n: u8) -> UTXO {
+        UTXO {
+            outpoint: OutPoint::new(tx(n, None).txid, 0),
+            txout: TxOut {
+                value: 50_000,
+                script_pubkey: Script::new(),
+            },
+            script_type: ScriptType::External,
+        }
+    }
+
+    #[test]
+    fn test_diff() {
+        let mut db = MemoryDatabase::new();
+        db.set_tx(&tx(1, None)).unwrap();
+        db.set_tx(&tx(2, Some(100))).unwrap();
+        db.set_tx(&tx(3, Some(100))).unwrap();
+        db.set_tx(&tx(4, Some(100))).unwrap();
+        db.set_utxo(&utxo(1)).unwrap();
+        db.set_utxo(&utxo(2)).unwrap();
+
+        let before = Snapshot::new(&db).unwrap();
+
+        db.set_tx(&tx(1, Some(101))).unwrap();
+        db.set_tx(&tx(2, None)).unwrap();
+        db.del_tx(&tx(3, None).txid, false).unwrap();
+        db.set_tx(&tx(5, None)).unwrap();
+        db.del_utxo(&utxo(1).outpoint).unwrap();
+        db.set_utxo(&utxo(5)).unwrap();
+
+        let mut diff = before.diff(&Snapshot::new(&db).unwrap());
+        diff.reorged_txs.sort_by_key(|tx| tx.txid);
+
+        assert_eq!(diff.new_txs, vec![tx(5, None)]);
+        assert_eq!(diff.confirmed_txs, vec![tx(1, Some(101))]);
+        assert_eq!(diff.reorged_txs, vec![tx(2, None), tx(3, Some(100))]);
+        assert_eq!(diff.new_utxos, vec![utxo(5)]);
+        assert_eq!(diff.spent_utxos, vec![utxo(1)]);
+    }
+
+    #[test]
+    fn test_diff_empty() {
+        let mut db = MemoryDatabase::new();
+        db.set_tx(&tx(1, Some(100))).unwrap();
+        db.set_utxo(&utxo(1)).unwrap();
+
+        let before = Snapshot::new(&db).unwrap();
+        let diff = before.diff(&Snapshot::new(&db).unwrap());
+
+        assert!(diff.is_empty());
+    }
+
+    #[cfg(not(feature = "async-interface"))]
+    mod sync {
+        use std::collections::HashSet;
+        use std::sync::{Arc, Mutex};
+
+        use bitcoin::{Network, Transaction};
+
+        use super::*;
+        use crate::blockchain::*;
+        use crate::database::BatchDatabase;
+        use crate::{FeeRate, Wallet};
+
+        /// Blockchain that stores its transactions and UTXOs in the database at every sync
+        #[derive(Default)]
+        struct MockBlockchain {
+            txs: Mutex<Vec<TransactionDetails>>,
+            utxos: Mutex<Vec<UTXO>>,
+        }
+
+        impl Blockchain for MockBlockchain {
+            fn get_capabilities(&self) -> HashSet<Capability> {
+                HashSet::new()
+            }
+
+            fn setup<D: BatchDatabase, P: 'static + Progress>(
+                &self,
+                _stop_gap: Option<usize>,
+                database: &mut D,
+                _progress_update: P,
+            ) -> Result<(), Error> {
+                for tx in self.txs.lock().unwrap().iter() {
+                    database.set_tx(tx)?;
+                }
+                for utxo in self.utxos.lock().unwrap().iter() {
+                    database.set_utxo(utxo)?;
+                }
+
+                Ok(())
+            }
+
+            fn get_tx(&self, _txid: &Txid) -> Result<Option<Transaction>, Error> {
+                Ok(None)
+            }
+            fn broadcast(&self, _tx: &Transaction) -> Result<(), Error> {
+                Ok(())
+            }
+            fn get_height(&self) -> Result<u32, Error> {
+                Ok(100)
+            }
+            fn estimate_fee(&self, _target: usize) -> Result<FeeRate, Error> {
+                Ok(FeeRate::default())
+            }
+        }
+
+        #[derive(Default)]
+        struct RecordDiffs(Mutex<Vec<SyncDiff>>);
+
+        impl WalletListener for RecordDiffs {
+            fn on_sync(&self, diff: &SyncDiff) {
+                self.0.lock().unwrap().push(diff.clone());
+            }
+        }
+
+        #[test]
+        fn test_listener_notified_on_sync() {
+            let client = Arc::new(MockBlockchain::default());
+            let mut wallet = Wallet::new(
+                "wpkh(tpubD6NzVbkrYhZ4Xferm7Pz4VnjdcDPFyjVu5K4iZXQ4pVN8Cks4pHVowTBXBKRhX64pkRyJZJN5xAKj4UDNnLPb5p2sSKXhewoYx5GbTdUFWq/*)",
+                None,
+                Network::Testnet,
+                MemoryDatabase::new(),
+                Arc::clone(&client),
+            )
+            .unwrap();
+            let listener = Arc::new(RecordDiffs::default());
+            wallet.add_listener(listener.clone());
+
+            wallet.sync(noop_progress(), None).unwrap();
+            assert_eq!(*listener.0.lock().unwrap(), vec![SyncDiff::default()]);
+
+            let mut received = utxo(1);
+            received.txout.script_pubkey = wallet.get_new_address().unwrap().script_pubkey();
+            client.txs.lock().unwrap().push(tx(1, None));
+            client.utxos.lock().unwrap().push(received.clone());
+
+            wallet.sync(noop_progress(), None).unwrap();
+            assert_eq!(
+                listener.0.lock().unwrap()[1],
+                SyncDiff {
+                    new_txs: vec![tx(1, None)],
+                    new_utxos: vec![received],
+                    ..Default::default()
+                }
+            );
+
+            client.txs.lock().unwrap()[0].height = Some(100);
+
+            wallet.sync(noop_progress(), None).unwrap();
+            assert_eq!(
+                listener.0.lock().unwrap()[2],
+                SyncDiff {
+                    confirmed_txs: vec![tx(1, Some(100))],
+                    ..Default::default()
+                }
+            );
+            assert_eq!(listener.0.lock().unwrap().len(), 3);
+        }
+    }
+}
@@ -26,7 +26,7 @@
 //!
 //! This module defines the [`Wallet`] structure.
 
-use std::cell::RefCell;
+use std::cell::{Cell, RefCell};
 use std::collections::HashMap;
 use std::collections::{BTreeMap, HashSet};
 use std::ops::{Deref, DerefMut};
@@ -38,19 +38,28 @@ use bitcoin::consensus::encode::serialize;
 use bitcoin::util::base58;
 use bitcoin::util::bip32::ChildNumber;
 use bitcoin::util::psbt::raw::Key as PSBTKey;
-use bitcoin::util::psbt::PartiallySignedTransaction as PSBT;
-use bitcoin::{Address, Network, OutPoint, Script, Transaction, TxOut, Txid};
+use bitcoin::util::psbt::{
+    Input as PSBTInput, Output as PSBTOutput, PartiallySignedTransaction as PSBT,
+};
+use bitcoin::{
+    Address, Network, OutPoint, PrivateKey, Script, SigHashType, Transaction, TxOut, Txid,
+};
 
 use miniscript::psbt::PsbtInputSatisfier;
+use rand::Rng;
 
 #[allow(unused_imports)]
 use log::{debug, error, info, trace};
 
 #[allow(missing_docs)] // TODO add missing docs and remove this allow
 pub mod address_validator;
+pub mod bip21;
 #[allow(missing_docs)] // TODO add missing docs and remove this allow
 pub mod coin_selection;
+pub mod coinjoin;
 pub mod export;
+pub mod listener;
+pub mod payjoin;
 #[allow(missing_docs)] // TODO add missing docs and remove this allow
 pub mod signer;
 pub mod time;
@@ -61,21 +70,31 @@ pub(crate) mod utils;
 pub use utils::IsDust;
 
 use address_validator::AddressValidator;
-use signer::{Signer, SignerId, SignerOrdering, SignersContainer};
+use listener::{Snapshot, WalletListener};
+use signer::{
+    SignOptions, SignPolicy, Signer, SignerError, SignerId, SignerOrdering, SignersContainer,
+};
 use tx_builder::{BumpFee, CreateTx, FeePolicy, TxBuilder, TxBuilderContext};
-use utils::{descriptor_to_pk_ctx, After, Older, SecpCtx};
+use utils::{
+    anti_fee_sniping_locktime, descriptor_to_pk_ctx, After, Older, SecpCtx,
+    BLOCKS_TIMELOCK_THRESHOLD,
+};
 
-use crate::blockchain::{Blockchain, BlockchainMarker, OfflineBlockchain, Progress};
+use crate::blockchain::{noop_progress, Blockchain, BlockchainMarker, OfflineBlockchain, Progress};
+use crate::database::memory::MemoryDatabase;
 use crate::database::{BatchDatabase, BatchOperations, DatabaseUtils};
+use crate::descriptor::template::{P2PKH, P2WPKH, P2WPKH_P2SH};
 use crate::descriptor::{
-    get_checksum, DescriptorMeta, DescriptorScripts, ExtendedDescriptor, ExtractPolicy, Policy,
-    ToWalletDescriptor, XKeyUtils,
+    get_checksum, DescriptorMeta, DescriptorScripts, ExtendedDescriptor, ExtractPolicy, KeyMap,
+    Policy, ToWalletDescriptor, XKeyUtils,
 };
 use crate::error::Error;
 use crate::psbt::PSBTUtils;
 use crate::types::*;
 
 const CACHE_ADDR_BATCH_SIZE: u32 = 100;
+/// Maximum number of new addresses that [`Wallet::reveal_up_to`] can reveal in a single call
+const MAX_REVEAL_BATCH_SIZE: u32 = 1000;
 
 /// Type alias for a [`Wallet`] that uses [`OfflineBlockchain`]
 pub type OfflineWallet<D> = Wallet<OfflineBlockchain, D>;
@@ -97,11 +116,16 @@ pub struct Wallet<B, D> {
     signers: Arc<SignersContainer>,
     change_signers: Arc<SignersContainer>,
 
+    keychains: Vec<(ExtendedDescriptor, Arc<SignersContainer>)>,
+
     address_validators: Vec<Arc<dyn AddressValidator>>,
+    listeners: Vec<Arc<dyn WalletListener>>,
 
     network: Network,
+    psbt_for_offline_signer: bool,
+    sign_policy: SignPolicy,
 
-    current_height: Option<u32>,
+    current_height: Cell<Option<u32>>,
 
     client: Option<B>,
     database: RefCell<D>,
@@ -151,11 +175,15 @@ where
             change_descriptor,
             signers,
             change_signers,
+            keychains: Vec::new(),
             address_validators: Vec::new(),
+            listeners: Vec::new(),
 
             network,
+            psbt_for_offline_signer: false,
+            sign_policy: SignPolicy::default(),
 
-            current_height: None,
+            current_height: Cell::new(None),
 
             client: None,
             database: RefCell::new(database),
@@ -164,9 +192,41 @@ where
         })
     }
 
+    /// Create a new "offline" wallet with a birthday
+    ///
+    /// The `birthday` is only stored if the `database` is empty: use [`WalletBirthday::now`] for
+    /// a new key, or the birthday of an imported wallet. Wallets restored from an existing key
+    /// shouldn't set it, since they may have transactions from before their creation in this
+    /// database. See [`Wallet::set_birthday`] to change it later.
+    pub fn new_offline_with_birthday<E: ToWalletDescriptor>(
+        descriptor: E,
+        change_descriptor: Option<E>,
+        network: Network,
+        mut database: D,
+        birthday: WalletBirthday,
+    ) -> Result<Self, Error> {
+        if database.get_birthday()?.is_none()
+            && database.iter_script_pubkeys(None)?.is_empty()
+            && database.iter_txs(false)?.is_empty()
+        {
+            database.set_birthday(birthday)?;
+        }
+
+        Self::new_offline(descriptor, change_descriptor, network, database)
+    }
+
     /// Return a newly generated address using the external descriptor
     pub fn get_new_address(&self) -> Result<Address, Error> {
         let index = self.fetch_and_increment_index(ScriptType::External)?;
+
+        self.peek_address(index)
+    }
+
+    /// Return the address at `index` using the external descriptor, without revealing it
+    ///
+    /// This doesn't change the index used by [`Wallet::get_new_address`], and the address
+    /// validators are not polled.
+    pub fn peek_address(&self, index: u32) -> Result<Address, Error> {
         let deriv_ctx = descriptor_to_pk_ctx(&self.secp);
 
         self.descriptor
@@ -175,6 +235,152 @@ where
             .ok_or(Error::ScriptDoesntHaveAddressForm)
     }
 
+    /// Reveal all the external addresses up to `index` (included), returning the ones that were
+    /// not revealed before
+    ///
+    /// The address validators are polled for every address that gets revealed. At most 1000 new
+    /// addresses can be revealed at once, an [`Error::AddressIndexTooHigh`] is returned if `index`
+    /// is further away from the last revealed one.
+    pub fn reveal_up_to(&self, index: u32) -> Result<Vec<Address>, Error> {
+        let index = match self.descriptor.is_fixed() {
+            true => 0,
+            false => index,
+        };
+        let last_index = self
+            .database
+            .borrow()
+            .get_last_index(ScriptType::External)?;
+        let from = match last_index {
+            Some(last) if last >= index => return Ok(vec![]),
+            Some(last) => last + 1,
+            None => 0,
+        };
+        let max = from.saturating_add(MAX_REVEAL_BATCH_SIZE - 1);
+        if index > max {
+            return Err(Error::AddressIndexTooHigh { max });
+        }
+
+        if self
+            .database
+            .borrow()
+            .get_script_pubkey_from_path(ScriptType::External, index)?
+            .is_none()
+        {
+            self.cache_addresses(
+                ScriptType::External,
+                from,
+                std::cmp::max(index - from + 1, CACHE_ADDR_BATCH_SIZE),
+            )?;
+        }
+
+        for i in from..=index {
+            self.validate_address(ScriptType::External, i)?;
+        }
+        self.database
+            .borrow_mut()
+            .set_last_index(ScriptType::External, index)?;
+
+        (from..=index).map(|i| self.peek_address(i)).collect()
+    }
+
+    /// Return the revealed external address with the lowest index that is still unused, or reveal
+    /// a new one if all of them have been used
+    ///
+    /// An address is considered used if it has received a transaction or if it has been marked
+    /// with [`Wallet::mark_used`].
+    ///
+    /// Note that this methods only operate on the internal database, which first needs to be
+    /// [`Wallet::sync`] manually.
+    pub fn get_unused_address(&self) -> Result<Address, Error> {
+        let last_index = self
+            .database
+            .borrow()
+            .get_last_index(ScriptType::External)?;
+        let last_index = match last_index {
+            Some(last_index) => last_index,
+            None => return self.get_new_address(),
+        };
+
+        let used = self.get_used_indexes(ScriptType::External)?;
+        match (0..=last_index).find(|i| !used.contains(i)) {
+            Some(index) => self.peek_address(index),
+            None => self.get_new_address(),
+        }
+    }
+
+    /// Mark the external address at `index` as used, so that it won't be returned by
+    /// [`Wallet::get_unused_address`]
+    ///
+    /// This is useful when an address has been handed out, but hasn't received any payment yet.
+    pub fn mark_used(&self, index: u32) -> Result<(), Error> {
+        self.database
+            .borrow_mut()
+            .set_marked_used(ScriptType::External, index)
+    }
+
+    /// Remove the mark set with [`Wallet::mark_used`] from the external address at `index`,
+    /// returning whether it was marked
+    pub fn unmark_used(&self, index: u32) -> Result<bool, Error> {
+        self.database
+            .borrow_mut()
+            .del_marked_used(ScriptType::External, index)
+    }
+
+    /// Set the birthday of the wallet, the earliest point in the chain that may contain its
+    /// transactions
+    ///
+    /// The blockchain backends ignore the transactions confirmed before the birthday. See
+    /// [`Wallet::new_offline_with_birthday`] to record it when the wallet is created.
+    pub fn set_birthday(&self, birthday: WalletBirthday) -> Result<(), Error> {
+        self.database.borrow_mut().set_birthday(birthday)
+    }
+
+    /// Return the birthday of the wallet, if it has been set
+    pub fn get_birthday(&self) -> Result<Option<WalletBirthday>, Error> {
+        self.database.borrow().get_birthday()
+    }
+
+    /// Return the list of revealed external addresses, along with their usage status and balance
+    ///
+    /// Note that this methods only operate on the internal database, which first needs to be
+    /// [`Wallet::sync`] manually.
+    pub fn list_addresses(&self) -> Result<Vec<AddressInfo>, Error> {
+        let last_index = self
+            .database
+            .borrow()
+            .get_last_index(ScriptType::External)?;
+        let last_index = match last_index {
+            Some(last_index) => last_index,
+            None => return Ok(vec![]),
+        };
+
+        let used = self.get_used_indexes(ScriptType::External)?;
+        let utxos = self.list_unspent()?;
+
+        (0..=last_index)
+            .map(|index| {
+                let address = self.peek_address(index)?;
+                let balance = utxos
+                    .iter()
+                    .filter(|u| u.txout.script_pubkey == address.script_pubkey())
+                    .map(|u| u.txout.value)
+                    .sum();
+
+                Ok(AddressInfo {
+                    index,
+                    used: used.contains(&index),
+                    address,
+                    balance,
+                })
+            })
+            .collect()
+    }
+
+    /// Get the Bitcoin network the wallet is using.
+    pub fn network(&self) -> Network {
+        self.network
+    }
+
     /// Return whether or not a `script` is part of this wallet (either internal or external)
     pub fn is_mine(&self, script: &Script) -> Result<bool, Error> {
         self.database.borrow().is_mine(script)
@@ -210,8 +416,91 @@ where
             .fold(0, |sum, i| sum + i.txout.value))
     }
 
+    /// Return the balance of a single keychain, meaning the sum of the unspent outputs' values
+    /// that belong to the given `script_type`
+    ///
+    /// Note that this methods only operate on the internal database, which first needs to be
+    /// [`Wallet::sync`] manually.
+    pub fn get_keychain_balance(&self, script_type: ScriptType) -> Result<u64, Error> {
+        Ok(self
+            .list_unspent()?
+            .iter()
+            .filter(|i| i.script_type == script_type)
+            .fold(0, |sum, i| sum + i.txout.value))
+    }
+
+    /// Add an additional keychain to the wallet
+    ///
+    /// Keychains are identified by [`ScriptType::Keychain`] with an index that is assigned
+    /// incrementally, starting from `0`, in the order they are added. Since the index is used to
+    /// store data in the database, the same descriptors must always be added in the same order:
+    /// the descriptor's checksum is verified against the one in the database to detect mistakes.
+    ///
+    /// The outputs received by every keychain are available for coin selection together with
+    /// the ones received by the main descriptors, while the change always goes back to the main
+    /// change descriptor. This can be used for instance to track multiple accounts in the same
+    /// database, or to migrate the funds of a legacy descriptor to a native segwit one.
+    ///
+    /// ## Example
+    ///
+    /// ```
+    /// # use bitcoin::*;
+    /// # use bdk::*;
+    /// # use bdk::database::*;
+    /// let descriptor = "wpkh(tpubD6NzVbkrYhZ4Xferm7Pz4VnjdcDPFyjVu5K4iZXQ4pVN8Cks4pHVowTBXBKRhX64pkRyJZJN5xAKj4UDNnLPb5p2sSKXhewoYx5GbTdUFWq/0/*)";
+    /// let legacy = "pkh(tpubD6NzVbkrYhZ4Xferm7Pz4VnjdcDPFyjVu5K4iZXQ4pVN8Cks4pHVowTBXBKRhX64pkRyJZJN5xAKj4UDNnLPb5p2sSKXhewoYx5GbTdUFWq/1/*)";
+    /// let mut wallet: OfflineWallet<_> = Wallet::new_offline(descriptor, None, Network::Testnet, MemoryDatabase::default())?;
+    ///
+    /// let script_type = wallet.add_keychain(legacy)?;
+    /// assert_eq!(script_type, ScriptType::Keychain(0));
+    /// println!("legacy balance: {}", wallet.get_keychain_balance(script_type)?);
+    /// # Ok::<(), bdk::Error>(())
+    /// ```
+    pub fn add_keychain<E: ToWalletDescriptor>(
+        &mut self,
+        descriptor: E,
+    ) -> Result<ScriptType, Error> {
+        if self.keychains.len() > u8::MAX as usize {
+            return Err(Error::Generic("Too many keychains".into()));
+        }
+        let script_type = ScriptType::Keychain(self.keychains.len() as u8);
+
+        let (descriptor, keymap) = descriptor.to_wallet_descriptor(self.network)?;
+        self.database.borrow_mut().check_descriptor_checksum(
+            script_type,
+            get_checksum(&descriptor.to_string())?.as_bytes(),
+        )?;
+
+        let signers = Arc::new(SignersContainer::from(keymap));
+        self.keychains.push((descriptor, signers));
+
+        Ok(script_type)
+    }
+
+    /// Return a newly generated address using the descriptor of the given `script_type`
+    ///
+    /// This is mostly useful to receive on one of the keychains added with
+    /// [`Wallet::add_keychain`]: for the main descriptor [`Wallet::get_new_address`] can be
+    /// used instead.
+    pub fn get_new_keychain_address(&self, script_type: ScriptType) -> Result<Address, Error> {
+        if !self.has_keychain(script_type) {
+            return Err(Error::UnknownKeychain(script_type));
+        }
+
+        let index = self.fetch_and_increment_index(script_type)?;
+        let (descriptor, _) = self.get_descriptor_for_script_type(script_type)?;
+
+        descriptor
+            .derive(ChildNumber::from_normal_idx(index)?)
+            .address(self.network, descriptor_to_pk_ctx(&self.secp))
+            .ok_or(Error::ScriptDoesntHaveAddressForm)
+    }
+
     /// Add an external signer
     ///
+    /// Returns [`Error::UnknownKeychain`] if `script_type` refers to a keychain that hasn't been
+    /// added to the wallet.
+    ///
     /// See [the `signer` module](signer) for an example.
     pub fn add_signer(
         &mut self,
@@ -219,13 +508,47 @@ where
         id: SignerId,
         ordering: SignerOrdering,
         signer: Arc<dyn Signer>,
-    ) {
+    ) -> Result<(), Error> {
         let signers = match script_type {
             ScriptType::External => Arc::make_mut(&mut self.signers),
             ScriptType::Internal => Arc::make_mut(&mut self.change_signers),
+            ScriptType::Keychain(index) => match self.keychains.get_mut(index as usize) {
+                Some((_, signers)) => Arc::make_mut(signers),
+                None => return Err(Error::UnknownKeychain(script_type)),
+            },
         };
 
         signers.add_external(id, ordering, signer);
+
+        Ok(())
+    }
+
+    /// Set whether the PSBTs created by this wallet will be signed by an offline signer
+    ///
+    /// When enabled, [`Wallet::create_tx`] and [`Wallet::bump_fee`] behave as if the
+    /// [`TxBuilder::force_non_witness_utxo`], [`TxBuilder::include_output_redeem_witness_script`]
+    /// and [`TxBuilder::add_global_xpubs`] options were always set, so that the signer receives
+    /// everything it needs to validate the transaction on its own. This is meant for watch-only
+    /// wallets created from extended public keys with their origin, like
+    /// `wpkh([c55b303f/84'/1'/0']tpub.../0/*)`, otherwise the global xpubs can't be added.
+    ///
+    /// On the signer side [`Wallet::verify_change`] can be used to check the outputs that claim
+    /// to be change before signing.
+    ///
+    /// ## Example
+    ///
+    /// ```
+    /// # use bitcoin::*;
+    /// # use bdk::*;
+    /// # use bdk::database::*;
+    /// let descriptor = "wpkh([c55b303f/84'/1'/0']tpubDC2Qwo2TFsaNC4ju8nrUJ9mqVT3eSgdmy1yPqhgkjwmke3PRXutNGRYAUo6RCHTcVQaDR3ohNU9we59brGHuEKPvH1ags2nevW5opEE9Z5Q/0/*)";
+    /// let change_descriptor = "wpkh([c55b303f/84'/1'/0']tpubDC2Qwo2TFsaNC4ju8nrUJ9mqVT3eSgdmy1yPqhgkjwmke3PRXutNGRYAUo6RCHTcVQaDR3ohNU9we59brGHuEKPvH1ags2nevW5opEE9Z5Q/1/*)";
+    /// let mut wallet: OfflineWallet<_> = Wallet::new_offline(descriptor, Some(change_descriptor), Network::Testnet, MemoryDatabase::default())?;
+    /// wallet.set_psbt_for_offline_signer(true);
+    /// # Ok::<(), bdk::Error>(())
+    /// ```
+    pub fn set_psbt_for_offline_signer(&mut self, enabled: bool) {
+        self.psbt_for_offline_signer = enabled;
     }
 
     /// Add an address validator
@@ -235,6 +558,13 @@ where
         self.address_validators.push(validator);
     }
 
+    /// Add a listener that will be notified at the end of every sync
+    ///
+    /// See [the `listener` module](listener) for an example.
+    pub fn add_listener(&mut self, listener: Arc<dyn WalletListener>) {
+        self.listeners.push(listener);
+    }
+
     /// Create a new transaction following the options specified in the `builder`
     ///
     /// ## Example
@@ -271,6 +601,17 @@ where
                 )
             })
             .transpose()?;
+        let keychain_policies = self
+            .keychains
+            .iter()
+            .enumerate()
+            .filter_map(|(index, (desc, signers))| {
+                // keychains without a policy don't add any requirement
+                desc.extract_policy(signers, &self.secp)
+                    .map(|policy| policy.map(|policy| (index as u8, policy)))
+                    .transpose()
+            })
+            .collect::<Result<Vec<_>, _>>()?;
 
         // The policy allows spending external outputs, but it requires a policy path that hasn't been
         // provided
@@ -289,6 +630,15 @@ where
                 return Err(Error::SpendingPolicyRequired(ScriptType::Internal));
             };
         }
+        // And for the additional keychains, which are never considered change
+        for (index, policy) in &keychain_policies {
+            if builder.change_policy != tx_builder::ChangeSpendPolicy::OnlyChange
+                && policy.requires_path()
+                && !builder.keychain_policy_paths.contains_key(index)
+            {
+                return Err(Error::SpendingPolicyRequired(ScriptType::Keychain(*index)));
+            }
+        }
 
         let external_requirements = external_policy.get_condition(
             builder
@@ -309,11 +659,27 @@ where
             })
             .transpose()?;
 
-        let requirements = external_requirements
+        let mut requirements = external_requirements
             .clone()
             .merge(&internal_requirements.unwrap_or_default())?;
+        for (index, policy) in keychain_policies {
+            let keychain_requirements = policy.get_condition(
+                builder
+                    .keychain_policy_paths
+                    .get(&index)
+                    .unwrap_or(&BTreeMap::new()),
+            )?;
+            requirements = requirements.merge(&keychain_requirements)?;
+        }
         debug!("Policy requirements: {:?}", requirements);
 
+        let anti_fee_sniping = match (builder.anti_fee_sniping, self.current_height.get()) {
+            (true, Some(height)) if builder.locktime.is_none() => {
+                Some(anti_fee_sniping_locktime(height, &mut rand::thread_rng()))
+            }
+            _ => None,
+        };
+
         let version = match builder.version {
             Some(tx_builder::Version(0)) => {
                 return Err(Error::Generic("Invalid version `0`".into()))
@@ -325,12 +691,21 @@ where
                 ))
             }
             Some(tx_builder::Version(x)) => x,
-            None if requirements.csv.is_some() => 2,
+            None if requirements.csv.is_some() || anti_fee_sniping.is_some() => 2,
             _ => 1,
         };
 
         let lock_time = match builder.locktime {
-            None => requirements.timelock.unwrap_or(0),
+            // height-based timelocks can be raised to the anti-fee-sniping height, time-based ones
+            // can't be mixed with it and are kept as they are
+            None => match (requirements.timelock, anti_fee_sniping) {
+                (Some(timelock), Some(height)) if timelock < BLOCKS_TIMELOCK_THRESHOLD => {
+                    std::cmp::max(timelock, height)
+                }
+                (Some(timelock), _) => timelock,
+                (None, Some(height)) => height,
+                (None, None) => 0,
+            },
             Some(x) if requirements.timelock.is_none() => x,
             Some(x) if requirements.timelock.unwrap() <= x => x,
             Some(x) => return Err(Error::Generic(format!("TxBuilder requested timelock of `{}`, but at least `{}` is required to spend from this script", x, requirements.timelock.unwrap())))
@@ -339,7 +714,7 @@ where
         let n_sequence = match (builder.rbf, requirements.csv) {
             (None, Some(csv)) => csv,
             (Some(rbf), Some(csv)) if rbf < csv => return Err(Error::Generic(format!("Cannot enable RBF with nSequence `{}`, since at least `{}` is required to spend with OP_CSV", rbf, csv))),
-            (None, _) if requirements.timelock.is_some() => 0xFFFFFFFE,
+            (None, _) if requirements.timelock.is_some() || anti_fee_sniping.is_some() => 0xFFFFFFFE,
             (Some(rbf), _) if rbf >= 0xFFFFFFFE => return Err(Error::Generic("Cannot enable RBF with a nSequence >= 0xFFFFFFFE".into())),
             (Some(rbf), _) => rbf,
             (None, _) => 0xFFFFFFFF,
@@ -376,7 +751,10 @@ where
             return Err(Error::NoRecipients);
         }
 
-        if builder.manually_selected_only && builder.utxos.is_empty() {
+        if builder.manually_selected_only
+            && builder.utxos.is_empty()
+            && builder.foreign_utxos.is_empty()
+        {
             return Err(Error::NoUtxosSelected);
         }
 
@@ -417,7 +795,7 @@ where
             ));
         }
 
-        let (required_utxos, optional_utxos) = self.preselect_utxos(
+        let (mut required_utxos, optional_utxos) = self.preselect_utxos(
             builder.change_policy,
             &builder.unspendable,
             &builder.utxos,
@@ -426,6 +804,42 @@ where
             false, // we don't mind using unconfirmed outputs here, hopefully coin selection will sort this out?
         )?;
 
+        let mut foreign_amount = 0;
+        for (outpoint, psbt_input, satisfaction_weight) in &builder.foreign_utxos {
+            // our own utxos have to be added with `add_utxo`, otherwise they wouldn't be counted
+            // in the amount we send
+            if self.database.borrow().get_utxo(outpoint)?.is_some() {
+                return Err(Error::InvalidForeignUtxo(*outpoint));
+            }
+
+            let prev_output = match &psbt_input.non_witness_utxo {
+                Some(prev_tx) if prev_tx.txid() == outpoint.txid => Some(
+                    prev_tx
+                        .output
+                        .get(outpoint.vout as usize)
+                        .ok_or(Error::InvalidForeignUtxo(*outpoint))?,
+                ),
+                Some(_) => return Err(Error::InvalidForeignUtxo(*outpoint)),
+                None => None,
+            };
+            let txout = match (&psbt_input.witness_utxo, prev_output) {
+                (Some(txout), Some(prev_output)) if txout != prev_output => {
+                    return Err(Error::InvalidForeignUtxo(*outpoint))
+                }
+                (Some(txout), _) | (None, Some(txout)) => txout.clone(),
+                (None, None) => return Err(Error::InvalidForeignUtxo(*outpoint)),
+            };
+            foreign_amount += txout.value;
+
+            // the script type is not really meaningful here, we don't own these utxos
+            let utxo = UTXO {
+                outpoint: *outpoint,
+                txout,
+                script_type: ScriptType::External,
+            };
+            required_utxos.push((utxo, *satisfaction_weight));
+        }
+
         let coin_selection::CoinSelectionResult {
             selected,
             selected_amount,
@@ -466,6 +880,7 @@ where
 
         let mut fee_amount = fee_amount.ceil() as u64;
         let change_val = (selected_amount - outgoing).saturating_sub(fee_amount);
+        let mut change_index = None;
 
         match change_output {
             None if change_val.is_dust() => {
@@ -480,6 +895,7 @@ where
                 change_output.value = change_val;
                 received += change_val;
 
+                change_index = Some(tx.output.len());
                 tx.output.push(change_output);
             }
             None => {
@@ -494,7 +910,7 @@ where
         }
 
         // sort input/outputs according to the chosen algorithm
-        builder.ordering.sort_tx(&mut tx);
+        builder.ordering.sort_tx_with_change(&mut tx, change_index);
 
         let txid = tx.txid();
         let psbt = self.complete_transaction(tx, selected, builder)?;
@@ -504,7 +920,7 @@ where
             txid,
             timestamp: time::get_timestamp(),
             received,
-            sent: selected_amount,
+            sent: selected_amount - foreign_amount,
             fees: fee_amount,
             height: None,
         };
@@ -576,7 +992,7 @@ where
                     // addresses really is, because if there's no change_descriptor it's actually equal
                     // to "External"
                     let (_, change_type) =
-                        self.get_descriptor_for_script_type(ScriptType::Internal);
+                        self.get_descriptor_for_script_type(ScriptType::Internal)?;
                     match self
                         .database
                         .borrow()
@@ -634,7 +1050,7 @@ where
                     .get_path_from_script_pubkey(&txout.script_pubkey)?
                 {
                     Some((script_type, _)) => (
-                        self.get_descriptor_for_script_type(script_type)
+                        self.get_descriptor_for_script_type(script_type)?
                             .0
                             .max_satisfaction_weight(deriv_ctx)
                             .unwrap(),
@@ -742,6 +1158,7 @@ where
 
         let change_val = selected_amount - amount_needed - fee_amount;
         let change_val_after_add = change_val.saturating_sub(removed_output_fee_cost);
+        let mut change_index = None;
         match builder.single_recipient {
             None if change_val_after_add.is_dust() => {
                 // skip the change output because it's dust, this adds up to the fees
@@ -756,6 +1173,7 @@ where
                 fee_amount += removed_output_fee_cost;
                 details.received += change_val_after_add;
 
+                change_index = Some(tx.output.len());
                 tx.output.push(removed_updatable_output);
             }
             Some(_) => {
@@ -772,7 +1190,7 @@ where
         }
 
         // sort input/outputs according to the chosen algorithm
-        builder.ordering.sort_tx(&mut tx);
+        builder.ordering.sort_tx_with_change(&mut tx, change_index);
 
         // TODO: check that we are not replacing more than 100 txs from mempool
 
@@ -788,6 +1206,11 @@ where
     /// Sign a transaction with all the wallet's signers, in the order specified by every signer's
     /// [`SignerOrdering`]
     ///
+    /// The [`SignOptions`] are forwarded to every signer and control, among other things, which
+    /// sighash types are allowed and whether the PSBT is finalized after signing. When
+    /// [`SignOptions::sign_unknown_inputs`] is not set, inputs that can't be matched to any of the
+    /// wallet's descriptors are not passed to the signers.
+    ///
     /// ## Example
     ///
     /// ```no_run
@@ -795,32 +1218,124 @@ where
     /// # use bitcoin::*;
     /// # use bdk::*;
     /// # use bdk::database::*;
+    /// # use bdk::signer::SignOptions;
     /// # let descriptor = "wpkh(tpubD6NzVbkrYhZ4Xferm7Pz4VnjdcDPFyjVu5K4iZXQ4pVN8Cks4pHVowTBXBKRhX64pkRyJZJN5xAKj4UDNnLPb5p2sSKXhewoYx5GbTdUFWq/*)";
     /// # let wallet: OfflineWallet<_> = Wallet::new_offline(descriptor, None, Network::Testnet, MemoryDatabase::default())?;
     /// # let (psbt, _) = wallet.create_tx(TxBuilder::new())?;
-    /// let (signed_psbt, finalized) = wallet.sign(psbt, None)?;
+    /// let (signed_psbt, finalized) = wallet.sign(psbt, SignOptions::default())?;
     /// # Ok::<(), bdk::Error>(())
-    pub fn sign(&self, mut psbt: PSBT, assume_height: Option<u32>) -> Result<(PSBT, bool), Error> {
+    pub fn sign(
+        &self,
+        mut psbt: PSBT,
+        mut sign_options: SignOptions,
+    ) -> Result<(PSBT, bool), Error> {
         // this helps us doing our job later
         self.add_input_hd_keypaths(&mut psbt)?;
 
+        self.check_sign_policy(&psbt)?;
+        if self.sign_policy.only_sighash_all {
+            sign_options
+                .allowed_sighash
+                .retain(|sighash| *sighash == SigHashType::All);
+        }
+
+        let mut input_indexes = Vec::with_capacity(psbt.inputs.len());
+        for index in 0..psbt.inputs.len() {
+            if sign_options.sign_unknown_inputs
+                || self.get_descriptor_for_psbt_input(&psbt, index)?.is_some()
+            {
+                input_indexes.push(index);
+            }
+        }
+
         for signer in self
             .signers
             .signers()
-            .iter()
-            .chain(self.change_signers.signers().iter())
+            .into_iter()
+            .chain(self.change_signers.signers())
+            .chain(
+                self.keychains
+                    .iter()
+                    .flat_map(|(_, signers)| signers.signers()),
+            )
         {
             if signer.sign_whole_tx() {
-                signer.sign(&mut psbt, None, &self.secp)?;
+                signer.sign(&mut psbt, None, &sign_options, &self.secp)?;
             } else {
-                for index in 0..psbt.inputs.len() {
-                    signer.sign(&mut psbt, Some(index), &self.secp)?;
+                for &index in &input_indexes {
+                    signer.sign(&mut psbt, Some(index), &sign_options, &self.secp)?;
                 }
             }
         }
 
-        // attempt to finalize
-        self.finalize_psbt(psbt, assume_height)
+        if sign_options.try_finalize {
+            self.finalize_psbt_inner(
+                psbt,
+                sign_options.assume_height,
+                sign_options.remove_partial_sigs,
+            )
+        } else {
+            Ok((psbt, false))
+        }
+    }
+
+    /// Verify the outputs of a PSBT that claim to belong to this wallet
+    ///
+    /// An output claims to belong to the wallet when the `hd_keypaths` of the corresponding PSBT
+    /// output reference the fingerprint of one of the wallet's extended keys, which is how
+    /// [`Wallet::create_tx`] marks the change. For each of them, the `hd_keypaths` must derive
+    /// one of the wallet's descriptors and the derived script must be equal to the output's
+    /// `script_pubkey`, otherwise an
+    /// [`InvalidChangeOutput`](signer::SignerError::InvalidChangeOutput) error is returned.
+    ///
+    /// This is meant to be used by offline signers before signing a PSBT created by an online
+    /// watch-only wallet, to make sure that the change is really coming back to them.
+    ///
+    /// Return the indexes of the outputs that belong to the wallet.
+    pub fn verify_change(&self, psbt: &PSBT) -> Result<Vec<usize>, Error> {
+        let deriv_ctx = descriptor_to_pk_ctx(&self.secp);
+
+        let descriptors = self.get_descriptors();
+        let mut our_fingerprints = HashSet::new();
+        for (_, descriptor) in &descriptors {
+            for xpub in descriptor.get_extended_keys()? {
+                our_fingerprints.insert(xpub.root_fingerprint(&self.secp));
+            }
+        }
+
+        let mut change = Vec::new();
+        for (index, (psbt_output, txout)) in psbt
+            .outputs
+            .iter()
+            .zip(psbt.global.unsigned_tx.output.iter())
+            .enumerate()
+        {
+            let belongs = descriptors.iter().any(|(_, descriptor)| {
+                descriptor
+                    .derive_from_hd_keypaths(&psbt_output.hd_keypaths, &self.secp)
+                    .map(|derived| derived.script_pubkey(deriv_ctx) == txout.script_pubkey)
+                    .unwrap_or(false)
+            });
+            let claimed = psbt_output
+                .hd_keypaths
+                .values()
+                .any(|(fingerprint, _)| our_fingerprints.contains(fingerprint));
+
+            if belongs {
+                change.push(index);
+            } else if claimed {
+                return Err(signer::SignerError::InvalidChangeOutput(index).into());
+            }
+        }
+
+        Ok(change)
+    }
+
+    /// Set the checks performed on every PSBT before signing it
+    ///
+    /// See [`SignPolicy`](signer::SignPolicy) for the available checks.
+    pub fn set_sign_policy(&mut self, sign_policy: signer::SignPolicy) {
+        self.sign_policy = sign_policy;
     }
 
     /// Return the spending policies for the wallet's descriptor
@@ -833,6 +1348,10 @@ where
             (ScriptType::Internal, Some(desc)) => {
                 Ok(desc.extract_policy(&self.change_signers, &self.secp)?)
             }
+            (ScriptType::Keychain(index), _) => match self.keychains.get(index as usize) {
+                Some((desc, signers)) => Ok(desc.extract_policy(signers, &self.secp)?),
+                None => Ok(None),
+            },
         }
     }
 
@@ -848,90 +1367,551 @@ where
             (ScriptType::External, _) => Ok(Some(self.descriptor.clone())),
             (ScriptType::Internal, None) => Ok(None),
             (ScriptType::Internal, Some(desc)) => Ok(Some(desc.clone())),
+            (ScriptType::Keychain(index), _) => Ok(self
+                .keychains
+                .get(index as usize)
+                .map(|(desc, _)| desc.clone())),
         }
     }
 
     /// Try to finalize a PSBT
     pub fn finalize_psbt(
         &self,
-        mut psbt: PSBT,
+        psbt: PSBT,
         assume_height: Option<u32>,
     ) -> Result<(PSBT, bool), Error> {
-        let tx = &psbt.global.unsigned_tx;
-        let mut finished = true;
+        self.finalize_psbt_inner(psbt, assume_height, false)
+    }
 
-        for (n, input) in tx.input.iter().enumerate() {
-            let psbt_input = &psbt.inputs[n];
-            if psbt_input.final_script_sig.is_some() || psbt_input.final_script_witness.is_some() {
-                continue;
-            }
-            // if the height is None in the database it means it's still unconfirmed, so consider
-            // that as a very high value
-            let create_height = self
-                .database
-                .borrow()
-                .get_tx(&input.previous_output.txid, false)?
-                .map(|tx| tx.height.unwrap_or(std::u32::MAX));
-            let current_height = assume_height.or(self.current_height);
+    /// Send a payjoin request and sign the receiver's proposal
+    ///
+    /// The `original` PSBT must be signed and finalized, since the receiver is allowed to
+    /// broadcast it instead of the payjoin. The proposal returned by the `transport` is validated
+    /// with [`Wallet::process_payjoin_proposal`] and then signed with `sign_options`.
+    ///
+    /// See the [`payjoin`] module for an example.
+    #[maybe_async]
+    pub fn send_payjoin<T: payjoin::PayjoinTransport>(
+        &self,
+        original: PSBT,
+        endpoint: &str,
+        options: &payjoin::PayjoinOptions,
+        transport: &T,
+        sign_options: SignOptions,
+    ) -> Result<(PSBT, bool), Error> {
+        if !original.inputs.iter().all(payjoin::is_finalized) {
+            return Err(payjoin::PayjoinError::OriginalNotFinalized.into());
+        }
 
-            debug!(
-                "Input #{} - {}, using `create_height` = {:?}, `current_height` = {:?}",
-                n, input.previous_output, create_height, current_height
-            );
+        let url = payjoin::request_url(endpoint, options, self.payjoin_fee_output(&original)?);
+        let proposal = maybe_await!(transport.post(&url, &original))?;
+        let psbt = self.process_payjoin_proposal(&original, proposal, options)?;
 
-            // - Try to derive the descriptor by looking at the txout. If it's in our database, we
-            //   know exactly which `script_type` to use, and which derivation index it is
-            // - If that fails, try to derive it by looking at the psbt input: the complete logic
-            //   is in `src/descriptor/mod.rs`, but it will basically look at `hd_keypaths`,
-            //   `redeem_script` and `witness_script` to determine the right derivation
-            // - If that also fails, it will try it on the internal descriptor, if present
-            let desc = psbt
-                .get_utxo_for(n)
-                .map(|txout| self.get_descriptor_for_txout(&txout))
-                .transpose()?
-                .flatten()
-                .or_else(|| {
-                    self.descriptor.derive_from_psbt_input(
-                        psbt_input,
-                        psbt.get_utxo_for(n),
-                        &self.secp,
-                    )
-                })
-                .or_else(|| {
-                    self.change_descriptor.as_ref().and_then(|desc| {
-                        desc.derive_from_psbt_input(psbt_input, psbt.get_utxo_for(n), &self.secp)
-                    })
-                });
+        self.sign(psbt, sign_options)
+    }
 
-            match desc {
-                Some(desc) => {
-                    let mut tmp_input = bitcoin::TxIn::default();
-                    let deriv_ctx = descriptor_to_pk_ctx(&self.secp);
-                    match desc.satisfy(
-                        &mut tmp_input,
-                        (
-                            PsbtInputSatisfier::new(&psbt, n),
-                            After::new(current_height, false),
-                            Older::new(current_height, create_height, false),
-                        ),
-                        deriv_ctx,
-                    ) {
-                        Ok(_) => {
-                            let psbt_input = &mut psbt.inputs[n];
-                            psbt_input.final_script_sig = Some(tmp_input.script_sig);
-                            psbt_input.final_script_witness = Some(tmp_input.witness);
-                        }
-                        Err(e) => {
-                            debug!("satisfy error {:?} for input {}", e, n);
-                            finished = false
-                        }
+    /// Validate the payjoin proposal received in response to `original`
+    ///
+    /// This performs the checks required by BIP78 to make sure the receiver didn't change the
+    /// transaction in a way that harms us: the version, nLockTime and nSequence of the inputs are
+    /// preserved, none of our outputs has been removed, the inputs added by the receiver are
+    /// finalized, don't belong to us and spend the same type of script as ours, and our
+    /// contribution to the fees respects the limits in `options`.
+    ///
+    /// The returned PSBT contains the data from the `original` PSBT needed to sign our inputs
+    /// again, which can be done with [`Wallet::sign`].
+    pub fn process_payjoin_proposal(
+        &self,
+        original: &PSBT,
+        mut proposal: PSBT,
+        options: &payjoin::PayjoinOptions,
+    ) -> Result<PSBT, Error> {
+        use payjoin::PayjoinError;
+
+        payjoin::check_psbt_maps(original)?;
+        payjoin::check_psbt_maps(&proposal)?;
+
+        let original_tx = &original.global.unsigned_tx;
+        if proposal.global.unsigned_tx.version != original_tx.version {
+            return Err(PayjoinError::VersionChanged.into());
+        }
+        if proposal.global.unsigned_tx.lock_time != original_tx.lock_time {
+            return Err(PayjoinError::LockTimeChanged.into());
+        }
+
+        let mut original_inputs = HashMap::new();
+        let mut our_scripts = Vec::new();
+        let mut original_fee = 0i64;
+        let mut original_weight = original_tx.get_weight();
+        for (index, txin) in original_tx.input.iter().enumerate() {
+            let utxo = original
+                .get_utxo_for(index)
+                .ok_or(PayjoinError::MissingUtxo(index))?;
+            original_fee += utxo.value as i64;
+            original_weight += payjoin::finalized_input_weight(&original.inputs[index])
+                - coin_selection::TXIN_BASE_WEIGHT;
+            our_scripts.push(utxo.script_pubkey.clone());
+            original_inputs.insert(txin.previous_output, (index, utxo));
+        }
+        original_fee -= original_tx
+            .output
+            .iter()
+            .map(|o| o.value as i64)
+            .sum::<i64>();
+        let our_sequence = original_tx.input.first().map(|txin| txin.sequence);
+
+        let mut new_fee = 0i64;
+        let mut new_weight = proposal.global.unsigned_tx.get_weight();
+        let mut receiver_inputs_weight = 0;
+        for (index, txin) in proposal.global.unsigned_tx.input.iter().enumerate() {
+            let psbt_input = &mut proposal.inputs[index];
+
+            match original_inputs.get(&txin.previous_output) {
+                Some((original_index, utxo)) => {
+                    if txin.sequence != original_tx.input[*original_index].sequence {
+                        return Err(PayjoinError::SequenceChanged(index).into());
+                    }
+                    if payjoin::is_finalized(psbt_input) || !psbt_input.partial_sigs.is_empty() {
+                        return Err(PayjoinError::SenderInputNotCleared(index).into());
+                    }
+
+                    // restore the data needed to sign our input, dropping the old signatures
+                    *psbt_input = original.inputs[*original_index].clone();
+                    psbt_input.final_script_sig = None;
+                    psbt_input.final_script_witness = None;
+                    psbt_input.partial_sigs.clear();
+
+                    new_fee += utxo.value as i64;
+                    new_weight +=
+                        payjoin::finalized_input_weight(&original.inputs[*original_index])
+                            - coin_selection::TXIN_BASE_WEIGHT;
+                }
+                None => {
+                    if Some(txin.sequence) != our_sequence {
+                        return Err(PayjoinError::SequenceChanged(index).into());
+                    }
+                    if !payjoin::is_finalized(psbt_input) {
+                        return Err(PayjoinError::ReceiverInputNotFinalized(index).into());
                     }
+                    let utxo = proposal
+                        .get_utxo_for(index)
+                        .ok_or(PayjoinError::ReceiverInputMissingUtxo(index))?;
+                    if self.is_mine(&utxo.script_pubkey)? {
+                        return Err(PayjoinError::ReceiverInputIsOurs(index).into());
+                    }
+                    if !our_scripts.iter().all(|our_script| {
+                        payjoin::same_script_type(our_script, &utxo.script_pubkey)
+                    }) {
+                        return Err(PayjoinError::MixedInputScripts(index).into());
+                    }
+
+                    let input_weight = payjoin::finalized_input_weight(&proposal.inputs[index]);
+                    new_fee += utxo.value as i64;
+                    new_weight += input_weight - coin_selection::TXIN_BASE_WEIGHT;
+                    receiver_inputs_weight += input_weight;
                 }
-                None => finished = false,
             }
         }
+        if let Some(outpoint) = original_inputs.keys().find(|outpoint| {
+            !proposal
+                .global
+                .unsigned_tx
+                .input
+                .iter()
+                .any(|txin| txin.previous_output == **outpoint)
+        }) {
+            return Err(PayjoinError::MissingOriginalInput(*outpoint).into());
+        }
 
-        Ok((psbt, finished))
+        let fee_output = self.payjoin_fee_output(original)?;
+        let mut contribution = 0;
+        for (index, txout) in original_tx.output.iter().enumerate() {
+            let is_ours = self.is_mine(&txout.script_pubkey)?;
+            if !is_ours && !options.disable_output_substitution {
+                continue;
+            }
+
+            let new_index = proposal
+                .global
+                .unsigned_tx
+                .output
+                .iter()
+                .position(|new_txout| new_txout.script_pubkey == txout.script_pubkey)
+                .ok_or(PayjoinError::MissingOutput(index))?;
+            let new_value = proposal.global.unsigned_tx.output[new_index].value;
+            if new_value < txout.value {
+                if Some(index) != fee_output {
+                    return Err(PayjoinError::OutputValueDecreased(index).into());
+                }
+                contribution = txout.value - new_value;
+            }
+
+            // keep the data that identifies our outputs
+            proposal.outputs[new_index] = original.outputs[index].clone();
+        }
+        new_fee -= proposal
+            .global
+            .unsigned_tx
+            .output
+            .iter()
+            .map(|o| o.value as i64)
+            .sum::<i64>();
+
+        if new_fee < original_fee {
+            return Err(PayjoinError::FeeDecreased(new_fee.max(0) as u64).into());
+        }
+
+        // we only pay for the fees of the receiver's inputs, at most at our original fee rate
+        let original_fee_rate = original_fee as f32 / (original_weight as f32 / 4.0);
+        let max_contribution = std::cmp::min(
+            options.max_additional_fee_contribution as i64,
+            std::cmp::min(
+                new_fee - original_fee,
+                (original_fee_rate * receiver_inputs_weight as f32 / 4.0).ceil() as i64,
+            ),
+        );
+        if contribution as i64 > max_contribution {
+            return Err(PayjoinError::FeeContributionTooHigh(contribution).into());
+        }
+
+        let new_fee_rate = FeeRate::from_sat_per_vb(new_fee as f32 / (new_weight as f32 / 4.0));
+        if let Some(min_fee_rate) = options.min_fee_rate {
+            if new_fee_rate < min_fee_rate {
+                return Err(PayjoinError::FeeRateTooLow(new_fee_rate).into());
+            }
+        }
+
+        Ok(proposal)
+    }
+
+    /// Build a payjoin proposal in response to the `original` PSBT of a sender
+    ///
+    /// This is meant to be called by the receiver's HTTP server, with the body of the request
+    /// and the `params` parsed from its query string. The original PSBT must be finalized, so that
+    /// it can be broadcast if the sender doesn't complete the payjoin, it must pay to one of our
+    /// addresses, and none of its inputs can belong to us.
+    ///
+    /// One of our UTXOs, of the same type as the sender's inputs, is chosen with `coin_selection`
+    /// and added to the transaction at a random position, increasing the value of our output. The
+    /// fees for the new input are taken from the sender's output, within the limits set in
+    /// `params`, and the rest is paid by us. Only our input is signed with `sign_options`: the
+    /// returned PSBT can be sent back to the sender.
+    pub fn process_payjoin_request<Cs: coin_selection::CoinSelectionAlgorithm<D>>(
+        &self,
+        original: PSBT,
+        params: &payjoin::PayjoinRequestParams,
+        coin_selection: Cs,
+        sign_options: SignOptions,
+    ) -> Result<PSBT, Error> {
+        use payjoin::PayjoinError;
+
+        payjoin::check_psbt_maps(&original)?;
+
+        // the original transaction must be ready to be broadcast
+        let mut original_fee = 0i64;
+        let mut original_weight = original.global.unsigned_tx.get_weight();
+        let mut sender_script: Option<Script> = None;
+        for (index, psbt_input) in original.inputs.iter().enumerate() {
+            if !payjoin::is_finalized(psbt_input) {
+                return Err(PayjoinError::OriginalNotFinalized.into());
+            }
+            let utxo = original
+                .get_utxo_for(index)
+                .ok_or(PayjoinError::MissingUtxo(index))?;
+            if self.is_mine(&utxo.script_pubkey)? {
+                return Err(PayjoinError::OriginalInputIsOurs(index).into());
+            }
+            match &sender_script {
+                Some(script) if !payjoin::same_script_type(script, &utxo.script_pubkey) => {
+                    return Err(PayjoinError::MixedInputScripts(index).into());
+                }
+                Some(_) => {}
+                None => sender_script = Some(utxo.script_pubkey.clone()),
+            }
+
+            original_fee += utxo.value as i64;
+            original_weight +=
+                payjoin::finalized_input_weight(psbt_input) - coin_selection::TXIN_BASE_WEIGHT;
+        }
+        let sender_script = sender_script.ok_or(PayjoinError::OriginalWithoutInputs)?;
+        original_fee -= original
+            .global
+            .unsigned_tx
+            .output
+            .iter()
+            .map(|o| o.value as i64)
+            .sum::<i64>();
+
+        let mut our_output = None;
+        for (index, txout) in original.global.unsigned_tx.output.iter().enumerate() {
+            if self.is_mine(&txout.script_pubkey)? {
+                our_output = Some(index);
+                break;
+            }
+        }
+        let our_output = our_output.ok_or(PayjoinError::NoOutputToUs)?;
+        let sender_output = match params.additional_fee_output_index {
+            Some(index)
+                if index == our_output || index >= original.global.unsigned_tx.output.len() =>
+            {
+                return Err(
+                    PayjoinError::InvalidParameter("additionalfeeoutputindex".to_string()).into(),
+                )
+            }
+            other => other,
+        };
+
+        let original_fee_rate =
+            FeeRate::from_sat_per_vb(original_fee as f32 / (original_weight as f32 / 4.0));
+        let fee_rate = match params.min_fee_rate {
+            Some(min_fee_rate) if min_fee_rate > original_fee_rate => min_fee_rate,
+            _ => original_fee_rate,
+        };
+
+        let candidates = self
+            .get_available_utxos()?
+            .into_iter()
+            .filter(|(utxo, _)| {
+                payjoin::same_script_type(&sender_script, &utxo.txout.script_pubkey)
+            })
+            .collect::<Vec<_>>();
+        let utxo = coin_selection
+            .coin_select(
+                self.database.borrow().deref(),
+                vec![],
+                candidates.clone(),
+                fee_rate,
+                1,
+                0.0,
+            )?
+            .selected
+            .into_iter()
+            .next()
+            .ok_or(PayjoinError::NoUtxoToContribute)?;
+        let satisfaction_weight = candidates
+            .iter()
+            .find(|(candidate, _)| candidate.outpoint == utxo.outpoint)
+            .map(|(_, weight)| *weight)
+            .unwrap_or(0);
+
+        // total fee required to keep the fee rate with our input, which is split between the
+        // sender, up to the original fee rate, and us
+        let input_weight = coin_selection::TXIN_BASE_WEIGHT + satisfaction_weight;
+        let required_fee = (fee_rate.as_sat_vb() * (original_weight + input_weight) as f32 / 4.0)
+            .ceil() as i64
+            - original_fee;
+        let required_fee = std::cmp::max(required_fee, 0) as u64;
+        let sender_contribution = match sender_output {
+            Some(index) => {
+                // our estimate of the input weight uses the largest possible signature: leave a
+                // margin of one vbyte so that the sender doesn't reject our proposal
+                let max_for_input = (original_fee_rate.as_sat_vb()
+                    * (input_weight as f32 / 4.0 - 1.0))
+                    .floor() as u64;
+                params
+                    .max_additional_fee_contribution
+                    .min(max_for_input)
+                    .min(required_fee)
+                    .min(original.global.unsigned_tx.output[index].value)
+            }
+            None => 0,
+        };
+        let our_fee = required_fee - sender_contribution;
+        if utxo.txout.value <= our_fee {
+            return Err(PayjoinError::NoUtxoToContribute.into());
+        }
+
+        let mut proposal = original;
+        // remove the sender's signatures, which are invalidated by our changes, and the key
+        // origins to avoid leaking information about the wallets
+        for psbt_input in &mut proposal.inputs {
+            psbt_input.final_script_sig = None;
+            psbt_input.final_script_witness = None;
+            psbt_input.partial_sigs.clear();
+            psbt_input.hd_keypaths.clear();
+        }
+        for psbt_output in &mut proposal.outputs {
+            psbt_output.hd_keypaths.clear();
+        }
+        proposal
+            .global
+            .unknown
+            .retain(|key, _| key.type_value != 0x01);
+
+        let tx = &mut proposal.global.unsigned_tx;
+        tx.output[our_output].value += utxo.txout.value - our_fee;
+        if let Some(index) = sender_output {
+            tx.output[index].value -= sender_contribution;
+        }
+
+        let position = rand::thread_rng().gen_range(0, tx.input.len() + 1);
+        let sequence = tx.input[0].sequence;
+        tx.input.insert(
+            position,
+            bitcoin::TxIn {
+                previous_output: utxo.outpoint,
+                script_sig: Script::default(),
+                sequence,
+                witness: vec![],
+            },
+        );
+        let mut psbt_input = PSBTInput::default();
+        self.add_input_metadata(&mut psbt_input, &utxo, false)?;
+        proposal.inputs.insert(position, psbt_input);
+
+        let sign_options = SignOptions {
+            sign_unknown_inputs: false,
+            try_finalize: true,
+            ..sign_options
+        };
+        let (mut proposal, _) = self.sign(proposal, sign_options)?;
+
+        let our_input = &mut proposal.inputs[position];
+        if !payjoin::is_finalized(our_input) {
+            return Err(PayjoinError::ReceiverInputNotFinalized(position).into());
+        }
+        our_input.partial_sigs.clear();
+        our_input.hd_keypaths.clear();
+        our_input.redeem_script = None;
+        our_input.witness_script = None;
+
+        Ok(proposal)
+    }
+
+    /// Add our inputs and outputs to a PSBT assembled by someone else
+    ///
+    /// The `recipients` are appended to the transaction, and our UTXOs are chosen with
+    /// `coin_selection` to pay for them and for the fees of the inputs and outputs we add at
+    /// `fee_rate`. A change output is also added, unless it would be dust. The inputs and
+    /// outputs already in the PSBT are left untouched, but any signature they already carry is
+    /// invalidated since the transaction changes.
+    ///
+    /// The returned [`PsbtContribution`](coinjoin::PsbtContribution) reports the indexes of the
+    /// inputs that don't belong to the wallet, which won't be signed by [`Wallet::sign`].
+    ///
+    /// See the [`coinjoin`] module for an example.
+    pub fn contribute_to_psbt<Cs: coin_selection::CoinSelectionAlgorithm<D>>(
+        &self,
+        mut psbt: PSBT,
+        recipients: Vec<(Script, u64)>,
+        fee_rate: FeeRate,
+        coin_selection: Cs,
+    ) -> Result<coinjoin::PsbtContribution, Error> {
+        if recipients.is_empty() {
+            return Err(Error::NoRecipients);
+        }
+
+        let mut foreign_inputs = Vec::new();
+        for index in 0..psbt.inputs.len() {
+            if self.get_descriptor_for_psbt_input(&psbt, index)?.is_none() {
+                foreign_inputs.push(index);
+            }
+        }
+
+        let calc_fee_bytes = |wu| (wu as f32) * fee_rate.as_sat_vb() / 4.0;
+        let mut fee_amount = 0.0;
+        let mut outgoing: u64 = 0;
+        let mut outputs = Vec::with_capacity(recipients.len() + 1);
+        for (index, (script_pubkey, value)) in recipients.into_iter().enumerate() {
+            if value.is_dust() {
+                return Err(Error::OutputBelowDustLimit(index));
+            }
+
+            let new_out = TxOut {
+                script_pubkey,
+                value,
+            };
+            fee_amount += calc_fee_bytes(serialize(&new_out).len() * 4);
+            outgoing += value;
+            outputs.push(new_out);
+        }
+
+        // don't try to spend again the utxos that are already in the transaction
+        let already_spent = psbt
+            .global
+            .unsigned_tx
+            .input
+            .iter()
+            .map(|txin| txin.previous_output)
+            .collect::<HashSet<_>>();
+        let optional_utxos = self
+            .get_available_utxos()?
+            .into_iter()
+            .filter(|(utxo, _)| !already_spent.contains(&utxo.outpoint))
+            .collect();
+
+        let coin_selection::CoinSelectionResult {
+            selected,
+            selected_amount,
+            mut fee_amount,
+        } = coin_selection.coin_select(
+            self.database.borrow().deref(),
+            vec![],
+            optional_utxos,
+            fee_rate,
+            outgoing,
+            fee_amount,
+        )?;
+
+        // only reveal a new change script if the change output is actually added
+        let change_output = TxOut {
+            script_pubkey: self.peek_change_address()?,
+            value: 0,
+        };
+        fee_amount += calc_fee_bytes(serialize(&change_output).len() * 4);
+
+        let mut fee_amount = fee_amount.ceil() as u64;
+        let change_val = (selected_amount - outgoing).saturating_sub(fee_amount);
+        let tx = &mut psbt.global.unsigned_tx;
+        let mut change = None;
+        if change_val.is_dust() {
+            // skip the change output because it's dust, this adds up to the fees
+            fee_amount = selected_amount - outgoing;
+        } else {
+            change = Some(tx.output.len() + outputs.len());
+            outputs.push(TxOut {
+                script_pubkey: self.get_change_address()?,
+                value: change_val,
+            });
+        }
+
+        // use the same nSequence as the other participants
+        let sequence = tx
+            .input
+            .first()
+            .map(|txin| txin.sequence)
+            .unwrap_or(0xFFFFFFFF);
+        let inputs = (tx.input.len()..tx.input.len() + selected.len()).collect();
+        for utxo in &selected {
+            tx.input.push(bitcoin::TxIn {
+                previous_output: utxo.outpoint,
+                script_sig: Script::default(),
+                sequence,
+                witness: vec![],
+            });
+
+            let mut psbt_input = PSBTInput::default();
+            self.add_input_metadata(&mut psbt_input, utxo, false)?;
+            psbt.inputs.push(psbt_input);
+        }
+
+        let outputs_start = psbt.global.unsigned_tx.output.len();
+        for txout in outputs {
+            let mut psbt_output = PSBTOutput::default();
+            self.add_output_metadata(&mut psbt_output, &txout, false)?;
+            psbt.global.unsigned_tx.output.push(txout);
+            psbt.outputs.push(psbt_output);
+        }
+        let outputs = (outputs_start..psbt.global.unsigned_tx.output.len()).collect();
+
+        Ok(coinjoin::PsbtContribution {
+            psbt,
+            inputs,
+            outputs,
+            change,
+            foreign_inputs,
+            fee: fee_amount,
+        })
     }
 
     #[allow(missing_docs)] // TODO add missing docs and remove this allow
@@ -941,32 +1921,216 @@ where
 
     // Internals
 
+    fn finalize_psbt_inner(
+        &self,
+        mut psbt: PSBT,
+        assume_height: Option<u32>,
+        remove_partial_sigs: bool,
+    ) -> Result<(PSBT, bool), Error> {
+        let tx = &psbt.global.unsigned_tx;
+        let mut finished = true;
+
+        for (n, input) in tx.input.iter().enumerate() {
+            let psbt_input = &psbt.inputs[n];
+            if psbt_input.final_script_sig.is_some() || psbt_input.final_script_witness.is_some() {
+                continue;
+            }
+            // if the height is None in the database it means it's still unconfirmed, so consider
+            // that as a very high value
+            let create_height = self
+                .database
+                .borrow()
+                .get_tx(&input.previous_output.txid, false)?
+                .map(|tx| tx.height.unwrap_or(std::u32::MAX));
+            let current_height = assume_height.or(self.current_height.get());
+
+            debug!(
+                "Input #{} - {}, using `create_height` = {:?}, `current_height` = {:?}",
+                n, input.previous_output, create_height, current_height
+            );
+
+            match self.get_descriptor_for_psbt_input(&psbt, n)? {
+                Some(desc) => {
+                    let mut tmp_input = bitcoin::TxIn::default();
+                    let deriv_ctx = descriptor_to_pk_ctx(&self.secp);
+                    match desc.satisfy(
+                        &mut tmp_input,
+                        (
+                            PsbtInputSatisfier::new(&psbt, n),
+                            After::new(current_height, false),
+                            Older::new(current_height, create_height, false),
+                        ),
+                        deriv_ctx,
+                    ) {
+                        Ok(_) => {
+                            let psbt_input = &mut psbt.inputs[n];
+                            psbt_input.final_script_sig = Some(tmp_input.script_sig);
+                            psbt_input.final_script_witness = Some(tmp_input.witness);
+                            if remove_partial_sigs {
+                                psbt_input.partial_sigs.clear();
+                            }
+                        }
+                        Err(e) => {
+                            debug!("satisfy error {:?} for input {}", e, n);
+                            finished = false
+                        }
+                    }
+                }
+                None => finished = false,
+            }
+        }
+
+        Ok((psbt, finished))
+    }
+
     fn get_descriptor_for_script_type(
         &self,
         script_type: ScriptType,
-    ) -> (&ExtendedDescriptor, ScriptType) {
+    ) -> Result<(&ExtendedDescriptor, ScriptType), Error> {
         match script_type {
-            ScriptType::Internal if self.change_descriptor.is_some() => (
+            ScriptType::Internal if self.change_descriptor.is_some() => Ok((
                 self.change_descriptor.as_ref().unwrap(),
                 ScriptType::Internal,
-            ),
-            _ => (&self.descriptor, ScriptType::External),
+            )),
+            ScriptType::External | ScriptType::Internal => {
+                Ok((&self.descriptor, ScriptType::External))
+            }
+            ScriptType::Keychain(index) => match self.keychains.get(index as usize) {
+                Some((descriptor, _)) => Ok((descriptor, script_type)),
+                None => Err(Error::UnknownKeychain(script_type)),
+            },
+        }
+    }
+
+    // The output the receiver of a payjoin can use to take our contribution to the fees: the first
+    // output of the transaction that belongs to us, which is normally the change
+    fn payjoin_fee_output(&self, psbt: &PSBT) -> Result<Option<usize>, Error> {
+        for (index, txout) in psbt.global.unsigned_tx.output.iter().enumerate() {
+            if self.is_mine(&txout.script_pubkey)? {
+                return Ok(Some(index));
+            }
+        }
+
+        Ok(None)
+    }
+
+    fn get_descriptor_for_psbt_input(
+        &self,
+        psbt: &PSBT,
+        n: usize,
+    ) -> Result<Option<ExtendedDescriptor>, Error> {
+        let psbt_input = &psbt.inputs[n];
+
+        // - Try to derive the descriptor by looking at the txout. If it's in our database, we
+        //   know exactly which `script_type` to use, and which derivation index it is
+        // - If that fails, try to derive it by looking at the psbt input: the complete logic
+        //   is in `src/descriptor/mod.rs`, but it will basically look at `hd_keypaths`,
+        //   `redeem_script` and `witness_script` to determine the right derivation
+        // - If that also fails, it will try it on the internal descriptor, if present, and
+        //   then on the additional keychains
+        Ok(psbt
+            .get_utxo_for(n)
+            .map(|txout| self.get_descriptor_for_txout(&txout))
+            .transpose()?
+            .flatten()
+            .or_else(|| {
+                self.descriptor
+                    .derive_from_psbt_input(psbt_input, psbt.get_utxo_for(n), &self.secp)
+            })
+            .or_else(|| {
+                self.change_descriptor.as_ref().and_then(|desc| {
+                    desc.derive_from_psbt_input(psbt_input, psbt.get_utxo_for(n), &self.secp)
+                })
+            })
+            .or_else(|| {
+                self.keychains.iter().find_map(|(desc, _)| {
+                    desc.derive_from_psbt_input(psbt_input, psbt.get_utxo_for(n), &self.secp)
+                })
+            }))
+    }
+
+    fn check_sign_policy(&self, psbt: &PSBT) -> Result<(), Error> {
+        let policy = &self.sign_policy;
+
+        if policy.only_sighash_all {
+            for (index, input) in psbt.inputs.iter().enumerate() {
+                match input.sighash_type {
+                    None | Some(SigHashType::All) => {}
+                    Some(_) => return Err(SignerError::SighashNotAllowed(index).into()),
+                }
+            }
+        }
+
+        if policy.verify_change {
+            self.verify_change(psbt)?;
+        }
+
+        if policy.max_fee.is_some() || policy.max_fee_percent.is_some() {
+            let mut inputs_sum = 0;
+            for index in 0..psbt.inputs.len() {
+                inputs_sum += psbt
+                    .get_utxo_for(index)
+                    .ok_or(SignerError::UnknownInputValue(index))?
+                    .value;
+            }
+            let outputs_sum = psbt
+                .global
+                .unsigned_tx
+                .output
+                .iter()
+                .fold(0, |sum, txout| sum + txout.value);
+            let fee = inputs_sum.saturating_sub(outputs_sum);
+
+            if policy.max_fee.map(|max| fee > max).unwrap_or(false)
+                || policy
+                    .max_fee_percent
+                    .map(|max| fee as f32 > inputs_sum as f32 * max / 100.0)
+                    .unwrap_or(false)
+            {
+                return Err(SignerError::FeeTooHigh(fee).into());
+            }
+        }
+
+        Ok(())
+    }
+
+    fn get_descriptors(&self) -> Vec<(ScriptType, &ExtendedDescriptor)> {
+        let mut descriptors = vec![(ScriptType::External, &self.descriptor)];
+        if let Some(change_descriptor) = &self.change_descriptor {
+            descriptors.push((ScriptType::Internal, change_descriptor));
+        }
+        for (index, (descriptor, _)) in self.keychains.iter().enumerate() {
+            descriptors.push((ScriptType::Keychain(index as u8), descriptor));
+        }
+
+        descriptors
+    }
+
+    fn has_keychain(&self, script_type: ScriptType) -> bool {
+        match script_type {
+            ScriptType::Keychain(index) => (index as usize) < self.keychains.len(),
+            _ => true,
         }
     }
 
     fn get_descriptor_for_txout(&self, txout: &TxOut) -> Result<Option<ExtendedDescriptor>, Error> {
-        Ok(self
+        let (script_type, child) = match self
             .database
             .borrow()
             .get_path_from_script_pubkey(&txout.script_pubkey)?
-            .map(|(script_type, child)| (self.get_descriptor_for_script_type(script_type).0, child))
-            .map(|(desc, child)| desc.derive(ChildNumber::from_normal_idx(child).unwrap())))
+        {
+            Some(path) => path,
+            None => return Ok(None),
+        };
+        let (desc, _) = self.get_descriptor_for_script_type(script_type)?;
+
+        Ok(Some(desc.derive(ChildNumber::from_normal_idx(child)?)))
     }
 
     fn get_change_address(&self) -> Result<Script, Error> {
         let deriv_ctx = descriptor_to_pk_ctx(&self.secp);
 
-        let (desc, script_type) = self.get_descriptor_for_script_type(ScriptType::Internal);
+        let (desc, script_type) = self.get_descriptor_for_script_type(ScriptType::Internal)?;
         let index = self.fetch_and_increment_index(script_type)?;
 
         Ok(desc
@@ -974,25 +2138,52 @@ where
             .script_pubkey(deriv_ctx))
     }
 
-    fn fetch_and_increment_index(&self, script_type: ScriptType) -> Result<u32, Error> {
-        let (descriptor, script_type) = self.get_descriptor_for_script_type(script_type);
-        let index = match descriptor.is_fixed() {
+    // Return the script that the next call to `get_change_address` will return, without
+    // incrementing the index
+    fn peek_change_address(&self) -> Result<Script, Error> {
+        let deriv_ctx = descriptor_to_pk_ctx(&self.secp);
+
+        let (desc, script_type) = self.get_descriptor_for_script_type(ScriptType::Internal)?;
+        let index = match desc.is_fixed() {
             true => 0,
             false => self
                 .database
-                .borrow_mut()
-                .increment_last_index(script_type)?,
+                .borrow()
+                .get_last_index(script_type)?
+                .map_or(0, |last| last + 1),
         };
 
-        if self
-            .database
-            .borrow()
+        Ok(desc
+            .derive(ChildNumber::from_normal_idx(index)?)
+            .script_pubkey(deriv_ctx))
+    }
+
+    fn fetch_and_increment_index(&self, script_type: ScriptType) -> Result<u32, Error> {
+        let (descriptor, script_type) = self.get_descriptor_for_script_type(script_type)?;
+        let index = match descriptor.is_fixed() {
+            true => 0,
+            false => self
+                .database
+                .borrow_mut()
+                .increment_last_index(script_type)?,
+        };
+
+        if self
+            .database
+            .borrow()
             .get_script_pubkey_from_path(script_type, index)?
             .is_none()
         {
             self.cache_addresses(script_type, index, CACHE_ADDR_BATCH_SIZE)?;
         }
 
+        self.validate_address(script_type, index)?;
+
+        Ok(index)
+    }
+
+    fn validate_address(&self, script_type: ScriptType, index: u32) -> Result<(), Error> {
+        let (descriptor, script_type) = self.get_descriptor_for_script_type(script_type)?;
         let deriv_ctx = descriptor_to_pk_ctx(&self.secp);
 
         let hd_keypaths = descriptor.get_hd_keypaths(index, &self.secp)?;
@@ -1003,7 +2194,34 @@ where
             validator.validate(script_type, &hd_keypaths, &script)?;
         }
 
-        Ok(index)
+        Ok(())
+    }
+
+    // Return the indexes that have either received a transaction or have been marked as used
+    fn get_used_indexes(&self, script_type: ScriptType) -> Result<HashSet<u32>, Error> {
+        let database = self.database.borrow();
+
+        let mut used = HashSet::new();
+        for tx in database.iter_raw_txs()? {
+            for output in tx.output {
+                match database.get_path_from_script_pubkey(&output.script_pubkey)? {
+                    Some((st, index)) if st == script_type => {
+                        used.insert(index);
+                    }
+                    _ => {}
+                }
+            }
+        }
+
+        if let Some(last_index) = database.get_last_index(script_type)? {
+            for index in 0..=last_index {
+                if database.is_marked_used(script_type, index)? {
+                    used.insert(index);
+                }
+            }
+        }
+
+        Ok(used)
     }
 
     fn cache_addresses(
@@ -1012,7 +2230,7 @@ where
         from: u32,
         mut count: u32,
     ) -> Result<(), Error> {
-        let (descriptor, script_type) = self.get_descriptor_for_script_type(script_type);
+        let (descriptor, script_type) = self.get_descriptor_for_script_type(script_type)?;
         if descriptor.is_fixed() {
             if from > 0 {
                 return Ok(());
@@ -1050,20 +2268,22 @@ where
 
     fn get_available_utxos(&self) -> Result<Vec<(UTXO, usize)>, Error> {
         let deriv_ctx = descriptor_to_pk_ctx(&self.secp);
-        Ok(self
-            .list_unspent()?
+        self.list_unspent()?
             .into_iter()
+            // skip the outputs of keychains that haven't been added to the wallet, we wouldn't
+            // know how to spend them
+            .filter(|utxo| self.has_keychain(utxo.script_type))
             .map(|utxo| {
                 let script_type = utxo.script_type;
-                (
+                Ok((
                     utxo,
-                    self.get_descriptor_for_script_type(script_type)
+                    self.get_descriptor_for_script_type(script_type)?
                         .0
                         .max_satisfaction_weight(deriv_ctx)
                         .unwrap(),
-                )
+                ))
             })
-            .collect())
+            .collect()
     }
 
     /// Given the options returns the list of utxos that must be used to form the
@@ -1138,6 +2358,46 @@ where
         Ok((must_spend, may_spend))
     }
 
+    fn add_input_metadata(
+        &self,
+        psbt_input: &mut PSBTInput,
+        utxo: &UTXO,
+        force_non_witness_utxo: bool,
+    ) -> Result<(), Error> {
+        // Try to find the prev_script in our db to figure out if this is internal or external,
+        // and the derivation index
+        let (script_type, child) = match self
+            .database
+            .borrow()
+            .get_path_from_script_pubkey(&utxo.txout.script_pubkey)?
+        {
+            Some(x) => x,
+            None => return Ok(()),
+        };
+
+        let (desc, _) = self.get_descriptor_for_script_type(script_type)?;
+        psbt_input.hd_keypaths = desc.get_hd_keypaths(child, &self.secp)?;
+        let derived_descriptor = desc.derive(ChildNumber::from_normal_idx(child)?);
+
+        psbt_input.redeem_script = derived_descriptor.psbt_redeem_script(&self.secp);
+        psbt_input.witness_script = derived_descriptor.psbt_witness_script(&self.secp);
+
+        let prev_output = utxo.outpoint;
+        if let Some(prev_tx) = self.database.borrow().get_raw_tx(&prev_output.txid)? {
+            if derived_descriptor.is_witness() {
+                psbt_input.witness_utxo = Some(prev_tx.output[prev_output.vout as usize].clone());
+            }
+            if !derived_descriptor.is_witness()
+                || force_non_witness_utxo
+                || self.psbt_for_offline_signer
+            {
+                psbt_input.non_witness_utxo = Some(prev_tx);
+            }
+        }
+
+        Ok(())
+    }
+
     fn complete_transaction<
         Cs: coin_selection::CoinSelectionAlgorithm<D>,
         Ctx: TxBuilderContext,
@@ -1151,11 +2411,14 @@ where
 
         let mut psbt = PSBT::from_unsigned_tx(tx)?;
 
-        if builder.add_global_xpubs {
+        if builder.add_global_xpubs || self.psbt_for_offline_signer {
             let mut all_xpubs = self.descriptor.get_extended_keys()?;
             if let Some(change_descriptor) = &self.change_descriptor {
                 all_xpubs.extend(change_descriptor.get_extended_keys()?);
             }
+            for (descriptor, _) in &self.keychains {
+                all_xpubs.extend(descriptor.get_extended_keys()?);
+            }
 
             for xpub in all_xpubs {
                 let serialized_xpub = base58::from_check(&xpub.xkey.to_string())
@@ -1188,6 +2451,18 @@ where
             .iter_mut()
             .zip(psbt.global.unsigned_tx.input.iter())
         {
+            if let Some((_, foreign_input, _)) = builder
+                .foreign_utxos
+                .iter()
+                .find(|(outpoint, _, _)| *outpoint == input.previous_output)
+            {
+                *psbt_input = foreign_input.clone();
+                if let Some(sighash_type) = builder.sighash {
+                    psbt_input.sighash_type = Some(sighash_type);
+                }
+                continue;
+            }
+
             let utxo = match lookup_output.get(&input.previous_output) {
                 Some(utxo) => utxo,
                 None => continue,
@@ -1199,34 +2474,7 @@ where
                 psbt_input.sighash_type = Some(sighash_type);
             }
 
-            // Try to find the prev_script in our db to figure out if this is internal or external,
-            // and the derivation index
-            let (script_type, child) = match self
-                .database
-                .borrow()
-                .get_path_from_script_pubkey(&utxo.txout.script_pubkey)?
-            {
-                Some(x) => x,
-                None => continue,
-            };
-
-            let (desc, _) = self.get_descriptor_for_script_type(script_type);
-            psbt_input.hd_keypaths = desc.get_hd_keypaths(child, &self.secp)?;
-            let derived_descriptor = desc.derive(ChildNumber::from_normal_idx(child)?);
-
-            psbt_input.redeem_script = derived_descriptor.psbt_redeem_script(&self.secp);
-            psbt_input.witness_script = derived_descriptor.psbt_witness_script(&self.secp);
-
-            let prev_output = input.previous_output;
-            if let Some(prev_tx) = self.database.borrow().get_raw_tx(&prev_output.txid)? {
-                if derived_descriptor.is_witness() {
-                    psbt_input.witness_utxo =
-                        Some(prev_tx.output[prev_output.vout as usize].clone());
-                }
-                if !derived_descriptor.is_witness() || builder.force_non_witness_utxo {
-                    psbt_input.non_witness_utxo = Some(prev_tx);
-                }
-            }
+            self.add_input_metadata(psbt_input, utxo, builder.force_non_witness_utxo)?;
         }
 
         // probably redundant but it doesn't hurt...
@@ -1238,24 +2486,39 @@ where
             .iter_mut()
             .zip(psbt.global.unsigned_tx.output.iter())
         {
-            if let Some((script_type, child)) = self
-                .database
-                .borrow()
-                .get_path_from_script_pubkey(&tx_output.script_pubkey)?
-            {
-                let (desc, _) = self.get_descriptor_for_script_type(script_type);
-                psbt_output.hd_keypaths = desc.get_hd_keypaths(child, &self.secp)?;
-                if builder.include_output_redeem_witness_script {
-                    let derived_descriptor = desc.derive(ChildNumber::from_normal_idx(child)?);
-                    psbt_output.witness_script = derived_descriptor.psbt_witness_script(&self.secp);
-                    psbt_output.redeem_script = derived_descriptor.psbt_redeem_script(&self.secp);
-                };
-            }
+            self.add_output_metadata(
+                psbt_output,
+                tx_output,
+                builder.include_output_redeem_witness_script,
+            )?;
         }
 
         Ok(psbt)
     }
 
+    fn add_output_metadata(
+        &self,
+        psbt_output: &mut PSBTOutput,
+        tx_output: &TxOut,
+        include_output_redeem_witness_script: bool,
+    ) -> Result<(), Error> {
+        if let Some((script_type, child)) = self
+            .database
+            .borrow()
+            .get_path_from_script_pubkey(&tx_output.script_pubkey)?
+        {
+            let (desc, _) = self.get_descriptor_for_script_type(script_type)?;
+            psbt_output.hd_keypaths = desc.get_hd_keypaths(child, &self.secp)?;
+            if include_output_redeem_witness_script || self.psbt_for_offline_signer {
+                let derived_descriptor = desc.derive(ChildNumber::from_normal_idx(child)?);
+                psbt_output.witness_script = derived_descriptor.psbt_witness_script(&self.secp);
+                psbt_output.redeem_script = derived_descriptor.psbt_redeem_script(&self.secp);
+            };
+        }
+
+        Ok(())
+    }
+
     fn add_input_hd_keypaths(&self, psbt: &mut PSBT) -> Result<(), Error> {
         let mut input_utxos = Vec::with_capacity(psbt.inputs.len());
         for n in 0..psbt.inputs.len() {
@@ -1273,7 +2536,7 @@ where
                     debug!("Found descriptor {:?}/{}", script_type, child);
 
                     // merge hd_keypaths
-                    let (desc, _) = self.get_descriptor_for_script_type(script_type);
+                    let (desc, _) = self.get_descriptor_for_script_type(script_type)?;
                     let mut hd_keypaths = desc.get_hd_keypaths(child, &self.secp)?;
                     psbt_input.hd_keypaths.append(&mut hd_keypaths);
                 }
@@ -1300,7 +2563,37 @@ where
     ) -> Result<Self, Error> {
         let mut wallet = Self::new_offline(descriptor, change_descriptor, network, database)?;
 
-        wallet.current_height = Some(maybe_await!(client.get_height())? as u32);
+        wallet
+            .current_height
+            .set(Some(maybe_await!(client.get_height())?));
+        wallet.client = Some(client);
+
+        Ok(wallet)
+    }
+
+    /// Create a new "online" wallet with a birthday
+    ///
+    /// See [`Wallet::new_offline_with_birthday`] for the `birthday`.
+    #[maybe_async]
+    pub fn new_with_birthday<E: ToWalletDescriptor>(
+        descriptor: E,
+        change_descriptor: Option<E>,
+        network: Network,
+        database: D,
+        client: B,
+        birthday: WalletBirthday,
+    ) -> Result<Self, Error> {
+        let mut wallet = Self::new_offline_with_birthday(
+            descriptor,
+            change_descriptor,
+            network,
+            database,
+            birthday,
+        )?;
+
+        wallet
+            .current_height
+            .set(Some(maybe_await!(client.get_height())?));
         wallet.client = Some(client);
 
         Ok(wallet)
@@ -1317,22 +2610,8 @@ where
 
         let mut run_setup = false;
 
-        let max_address = match self.descriptor.is_fixed() {
-            true => 0,
-            false => max_address_param.unwrap_or(CACHE_ADDR_BATCH_SIZE),
-        };
-        if self
-            .database
-            .borrow()
-            .get_script_pubkey_from_path(ScriptType::External, max_address.saturating_sub(1))?
-            .is_none()
-        {
-            run_setup = true;
-            self.cache_addresses(ScriptType::External, 0, max_address)?;
-        }
-
-        if let Some(change_descriptor) = &self.change_descriptor {
-            let max_address = match change_descriptor.is_fixed() {
+        for (script_type, descriptor) in self.get_descriptors() {
+            let max_address = match descriptor.is_fixed() {
                 true => 0,
                 false => max_address_param.unwrap_or(CACHE_ADDR_BATCH_SIZE),
             };
@@ -1340,14 +2619,19 @@ where
             if self
                 .database
                 .borrow()
-                .get_script_pubkey_from_path(ScriptType::Internal, max_address.saturating_sub(1))?
+                .get_script_pubkey_from_path(script_type, max_address.saturating_sub(1))?
                 .is_none()
             {
                 run_setup = true;
-                self.cache_addresses(ScriptType::Internal, 0, max_address)?;
+                self.cache_addresses(script_type, 0, max_address)?;
             }
         }
 
+        let snapshot = match self.listeners.is_empty() {
+            true => None,
+            false => Some(Snapshot::new(self.database.borrow().deref())?),
+        };
+
         // TODO: what if i generate an address first and cache some addresses?
         // TODO: we should sync if generating an address triggers a new batch to be stored
         if run_setup {
@@ -1355,14 +2639,29 @@ where
                 None,
                 self.database.borrow_mut().deref_mut(),
                 progress_update,
-            ))
+            ))?;
         } else {
             maybe_await!(self.client.as_ref().ok_or(Error::OfflineClient)?.sync(
                 None,
                 self.database.borrow_mut().deref_mut(),
                 progress_update,
-            ))
+            ))?;
+        }
+
+        self.current_height.set(Some(maybe_await!(self
+            .client
+            .as_ref()
+            .ok_or(Error::OfflineClient)?
+            .get_height())?));
+
+        if let Some(snapshot) = snapshot {
+            let diff = snapshot.diff(&Snapshot::new(self.database.borrow().deref())?);
+            for listener in &self.listeners {
+                listener.on_sync(&diff);
+            }
         }
+
+        Ok(())
     }
 
     /// Return a reference to the internal blockchain client
@@ -1370,11 +2669,6 @@ where
         self.client.as_ref()
     }
 
-    /// Get the Bitcoin network the wallet is using.
-    pub fn network(&self) -> Network {
-        self.network
-    }
-
     /// Broadcast a transaction to the network
     #[maybe_async]
     pub fn broadcast(&self, tx: Transaction) -> Result<Txid, Error> {
@@ -1386,6 +2680,105 @@ where
 
         Ok(tx.txid())
     }
+
+    /// Sweep the funds controlled by a private key into the wallet
+    ///
+    /// The `pkh`, `wpkh` and `sh(wpkh)` scripts of `key` are scanned with the blockchain and a
+    /// transaction moving all of their funds to a new internal address of the wallet is created
+    /// and signed at the given `fee_rate`. The segwit scripts are only scanned if the key is
+    /// compressed.
+    ///
+    /// This is mostly useful to redeem paper wallets: the transaction returned is not broadcast,
+    /// [`Wallet::broadcast`] can be used to do that after inspecting it. The
+    /// [`TransactionDetails`] returned are from the point of view of the swept key, so `sent` is
+    /// the total amount swept, including the `fees`.
+    ///
+    /// The internal address is only revealed if there are funds to sweep.
+    #[maybe_async]
+    pub fn sweep_private_key(
+        &self,
+        key: PrivateKey,
+        fee_rate: FeeRate,
+    ) -> Result<(PSBT, TransactionDetails), Error> {
+        let descriptor = P2PKH(key).to_wallet_descriptor(self.network)?;
+        let mut keychains = vec![];
+        if key.compressed {
+            keychains.push(P2WPKH(key).to_wallet_descriptor(self.network)?);
+            keychains.push(P2WPKH_P2SH(key).to_wallet_descriptor(self.network)?);
+        }
+
+        maybe_await!(self.sweep_descriptors(descriptor, keychains, fee_rate))
+    }
+
+    /// Sweep the funds controlled by a descriptor into the wallet
+    ///
+    /// Like [`Wallet::sweep_private_key`], but for any descriptor that contains the private keys
+    /// required to spend its outputs.
+    #[maybe_async]
+    pub fn sweep<E: ToWalletDescriptor>(
+        &self,
+        descriptor: E,
+        fee_rate: FeeRate,
+    ) -> Result<(PSBT, TransactionDetails), Error> {
+        let descriptor = descriptor.to_wallet_descriptor(self.network)?;
+        maybe_await!(self.sweep_descriptors(descriptor, vec![], fee_rate))
+    }
+
+    #[maybe_async]
+    fn sweep_descriptors(
+        &self,
+        descriptor: (ExtendedDescriptor, KeyMap),
+        keychains: Vec<(ExtendedDescriptor, KeyMap)>,
+        fee_rate: FeeRate,
+    ) -> Result<(PSBT, TransactionDetails), Error> {
+        let client = self.client.as_ref().ok_or(Error::OfflineClient)?;
+
+        // use a temporary wallet with a keychain for every other descriptor, to sync and spend
+        // them all at once
+        let mut sweep_wallet: OfflineWallet<MemoryDatabase> =
+            Wallet::new_offline(descriptor, None, self.network, MemoryDatabase::new())?;
+        for keychain in keychains {
+            sweep_wallet.add_keychain(keychain)?;
+        }
+
+        let script_types = sweep_wallet
+            .get_descriptors()
+            .into_iter()
+            .map(|(script_type, descriptor)| (script_type, descriptor.is_fixed()))
+            .collect::<Vec<_>>();
+        for (script_type, is_fixed) in script_types {
+            let max_address = match is_fixed {
+                true => 0,
+                false => CACHE_ADDR_BATCH_SIZE,
+            };
+            sweep_wallet.cache_addresses(script_type, 0, max_address)?;
+        }
+
+        maybe_await!(client.setup(
+            None,
+            sweep_wallet.database.borrow_mut().deref_mut(),
+            noop_progress(),
+        ))?;
+        sweep_wallet.current_height.set(self.current_height.get());
+
+        // build the transaction with the next change script, and only reveal it once we know
+        // there's something to sweep
+        let builder = TxBuilder::new()
+            .set_single_recipient(self.peek_change_address()?)
+            .drain_wallet()
+            .fee_rate(fee_rate);
+        let (mut psbt, details) = sweep_wallet.create_tx(builder)?;
+        psbt.global.unsigned_tx.output[0].script_pubkey = self.get_change_address()?;
+
+        let (psbt, finalized) = sweep_wallet.sign(psbt, SignOptions::default())?;
+        if !finalized {
+            return Err(Error::Generic(
+                "Unable to finalize the sweep transaction".into(),
+            ));
+        }
+
+        Ok((psbt, details))
+    }
 }
 
 #[cfg(test)]
@@ -1490,53 +2883,340 @@ mod test {
             .is_some());
     }
 
-    pub(crate) fn get_test_wpkh() -> &'static str {
-        "wpkh(cVpPVruEDdmutPzisEsYvtST1usBR3ntr8pXSyt6D2YYqXRyPcFW)"
-    }
+    #[test]
+    fn test_peek_address() {
+        let db = MemoryDatabase::new();
+        let wallet: OfflineWallet<_> = Wallet::new_offline("wpkh(tpubEBr4i6yk5nf5DAaJpsi9N2pPYBeJ7fZ5Z9rmN4977iYLCGco1VyjB9tvvuvYtfZzjD5A8igzgw3HeWeeKFmanHYqksqZXYXGsw5zjnj7KM9/*)", None, Network::Testnet, db).unwrap();
 
-    pub(crate) fn get_test_single_sig_csv() -> &'static str {
-        // and(pk(Alice),older(6))
-        "wsh(and_v(v:pk(cVpPVruEDdmutPzisEsYvtST1usBR3ntr8pXSyt6D2YYqXRyPcFW),older(6)))"
+        assert_eq!(
+            wallet.peek_address(1).unwrap().to_string(),
+            "tb1q4er7kxx6sssz3q7qp7zsqsdx4erceahhax77d7"
+        );
+        assert_eq!(
+            wallet.get_new_address().unwrap().to_string(),
+            "tb1q6yn66vajcctph75pvylgkksgpp6nq04ppwct9a"
+        );
     }
 
-    pub(crate) fn get_test_a_or_b_plus_csv() -> &'static str {
-        // or(pk(Alice),and(pk(Bob),older(144)))
-        "wsh(or_d(pk(cRjo6jqfVNP33HhSS76UhXETZsGTZYx8FMFvR9kpbtCSV1PmdZdu),and_v(v:pk(cMnkdebixpXMPfkcNEjjGin7s94hiehAH4mLbYkZoh9KSiNNmqC8),older(144))))"
+    #[test]
+    fn test_reveal_up_to() {
+        let db = MemoryDatabase::new();
+        let wallet: OfflineWallet<_> = Wallet::new_offline("wpkh(tpubEBr4i6yk5nf5DAaJpsi9N2pPYBeJ7fZ5Z9rmN4977iYLCGco1VyjB9tvvuvYtfZzjD5A8igzgw3HeWeeKFmanHYqksqZXYXGsw5zjnj7KM9/*)", None, Network::Testnet, db).unwrap();
+
+        let revealed = wallet.reveal_up_to(CACHE_ADDR_BATCH_SIZE + 1).unwrap();
+        assert_eq!(revealed.len() as u32, CACHE_ADDR_BATCH_SIZE + 2);
+        assert_eq!(
+            revealed[1].to_string(),
+            "tb1q4er7kxx6sssz3q7qp7zsqsdx4erceahhax77d7"
+        );
+        assert!(wallet
+            .database
+            .borrow_mut()
+            .get_script_pubkey_from_path(ScriptType::External, CACHE_ADDR_BATCH_SIZE + 1)
+            .unwrap()
+            .is_some());
+
+        assert!(wallet.reveal_up_to(5).unwrap().is_empty());
+        assert_eq!(
+            wallet.get_new_address().unwrap(),
+            wallet.peek_address(CACHE_ADDR_BATCH_SIZE + 2).unwrap()
+        );
     }
 
-    pub(crate) fn get_test_single_sig_cltv() -> &'static str {
-        // and(pk(Alice),after(100000))
-        "wsh(and_v(v:pk(cVpPVruEDdmutPzisEsYvtST1usBR3ntr8pXSyt6D2YYqXRyPcFW),after(100000)))"
+    #[test]
+    fn test_reveal_up_to_too_high() {
+        let db = MemoryDatabase::new();
+        let wallet: OfflineWallet<_> = Wallet::new_offline("wpkh(tpubEBr4i6yk5nf5DAaJpsi9N2pPYBeJ7fZ5Z9rmN4977iYLCGco1VyjB9tvvuvYtfZzjD5A8igzgw3HeWeeKFmanHYqksqZXYXGsw5zjnj7KM9/*)", None, Network::Testnet, db).unwrap();
+
+        assert!(matches!(
+            wallet.reveal_up_to(std::u32::MAX),
+            Err(Error::AddressIndexTooHigh { max }) if max == MAX_REVEAL_BATCH_SIZE - 1
+        ));
+        assert!(wallet
+            .database
+            .borrow()
+            .get_last_index(ScriptType::External)
+            .unwrap()
+            .is_none());
+
+        wallet.reveal_up_to(MAX_REVEAL_BATCH_SIZE - 1).unwrap();
+        assert!(matches!(
+            wallet.reveal_up_to(2 * MAX_REVEAL_BATCH_SIZE),
+            Err(Error::AddressIndexTooHigh { max }) if max == 2 * MAX_REVEAL_BATCH_SIZE - 1
+        ));
     }
 
-    pub(crate) fn get_funded_wallet(
-        descriptor: &str,
-    ) -> (
-        OfflineWallet<MemoryDatabase>,
-        (String, Option<String>),
-        bitcoin::Txid,
-    ) {
-        let descriptors = testutils!(@descriptors (descriptor));
-        let wallet: OfflineWallet<_> = Wallet::new_offline(
-            &descriptors.0,
-            None,
-            Network::Regtest,
-            MemoryDatabase::new(),
-        )
-        .unwrap();
+    #[test]
+    fn test_get_unused_address() {
+        let db = MemoryDatabase::new();
+        let wallet: OfflineWallet<_> = Wallet::new_offline("wpkh(tpubEBr4i6yk5nf5DAaJpsi9N2pPYBeJ7fZ5Z9rmN4977iYLCGco1VyjB9tvvuvYtfZzjD5A8igzgw3HeWeeKFmanHYqksqZXYXGsw5zjnj7KM9/*)", None, Network::Testnet, db).unwrap();
 
-        let txid = wallet.database.borrow_mut().received_tx(
-            testutils! {
-                @tx ( (@external descriptors, 0) => 50_000 ) (@confirmations 1)
-            },
-            Some(100),
+        // nothing revealed yet, reveal index 0
+        assert_eq!(
+            wallet.get_unused_address().unwrap(),
+            wallet.peek_address(0).unwrap()
+        );
+        // still unused, the same address is returned
+        assert_eq!(
+            wallet.get_unused_address().unwrap(),
+            wallet.peek_address(0).unwrap()
         );
 
-        (wallet, descriptors, txid)
+        wallet.mark_used(0).unwrap();
+        assert_eq!(
+            wallet.get_unused_address().unwrap(),
+            wallet.peek_address(1).unwrap()
+        );
+
+        assert!(wallet.unmark_used(0).unwrap());
+        assert_eq!(
+            wallet.get_unused_address().unwrap(),
+            wallet.peek_address(0).unwrap()
+        );
     }
 
-    macro_rules! assert_fee_rate {
-        ($tx:expr, $fees:expr, $fee_rate:expr $( ,@dust_change $( $dust_change:expr )* )* $( ,@add_signature $( $add_signature:expr )* )* ) => ({
+    #[test]
+    fn test_list_addresses() {
+        let (wallet, _, _) = get_funded_wallet("wpkh(tpubEBr4i6yk5nf5DAaJpsi9N2pPYBeJ7fZ5Z9rmN4977iYLCGco1VyjB9tvvuvYtfZzjD5A8igzgw3HeWeeKFmanHYqksqZXYXGsw5zjnj7KM9/*)");
+        wallet.reveal_up_to(2).unwrap();
+        wallet.mark_used(2).unwrap();
+
+        let addresses = wallet.list_addresses().unwrap();
+        assert_eq!(addresses.len(), 3);
+        assert_eq!(addresses[0].address, wallet.peek_address(0).unwrap());
+        assert!(addresses[0].used);
+        assert_eq!(addresses[0].balance, 50_000);
+        assert!(!addresses[1].used);
+        assert_eq!(addresses[1].balance, 0);
+        assert!(addresses[2].used);
+        assert_eq!(addresses[2].balance, 0);
+
+        assert_eq!(
+            wallet.get_unused_address().unwrap(),
+            wallet.peek_address(1).unwrap()
+        );
+    }
+
+    fn receive_on_keychain(
+        wallet: &OfflineWallet<MemoryDatabase>,
+        script_type: ScriptType,
+        value: u64,
+    ) -> bitcoin::Txid {
+        let address = wallet.get_new_keychain_address(script_type).unwrap();
+        let txid = wallet.database.borrow_mut().received_tx(
+            testutils::TestIncomingTx::new(
+                vec![testutils::TestIncomingOutput::new(value, address)],
+                Some(1),
+                None,
+                None,
+            ),
+            Some(100),
+        );
+
+        // `received_tx` always stores the utxos as external
+        let outpoint = OutPoint::new(txid, 0);
+        let utxo = wallet
+            .database
+            .borrow()
+            .get_utxo(&outpoint)
+            .unwrap()
+            .unwrap();
+        wallet
+            .database
+            .borrow_mut()
+            .set_utxo(&UTXO {
+                script_type,
+                ..utxo
+            })
+            .unwrap();
+
+        txid
+    }
+
+    #[test]
+    fn test_add_keychain() {
+        let (mut wallet, _, _) = get_funded_wallet(get_test_wpkh());
+        let legacy = wallet.add_keychain("pkh(tpubEBr4i6yk5nf5DAaJpsi9N2pPYBeJ7fZ5Z9rmN4977iYLCGco1VyjB9tvvuvYtfZzjD5A8igzgw3HeWeeKFmanHYqksqZXYXGsw5zjnj7KM9/*)").unwrap();
+        assert_eq!(legacy, ScriptType::Keychain(0));
+        let nested = wallet.add_keychain("sh(wpkh(tpubEBr4i6yk5nf5DAaJpsi9N2pPYBeJ7fZ5Z9rmN4977iYLCGco1VyjB9tvvuvYtfZzjD5A8igzgw3HeWeeKFmanHYqksqZXYXGsw5zjnj7KM9/*))").unwrap();
+        assert_eq!(nested, ScriptType::Keychain(1));
+
+        assert_eq!(
+            wallet
+                .get_new_keychain_address(legacy)
+                .unwrap()
+                .address_type(),
+            Some(bitcoin::AddressType::P2pkh)
+        );
+        assert_eq!(
+            wallet
+                .database
+                .borrow()
+                .get_last_index(ScriptType::Keychain(0))
+                .unwrap(),
+            Some(0)
+        );
+        assert!(wallet.public_descriptor(nested).unwrap().is_some());
+        assert!(wallet
+            .public_descriptor(ScriptType::Keychain(2))
+            .unwrap()
+            .is_none());
+        assert!(matches!(
+            wallet.get_new_keychain_address(ScriptType::Keychain(2)),
+            Err(Error::UnknownKeychain(ScriptType::Keychain(2)))
+        ));
+
+        let key =
+            bitcoin::PrivateKey::from_wif("cVpPVruEDdmutPzisEsYvtST1usBR3ntr8pXSyt6D2YYqXRyPcFW")
+                .unwrap();
+        assert!(matches!(
+            wallet.add_signer(
+                ScriptType::Keychain(2),
+                bitcoin::util::bip32::Fingerprint::default().into(),
+                signer::SignerOrdering::default(),
+                Arc::new(key),
+            ),
+            Err(Error::UnknownKeychain(ScriptType::Keychain(2)))
+        ));
+    }
+
+    #[test]
+    #[should_panic(expected = "ChecksumMismatch")]
+    fn test_add_keychain_checksum_mismatch() {
+        let mut database = MemoryDatabase::new();
+        database
+            .check_descriptor_checksum(ScriptType::Keychain(0), b"aaaaaaaa")
+            .unwrap();
+        let mut wallet: OfflineWallet<_> =
+            Wallet::new_offline(get_test_wpkh(), None, Network::Regtest, database).unwrap();
+
+        wallet.add_keychain("pkh(tpubEBr4i6yk5nf5DAaJpsi9N2pPYBeJ7fZ5Z9rmN4977iYLCGco1VyjB9tvvuvYtfZzjD5A8igzgw3HeWeeKFmanHYqksqZXYXGsw5zjnj7KM9/*)").unwrap();
+    }
+
+    #[test]
+    fn test_get_keychain_balance() {
+        let (mut wallet, _, _) = get_funded_wallet(get_test_wpkh());
+        let legacy = wallet.add_keychain("pkh(tpubEBr4i6yk5nf5DAaJpsi9N2pPYBeJ7fZ5Z9rmN4977iYLCGco1VyjB9tvvuvYtfZzjD5A8igzgw3HeWeeKFmanHYqksqZXYXGsw5zjnj7KM9/*)").unwrap();
+        receive_on_keychain(&wallet, legacy, 30_000);
+
+        assert_eq!(wallet.get_balance().unwrap(), 80_000);
+        assert_eq!(
+            wallet.get_keychain_balance(ScriptType::External).unwrap(),
+            50_000
+        );
+        assert_eq!(wallet.get_keychain_balance(legacy).unwrap(), 30_000);
+        assert_eq!(
+            wallet
+                .get_keychain_balance(ScriptType::Keychain(1))
+                .unwrap(),
+            0
+        );
+    }
+
+    #[test]
+    fn test_create_tx_spend_keychains() {
+        let (mut wallet, _, _) = get_funded_wallet(get_test_wpkh());
+        let legacy = wallet.add_keychain("pkh(tprv8ZgxMBicQKsPd3EupYiPRhaMooHKUHJxNsTfYuScep13go8QFfHdtkG9nRkFGb7busX4isf6X9dURGCoKgitaApQ6MupRhZMcELAxTBRJgS/44'/0'/0'/0/*)").unwrap();
+        receive_on_keychain(&wallet, legacy, 30_000);
+
+        let addr = Address::from_str("2N1Ffz3WaNzbeLFBb51xyFMHYSEUXcbiSoX").unwrap();
+        let (psbt, details) = wallet
+            .create_tx(TxBuilder::with_recipients(vec![(
+                addr.script_pubkey(),
+                70_000,
+            )]))
+            .unwrap();
+
+        assert_eq!(psbt.global.unsigned_tx.input.len(), 2);
+        assert_eq!(details.sent, 80_000);
+
+        // the change goes back to the main descriptor
+        let change = psbt
+            .global
+            .unsigned_tx
+            .output
+            .iter()
+            .find(|txout| txout.script_pubkey != addr.script_pubkey())
+            .unwrap();
+        assert_eq!(
+            wallet
+                .database
+                .borrow()
+                .get_path_from_script_pubkey(&change.script_pubkey)
+                .unwrap()
+                .map(|(script_type, _)| script_type),
+            Some(ScriptType::External)
+        );
+
+        let (_, finalized) = wallet.sign(psbt, SignOptions::default()).unwrap();
+        assert!(finalized);
+    }
+
+    #[test]
+    #[should_panic(expected = "InsufficientFunds")]
+    fn test_create_tx_skip_unknown_keychain() {
+        let (mut wallet, _, _) = get_funded_wallet(get_test_wpkh());
+        let legacy = wallet.add_keychain("pkh(tpubEBr4i6yk5nf5DAaJpsi9N2pPYBeJ7fZ5Z9rmN4977iYLCGco1VyjB9tvvuvYtfZzjD5A8igzgw3HeWeeKFmanHYqksqZXYXGsw5zjnj7KM9/*)").unwrap();
+        receive_on_keychain(&wallet, legacy, 30_000);
+        // forget about the keychain
+        wallet.keychains.clear();
+
+        let addr = Address::from_str("2N1Ffz3WaNzbeLFBb51xyFMHYSEUXcbiSoX").unwrap();
+        wallet
+            .create_tx(TxBuilder::with_recipients(vec![(
+                addr.script_pubkey(),
+                70_000,
+            )]))
+            .unwrap();
+    }
+
+    pub(crate) fn get_test_wpkh() -> &'static str {
+        "wpkh(cVpPVruEDdmutPzisEsYvtST1usBR3ntr8pXSyt6D2YYqXRyPcFW)"
+    }
+
+    pub(crate) fn get_test_single_sig_csv() -> &'static str {
+        // and(pk(Alice),older(6))
+        "wsh(and_v(v:pk(cVpPVruEDdmutPzisEsYvtST1usBR3ntr8pXSyt6D2YYqXRyPcFW),older(6)))"
+    }
+
+    pub(crate) fn get_test_a_or_b_plus_csv() -> &'static str {
+        // or(pk(Alice),and(pk(Bob),older(144)))
+        "wsh(or_d(pk(cRjo6jqfVNP33HhSS76UhXETZsGTZYx8FMFvR9kpbtCSV1PmdZdu),and_v(v:pk(cMnkdebixpXMPfkcNEjjGin7s94hiehAH4mLbYkZoh9KSiNNmqC8),older(144))))"
+    }
+
+    pub(crate) fn get_test_single_sig_cltv() -> &'static str {
+        // and(pk(Alice),after(100000))
+        "wsh(and_v(v:pk(cVpPVruEDdmutPzisEsYvtST1usBR3ntr8pXSyt6D2YYqXRyPcFW),after(100000)))"
+    }
+
+    pub(crate) fn get_funded_wallet(
+        descriptor: &str,
+    ) -> (
+        OfflineWallet<MemoryDatabase>,
+        (String, Option<String>),
+        bitcoin::Txid,
+    ) {
+        let descriptors = testutils!(@descriptors (descriptor));
+        let wallet: OfflineWallet<_> = Wallet::new_offline(
+            &descriptors.0,
+            None,
+            Network::Regtest,
+            MemoryDatabase::new(),
+        )
+        .unwrap();
+
+        let txid = wallet.database.borrow_mut().received_tx(
+            testutils! {
+                @tx ( (@external descriptors, 0) => 50_000 ) (@confirmations 1)
+            },
+            Some(100),
+        );
+
+        (wallet, descriptors, txid)
+    }
+
+    macro_rules! assert_fee_rate {
+        ($tx:expr, $fees:expr, $fee_rate:expr $( ,@dust_change $( $dust_change:expr )* )* $( ,@add_signature $( $add_signature:expr )* )* ) => ({
             let mut tx = $tx.clone();
             $(
                 $( $add_signature )*
@@ -1785,6 +3465,82 @@ mod test {
         assert_eq!(psbt.global.unsigned_tx.input[0].sequence, 0xFFFFFFFF);
     }
 
+    #[test]
+    fn test_create_tx_anti_fee_sniping() {
+        let (wallet, _, _) = get_funded_wallet(get_test_wpkh());
+        wallet.current_height.set(Some(100_000));
+        let addr = wallet.get_new_address().unwrap();
+        let (psbt, _) = wallet
+            .create_tx(
+                TxBuilder::with_recipients(vec![(addr.script_pubkey(), 25_000)])
+                    .enable_anti_fee_sniping(),
+            )
+            .unwrap();
+
+        let tx = &psbt.global.unsigned_tx;
+        assert!(tx.lock_time > 100_000 - 100 && tx.lock_time <= 100_000);
+        assert_eq!(tx.input[0].sequence, 0xFFFFFFFE);
+        assert_eq!(tx.version, 2);
+    }
+
+    #[test]
+    fn test_create_tx_anti_fee_sniping_rbf() {
+        let (wallet, _, _) = get_funded_wallet(get_test_wpkh());
+        wallet.current_height.set(Some(100_000));
+        let addr = wallet.get_new_address().unwrap();
+        let (psbt, _) = wallet
+            .create_tx(
+                TxBuilder::with_recipients(vec![(addr.script_pubkey(), 25_000)])
+                    .enable_anti_fee_sniping()
+                    .enable_rbf(),
+            )
+            .unwrap();
+
+        assert!(psbt.global.unsigned_tx.lock_time > 100_000 - 100);
+        assert_eq!(psbt.global.unsigned_tx.input[0].sequence, 0xFFFFFFFD);
+    }
+
+    #[test]
+    fn test_create_tx_anti_fee_sniping_unknown_height() {
+        let (wallet, _, _) = get_funded_wallet(get_test_wpkh());
+        let addr = wallet.get_new_address().unwrap();
+        let (psbt, _) = wallet
+            .create_tx(
+                TxBuilder::with_recipients(vec![(addr.script_pubkey(), 25_000)])
+                    .enable_anti_fee_sniping(),
+            )
+            .unwrap();
+
+        assert_eq!(psbt.global.unsigned_tx.lock_time, 0);
+        assert_eq!(psbt.global.unsigned_tx.input[0].sequence, 0xFFFFFFFF);
+        assert_eq!(psbt.global.unsigned_tx.version, 1);
+    }
+
+    #[test]
+    fn test_create_tx_anti_fee_sniping_cltv() {
+        let (wallet, _, _) = get_funded_wallet(get_test_single_sig_cltv());
+        let addr = wallet.get_new_address().unwrap();
+
+        // the timelock required by the descriptor is higher than the current height
+        wallet.current_height.set(Some(50_000));
+        let (psbt, _) = wallet
+            .create_tx(
+                TxBuilder::with_recipients(vec![(addr.script_pubkey(), 25_000)])
+                    .enable_anti_fee_sniping(),
+            )
+            .unwrap();
+        assert_eq!(psbt.global.unsigned_tx.lock_time, 100_000);
+
+        wallet.current_height.set(Some(200_000));
+        let (psbt, _) = wallet
+            .create_tx(
+                TxBuilder::with_recipients(vec![(addr.script_pubkey(), 25_000)])
+                    .enable_anti_fee_sniping(),
+            )
+            .unwrap();
+        assert!(psbt.global.unsigned_tx.lock_time > 200_000 - 100);
+    }
+
     #[test]
     #[should_panic(
         expected = "The `change_policy` can be set only if the wallet has a change_descriptor"
@@ -1979,6 +3735,40 @@ mod test {
         assert_eq!(psbt.global.unsigned_tx.output[2].value, 30_000);
     }
 
+    #[test]
+    fn test_create_tx_ordering_shuffle_change() {
+        let (wallet, _, _) = get_funded_wallet(get_test_wpkh());
+        let addr = wallet.get_new_address().unwrap();
+        let (psbt, details) = wallet
+            .create_tx(
+                TxBuilder::with_recipients(vec![
+                    (addr.script_pubkey(), 10_000),
+                    (addr.script_pubkey(), 20_000),
+                    (addr.script_pubkey(), 5_000),
+                ])
+                .ordering(super::tx_builder::TxOrdering::ShuffleChange),
+            )
+            .unwrap();
+
+        let change_value = 50_000 - 35_000 - details.fees;
+        let values = psbt
+            .global
+            .unsigned_tx
+            .output
+            .iter()
+            .map(|txout| txout.value)
+            .collect::<Vec<_>>();
+        assert_eq!(values.len(), 4);
+        assert!(values.contains(&change_value));
+        assert_eq!(
+            values
+                .into_iter()
+                .filter(|&v| v != change_value)
+                .collect::<Vec<_>>(),
+            vec![10_000, 20_000, 5_000]
+        );
+    }
+
     #[test]
     fn test_create_tx_default_sighash() {
         let (wallet, _, _) = get_funded_wallet(get_test_wpkh());
@@ -3232,157 +5022,1583 @@ mod test {
             details.received
         );
 
-        assert_eq!(details.fees, 250);
+        assert_eq!(details.fees, 250);
+    }
+
+    #[test]
+    fn test_sign_single_xprv() {
+        let (wallet, _, _) = get_funded_wallet("wpkh(tprv8ZgxMBicQKsPd3EupYiPRhaMooHKUHJxNsTfYuScep13go8QFfHdtkG9nRkFGb7busX4isf6X9dURGCoKgitaApQ6MupRhZMcELAxTBRJgS/*)");
+        let addr = wallet.get_new_address().unwrap();
+        let (psbt, _) = wallet
+            .create_tx(
+                TxBuilder::new()
+                    .set_single_recipient(addr.script_pubkey())
+                    .drain_wallet(),
+            )
+            .unwrap();
+
+        let (signed_psbt, finalized) = wallet.sign(psbt, SignOptions::default()).unwrap();
+        assert_eq!(finalized, true);
+
+        let extracted = signed_psbt.extract_tx();
+        assert_eq!(extracted.input[0].witness.len(), 2);
+    }
+
+    #[test]
+    fn test_sign_single_xprv_bip44_path() {
+        let (wallet, _, _) = get_funded_wallet("wpkh(tprv8ZgxMBicQKsPd3EupYiPRhaMooHKUHJxNsTfYuScep13go8QFfHdtkG9nRkFGb7busX4isf6X9dURGCoKgitaApQ6MupRhZMcELAxTBRJgS/44'/0'/0'/0/*)");
+        let addr = wallet.get_new_address().unwrap();
+        let (psbt, _) = wallet
+            .create_tx(
+                TxBuilder::new()
+                    .set_single_recipient(addr.script_pubkey())
+                    .drain_wallet(),
+            )
+            .unwrap();
+
+        let (signed_psbt, finalized) = wallet.sign(psbt, SignOptions::default()).unwrap();
+        assert_eq!(finalized, true);
+
+        let extracted = signed_psbt.extract_tx();
+        assert_eq!(extracted.input[0].witness.len(), 2);
+    }
+
+    #[test]
+    fn test_sign_single_xprv_sh_wpkh() {
+        let (wallet, _, _) = get_funded_wallet("sh(wpkh(tprv8ZgxMBicQKsPd3EupYiPRhaMooHKUHJxNsTfYuScep13go8QFfHdtkG9nRkFGb7busX4isf6X9dURGCoKgitaApQ6MupRhZMcELAxTBRJgS/*))");
+        let addr = wallet.get_new_address().unwrap();
+        let (psbt, _) = wallet
+            .create_tx(
+                TxBuilder::new()
+                    .set_single_recipient(addr.script_pubkey())
+                    .drain_wallet(),
+            )
+            .unwrap();
+
+        let (signed_psbt, finalized) = wallet.sign(psbt, SignOptions::default()).unwrap();
+        assert_eq!(finalized, true);
+
+        let extracted = signed_psbt.extract_tx();
+        assert_eq!(extracted.input[0].witness.len(), 2);
+    }
+
+    #[test]
+    fn test_sign_single_wif() {
+        let (wallet, _, _) =
+            get_funded_wallet("wpkh(cVpPVruEDdmutPzisEsYvtST1usBR3ntr8pXSyt6D2YYqXRyPcFW)");
+        let addr = wallet.get_new_address().unwrap();
+        let (psbt, _) = wallet
+            .create_tx(
+                TxBuilder::new()
+                    .set_single_recipient(addr.script_pubkey())
+                    .drain_wallet(),
+            )
+            .unwrap();
+
+        let (signed_psbt, finalized) = wallet.sign(psbt, SignOptions::default()).unwrap();
+        assert_eq!(finalized, true);
+
+        let extracted = signed_psbt.extract_tx();
+        assert_eq!(extracted.input[0].witness.len(), 2);
+    }
+
+    #[test]
+    fn test_sign_single_xprv_no_hd_keypaths() {
+        let (wallet, _, _) = get_funded_wallet("wpkh(tprv8ZgxMBicQKsPd3EupYiPRhaMooHKUHJxNsTfYuScep13go8QFfHdtkG9nRkFGb7busX4isf6X9dURGCoKgitaApQ6MupRhZMcELAxTBRJgS/*)");
+        let addr = wallet.get_new_address().unwrap();
+        let (mut psbt, _) = wallet
+            .create_tx(
+                TxBuilder::new()
+                    .set_single_recipient(addr.script_pubkey())
+                    .drain_wallet(),
+            )
+            .unwrap();
+
+        psbt.inputs[0].hd_keypaths.clear();
+        assert_eq!(psbt.inputs[0].hd_keypaths.len(), 0);
+
+        let (signed_psbt, finalized) = wallet.sign(psbt, SignOptions::default()).unwrap();
+        assert_eq!(finalized, true);
+
+        let extracted = signed_psbt.extract_tx();
+        assert_eq!(extracted.input[0].witness.len(), 2);
+    }
+
+    #[test]
+    fn test_include_output_redeem_witness_script() {
+        let (wallet, _, _) = get_funded_wallet("sh(wsh(multi(1,cVpPVruEDdmutPzisEsYvtST1usBR3ntr8pXSyt6D2YYqXRyPcFW,cRjo6jqfVNP33HhSS76UhXETZsGTZYx8FMFvR9kpbtCSV1PmdZdu)))");
+        let addr = Address::from_str("2N1Ffz3WaNzbeLFBb51xyFMHYSEUXcbiSoX").unwrap();
+        let (psbt, _) = wallet
+            .create_tx(
+                TxBuilder::with_recipients(vec![(addr.script_pubkey(), 45_000)])
+                    .include_output_redeem_witness_script(),
+            )
+            .unwrap();
+
+        // p2sh-p2wsh transaction should contain both witness and redeem scripts
+        assert!(psbt
+            .outputs
+            .iter()
+            .any(|output| output.redeem_script.is_some() && output.witness_script.is_some()));
+    }
+
+    #[test]
+    fn test_signing_only_one_of_multiple_inputs() {
+        let (wallet, _, _) = get_funded_wallet(get_test_wpkh());
+        let addr = Address::from_str("2N1Ffz3WaNzbeLFBb51xyFMHYSEUXcbiSoX").unwrap();
+        let (mut psbt, _) = wallet
+            .create_tx(
+                TxBuilder::with_recipients(vec![(addr.script_pubkey(), 45_000)])
+                    .include_output_redeem_witness_script(),
+            )
+            .unwrap();
+
+        // add another input to the psbt that is at least passable.
+        let mut dud_input = bitcoin::util::psbt::Input::default();
+        dud_input.witness_utxo = Some(TxOut {
+            value: 100_000,
+            script_pubkey: miniscript::Descriptor::<bitcoin::PublicKey>::from_str(
+                "wpkh(025476c2e83188368da1ff3e292e7acafcdb3566bb0ad253f62fc70f07aeee6357)",
+            )
+            .unwrap()
+            .script_pubkey(miniscript::NullCtx),
+        });
+        psbt.inputs.push(dud_input);
+        psbt.global.unsigned_tx.input.push(bitcoin::TxIn::default());
+        let (psbt, is_final) = wallet.sign(psbt, SignOptions::default()).unwrap();
+        assert!(
+            !is_final,
+            "shouldn't be final since we can't sign one of the inputs"
+        );
+        assert!(
+            psbt.inputs[0].final_script_witness.is_some(),
+            "should finalized input it signed"
+        )
+    }
+
+    fn get_offline_signer_wallets() -> (OfflineWallet<MemoryDatabase>, OfflineWallet<MemoryDatabase>)
+    {
+        use bitcoin::util::bip32::{DerivationPath, ExtendedPrivKey, ExtendedPubKey};
+
+        let secp = Secp256k1::new();
+        let tprv = "tprv8ZgxMBicQKsPd3EupYiPRhaMooHKUHJxNsTfYuScep13go8QFfHdtkG9nRkFGb7busX4isf6X9dURGCoKgitaApQ6MupRhZMcELAxTBRJgS";
+        let master = ExtendedPrivKey::from_str(tprv).unwrap();
+        let account = master
+            .derive_priv(&secp, &DerivationPath::from_str("m/84'/1'/0'").unwrap())
+            .unwrap();
+        let tpub = ExtendedPubKey::from_private(&secp, &account);
+        let origin = format!("[{}/84'/1'/0']", master.fingerprint(&secp));
+
+        let signer: OfflineWallet<_> = Wallet::new_offline(
+            &format!("wpkh({}/84'/1'/0'/0/*)", tprv),
+            Some(&format!("wpkh({}/84'/1'/0'/1/*)", tprv)),
+            Network::Regtest,
+            MemoryDatabase::new(),
+        )
+        .unwrap();
+
+        let descriptors = (
+            format!("wpkh({}{}/0/*)", origin, tpub),
+            Some(format!("wpkh({}{}/1/*)", origin, tpub)),
+        );
+        let mut watch_only: OfflineWallet<_> = Wallet::new_offline(
+            &descriptors.0,
+            descriptors.1.as_ref(),
+            Network::Regtest,
+            MemoryDatabase::new(),
+        )
+        .unwrap();
+        watch_only.set_psbt_for_offline_signer(true);
+        watch_only.get_new_address().unwrap();
+        watch_only.database.borrow_mut().received_tx(
+            testutils! {
+                @tx ( (@external descriptors, 0) => 50_000 ) (@confirmations 1)
+            },
+            Some(100),
+        );
+
+        (signer, watch_only)
+    }
+
+    #[test]
+    fn test_offline_signer_round_trip() {
+        use bitcoin::consensus::encode::{deserialize, serialize};
+
+        let (signer, watch_only) = get_offline_signer_wallets();
+        let addr = Address::from_str("2N1Ffz3WaNzbeLFBb51xyFMHYSEUXcbiSoX").unwrap();
+        let (psbt, _) = watch_only
+            .create_tx(TxBuilder::with_recipients(vec![(
+                addr.script_pubkey(),
+                25_000,
+            )]))
+            .unwrap();
+
+        assert!(psbt.inputs[0].non_witness_utxo.is_some());
+        assert_eq!(psbt.global.unknown.len(), 1);
+
+        let psbt: PSBT = deserialize(&serialize(&psbt)).unwrap();
+        let change_index = psbt
+            .global
+            .unsigned_tx
+            .output
+            .iter()
+            .position(|txout| txout.script_pubkey != addr.script_pubkey())
+            .unwrap();
+        assert_eq!(signer.verify_change(&psbt).unwrap(), vec![change_index]);
+
+        let (_, finalized) = signer.sign(psbt, SignOptions::default()).unwrap();
+        assert!(finalized);
+    }
+
+    #[test]
+    fn test_offline_signer_invalid_change() {
+        let (signer, watch_only) = get_offline_signer_wallets();
+        let addr = Address::from_str("2N1Ffz3WaNzbeLFBb51xyFMHYSEUXcbiSoX").unwrap();
+        let (mut psbt, _) = watch_only
+            .create_tx(TxBuilder::with_recipients(vec![(
+                addr.script_pubkey(),
+                25_000,
+            )]))
+            .unwrap();
+
+        // redirect the change somewhere else, keeping the `hd_keypaths` that claim it's ours
+        let change_index = psbt
+            .global
+            .unsigned_tx
+            .output
+            .iter()
+            .position(|txout| txout.script_pubkey != addr.script_pubkey())
+            .unwrap();
+        psbt.global.unsigned_tx.output[change_index].script_pubkey = addr.script_pubkey();
+
+        assert!(matches!(
+            signer.verify_change(&psbt),
+            Err(Error::Signer(signer::SignerError::InvalidChangeOutput(index))) if index == change_index
+        ));
+    }
+
+    #[test]
+    fn test_offline_signer_invalid_change_path() {
+        use bitcoin::util::bip32::ChildNumber;
+
+        let (signer, watch_only) = get_offline_signer_wallets();
+        let addr = Address::from_str("2N1Ffz3WaNzbeLFBb51xyFMHYSEUXcbiSoX").unwrap();
+        let (mut psbt, _) = watch_only
+            .create_tx(TxBuilder::with_recipients(vec![(
+                addr.script_pubkey(),
+                25_000,
+            )]))
+            .unwrap();
+
+        // keep our fingerprint but use a path that doesn't derive any of our descriptors
+        let change_index = psbt
+            .outputs
+            .iter()
+            .position(|output| !output.hd_keypaths.is_empty())
+            .unwrap();
+        for (_, path) in psbt.outputs[change_index].hd_keypaths.values_mut() {
+            *path = path.child(ChildNumber::from_normal_idx(42).unwrap());
+        }
+
+        assert!(matches!(
+            signer.verify_change(&psbt),
+            Err(Error::Signer(signer::SignerError::InvalidChangeOutput(index))) if index == change_index
+        ));
+    }
+
+    #[test]
+    fn test_sign_policy_verify_change() {
+        let (mut signer, watch_only) = get_offline_signer_wallets();
+        signer.set_sign_policy(SignPolicy {
+            verify_change: true,
+            ..Default::default()
+        });
+
+        let addr = Address::from_str("2N1Ffz3WaNzbeLFBb51xyFMHYSEUXcbiSoX").unwrap();
+        let (mut psbt, _) = watch_only
+            .create_tx(TxBuilder::with_recipients(vec![(
+                addr.script_pubkey(),
+                25_000,
+            )]))
+            .unwrap();
+        let (_, finalized) = signer.sign(psbt.clone(), SignOptions::default()).unwrap();
+        assert!(finalized);
+
+        let change_index = psbt
+            .outputs
+            .iter()
+            .position(|output| !output.hd_keypaths.is_empty())
+            .unwrap();
+        psbt.global.unsigned_tx.output[change_index].script_pubkey = addr.script_pubkey();
+        assert!(matches!(
+            signer.sign(psbt, SignOptions::default()),
+            Err(Error::Signer(SignerError::InvalidChangeOutput(_)))
+        ));
+    }
+
+    #[test]
+    fn test_sign_policy_max_fee() {
+        let (mut wallet, _, _) = get_funded_wallet(get_test_wpkh());
+        let addr = wallet.get_new_address().unwrap();
+        let (mut psbt, _) = wallet
+            .create_tx(
+                TxBuilder::with_recipients(vec![(addr.script_pubkey(), 25_000)])
+                    .fee_absolute(10_000),
+            )
+            .unwrap();
+
+        wallet.set_sign_policy(SignPolicy {
+            max_fee: Some(5_000),
+            ..Default::default()
+        });
+        assert!(matches!(
+            wallet.sign(psbt.clone(), SignOptions::default()),
+            Err(Error::Signer(SignerError::FeeTooHigh(10_000)))
+        ));
+
+        // 10_000 is 20% of the 50_000 input
+        wallet.set_sign_policy(SignPolicy {
+            max_fee_percent: Some(15.0),
+            ..Default::default()
+        });
+        assert!(matches!(
+            wallet.sign(psbt.clone(), SignOptions::default()),
+            Err(Error::Signer(SignerError::FeeTooHigh(10_000)))
+        ));
+
+        wallet.set_sign_policy(SignPolicy {
+            max_fee: Some(10_000),
+            max_fee_percent: Some(25.0),
+            ..Default::default()
+        });
+        let (_, finalized) = wallet.sign(psbt.clone(), SignOptions::default()).unwrap();
+        assert!(finalized);
+
+        psbt.inputs[0].witness_utxo = None;
+        psbt.inputs[0].non_witness_utxo = None;
+        assert!(matches!(
+            wallet.sign(psbt, SignOptions::default()),
+            Err(Error::Signer(SignerError::UnknownInputValue(0)))
+        ));
+    }
+
+    #[test]
+    fn test_sign_policy_only_sighash_all() {
+        let (mut wallet, _, _) = get_funded_wallet(get_test_wpkh());
+        wallet.set_sign_policy(SignPolicy {
+            only_sighash_all: true,
+            ..Default::default()
+        });
+
+        let addr = wallet.get_new_address().unwrap();
+        let (psbt, _) = wallet
+            .create_tx(
+                TxBuilder::with_recipients(vec![(addr.script_pubkey(), 25_000)])
+                    .sighash(bitcoin::SigHashType::Single),
+            )
+            .unwrap();
+
+        // the policy wins over the options of the signing session
+        let sign_options = SignOptions {
+            allowed_sighash: vec![SigHashType::All, SigHashType::Single],
+            ..Default::default()
+        };
+        assert!(matches!(
+            wallet.sign(psbt, sign_options),
+            Err(Error::Signer(SignerError::SighashNotAllowed(0)))
+        ));
+    }
+
+    #[test]
+    fn test_sign_policy_only_sighash_all_external_signer() {
+        // an external signer that doesn't look at the `SignOptions`
+        #[derive(Debug)]
+        struct IgnoreOptionsSigner;
+        impl Signer for IgnoreOptionsSigner {
+            fn sign(
+                &self,
+                _psbt: &mut PSBT,
+                _input_index: Option<usize>,
+                _sign_options: &SignOptions,
+                _secp: &SecpCtx,
+            ) -> Result<(), SignerError> {
+                Ok(())
+            }
+
+            fn sign_whole_tx(&self) -> bool {
+                true
+            }
+        }
+
+        let (mut wallet, _, _) = get_funded_wallet("wpkh(tpubD6NzVbkrYhZ4Xferm7Pz4VnjdcDPFyjVu5K4iZXQ4pVN8Cks4pHVowTBXBKRhX64pkRyJZJN5xAKj4UDNnLPb5p2sSKXhewoYx5GbTdUFWq/*)");
+        wallet
+            .add_signer(
+                ScriptType::External,
+                bitcoin::util::bip32::Fingerprint::default().into(),
+                SignerOrdering::default(),
+                Arc::new(IgnoreOptionsSigner),
+            )
+            .unwrap();
+        wallet.set_sign_policy(SignPolicy {
+            only_sighash_all: true,
+            ..Default::default()
+        });
+
+        let addr = wallet.get_new_address().unwrap();
+        let (psbt, _) = wallet
+            .create_tx(
+                TxBuilder::with_recipients(vec![(addr.script_pubkey(), 25_000)])
+                    .sighash(bitcoin::SigHashType::Single),
+            )
+            .unwrap();
+        assert!(matches!(
+            wallet.sign(psbt, SignOptions::default()),
+            Err(Error::Signer(SignerError::SighashNotAllowed(0)))
+        ));
+    }
+
+    #[test]
+    fn test_sign_options_allowed_sighash() {
+        let (wallet, _, _) = get_funded_wallet(get_test_wpkh());
+        let addr = wallet.get_new_address().unwrap();
+        let (psbt, _) = wallet
+            .create_tx(
+                TxBuilder::with_recipients(vec![(addr.script_pubkey(), 25_000)])
+                    .sighash(bitcoin::SigHashType::Single),
+            )
+            .unwrap();
+        let sign_options = SignOptions {
+            allowed_sighash: vec![bitcoin::SigHashType::All],
+            ..Default::default()
+        };
+        assert!(matches!(
+            wallet.sign(psbt.clone(), sign_options),
+            Err(Error::Signer(SignerError::SighashNotAllowed(0)))
+        ));
+
+        let (_, finalized) = wallet.sign(psbt, SignOptions::default()).unwrap();
+        assert!(finalized);
+    }
+
+    #[test]
+    fn test_sign_options_trust_witness_utxo() {
+        let (wallet, _, _) = get_funded_wallet(get_test_wpkh());
+        let addr = wallet.get_new_address().unwrap();
+        let sign_options = SignOptions {
+            trust_witness_utxo: false,
+            ..Default::default()
+        };
+
+        let (psbt, _) = wallet
+            .create_tx(
+                TxBuilder::new()
+                    .drain_wallet()
+                    .set_single_recipient(addr.script_pubkey()),
+            )
+            .unwrap();
+        assert!(psbt.inputs[0].non_witness_utxo.is_none());
+        assert!(matches!(
+            wallet.sign(psbt, sign_options.clone()),
+            Err(Error::Signer(SignerError::MissingNonWitnessUtxo))
+        ));
+
+        let (mut psbt, _) = wallet
+            .create_tx(
+                TxBuilder::new()
+                    .drain_wallet()
+                    .set_single_recipient(addr.script_pubkey())
+                    .force_non_witness_utxo(),
+            )
+            .unwrap();
+        let (signed_psbt, finalized) = wallet.sign(psbt.clone(), sign_options.clone()).unwrap();
+        assert!(finalized);
+        assert!(signed_psbt.inputs[0].final_script_witness.is_some());
+
+        // lie about the value of the input
+        psbt.inputs[0].witness_utxo.as_mut().unwrap().value += 1;
+        assert!(matches!(
+            wallet.sign(psbt, sign_options),
+            Err(Error::Signer(SignerError::InvalidNonWitnessUtxo))
+        ));
+    }
+
+    #[test]
+    fn test_sign_options_try_finalize() {
+        let (wallet, _, _) = get_funded_wallet(get_test_wpkh());
+        let addr = wallet.get_new_address().unwrap();
+        let (psbt, _) = wallet
+            .create_tx(
+                TxBuilder::new()
+                    .drain_wallet()
+                    .set_single_recipient(addr.script_pubkey()),
+            )
+            .unwrap();
+
+        let sign_options = SignOptions {
+            try_finalize: false,
+            ..Default::default()
+        };
+        let (signed_psbt, finalized) = wallet.sign(psbt.clone(), sign_options).unwrap();
+        assert!(!finalized);
+        assert_eq!(signed_psbt.inputs[0].partial_sigs.len(), 1);
+        assert!(signed_psbt.inputs[0].final_script_witness.is_none());
+
+        let (signed_psbt, finalized) = wallet.sign(psbt.clone(), SignOptions::default()).unwrap();
+        assert!(finalized);
+        assert_eq!(signed_psbt.inputs[0].partial_sigs.len(), 1);
+
+        let sign_options = SignOptions {
+            remove_partial_sigs: true,
+            ..Default::default()
+        };
+        let (signed_psbt, finalized) = wallet.sign(psbt, sign_options).unwrap();
+        assert!(finalized);
+        assert!(signed_psbt.inputs[0].partial_sigs.is_empty());
+        assert!(signed_psbt.inputs[0].final_script_witness.is_some());
+    }
+
+    #[test]
+    fn test_sign_options_sign_unknown_inputs() {
+        let (wallet, _, _) = get_funded_wallet(get_test_wpkh());
+        let addr = wallet.get_new_address().unwrap();
+        let (psbt, _) = wallet
+            .create_tx(
+                TxBuilder::new()
+                    .drain_wallet()
+                    .set_single_recipient(addr.script_pubkey()),
+            )
+            .unwrap();
+
+        // same key, different descriptor: the input doesn't belong to this wallet
+        let other_wallet: OfflineWallet<_> = Wallet::new_offline(
+            "pkh(cVpPVruEDdmutPzisEsYvtST1usBR3ntr8pXSyt6D2YYqXRyPcFW)",
+            None,
+            Network::Regtest,
+            MemoryDatabase::new(),
+        )
+        .unwrap();
+
+        let sign_options = SignOptions {
+            sign_unknown_inputs: false,
+            ..Default::default()
+        };
+        let (signed_psbt, finalized) = other_wallet.sign(psbt.clone(), sign_options).unwrap();
+        assert!(!finalized);
+        assert!(signed_psbt.inputs[0].partial_sigs.is_empty());
+
+        let (signed_psbt, finalized) = other_wallet.sign(psbt, SignOptions::default()).unwrap();
+        assert!(!finalized);
+        assert_eq!(signed_psbt.inputs[0].partial_sigs.len(), 1);
+    }
+
+    struct TestPayjoinReceiver {
+        wallet: OfflineWallet<MemoryDatabase>,
+        contribution: u64,
+        tamper: Box<dyn Fn(&mut PSBT)>,
+        url: RefCell<String>,
+    }
+
+    impl TestPayjoinReceiver {
+        fn new(contribution: u64) -> Self {
+            let (wallet, _, _) =
+                get_funded_wallet("wpkh(cRjo6jqfVNP33HhSS76UhXETZsGTZYx8FMFvR9kpbtCSV1PmdZdu)");
+            TestPayjoinReceiver {
+                wallet,
+                contribution,
+                tamper: Box::new(|_| {}),
+                url: RefCell::new(String::new()),
+            }
+        }
+
+        fn with_tamper<F: Fn(&mut PSBT) + 'static>(mut self, tamper: F) -> Self {
+            self.tamper = Box::new(tamper);
+            self
+        }
+    }
+
+    impl payjoin::PayjoinTransport for TestPayjoinReceiver {
+        fn post(&self, url: &str, original: &PSBT) -> Result<PSBT, Error> {
+            *self.url.borrow_mut() = url.to_string();
+
+            let utxo = self.wallet.list_unspent()?.remove(0);
+            let mut proposal = original.clone();
+            for input in &mut proposal.inputs {
+                input.final_script_sig = None;
+                input.final_script_witness = None;
+                input.partial_sigs.clear();
+            }
+
+            let tx = &mut proposal.global.unsigned_tx;
+            let sequence = tx.input[0].sequence;
+            tx.input.push(bitcoin::TxIn {
+                previous_output: utxo.outpoint,
+                sequence,
+                ..Default::default()
+            });
+            proposal.inputs.push(bitcoin::util::psbt::Input {
+                witness_utxo: Some(utxo.txout.clone()),
+                ..Default::default()
+            });
+
+            for (index, txout) in tx.output.iter_mut().enumerate() {
+                if self.wallet.is_mine(&txout.script_pubkey)? {
+                    txout.value += utxo.txout.value;
+                } else if url.contains(&format!("additionalfeeoutputindex={}", index)) {
+                    txout.value -= self.contribution;
+                }
+            }
+
+            // only sign the input we added
+            let sign_options = SignOptions {
+                sign_unknown_inputs: false,
+                ..Default::default()
+            };
+            let (mut proposal, _) = self.wallet.sign(proposal, sign_options)?;
+            (self.tamper)(&mut proposal);
+            Ok(proposal)
+        }
+    }
+
+    fn get_payjoin_original(
+        receiver: &TestPayjoinReceiver,
+    ) -> (OfflineWallet<MemoryDatabase>, PSBT) {
+        let (wallet, _, _) = get_funded_wallet(get_test_wpkh());
+        let addr = receiver.wallet.get_new_address().unwrap();
+        let (psbt, _) = wallet
+            .create_tx(TxBuilder::with_recipients(vec![(
+                addr.script_pubkey(),
+                25_000,
+            )]))
+            .unwrap();
+        let (psbt, finalized) = wallet.sign(psbt, SignOptions::default()).unwrap();
+        assert!(finalized);
+
+        (wallet, psbt)
+    }
+
+    fn send_test_payjoin(
+        receiver: &TestPayjoinReceiver,
+        options: &payjoin::PayjoinOptions,
+    ) -> Result<(PSBT, bool), Error> {
+        let (wallet, original) = get_payjoin_original(receiver);
+        wallet.send_payjoin(
+            original,
+            "https://example.com/pj",
+            options,
+            receiver,
+            SignOptions::default(),
+        )
+    }
+
+    #[test]
+    fn test_payjoin_send() {
+        let receiver = TestPayjoinReceiver::new(50);
+        let (wallet, original) = get_payjoin_original(&receiver);
+        let options = payjoin::PayjoinOptions {
+            max_additional_fee_contribution: 1_000,
+            ..Default::default()
+        };
+
+        let (psbt, finalized) = wallet
+            .send_payjoin(
+                original.clone(),
+                "https://example.com/pj",
+                &options,
+                &receiver,
+                SignOptions::default(),
+            )
+            .unwrap();
+        assert!(finalized);
+
+        let change_index = wallet.payjoin_fee_output(&original).unwrap().unwrap();
+        assert_eq!(
+            *receiver.url.borrow(),
+            format!(
+                "https://example.com/pj?v=1&additionalfeeoutputindex={}&maxadditionalfeecontribution=1000",
+                change_index
+            )
+        );
+
+        let tx = psbt.extract_tx();
+        let original_tx = &original.global.unsigned_tx;
+        assert_eq!(tx.input.len(), 2);
+        assert_eq!(
+            tx.output[change_index].value,
+            original_tx.output[change_index].value - 50
+        );
+        assert_eq!(tx.output[1 - change_index].value, 25_000 + 50_000);
+    }
+
+    #[test]
+    fn test_payjoin_send_original_not_finalized() {
+        let receiver = TestPayjoinReceiver::new(0);
+        let (wallet, _, _) = get_funded_wallet(get_test_wpkh());
+        let addr = receiver.wallet.get_new_address().unwrap();
+        let (psbt, _) = wallet
+            .create_tx(TxBuilder::with_recipients(vec![(
+                addr.script_pubkey(),
+                25_000,
+            )]))
+            .unwrap();
+
+        assert!(matches!(
+            wallet.send_payjoin(
+                psbt,
+                "https://example.com/pj",
+                &Default::default(),
+                &receiver,
+                SignOptions::default(),
+            ),
+            Err(Error::Payjoin(payjoin::PayjoinError::OriginalNotFinalized))
+        ));
+    }
+
+    #[test]
+    fn test_payjoin_send_fee_contribution_too_high() {
+        let options = payjoin::PayjoinOptions {
+            max_additional_fee_contribution: 100,
+            ..Default::default()
+        };
+
+        // above the limit set by the sender
+        let receiver = TestPayjoinReceiver::new(150);
+        assert!(matches!(
+            send_test_payjoin(&receiver, &options),
+            Err(Error::Payjoin(
+                payjoin::PayjoinError::FeeContributionTooHigh(150)
+            ))
+        ));
+
+        // more than the fees for the receiver's input at the original fee rate
+        let options = payjoin::PayjoinOptions {
+            max_additional_fee_contribution: 10_000,
+            ..Default::default()
+        };
+        let receiver = TestPayjoinReceiver::new(5_000);
+        assert!(matches!(
+            send_test_payjoin(&receiver, &options),
+            Err(Error::Payjoin(
+                payjoin::PayjoinError::FeeContributionTooHigh(5_000)
+            ))
+        ));
+    }
+
+    #[test]
+    fn test_payjoin_send_fee_decreased() {
+        // the receiver's output is the largest one, take the extra value from the fees
+        let receiver = TestPayjoinReceiver::new(0).with_tamper(|proposal| {
+            let output = proposal
+                .global
+                .unsigned_tx
+                .output
+                .iter_mut()
+                .max_by_key(|txout| txout.value)
+                .unwrap();
+            output.value += 1_000;
+        });
+        assert!(matches!(
+            send_test_payjoin(&receiver, &Default::default()),
+            Err(Error::Payjoin(payjoin::PayjoinError::FeeDecreased(_)))
+        ));
+
+        // a negative fee is reported as zero
+        let receiver = TestPayjoinReceiver::new(0).with_tamper(|proposal| {
+            proposal.global.unsigned_tx.output[0].value += 1_000_000;
+        });
+        assert!(matches!(
+            send_test_payjoin(&receiver, &Default::default()),
+            Err(Error::Payjoin(payjoin::PayjoinError::FeeDecreased(0)))
+        ));
+    }
+
+    #[test]
+    fn test_payjoin_send_mixed_input_scripts() {
+        let receiver = TestPayjoinReceiver::new(0).with_tamper(|proposal| {
+            let utxo = proposal.inputs[1].witness_utxo.as_mut().unwrap();
+            utxo.script_pubkey = Address::from_str("mkHS9ne12qx9pS9VojpwU5xtRd4T7X7ZUt")
+                .unwrap()
+                .script_pubkey();
+        });
+        assert!(matches!(
+            send_test_payjoin(&receiver, &Default::default()),
+            Err(Error::Payjoin(payjoin::PayjoinError::MixedInputScripts(1)))
+        ));
+    }
+
+    #[test]
+    fn test_payjoin_send_min_fee_rate() {
+        let receiver = TestPayjoinReceiver::new(0);
+        let options = payjoin::PayjoinOptions {
+            min_fee_rate: Some(FeeRate::from_sat_per_vb(1.0)),
+            ..Default::default()
+        };
+        assert!(matches!(
+            send_test_payjoin(&receiver, &options),
+            Err(Error::Payjoin(payjoin::PayjoinError::FeeRateTooLow(_)))
+        ));
+    }
+
+    #[test]
+    fn test_payjoin_send_locktime_changed() {
+        let receiver = TestPayjoinReceiver::new(0)
+            .with_tamper(|proposal| proposal.global.unsigned_tx.lock_time = 42);
+        assert!(matches!(
+            send_test_payjoin(&receiver, &Default::default()),
+            Err(Error::Payjoin(payjoin::PayjoinError::LockTimeChanged))
+        ));
+    }
+
+    #[test]
+    fn test_payjoin_send_sequence_changed() {
+        let receiver = TestPayjoinReceiver::new(0)
+            .with_tamper(|proposal| proposal.global.unsigned_tx.input[1].sequence = 0);
+        assert!(matches!(
+            send_test_payjoin(&receiver, &Default::default()),
+            Err(Error::Payjoin(payjoin::PayjoinError::SequenceChanged(1)))
+        ));
+    }
+
+    #[test]
+    fn test_payjoin_send_invalid_proposal() {
+        // one PSBT input less than the inputs of the transaction
+        let receiver = TestPayjoinReceiver::new(0).with_tamper(|proposal| {
+            proposal.inputs.pop();
+        });
+        assert!(matches!(
+            send_test_payjoin(&receiver, &Default::default()),
+            Err(Error::Payjoin(payjoin::PayjoinError::InvalidPsbt))
+        ));
+
+        // one PSBT output more than the outputs of the transaction
+        let receiver = TestPayjoinReceiver::new(0).with_tamper(|proposal| {
+            proposal.outputs.push(Default::default());
+        });
+        assert!(matches!(
+            send_test_payjoin(&receiver, &Default::default()),
+            Err(Error::Payjoin(payjoin::PayjoinError::InvalidPsbt))
+        ));
+    }
+
+    #[test]
+    fn test_payjoin_send_receiver_input_not_finalized() {
+        let receiver = TestPayjoinReceiver::new(0).with_tamper(|proposal| {
+            proposal.inputs[1].final_script_sig = None;
+            proposal.inputs[1].final_script_witness = None;
+        });
+        assert!(matches!(
+            send_test_payjoin(&receiver, &Default::default()),
+            Err(Error::Payjoin(
+                payjoin::PayjoinError::ReceiverInputNotFinalized(1)
+            ))
+        ));
+    }
+
+    #[test]
+    fn test_payjoin_send_output_removed() {
+        // the receiver takes our change
+        let receiver = TestPayjoinReceiver::new(0).with_tamper(|proposal| {
+            let change = proposal
+                .global
+                .unsigned_tx
+                .output
+                .iter()
+                .position(|txout| txout.value != 75_000)
+                .unwrap();
+            proposal.global.unsigned_tx.output.remove(change);
+            proposal.outputs.remove(change);
+        });
+        assert!(matches!(
+            send_test_payjoin(&receiver, &Default::default()),
+            Err(Error::Payjoin(payjoin::PayjoinError::MissingOutput(_)))
+        ));
+    }
+
+    #[test]
+    fn test_payjoin_send_output_substitution_disabled() {
+        let receiver = TestPayjoinReceiver::new(0).with_tamper(|proposal| {
+            for txout in &mut proposal.global.unsigned_tx.output {
+                if txout.value == 75_000 {
+                    txout.value = 20_000;
+                }
+            }
+        });
+        let options = payjoin::PayjoinOptions {
+            disable_output_substitution: true,
+            ..Default::default()
+        };
+        assert!(matches!(
+            send_test_payjoin(&receiver, &options),
+            Err(Error::Payjoin(payjoin::PayjoinError::OutputValueDecreased(
+                _
+            )))
+        ));
+    }
+
+    #[test]
+    fn test_payjoin_receive() {
+        let receiver = TestPayjoinReceiver::new(0);
+        let (wallet, original) = get_payjoin_original(&receiver);
+        let options = payjoin::PayjoinOptions {
+            max_additional_fee_contribution: 1_000,
+            ..Default::default()
+        };
+
+        let change_index = wallet.payjoin_fee_output(&original).unwrap().unwrap();
+        let url = payjoin::request_url("https://example.com/pj", &options, Some(change_index));
+        let params =
+            payjoin::PayjoinRequestParams::from_str(url.split('?').nth(1).unwrap()).unwrap();
+        let proposal = receiver
+            .wallet
+            .process_payjoin_request(
+                original.clone(),
+                &params,
+                coin_selection::DefaultCoinSelectionAlgorithm::default(),
+                SignOptions::default(),
+            )
+            .unwrap();
+        assert_eq!(proposal.inputs.len(), 2);
+        assert!(proposal
+            .inputs
+            .iter()
+            .all(|input| input.hd_keypaths.is_empty() && input.partial_sigs.is_empty()));
+
+        let psbt = wallet
+            .process_payjoin_proposal(&original, proposal, &options)
+            .unwrap();
+        let (psbt, finalized) = wallet.sign(psbt, SignOptions::default()).unwrap();
+        assert!(finalized);
+
+        let tx = psbt.extract_tx();
+        let original_tx = &original.global.unsigned_tx;
+        let contribution = original_tx.output[change_index].value - tx.output[change_index].value;
+        assert!(contribution > 0 && contribution <= 1_000);
+        assert!(tx.output[1 - change_index].value > 25_000 + 50_000 - 1_000);
+        assert!(tx.output[1 - change_index].value < 25_000 + 50_000);
+    }
+
+    #[test]
+    fn test_payjoin_receive_original_not_finalized() {
+        let receiver = TestPayjoinReceiver::new(0);
+        let (wallet, _, _) = get_funded_wallet(get_test_wpkh());
+        let addr = receiver.wallet.get_new_address().unwrap();
+        let (psbt, _) = wallet
+            .create_tx(TxBuilder::with_recipients(vec![(
+                addr.script_pubkey(),
+                25_000,
+            )]))
+            .unwrap();
+
+        assert!(matches!(
+            receiver.wallet.process_payjoin_request(
+                psbt,
+                &Default::default(),
+                coin_selection::DefaultCoinSelectionAlgorithm::default(),
+                SignOptions::default(),
+            ),
+            Err(Error::Payjoin(payjoin::PayjoinError::OriginalNotFinalized))
+        ));
+    }
+
+    #[test]
+    fn test_payjoin_receive_invalid_original() {
+        let receiver = TestPayjoinReceiver::new(0);
+        let (_, mut original) = get_payjoin_original(&receiver);
+        original.inputs.clear();
+
+        assert!(matches!(
+            receiver.wallet.process_payjoin_request(
+                original,
+                &Default::default(),
+                coin_selection::DefaultCoinSelectionAlgorithm::default(),
+                SignOptions::default(),
+            ),
+            Err(Error::Payjoin(payjoin::PayjoinError::InvalidPsbt))
+        ));
+
+        // a valid PSBT, but without any input to look at
+        let (_, mut original) = get_payjoin_original(&receiver);
+        original.inputs.clear();
+        original.global.unsigned_tx.input.clear();
+
+        assert!(matches!(
+            receiver.wallet.process_payjoin_request(
+                original,
+                &Default::default(),
+                coin_selection::DefaultCoinSelectionAlgorithm::default(),
+                SignOptions::default(),
+            ),
+            Err(Error::Payjoin(payjoin::PayjoinError::OriginalWithoutInputs))
+        ));
+    }
+
+    #[test]
+    fn test_payjoin_receive_original_input_is_ours() {
+        let receiver = TestPayjoinReceiver::new(0);
+        let (_, original) = get_payjoin_original(&receiver);
+
+        let (wallet, _, _) = get_funded_wallet(get_test_wpkh());
+        wallet.get_new_address().unwrap();
+        assert!(matches!(
+            wallet.process_payjoin_request(
+                original,
+                &Default::default(),
+                coin_selection::DefaultCoinSelectionAlgorithm::default(),
+                SignOptions::default(),
+            ),
+            Err(Error::Payjoin(payjoin::PayjoinError::OriginalInputIsOurs(
+                0
+            )))
+        ));
+    }
+
+    #[test]
+    fn test_payjoin_receive_no_output_to_us() {
+        let receiver = TestPayjoinReceiver::new(0);
+        let (wallet, _, _) = get_funded_wallet(get_test_wpkh());
+        let addr = Address::from_str("2N1Ffz3WaNzbeLFBb51xyFMHYSEUXcbiSoX").unwrap();
+        let (psbt, _) = wallet
+            .create_tx(TxBuilder::with_recipients(vec![(
+                addr.script_pubkey(),
+                25_000,
+            )]))
+            .unwrap();
+        let (psbt, _) = wallet.sign(psbt, SignOptions::default()).unwrap();
+
+        assert!(matches!(
+            receiver.wallet.process_payjoin_request(
+                psbt,
+                &Default::default(),
+                coin_selection::DefaultCoinSelectionAlgorithm::default(),
+                SignOptions::default(),
+            ),
+            Err(Error::Payjoin(payjoin::PayjoinError::NoOutputToUs))
+        ));
+    }
+
+    #[test]
+    fn test_payjoin_receive_invalid_fee_output() {
+        let receiver = TestPayjoinReceiver::new(0);
+        let (_, original) = get_payjoin_original(&receiver);
+        let params = payjoin::PayjoinRequestParams {
+            additional_fee_output_index: Some(2),
+            ..Default::default()
+        };
+
+        assert!(matches!(
+            receiver.wallet.process_payjoin_request(
+                original,
+                &params,
+                coin_selection::DefaultCoinSelectionAlgorithm::default(),
+                SignOptions::default(),
+            ),
+            Err(Error::Payjoin(payjoin::PayjoinError::InvalidParameter(_)))
+        ));
     }
 
     #[test]
-    fn test_sign_single_xprv() {
-        let (wallet, _, _) = get_funded_wallet("wpkh(tprv8ZgxMBicQKsPd3EupYiPRhaMooHKUHJxNsTfYuScep13go8QFfHdtkG9nRkFGb7busX4isf6X9dURGCoKgitaApQ6MupRhZMcELAxTBRJgS/*)");
-        let addr = wallet.get_new_address().unwrap();
-        let (psbt, _) = wallet
-            .create_tx(
-                TxBuilder::new()
-                    .set_single_recipient(addr.script_pubkey())
-                    .drain_wallet(),
+    fn test_payjoin_receive_min_fee_rate() {
+        let receiver = TestPayjoinReceiver::new(0);
+        let (wallet, original) = get_payjoin_original(&receiver);
+        let params = payjoin::PayjoinRequestParams {
+            min_fee_rate: Some(FeeRate::from_sat_per_vb(5.0)),
+            ..Default::default()
+        };
+
+        let proposal = receiver
+            .wallet
+            .process_payjoin_request(
+                original.clone(),
+                &params,
+                coin_selection::DefaultCoinSelectionAlgorithm::default(),
+                SignOptions::default(),
             )
             .unwrap();
-
-        let (signed_psbt, finalized) = wallet.sign(psbt, None).unwrap();
-        assert_eq!(finalized, true);
-
-        let extracted = signed_psbt.extract_tx();
-        assert_eq!(extracted.input[0].witness.len(), 2);
+        let options = payjoin::PayjoinOptions {
+            min_fee_rate: Some(FeeRate::from_sat_per_vb(5.0)),
+            ..Default::default()
+        };
+        let psbt = wallet
+            .process_payjoin_proposal(&original, proposal, &options)
+            .unwrap();
+        let (_, finalized) = wallet.sign(psbt, SignOptions::default()).unwrap();
+        assert!(finalized);
     }
 
     #[test]
-    fn test_sign_single_xprv_bip44_path() {
-        let (wallet, _, _) = get_funded_wallet("wpkh(tprv8ZgxMBicQKsPd3EupYiPRhaMooHKUHJxNsTfYuScep13go8QFfHdtkG9nRkFGb7busX4isf6X9dURGCoKgitaApQ6MupRhZMcELAxTBRJgS/44'/0'/0'/0/*)");
-        let addr = wallet.get_new_address().unwrap();
-        let (psbt, _) = wallet
-            .create_tx(
-                TxBuilder::new()
-                    .set_single_recipient(addr.script_pubkey())
-                    .drain_wallet(),
+    fn test_contribute_to_psbt() {
+        let (coordinator, _, _) =
+            get_funded_wallet("wpkh(cRjo6jqfVNP33HhSS76UhXETZsGTZYx8FMFvR9kpbtCSV1PmdZdu)");
+        let addr = Address::from_str("2N1Ffz3WaNzbeLFBb51xyFMHYSEUXcbiSoX").unwrap();
+        let (original, _) = coordinator
+            .create_tx(TxBuilder::with_recipients(vec![(
+                addr.script_pubkey(),
+                20_000,
+            )]))
+            .unwrap();
+
+        let (wallet, _, _) = get_funded_wallet(get_test_wpkh());
+        let mix_addr = wallet.get_new_address().unwrap();
+        let contribution = wallet
+            .contribute_to_psbt(
+                original.clone(),
+                vec![(mix_addr.script_pubkey(), 20_000)],
+                FeeRate::from_sat_per_vb(2.0),
+                coin_selection::DefaultCoinSelectionAlgorithm::default(),
             )
             .unwrap();
+        let psbt = &contribution.psbt;
+        let tx = &psbt.global.unsigned_tx;
 
-        let (signed_psbt, finalized) = wallet.sign(psbt, None).unwrap();
-        assert_eq!(finalized, true);
+        assert_eq!(contribution.foreign_inputs, vec![0]);
+        assert_eq!(contribution.inputs, vec![1]);
+        assert_eq!(contribution.outputs, vec![2, 3]);
+        assert_eq!(contribution.change, Some(3));
+        assert_eq!(psbt.inputs[0], original.inputs[0]);
+        assert_eq!(tx.output[..2], original.global.unsigned_tx.output[..]);
+        assert_eq!(tx.output[2].value, 20_000);
+        assert_eq!(tx.output[3].value, 50_000 - 20_000 - contribution.fee);
+
+        // one input and two outputs at 2 sat/vbyte
+        let weight = 4 * (serialize(&tx.output[2]).len() + serialize(&tx.output[3]).len())
+            + coin_selection::TXIN_BASE_WEIGHT
+            + 108;
+        let fee_rate = contribution.fee as f32 / (weight as f32 / 4.0);
+        assert!((fee_rate - 2.0).abs() < 0.1, "fee rate {}", fee_rate);
+
+        // we only sign our input
+        let sign_options = SignOptions {
+            sign_unknown_inputs: false,
+            ..Default::default()
+        };
+        let (psbt, finalized) = wallet.sign(contribution.psbt, sign_options).unwrap();
+        assert!(!finalized);
+        assert_eq!(psbt.inputs[0], original.inputs[0]);
+        assert!(psbt.inputs[1].final_script_witness.is_some());
 
-        let extracted = signed_psbt.extract_tx();
-        assert_eq!(extracted.input[0].witness.len(), 2);
+        let (_, finalized) = coordinator.sign(psbt, SignOptions::default()).unwrap();
+        assert!(finalized);
     }
 
     #[test]
-    fn test_sign_single_xprv_sh_wpkh() {
-        let (wallet, _, _) = get_funded_wallet("sh(wpkh(tprv8ZgxMBicQKsPd3EupYiPRhaMooHKUHJxNsTfYuScep13go8QFfHdtkG9nRkFGb7busX4isf6X9dURGCoKgitaApQ6MupRhZMcELAxTBRJgS/*))");
-        let addr = wallet.get_new_address().unwrap();
+    fn test_contribute_to_psbt_skip_spent_utxos() {
+        let (wallet, _, _) = get_funded_wallet(get_test_wpkh());
+        let addr = Address::from_str("2N1Ffz3WaNzbeLFBb51xyFMHYSEUXcbiSoX").unwrap();
         let (psbt, _) = wallet
-            .create_tx(
-                TxBuilder::new()
-                    .set_single_recipient(addr.script_pubkey())
-                    .drain_wallet(),
-            )
+            .create_tx(TxBuilder::with_recipients(vec![(
+                addr.script_pubkey(),
+                20_000,
+            )]))
             .unwrap();
 
-        let (signed_psbt, finalized) = wallet.sign(psbt, None).unwrap();
-        assert_eq!(finalized, true);
+        assert!(matches!(
+            wallet.contribute_to_psbt(
+                psbt,
+                vec![(addr.script_pubkey(), 20_000)],
+                FeeRate::default(),
+                coin_selection::DefaultCoinSelectionAlgorithm::default(),
+            ),
+            Err(Error::InsufficientFunds)
+        ));
+        // no change script is revealed if the contribution fails
+        assert_eq!(
+            wallet
+                .database
+                .borrow()
+                .get_last_index(ScriptType::Internal)
+                .unwrap(),
+            None
+        );
+    }
 
-        let extracted = signed_psbt.extract_tx();
-        assert_eq!(extracted.input[0].witness.len(), 2);
+    #[test]
+    fn test_contribute_to_psbt_dust_output() {
+        let (wallet, _, _) = get_funded_wallet(get_test_wpkh());
+        let addr = Address::from_str("2N1Ffz3WaNzbeLFBb51xyFMHYSEUXcbiSoX").unwrap();
+
+        assert!(matches!(
+            wallet.contribute_to_psbt(
+                PSBT::from_unsigned_tx(Transaction {
+                    version: 2,
+                    lock_time: 0,
+                    input: vec![],
+                    output: vec![],
+                })
+                .unwrap(),
+                vec![(addr.script_pubkey(), 100)],
+                FeeRate::default(),
+                coin_selection::DefaultCoinSelectionAlgorithm::default(),
+            ),
+            Err(Error::OutputBelowDustLimit(0))
+        ));
     }
 
     #[test]
-    fn test_sign_single_wif() {
-        let (wallet, _, _) =
-            get_funded_wallet("wpkh(cVpPVruEDdmutPzisEsYvtST1usBR3ntr8pXSyt6D2YYqXRyPcFW)");
-        let addr = wallet.get_new_address().unwrap();
-        let (psbt, _) = wallet
-            .create_tx(
-                TxBuilder::new()
-                    .set_single_recipient(addr.script_pubkey())
-                    .drain_wallet(),
-            )
+    fn test_create_tx_add_foreign_utxo() {
+        let (wallet, _, _) = get_funded_wallet(get_test_wpkh());
+        let (foreign_wallet, _, _) =
+            get_funded_wallet("wpkh(cRjo6jqfVNP33HhSS76UhXETZsGTZYx8FMFvR9kpbtCSV1PmdZdu)");
+        foreign_wallet.get_new_address().unwrap();
+
+        let utxo = foreign_wallet.list_unspent().unwrap().remove(0);
+        let psbt_input = bitcoin::util::psbt::Input {
+            witness_utxo: Some(utxo.txout.clone()),
+            ..Default::default()
+        };
+        let satisfaction_weight = foreign_wallet
+            .get_descriptor_for_script_type(ScriptType::External)
+            .unwrap()
+            .0
+            .max_satisfaction_weight(descriptor_to_pk_ctx(&foreign_wallet.secp))
             .unwrap();
 
-        let (signed_psbt, finalized) = wallet.sign(psbt, None).unwrap();
-        assert_eq!(finalized, true);
+        let addr = Address::from_str("2N1Ffz3WaNzbeLFBb51xyFMHYSEUXcbiSoX").unwrap();
+        let (psbt, details) =
+            wallet
+                .create_tx(
+                    TxBuilder::with_recipients(vec![(addr.script_pubkey(), 60_000)])
+                        .add_foreign_utxo(utxo.outpoint, psbt_input.clone(), satisfaction_weight),
+                )
+                .unwrap();
 
-        let extracted = signed_psbt.extract_tx();
-        assert_eq!(extracted.input[0].witness.len(), 2);
+        assert_eq!(psbt.global.unsigned_tx.input.len(), 2);
+        let foreign_index = psbt
+            .global
+            .unsigned_tx
+            .input
+            .iter()
+            .position(|txin| txin.previous_output == utxo.outpoint)
+            .unwrap();
+        assert_eq!(psbt.inputs[foreign_index], psbt_input);
+        assert_eq!(details.sent, 50_000);
+        assert_eq!(
+            details.sent + utxo.txout.value - details.fees,
+            psbt.global
+                .unsigned_tx
+                .output
+                .iter()
+                .map(|txout| txout.value)
+                .sum::<u64>()
+        );
+        assert_fee_rate!(psbt.clone().extract_tx(), details.fees, FeeRate::default(), @add_signature);
+
+        // the foreign input has to be signed by its owner
+        let (psbt, finalized) = wallet.sign(psbt, SignOptions::default()).unwrap();
+        assert!(!finalized);
+        assert_eq!(psbt.inputs[foreign_index].final_script_witness, None);
+
+        let (_, finalized) = foreign_wallet.sign(psbt, SignOptions::default()).unwrap();
+        assert!(finalized);
     }
 
     #[test]
-    fn test_sign_single_xprv_no_hd_keypaths() {
-        let (wallet, _, _) = get_funded_wallet("wpkh(tprv8ZgxMBicQKsPd3EupYiPRhaMooHKUHJxNsTfYuScep13go8QFfHdtkG9nRkFGb7busX4isf6X9dURGCoKgitaApQ6MupRhZMcELAxTBRJgS/*)");
-        let addr = wallet.get_new_address().unwrap();
-        let (mut psbt, _) = wallet
+    fn test_create_tx_add_foreign_utxo_non_witness_utxo() {
+        let (wallet, _, _) = get_funded_wallet(get_test_wpkh());
+        let (foreign_wallet, _, foreign_txid) =
+            get_funded_wallet("wpkh(cRjo6jqfVNP33HhSS76UhXETZsGTZYx8FMFvR9kpbtCSV1PmdZdu)");
+        let foreign_tx = foreign_wallet
+            .database
+            .borrow()
+            .get_raw_tx(&foreign_txid)
+            .unwrap()
+            .unwrap();
+        let outpoint = OutPoint {
+            txid: foreign_txid,
+            vout: 0,
+        };
+        let psbt_input = bitcoin::util::psbt::Input {
+            non_witness_utxo: Some(foreign_tx),
+            ..Default::default()
+        };
+
+        let addr = Address::from_str("2N1Ffz3WaNzbeLFBb51xyFMHYSEUXcbiSoX").unwrap();
+        let (psbt, details) = wallet
             .create_tx(
-                TxBuilder::new()
-                    .set_single_recipient(addr.script_pubkey())
-                    .drain_wallet(),
+                TxBuilder::with_recipients(vec![(addr.script_pubkey(), 60_000)])
+                    .add_foreign_utxo(outpoint, psbt_input, 108),
             )
             .unwrap();
 
-        psbt.inputs[0].hd_keypaths.clear();
-        assert_eq!(psbt.inputs[0].hd_keypaths.len(), 0);
+        assert_eq!(psbt.global.unsigned_tx.input.len(), 2);
+        assert_eq!(details.sent, 50_000);
+    }
 
-        let (signed_psbt, finalized) = wallet.sign(psbt, None).unwrap();
-        assert_eq!(finalized, true);
+    #[test]
+    fn test_create_tx_add_foreign_utxo_invalid() {
+        let (wallet, _, txid) = get_funded_wallet(get_test_wpkh());
+        let addr = Address::from_str("2N1Ffz3WaNzbeLFBb51xyFMHYSEUXcbiSoX").unwrap();
+        let outpoint = OutPoint { txid, vout: 42 };
+
+        // no utxo data
+        let result = wallet.create_tx(
+            TxBuilder::with_recipients(vec![(addr.script_pubkey(), 25_000)]).add_foreign_utxo(
+                outpoint,
+                Default::default(),
+                108,
+            ),
+        );
+        assert!(matches!(result, Err(Error::InvalidForeignUtxo(o)) if o == outpoint));
 
-        let extracted = signed_psbt.extract_tx();
-        assert_eq!(extracted.input[0].witness.len(), 2);
+        // the previous transaction doesn't have enough outputs
+        let prev_tx = wallet.database.borrow().get_raw_tx(&txid).unwrap().unwrap();
+        let psbt_input = bitcoin::util::psbt::Input {
+            non_witness_utxo: Some(prev_tx),
+            ..Default::default()
+        };
+        let result = wallet.create_tx(
+            TxBuilder::with_recipients(vec![(addr.script_pubkey(), 25_000)]).add_foreign_utxo(
+                outpoint,
+                psbt_input.clone(),
+                108,
+            ),
+        );
+        assert!(matches!(result, Err(Error::InvalidForeignUtxo(o)) if o == outpoint));
+
+        // the previous transaction doesn't match the outpoint, even if the witness_utxo is set
+        let outpoint = OutPoint {
+            txid: bitcoin::Txid::default(),
+            vout: 0,
+        };
+        let psbt_input = bitcoin::util::psbt::Input {
+            witness_utxo: Some(psbt_input.non_witness_utxo.as_ref().unwrap().output[0].clone()),
+            ..psbt_input
+        };
+        let result = wallet.create_tx(
+            TxBuilder::with_recipients(vec![(addr.script_pubkey(), 25_000)]).add_foreign_utxo(
+                outpoint,
+                psbt_input.clone(),
+                108,
+            ),
+        );
+        assert!(matches!(result, Err(Error::InvalidForeignUtxo(o)) if o == outpoint));
+
+        // the witness_utxo doesn't match the output of the previous transaction
+        let outpoint = OutPoint { txid, vout: 0 };
+        let mut psbt_input = psbt_input;
+        psbt_input.witness_utxo.as_mut().unwrap().value += 1;
+        let result = wallet.create_tx(
+            TxBuilder::with_recipients(vec![(addr.script_pubkey(), 25_000)])
+                .add_foreign_utxo(outpoint, psbt_input, 108),
+        );
+        assert!(matches!(result, Err(Error::InvalidForeignUtxo(o)) if o == outpoint));
     }
 
     #[test]
-    fn test_include_output_redeem_witness_script() {
-        let (wallet, _, _) = get_funded_wallet("sh(wsh(multi(1,cVpPVruEDdmutPzisEsYvtST1usBR3ntr8pXSyt6D2YYqXRyPcFW,cRjo6jqfVNP33HhSS76UhXETZsGTZYx8FMFvR9kpbtCSV1PmdZdu)))");
+    fn test_create_tx_add_foreign_utxo_no_duplicates() {
+        let (wallet, _, _) = get_funded_wallet(get_test_wpkh());
+        let (foreign_wallet, _, _) =
+            get_funded_wallet("wpkh(cRjo6jqfVNP33HhSS76UhXETZsGTZYx8FMFvR9kpbtCSV1PmdZdu)");
         let addr = Address::from_str("2N1Ffz3WaNzbeLFBb51xyFMHYSEUXcbiSoX").unwrap();
-        let (psbt, _) = wallet
+
+        // the same foreign utxo added twice
+        let utxo = foreign_wallet.list_unspent().unwrap().remove(0);
+        let psbt_input = bitcoin::util::psbt::Input {
+            witness_utxo: Some(utxo.txout.clone()),
+            ..Default::default()
+        };
+        let (psbt, details) = wallet
             .create_tx(
-                TxBuilder::with_recipients(vec![(addr.script_pubkey(), 45_000)])
-                    .include_output_redeem_witness_script(),
+                TxBuilder::with_recipients(vec![(addr.script_pubkey(), 25_000)])
+                    .add_foreign_utxo(utxo.outpoint, psbt_input.clone(), 108)
+                    .add_foreign_utxo(utxo.outpoint, psbt_input, 108)
+                    .manually_selected_only()
+                    .sighash(bitcoin::SigHashType::AllPlusAnyoneCanPay),
             )
             .unwrap();
 
-        // p2sh-p2wsh transaction should contain both witness and redeem scripts
-        assert!(psbt
-            .outputs
-            .iter()
-            .any(|output| output.redeem_script.is_some() && output.witness_script.is_some()));
+        assert_eq!(psbt.global.unsigned_tx.input.len(), 1);
+        assert_eq!(
+            psbt.global.unsigned_tx.input[0].previous_output,
+            utxo.outpoint
+        );
+        assert_eq!(
+            psbt.inputs[0].sighash_type,
+            Some(bitcoin::SigHashType::AllPlusAnyoneCanPay)
+        );
+        assert_eq!(details.sent, 0);
     }
 
     #[test]
-    fn test_signing_only_one_of_multiple_inputs() {
+    fn test_create_tx_add_foreign_utxo_is_ours() {
         let (wallet, _, _) = get_funded_wallet(get_test_wpkh());
         let addr = Address::from_str("2N1Ffz3WaNzbeLFBb51xyFMHYSEUXcbiSoX").unwrap();
-        let (mut psbt, _) = wallet
-            .create_tx(
-                TxBuilder::with_recipients(vec![(addr.script_pubkey(), 45_000)])
-                    .include_output_redeem_witness_script(),
-            )
+
+        let utxo = wallet.list_unspent().unwrap().remove(0);
+        let psbt_input = bitcoin::util::psbt::Input {
+            witness_utxo: Some(utxo.txout.clone()),
+            ..Default::default()
+        };
+        let result = wallet.create_tx(
+            TxBuilder::with_recipients(vec![(addr.script_pubkey(), 25_000)])
+                .add_utxo(utxo.outpoint)
+                .add_foreign_utxo(utxo.outpoint, psbt_input, 108),
+        );
+        assert!(matches!(result, Err(Error::InvalidForeignUtxo(o)) if o == utxo.outpoint));
+    }
+
+    /// Blockchain that funds the first external script of the wallet being set up
+    #[cfg(not(feature = "async-interface"))]
+    struct SweepBlockchain(Option<u64>);
+
+    #[cfg(not(feature = "async-interface"))]
+    impl Blockchain for SweepBlockchain {
+        fn get_capabilities(&self) -> std::collections::HashSet<crate::blockchain::Capability> {
+            Default::default()
+        }
+
+        fn setup<D: BatchDatabase, P: 'static + Progress>(
+            &self,
+            _stop_gap: Option<usize>,
+            database: &mut D,
+            _progress_update: P,
+        ) -> Result<(), Error> {
+            let value = match self.0 {
+                Some(value) => value,
+                None => return Ok(()),
+            };
+            let script_pubkey = database
+                .get_script_pubkey_from_path(ScriptType::External, 0)?
+                .unwrap();
+            let tx = Transaction {
+                version: 1,
+                lock_time: 0,
+                input: vec![],
+                output: vec![TxOut {
+                    value,
+                    script_pubkey,
+                }],
+            };
+            database.set_tx(&TransactionDetails {
+                transaction: Some(tx.clone()),
+                txid: tx.txid(),
+                timestamp: 0,
+                received: value,
+                sent: 0,
+                fees: 0,
+                height: Some(1),
+            })?;
+            database.set_utxo(&UTXO {
+                outpoint: OutPoint::new(tx.txid(), 0),
+                txout: tx.output[0].clone(),
+                script_type: ScriptType::External,
+            })?;
+
+            Ok(())
+        }
+
+        fn get_tx(&self, _txid: &Txid) -> Result<Option<Transaction>, Error> {
+            Ok(None)
+        }
+
+        fn broadcast(&self, _tx: &Transaction) -> Result<(), Error> {
+            Ok(())
+        }
+
+        fn get_height(&self) -> Result<u32, Error> {
+            Ok(100)
+        }
+
+        fn estimate_fee(&self, _target: usize) -> Result<FeeRate, Error> {
+            Ok(FeeRate::default())
+        }
+    }
+
+    #[test]
+    #[cfg(not(feature = "async-interface"))]
+    fn test_sweep_private_key() {
+        let wallet = Wallet::new(
+            "wpkh(tpubEBr4i6yk5nf5DAaJpsi9N2pPYBeJ7fZ5Z9rmN4977iYLCGco1VyjB9tvvuvYtfZzjD5A8igzgw3HeWeeKFmanHYqksqZXYXGsw5zjnj7KM9/*)",
+            None,
+            Network::Regtest,
+            MemoryDatabase::new(),
+            SweepBlockchain(Some(50_000)),
+        )
+        .unwrap();
+        let key =
+            PrivateKey::from_wif("cVpPVruEDdmutPzisEsYvtST1usBR3ntr8pXSyt6D2YYqXRyPcFW").unwrap();
+
+        let (psbt, details) = wallet
+            .sweep_private_key(key, FeeRate::from_sat_per_vb(1.0))
             .unwrap();
 
-        // add another input to the psbt that is at least passable.
-        let mut dud_input = bitcoin::util::psbt::Input::default();
-        dud_input.witness_utxo = Some(TxOut {
-            value: 100_000,
-            script_pubkey: miniscript::Descriptor::<bitcoin::PublicKey>::from_str(
-                "wpkh(025476c2e83188368da1ff3e292e7acafcdb3566bb0ad253f62fc70f07aeee6357)",
+        assert_eq!(psbt.global.unsigned_tx.output.len(), 1);
+        assert_eq!(
+            psbt.global.unsigned_tx.output[0].script_pubkey,
+            wallet.peek_address(0).unwrap().script_pubkey()
+        );
+        assert_eq!(details.sent, 50_000);
+        assert_eq!(details.received, 0);
+        assert_eq!(
+            psbt.global.unsigned_tx.output[0].value,
+            details.sent - details.fees
+        );
+        assert_eq!(
+            wallet
+                .database
+                .borrow()
+                .get_last_index(ScriptType::External)
+                .unwrap(),
+            Some(0)
+        );
+    }
+
+    #[test]
+    #[cfg(not(feature = "async-interface"))]
+    fn test_sweep_insufficient_funds() {
+        let wallet = Wallet::new(
+            "wpkh(tpubEBr4i6yk5nf5DAaJpsi9N2pPYBeJ7fZ5Z9rmN4977iYLCGco1VyjB9tvvuvYtfZzjD5A8igzgw3HeWeeKFmanHYqksqZXYXGsw5zjnj7KM9/*)",
+            None,
+            Network::Regtest,
+            MemoryDatabase::new(),
+            SweepBlockchain(None),
+        )
+        .unwrap();
+        let key =
+            PrivateKey::from_wif("cVpPVruEDdmutPzisEsYvtST1usBR3ntr8pXSyt6D2YYqXRyPcFW").unwrap();
+
+        assert!(matches!(
+            wallet.sweep_private_key(key, FeeRate::from_sat_per_vb(1.0)),
+            Err(Error::InsufficientFunds)
+        ));
+        assert_eq!(
+            wallet
+                .database
+                .borrow()
+                .get_last_index(ScriptType::External)
+                .unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn test_new_offline_with_birthday() {
+        let new_wallet = |database| -> OfflineWallet<MemoryDatabase> {
+            Wallet::new_offline_with_birthday(
+                get_test_wpkh(),
+                None,
+                Network::Regtest,
+                database,
+                WalletBirthday::Height(200),
             )
             .unwrap()
-            .script_pubkey(miniscript::NullCtx),
-        });
-        psbt.inputs.push(dud_input);
-        psbt.global.unsigned_tx.input.push(bitcoin::TxIn::default());
-        let (psbt, is_final) = wallet.sign(psbt, None).unwrap();
-        assert!(
-            !is_final,
-            "shouldn't be final since we can't sign one of the inputs"
+        };
+
+        let wallet = new_wallet(MemoryDatabase::new());
+        assert_eq!(
+            wallet.get_birthday().unwrap(),
+            Some(WalletBirthday::Height(200))
         );
-        assert!(
-            psbt.inputs[0].final_script_witness.is_some(),
-            "should finalized input it signed"
-        )
+
+        // an existing birthday is kept
+        wallet.set_birthday(WalletBirthday::Height(100)).unwrap();
+        let wallet = new_wallet(wallet.database.into_inner());
+        assert_eq!(
+            wallet.get_birthday().unwrap(),
+            Some(WalletBirthday::Height(100))
+        );
+
+        // the transactions of a database that isn't empty may be older than the birthday
+        let (wallet, _, _) = get_funded_wallet(get_test_wpkh());
+        let wallet = new_wallet(wallet.database.into_inner());
+        assert_eq!(wallet.get_birthday().unwrap(), None);
     }
 }
@@ -0,0 +1,443 @@
+// Magical Bitcoin Library
+// Written in 2020 by
+//     Alekos Filini <alekos.filini@gmail.com>
+//
+// Copyright (c) 2020 Magical Bitcoin
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+//! Payjoin
+//!
+//! This module contains the types used to send and receive payjoin transactions, as described
+//! in [BIP78](https://github.com/bitcoin/bips/blob/master/bip-0078.mediawiki).
+//!
+//! The sender creates, signs and finalizes a normal transaction (the "original PSBT") paying the
+//! receiver, and posts it to the receiver's payjoin endpoint, usually found in the `pj` parameter
+//! of a [`PaymentURI`](super::bip21::PaymentURI). The receiver answers with a "proposal PSBT"
+//! that also spends some of its coins, which is validated by
+//! [`Wallet::process_payjoin_proposal`](super::Wallet::process_payjoin_proposal) and then signed
+//! again by the sender.
+//!
+//! The library doesn't include an HTTP client: the request is made through a
+//! [`PayjoinTransport`], which can be implemented with any client. Similarly, receivers handle
+//! the requests with their own HTTP server and call
+//! [`Wallet::process_payjoin_request`](super::Wallet::process_payjoin_request) to build the
+//! proposal.
+//!
+//! ## Examples
+//!
+//! ### Send a payjoin
+//!
+//! ```no_run
+//! # use bitcoin::*;
+//! # use bitcoin::util::psbt::PartiallySignedTransaction as PSBT;
+//! # use bdk::database::*;
+//! # use bdk::signer::SignOptions;
+//! # use bdk::wallet::payjoin::*;
+//! # use bdk::*;
+//! # struct HttpClient;
+//! # impl HttpClient {
+//! #     fn post_base64_psbt(&self, _url: &str, psbt: &PSBT) -> Result<PSBT, bdk::Error> {
+//! #         Ok(psbt.clone())
+//! #     }
+//! # }
+//! struct HttpTransport {
+//!     client: HttpClient,
+//! }
+//!
+//! impl PayjoinTransport for HttpTransport {
+//!     fn post(&self, url: &str, original: &PSBT) -> Result<PSBT, bdk::Error> {
+//!         // base64-encode `original`, POST it to `url` as `text/plain` and decode the response
+//!         self.client.post_base64_psbt(url, original)
+//!     }
+//! }
+//!
+//! let transport = HttpTransport { client: HttpClient };
+//!
+//! # let descriptor = "wpkh(tpubD6NzVbkrYhZ4Xferm7Pz4VnjdcDPFyjVu5K4iZXQ4pVN8Cks4pHVowTBXBKRhX64pkRyJZJN5xAKj4UDNnLPb5p2sSKXhewoYx5GbTdUFWq/*)";
+//! # let wallet: OfflineWallet<_> = Wallet::new_offline(descriptor, None, Network::Testnet, MemoryDatabase::default())?;
+//! # let (original, _) = wallet.create_tx(TxBuilder::new())?;
+//! let options = PayjoinOptions {
+//!     max_additional_fee_contribution: 1_000,
+//!     ..Default::default()
+//! };
+//! let (psbt, finalized) = wallet.send_payjoin(
+//!     original,
+//!     "https://example.com/pj",
+//!     &options,
+//!     &transport,
+//!     SignOptions::default(),
+//! )?;
+//! # Ok::<(), bdk::Error>(())
+//! ```
+//!
+//! ### Receive a payjoin
+//!
+//! ```no_run
+//! # use std::str::FromStr;
+//! # use bitcoin::util::psbt::PartiallySignedTransaction as PSBT;
+//! # use bdk::signer::SignOptions;
+//! # use bdk::wallet::coin_selection::DefaultCoinSelectionAlgorithm;
+//! # use bdk::wallet::payjoin::*;
+//! # use bdk::*;
+//! // called by the HTTP server with the query string and the decoded body of the request
+//! fn handle_payjoin_request<D: bdk::database::BatchDatabase>(
+//!     wallet: &OfflineWallet<D>,
+//!     query: &str,
+//!     original: PSBT,
+//! ) -> Result<PSBT, bdk::Error> {
+//!     let params = PayjoinRequestParams::from_str(query)?;
+//!     wallet.process_payjoin_request(
+//!         original,
+//!         &params,
+//!         DefaultCoinSelectionAlgorithm::default(),
+//!         SignOptions::default(),
+//!     )
+//! }
+//! ```
+
+use std::fmt;
+use std::str::FromStr;
+
+use bitcoin::consensus::encode::{serialize, VarInt};
+use bitcoin::util::psbt::{Input, PartiallySignedTransaction as PSBT};
+use bitcoin::{OutPoint, Script};
+
+use crate::error::Error;
+use crate::types::FeeRate;
+use crate::wallet::bip21::percent_decode;
+use crate::wallet::coin_selection::TXIN_BASE_WEIGHT;
+
+/// Errors that can be thrown while validating a payjoin proposal
+#[derive(Debug, PartialEq)]
+pub enum PayjoinError {
+    /// The PSBT doesn't have exactly one input and one output for each input and output of its
+    /// unsigned transaction
+    InvalidPsbt,
+    /// The original PSBT must be finalized, so that the receiver can broadcast it
+    OriginalNotFinalized,
+    /// The input at this index of the original PSBT is missing the `witness_utxo` or
+    /// `non_witness_utxo`
+    MissingUtxo(usize),
+    /// The proposal changed the version of the transaction
+    VersionChanged,
+    /// The proposal changed the nLockTime of the transaction
+    LockTimeChanged,
+    /// One of the original inputs is missing from the proposal
+    MissingOriginalInput(OutPoint),
+    /// The input at this index of the proposal doesn't use the same nSequence as the original
+    /// inputs
+    SequenceChanged(usize),
+    /// The input at this index of the proposal is one of ours, but it still contains signatures
+    /// or final scripts
+    SenderInputNotCleared(usize),
+    /// The input at this index of the proposal was added by the receiver, but it's not finalized
+    ReceiverInputNotFinalized(usize),
+    /// The input at this index of the proposal was added by the receiver, but it doesn't include
+    /// the previous output it spends
+    ReceiverInputMissingUtxo(usize),
+    /// The input at this index of the proposal was added by the receiver, but it spends one of
+    /// our outputs
+    ReceiverInputIsOurs(usize),
+    /// The input at this index of the proposal spends a different type of script than ours
+    MixedInputScripts(usize),
+    /// The output at this index of the original PSBT is missing from the proposal
+    MissingOutput(usize),
+    /// The value of the output at this index of the original PSBT has been decreased
+    OutputValueDecreased(usize),
+    /// The absolute fee of the proposal, in satoshi, is lower than the one of the original
+    /// transaction
+    FeeDecreased(u64),
+    /// The fee contributed by the sender, in satoshi, is higher than allowed
+    FeeContributionTooHigh(u64),
+    /// The fee rate of the proposal is lower than the minimum requested
+    FeeRateTooLow(FeeRate),
+    /// A parameter of the request is invalid or not supported
+    InvalidParameter(String),
+    /// The original PSBT doesn't have any input
+    OriginalWithoutInputs,
+    /// The input at this index of the original PSBT spends one of our outputs
+    OriginalInputIsOurs(usize),
+    /// The original PSBT doesn't pay to any of our addresses
+    NoOutputToUs,
+    /// The wallet doesn't have any UTXO that can be added to the transaction
+    NoUtxoToContribute,
+}
+
+impl fmt::Display for PayjoinError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+impl std::error::Error for PayjoinError {}
+
+/// Parameters of a payjoin request
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct PayjoinOptions {
+    /// Maximum amount, in satoshi, the receiver can take from our change output to pay the fees
+    /// of the inputs it adds
+    pub max_additional_fee_contribution: u64,
+    /// Forbid the receiver from changing the script or decreasing the value of its outputs
+    pub disable_output_substitution: bool,
+    /// Minimum fee rate of the proposal
+    pub min_fee_rate: Option<FeeRate>,
+}
+
+/// Parameters of a payjoin request, as received by the receiver
+///
+/// They can be parsed from the query string of the request with [`FromStr`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct PayjoinRequestParams {
+    /// Version of the protocol
+    pub version: u32,
+    /// Index of the sender's output that can be used to pay for the fees of the receiver's inputs
+    pub additional_fee_output_index: Option<usize>,
+    /// Maximum amount, in satoshi, that can be taken from the sender's output
+    pub max_additional_fee_contribution: u64,
+    /// Whether the receiver is forbidden from changing its own outputs
+    pub disable_output_substitution: bool,
+    /// Minimum fee rate of the proposal
+    pub min_fee_rate: Option<FeeRate>,
+}
+
+impl Default for PayjoinRequestParams {
+    fn default() -> Self {
+        PayjoinRequestParams {
+            version: 1,
+            additional_fee_output_index: None,
+            max_additional_fee_contribution: 0,
+            disable_output_substitution: false,
+            min_fee_rate: None,
+        }
+    }
+}
+
+impl FromStr for PayjoinRequestParams {
+    type Err = PayjoinError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        fn parse<T: FromStr>(key: &str, value: &str) -> Result<T, PayjoinError> {
+            percent_decode(value)
+                .and_then(|value| value.parse().ok())
+                .ok_or_else(|| PayjoinError::InvalidParameter(key.to_string()))
+        }
+
+        let mut params = PayjoinRequestParams::default();
+        for pair in s
+            .trim_start_matches('?')
+            .split('&')
+            .filter(|p| !p.is_empty())
+        {
+            let mut split = pair.splitn(2, '=');
+            let key = split.next().unwrap_or_default();
+            let value = split.next().unwrap_or_default();
+
+            match key {
+                "v" => params.version = parse(key, value)?,
+                "additionalfeeoutputindex" => {
+                    params.additional_fee_output_index = Some(parse(key, value)?)
+                }
+                "maxadditionalfeecontribution" => {
+                    params.max_additional_fee_contribution = parse(key, value)?
+                }
+                "disableoutputsubstitution" => {
+                    params.disable_output_substitution = parse(key, value)?
+                }
+                "minfeerate" => {
+                    params.min_fee_rate = Some(FeeRate::from_sat_per_vb(parse(key, value)?))
+                }
+                // unknown parameters are ignored, as required by the BIP
+                _ => {}
+            }
+        }
+
+        if params.version != 1 {
+            return Err(PayjoinError::InvalidParameter("v".to_string()));
+        }
+
+        Ok(params)
+    }
+}
+
+/// Trait for the transports used to send payjoin requests
+///
+/// Implementations should POST the base64-encoded `original` PSBT as `text/plain` to `url`, which
+/// already contains the BIP78 query parameters, and decode the PSBT returned by the receiver.
+#[maybe_async]
+pub trait PayjoinTransport {
+    /// Send the original PSBT and return the receiver's proposal
+    fn post(&self, url: &str, original: &PSBT) -> Result<PSBT, Error>;
+}
+
+pub(crate) fn request_url(
+    endpoint: &str,
+    options: &PayjoinOptions,
+    fee_output_index: Option<usize>,
+) -> String {
+    let mut url = endpoint.to_string();
+    url.push(if endpoint.contains('?') { '&' } else { '?' });
+    url.push_str("v=1");
+
+    if let Some(index) = fee_output_index {
+        url.push_str(&format!(
+            "&additionalfeeoutputindex={}&maxadditionalfeecontribution={}",
+            index, options.max_additional_fee_contribution
+        ));
+    }
+    if options.disable_output_substitution {
+        url.push_str("&disableoutputsubstitution=true");
+    }
+    if let Some(min_fee_rate) = options.min_fee_rate {
+        url.push_str(&format!("&minfeerate={}", min_fee_rate.as_sat_vb()));
+    }
+
+    url
+}
+
+// The PSBTs exchanged with the other party are indexed like their unsigned transaction, make sure
+// they actually match before looking at them
+pub(crate) fn check_psbt_maps(psbt: &PSBT) -> Result<(), PayjoinError> {
+    let tx = &psbt.global.unsigned_tx;
+    if psbt.inputs.len() != tx.input.len() || psbt.outputs.len() != tx.output.len() {
+        return Err(PayjoinError::InvalidPsbt);
+    }
+
+    Ok(())
+}
+
+pub(crate) fn is_finalized(input: &Input) -> bool {
+    input.final_script_sig.is_some() || input.final_script_witness.is_some()
+}
+
+// Weight of a finalized input, including the data outside of the scripts
+pub(crate) fn finalized_input_weight(input: &Input) -> usize {
+    let script_sig_len = input
+        .final_script_sig
+        .as_ref()
+        .map(Script::len)
+        .unwrap_or(0);
+    let witness_len = input
+        .final_script_witness
+        .as_ref()
+        .map(|witness| {
+            serialize(&VarInt(witness.len() as u64)).len()
+                + witness
+                    .iter()
+                    .map(|item| serialize(item).len())
+                    .sum::<usize>()
+        })
+        .unwrap_or(1);
+
+    TXIN_BASE_WEIGHT
+        + (serialize(&VarInt(script_sig_len as u64)).len() - 1 + script_sig_len) * 4
+        + witness_len
+}
+
+pub(crate) fn same_script_type(a: &Script, b: &Script) -> bool {
+    let script_type = |s: &Script| (s.is_p2pkh(), s.is_p2sh(), s.is_v0_p2wpkh(), s.is_v0_p2wsh());
+    script_type(a) == script_type(b)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_request_url() {
+        let options = PayjoinOptions {
+            max_additional_fee_contribution: 182,
+            disable_output_substitution: true,
+            min_fee_rate: Some(FeeRate::from_sat_per_vb(2.0)),
+        };
+
+        assert_eq!(
+            request_url("https://example.com/pj", &options, Some(1)),
+            "https://example.com/pj?v=1&additionalfeeoutputindex=1&maxadditionalfeecontribution=182&disableoutputsubstitution=true&minfeerate=2"
+        );
+        assert_eq!(
+            request_url(
+                "https://example.com/pj?id=42",
+                &PayjoinOptions::default(),
+                None
+            ),
+            "https://example.com/pj?id=42&v=1"
+        );
+    }
+
+    #[test]
+    fn test_request_params_roundtrip() {
+        let options = PayjoinOptions {
+            max_additional_fee_contribution: 182,
+            disable_output_substitution: true,
+            min_fee_rate: Some(FeeRate::from_sat_per_vb(2.5)),
+        };
+        let url = request_url("https://example.com/pj", &options, Some(1));
+        let query = &url[url.find('?').unwrap() + 1..];
+
+        assert_eq!(
+            PayjoinRequestParams::from_str(query).unwrap(),
+            PayjoinRequestParams {
+                version: 1,
+                additional_fee_output_index: Some(1),
+                max_additional_fee_contribution: 182,
+                disable_output_substitution: true,
+                min_fee_rate: Some(FeeRate::from_sat_per_vb(2.5)),
+            }
+        );
+    }
+
+    #[test]
+    fn test_request_params_errors() {
+        assert_eq!(
+            PayjoinRequestParams::from_str("v=2"),
+            Err(PayjoinError::InvalidParameter("v".to_string()))
+        );
+        assert_eq!(
+            PayjoinRequestParams::from_str("v=1&maxadditionalfeecontribution=abc"),
+            Err(PayjoinError::InvalidParameter(
+                "maxadditionalfeecontribution".to_string()
+            ))
+        );
+        assert_eq!(
+            PayjoinRequestParams::from_str("v=1&foo=bar"),
+            Ok(PayjoinRequestParams::default())
+        );
+        assert_eq!(
+            PayjoinRequestParams::from_str("v=1&minfeerate=2%zz"),
+            Err(PayjoinError::InvalidParameter("minfeerate".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_request_params_percent_encoded() {
+        assert_eq!(
+            PayjoinRequestParams::from_str(
+                "v=%31&minfeerate=2%2E5&disableoutputsubstitution=tru%65"
+            )
+            .unwrap(),
+            PayjoinRequestParams {
+                version: 1,
+                disable_output_substitution: true,
+                min_fee_rate: Some(FeeRate::from_sat_per_vb(2.5)),
+                ..Default::default()
+            }
+        );
+    }
+}
@@ -63,6 +63,7 @@
 //!         &self,
 //!         psbt: &mut psbt::PartiallySignedTransaction,
 //!         input_index: Option<usize>,
+//!         _sign_options: &SignOptions,
 //!         _secp: &Secp256k1<All>,
 //!     ) -> Result<(), SignerError> {
 //!         let input_index = input_index.ok_or(SignerError::InputIndexOutOfRange)?;
@@ -85,7 +86,7 @@
 //!     Fingerprint::from_str("e30f11b8").unwrap().into(),
 //!     SignerOrdering(200),
 //!     Arc::new(custom_signer)
-//! );
+//! )?;
 //!
 //! # Ok::<_, bdk::Error>(())
 //! ```
@@ -150,6 +151,17 @@ pub enum SignerError {
     MissingWitnessScript,
     /// The fingerprint and derivation path are missing from the psbt input
     MissingHDKeypath,
+    /// The output at this index claims to belong to the wallet, but its script doesn't match
+    /// the one derived from the wallet's descriptors
+    InvalidChangeOutput(usize),
+    /// The fee of the transaction, in satoshi, is above the limit set in the [`SignPolicy`]
+    FeeTooHigh(u64),
+    /// The input at this index has neither a `witness_utxo` nor a `non_witness_utxo`, so its value
+    /// is unknown and the fee can't be checked against the [`SignPolicy`]
+    UnknownInputValue(usize),
+    /// The input at this index requests a sighash type that is not allowed by the [`SignPolicy`]
+    /// or the [`SignOptions`]
+    SighashNotAllowed(usize),
 }
 
 impl fmt::Display for SignerError {
@@ -170,10 +182,14 @@ pub trait Signer: fmt::Debug + Send + Sync {
     /// The `input_index` argument is only provided if the wallet doesn't declare to sign the whole
     /// transaction in one go (see [`Signer::sign_whole_tx`]). Otherwise its value is `None` and
     /// can be ignored.
+    ///
+    /// Signers are expected to honor the [`SignOptions`] they receive, in particular the list of
+    /// allowed sighash types.
     fn sign(
         &self,
         psbt: &mut psbt::PartiallySignedTransaction,
         input_index: Option<usize>,
+        sign_options: &SignOptions,
         secp: &SecpCtx,
     ) -> Result<(), SignerError>;
 
@@ -196,6 +212,7 @@ impl Signer for DescriptorXKey<ExtendedPrivKey> {
         &self,
         psbt: &mut psbt::PartiallySignedTransaction,
         input_index: Option<usize>,
+        sign_options: &SignOptions,
         secp: &SecpCtx,
     ) -> Result<(), SignerError> {
         let input_index = input_index.unwrap();
@@ -223,7 +240,9 @@ impl Signer for DescriptorXKey<ExtendedPrivKey> {
         if &derived_key.private_key.public_key(&secp) != public_key {
             Err(SignerError::InvalidKey)
         } else {
-            derived_key.private_key.sign(psbt, Some(input_index), secp)
+            derived_key
+                .private_key
+                .sign(psbt, Some(input_index), sign_options, secp)
         }
     }
 
@@ -241,6 +260,7 @@ impl Signer for PrivateKey {
         &self,
         psbt: &mut psbt::PartiallySignedTransaction,
         input_index: Option<usize>,
+        sign_options: &SignOptions,
         secp: &SecpCtx,
     ) -> Result<(), SignerError> {
         let input_index = input_index.unwrap();
@@ -253,12 +273,24 @@ impl Signer for PrivateKey {
             return Ok(());
         }
 
+        let sighash = psbt.inputs[input_index]
+            .sighash_type
+            .unwrap_or(SigHashType::All);
+        if !sign_options.allowed_sighash.contains(&sighash) {
+            return Err(SignerError::SighashNotAllowed(input_index));
+        }
+
         // FIXME: use the presence of `witness_utxo` as an indication that we should make a bip143
         // sig. Does this make sense? Should we add an extra argument to explicitly swith between
         // these? The original idea was to declare sign() as sign<Ctx: ScriptContex>() and use Ctx,
         // but that violates the rules for trait-objects, so we can't do it.
         let (hash, sighash) = match psbt.inputs[input_index].witness_utxo {
-            Some(_) => Segwitv0::sighash(psbt, input_index)?,
+            Some(_) => {
+                if !sign_options.trust_witness_utxo {
+                    check_non_witness_utxo(psbt, input_index)?;
+                }
+                Segwitv0::sighash(psbt, input_index)?
+            }
             None => Legacy::sighash(psbt, input_index)?,
         };
 
@@ -304,6 +336,102 @@ impl std::default::Default for SignerOrdering {
     }
 }
 
+/// Checks performed by the wallet on a PSBT before signing it
+///
+/// By default no check is enabled. A policy can be set with
+/// [`Wallet::set_sign_policy`](super::Wallet::set_sign_policy), and the wallet will refuse to
+/// sign a PSBT that doesn't satisfy it by returning the corresponding [`SignerError`].
+///
+/// ## Example
+///
+/// ```
+/// # use bdk::signer::SignPolicy;
+/// let policy = SignPolicy {
+///     verify_change: true,
+///     max_fee: Some(100_000),
+///     max_fee_percent: Some(5.0),
+///     only_sighash_all: true,
+/// };
+/// ```
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SignPolicy {
+    /// Refuse to sign if an output claims to be change but doesn't belong to the wallet (see
+    /// [`Wallet::verify_change`](super::Wallet::verify_change))
+    pub verify_change: bool,
+    /// Maximum absolute fee, in satoshi
+    pub max_fee: Option<u64>,
+    /// Maximum fee, as a percentage of the total value of the inputs
+    pub max_fee_percent: Option<f32>,
+    /// Refuse to sign inputs that request a sighash type other than `SIGHASH_ALL`
+    ///
+    /// The wallet checks every input before running any signer, so this also applies to the
+    /// external signers that don't honor the [`SignOptions`]. It takes precedence over
+    /// [`SignOptions::allowed_sighash`]: when set, the other sighash types are also removed from
+    /// the options of every signing session.
+    pub only_sighash_all: bool,
+}
+
+/// Options for a signing session
+///
+/// These are passed to [`Wallet::sign`](super::Wallet::sign) and forwarded to every
+/// [`Signer`]. The default value behaves like the wallet always did: every sighash type is
+/// allowed, the `witness_utxo` of segwit inputs is trusted, every input is passed to the signers
+/// and the PSBT is finalized after signing. The other checks have to be enabled explicitly.
+///
+/// ## Example
+///
+/// ```
+/// # use bitcoin::SigHashType;
+/// # use bdk::signer::SignOptions;
+/// // Only sign with `SIGHASH_ALL`, and refuse to sign segwit inputs unless the full previous
+/// // transaction is provided
+/// let sign_options = SignOptions {
+///     allowed_sighash: vec![SigHashType::All],
+///     trust_witness_utxo: false,
+///     ..Default::default()
+/// };
+/// ```
+#[derive(Debug, Clone, PartialEq)]
+pub struct SignOptions {
+    /// Assume the blockchain has reached a specific height when finalizing the PSBT. If `None`,
+    /// the wallet's current height is used
+    pub assume_height: Option<u32>,
+    /// Whether to sign segwit inputs using only their `witness_utxo`
+    ///
+    /// When set to `false` the `non_witness_utxo` must also be present and match the
+    /// `witness_utxo`, so that a malicious coordinator can't lie about the value of the inputs
+    /// to make the signer pay a higher fee than expected.
+    pub trust_witness_utxo: bool,
+    /// Sighash types the signers are allowed to use. By default all of them are allowed
+    pub allowed_sighash: Vec<SigHashType>,
+    /// Whether to try finalizing the PSBT after signing it
+    pub try_finalize: bool,
+    /// Whether to remove the `partial_sigs` of the inputs that have been finalized
+    pub remove_partial_sigs: bool,
+    /// Whether to sign inputs that can't be matched to any of the wallet's descriptors
+    pub sign_unknown_inputs: bool,
+}
+
+impl Default for SignOptions {
+    fn default() -> Self {
+        SignOptions {
+            assume_height: None,
+            trust_witness_utxo: true,
+            allowed_sighash: vec![
+                SigHashType::All,
+                SigHashType::None,
+                SigHashType::Single,
+                SigHashType::AllPlusAnyoneCanPay,
+                SigHashType::NonePlusAnyoneCanPay,
+                SigHashType::SinglePlusAnyoneCanPay,
+            ],
+            try_finalize: true,
+            remove_partial_sigs: false,
+            sign_unknown_inputs: true,
+        }
+    }
+}
+
 #[derive(Debug, Clone, Hash, Eq, PartialEq)]
 struct SignersContainerKey {
     id: SignerId,
@@ -454,6 +582,29 @@ impl ComputeSighash for Legacy {
     }
 }
 
+// Make sure the `non_witness_utxo` is present, that it's the transaction being spent and that
+// it agrees with the `witness_utxo` used to compute the segwit sighash
+fn check_non_witness_utxo(
+    psbt: &psbt::PartiallySignedTransaction,
+    input_index: usize,
+) -> Result<(), SignerError> {
+    let psbt_input = &psbt.inputs[input_index];
+    let prev_out = psbt.global.unsigned_tx.input[input_index].previous_output;
+
+    let non_witness_utxo = psbt_input
+        .non_witness_utxo
+        .as_ref()
+        .ok_or(SignerError::MissingNonWitnessUtxo)?;
+    if non_witness_utxo.txid() != prev_out.txid {
+        return Err(SignerError::InvalidNonWitnessUtxo);
+    }
+
+    match non_witness_utxo.output.get(prev_out.vout as usize) {
+        Some(txout) if Some(txout) == psbt_input.witness_utxo.as_ref() => Ok(()),
+        _ => Err(SignerError::InvalidNonWitnessUtxo),
+    }
+}
+
 fn p2wpkh_script_code(script: &Script) -> Script {
     ScriptBuilder::new()
         .push_opcode(opcodes::all::OP_DUP)
@@ -630,6 +781,7 @@ mod signers_container_tests {
             &self,
             _psbt: &mut PartiallySignedTransaction,
             _input_index: Option<usize>,
+            _sign_options: &SignOptions,
             _secp: &SecpCtx,
         ) -> Result<(), SignerError> {
             Ok(())
@@ -42,12 +42,17 @@
 //! # let builder: TxBuilder<bdk::database::MemoryDatabase, _, CreateTx> = builder;
 //! ```
 
+use std::cmp::Ordering;
 use std::collections::BTreeMap;
 use std::collections::HashSet;
 use std::default::Default;
+use std::fmt;
 use std::marker::PhantomData;
+use std::sync::Arc;
 
-use bitcoin::{OutPoint, Script, SigHashType, Transaction};
+use bitcoin::util::psbt::Input as PSBTInput;
+use bitcoin::{OutPoint, Script, SigHashType, Transaction, TxIn, TxOut};
+use rand::{Rng, RngCore};
 
 use super::coin_selection::{CoinSelectionAlgorithm, DefaultCoinSelectionAlgorithm};
 use crate::database::Database;
@@ -79,13 +84,16 @@ pub struct TxBuilder<D: Database, Cs: CoinSelectionAlgorithm<D>, Ctx: TxBuilderC
     pub(crate) fee_policy: Option<FeePolicy>,
     pub(crate) internal_policy_path: Option<BTreeMap<String, Vec<usize>>>,
     pub(crate) external_policy_path: Option<BTreeMap<String, Vec<usize>>>,
+    pub(crate) keychain_policy_paths: BTreeMap<u8, BTreeMap<String, Vec<usize>>>,
     pub(crate) utxos: Vec<OutPoint>,
+    pub(crate) foreign_utxos: Vec<(OutPoint, PSBTInput, usize)>,
     pub(crate) unspendable: HashSet<OutPoint>,
     pub(crate) manually_selected_only: bool,
     pub(crate) sighash: Option<SigHashType>,
     pub(crate) ordering: TxOrdering,
     pub(crate) locktime: Option<u32>,
     pub(crate) rbf: Option<u32>,
+    pub(crate) anti_fee_sniping: bool,
     pub(crate) version: Option<Version>,
     pub(crate) change_policy: ChangeSpendPolicy,
     pub(crate) force_non_witness_utxo: bool,
@@ -122,13 +130,16 @@ where
             fee_policy: Default::default(),
             internal_policy_path: Default::default(),
             external_policy_path: Default::default(),
+            keychain_policy_paths: Default::default(),
             utxos: Default::default(),
+            foreign_utxos: Default::default(),
             unspendable: Default::default(),
             manually_selected_only: Default::default(),
             sighash: Default::default(),
             ordering: Default::default(),
             locktime: Default::default(),
             rbf: Default::default(),
+            anti_fee_sniping: Default::default(),
             version: Default::default(),
             change_policy: Default::default(),
             force_non_witness_utxo: Default::default(),
@@ -223,12 +234,14 @@ impl<D: Database, Cs: CoinSelectionAlgorithm<D>, Ctx: TxBuilderContext> TxBuilde
         policy_path: BTreeMap<String, Vec<usize>>,
         script_type: ScriptType,
     ) -> Self {
-        let to_update = match script_type {
-            ScriptType::Internal => &mut self.internal_policy_path,
-            ScriptType::External => &mut self.external_policy_path,
-        };
+        match script_type {
+            ScriptType::Internal => self.internal_policy_path = Some(policy_path),
+            ScriptType::External => self.external_policy_path = Some(policy_path),
+            ScriptType::Keychain(index) => {
+                self.keychain_policy_paths.insert(index, policy_path);
+            }
+        }
 
-        *to_update = Some(policy_path);
         self
     }
 
@@ -386,13 +399,16 @@ impl<D: Database, Cs: CoinSelectionAlgorithm<D>, Ctx: TxBuilderContext> TxBuilde
             fee_policy: self.fee_policy,
             internal_policy_path: self.internal_policy_path,
             external_policy_path: self.external_policy_path,
+            keychain_policy_paths: self.keychain_policy_paths,
             utxos: self.utxos,
+            foreign_utxos: self.foreign_utxos,
             unspendable: self.unspendable,
             manually_selected_only: self.manually_selected_only,
             sighash: self.sighash,
             ordering: self.ordering,
             locktime: self.locktime,
             rbf: self.rbf,
+            anti_fee_sniping: self.anti_fee_sniping,
             version: self.version,
             change_policy: self.change_policy,
             force_non_witness_utxo: self.force_non_witness_utxo,
@@ -466,6 +482,52 @@ impl<D: Database, Cs: CoinSelectionAlgorithm<D>> TxBuilder<D, Cs, CreateTx> {
         self.rbf = Some(nsequence);
         self
     }
+
+    /// Add a utxo that doesn't belong to the wallet to the internal list of utxos that **must**
+    /// be spent
+    ///
+    /// The `psbt_input` must contain the output being spent, either in its `witness_utxo` or in
+    /// its `non_witness_utxo`, and it's copied as-is in the PSBT, so it should also include any
+    /// other data the owner of the utxo needs to sign it. The `satisfaction_weight` is the weight
+    /// of the `scriptSig` and witness that will spend the utxo, and it's used to compute the fees.
+    ///
+    /// The wallet won't sign these inputs: the PSBT will have to be signed by their owners too.
+    /// The [`sighash`](Self::sighash) set on the builder, if any, is applied to them as well.
+    ///
+    /// Adding the same `outpoint` again replaces the previous entry. If the `psbt_input` doesn't
+    /// contain the output being spent, if its `non_witness_utxo` doesn't match the `outpoint`
+    /// or the `witness_utxo`, or if the `outpoint` is one of the wallet's own utxos, which should
+    /// be added with [`add_utxo`](Self::add_utxo) instead,
+    /// [`Wallet::create_tx`](super::Wallet::create_tx) will return an
+    /// [`InvalidForeignUtxo`](crate::Error::InvalidForeignUtxo) error.
+    pub fn add_foreign_utxo(
+        mut self,
+        outpoint: OutPoint,
+        psbt_input: PSBTInput,
+        satisfaction_weight: usize,
+    ) -> Self {
+        self.foreign_utxos
+            .retain(|(other, _, _)| *other != outpoint);
+        self.foreign_utxos
+            .push((outpoint, psbt_input, satisfaction_weight));
+        self
+    }
+
+    /// Set the nLockTime to the current height to discourage fee sniping
+    ///
+    /// This mimics what Bitcoin Core does for its own transactions: the nLockTime is set to the
+    /// latest height known by the wallet, and 10% of the times it's moved back by a random number
+    /// of blocks, up to 99. Unless RBF is enabled the inputs will use an nSequence of
+    /// `0xFFFFFFFE` and, if no other version is requested, the transaction will be version `2`.
+    ///
+    /// The height is cached by the wallet when it's created and refreshed at every
+    /// [`sync`](super::Wallet::sync). If the wallet doesn't know the current height, like in
+    /// offline wallets, this option is ignored. It's also ignored if the nLockTime is set
+    /// explicitly with [`TxBuilder::nlocktime`].
+    pub fn enable_anti_fee_sniping(mut self) -> Self {
+        self.anti_fee_sniping = true;
+        self
+    }
 }
 
 // methods supported only by bump_fee
@@ -489,8 +551,30 @@ impl<D: Database> TxBuilder<D, DefaultCoinSelectionAlgorithm, BumpFee> {
     }
 }
 
+type SortFn<T> = Arc<dyn Fn(&T, &T) -> Ordering + Send + Sync>;
+
+/// Function used to sort the inputs or the outputs of a transaction in [`TxOrdering::Custom`]
+#[derive(Clone)]
+pub struct TxSortFn<T>(pub SortFn<T>);
+
+impl<T> TxSortFn<T> {
+    /// Wrap a comparison function
+    pub fn new<F>(f: F) -> Self
+    where
+        F: Fn(&T, &T) -> Ordering + Send + Sync + 'static,
+    {
+        TxSortFn(Arc::new(f))
+    }
+}
+
+impl<T> fmt::Debug for TxSortFn<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "TxSortFn")
+    }
+}
+
 /// Ordering of the transaction's inputs and outputs
-#[derive(Debug, Ord, PartialOrd, Eq, PartialEq, Hash, Clone, Copy)]
+#[derive(Debug, Clone)]
 pub enum TxOrdering {
     /// Randomized (default)
     Shuffle,
@@ -498,6 +582,33 @@ pub enum TxOrdering {
     Untouched,
     /// BIP69 / Lexicographic
     BIP69Lexicographic,
+    /// Keep the recipients in the order they were added and insert the change output, if any, at
+    /// a random position among them
+    ///
+    /// This is useful to guarantee the index of the recipients' outputs without revealing which
+    /// one is the change.
+    ShuffleChange,
+    /// Sort the inputs and the outputs with the given comparison functions
+    ///
+    /// The sort is stable, so elements that compare as equal keep their original order.
+    ///
+    /// ## Example
+    ///
+    /// ```
+    /// # use bitcoin::{TxIn, TxOut};
+    /// # use bdk::wallet::tx_builder::{TxOrdering, TxSortFn};
+    /// // sort the inputs by outpoint and the outputs from the largest to the smallest
+    /// let ordering = TxOrdering::Custom {
+    ///     input_sort: TxSortFn::new(|a: &TxIn, b: &TxIn| a.previous_output.cmp(&b.previous_output)),
+    ///     output_sort: TxSortFn::new(|a: &TxOut, b: &TxOut| b.value.cmp(&a.value)),
+    /// };
+    /// ```
+    Custom {
+        /// Comparison function for the inputs
+        input_sort: TxSortFn<TxIn>,
+        /// Comparison function for the outputs
+        output_sort: TxSortFn<TxOut>,
+    },
 }
 
 impl Default for TxOrdering {
@@ -506,21 +617,35 @@ impl Default for TxOrdering {
     }
 }
 
+#[cfg(not(test))]
+fn ordering_rng() -> impl RngCore {
+    rand::thread_rng()
+}
+
+#[cfg(test)]
+fn ordering_rng() -> impl RngCore {
+    use rand::SeedableRng;
+
+    rand::rngs::StdRng::seed_from_u64(0)
+}
+
 impl TxOrdering {
+    /// Sort the inputs and outputs of a transaction
+    ///
+    /// The transaction is assumed not to have a change output: use
+    /// [`TxOrdering::sort_tx_with_change`] to specify it.
     pub fn sort_tx(&self, tx: &mut Transaction) {
+        self.sort_tx_with_change(tx, None)
+    }
+
+    /// Sort the inputs and outputs of a transaction, whose change output is at `change_index`
+    pub fn sort_tx_with_change(&self, tx: &mut Transaction, change_index: Option<usize>) {
         match self {
             TxOrdering::Untouched => {}
             TxOrdering::Shuffle => {
                 use rand::seq::SliceRandom;
-                #[cfg(test)]
-                use rand::SeedableRng;
 
-                #[cfg(not(test))]
-                let mut rng = rand::thread_rng();
-                #[cfg(test)]
-                let mut rng = rand::rngs::StdRng::seed_from_u64(0);
-
-                tx.output.shuffle(&mut rng);
+                tx.output.shuffle(&mut ordering_rng());
             }
             TxOrdering::BIP69Lexicographic => {
                 tx.input.sort_unstable_by_key(|txin| {
@@ -529,6 +654,20 @@ impl TxOrdering {
                 tx.output
                     .sort_unstable_by_key(|txout| (txout.value, txout.script_pubkey.clone()));
             }
+            TxOrdering::ShuffleChange => {
+                if let Some(index) = change_index.filter(|&i| i < tx.output.len()) {
+                    let change = tx.output.remove(index);
+                    let position = ordering_rng().gen_range(0, tx.output.len() + 1);
+                    tx.output.insert(position, change);
+                }
+            }
+            TxOrdering::Custom {
+                input_sort,
+                output_sort,
+            } => {
+                tx.input.sort_by(|a, b| (input_sort.0)(a, b));
+                tx.output.sort_by(|a, b| (output_sort.0)(a, b));
+            }
         }
     }
 }
@@ -567,7 +706,7 @@ impl ChangeSpendPolicy {
         match self {
             ChangeSpendPolicy::ChangeAllowed => true,
             ChangeSpendPolicy::OnlyChange => utxo.script_type == ScriptType::Internal,
-            ChangeSpendPolicy::ChangeForbidden => utxo.script_type == ScriptType::External,
+            ChangeSpendPolicy::ChangeForbidden => utxo.script_type != ScriptType::Internal,
         }
     }
 }
@@ -594,7 +733,7 @@ mod test {
 
     #[test]
     fn test_output_ordering_default_shuffle() {
-        assert_eq!(TxOrdering::default(), TxOrdering::Shuffle);
+        assert!(matches!(TxOrdering::default(), TxOrdering::Shuffle));
     }
 
     #[test]
@@ -654,6 +793,83 @@ mod test {
         assert_eq!(tx.output[2].script_pubkey, From::from(vec![0xAA, 0xEE]));
     }
 
+    #[test]
+    fn test_output_ordering_shuffle_change() {
+        let original_tx = ordering_test_tx!();
+
+        for change_index in 0..original_tx.output.len() {
+            let mut tx = original_tx.clone();
+            TxOrdering::ShuffleChange.sort_tx_with_change(&mut tx, Some(change_index));
+
+            assert_eq!(original_tx.input, tx.input);
+            assert_eq!(original_tx.output.len(), tx.output.len());
+
+            let change = &original_tx.output[change_index];
+            let recipients = original_tx
+                .output
+                .iter()
+                .filter(|txout| *txout != change)
+                .collect::<Vec<_>>();
+            let sorted_recipients = tx
+                .output
+                .iter()
+                .filter(|txout| *txout != change)
+                .collect::<Vec<_>>();
+            assert_eq!(recipients, sorted_recipients);
+        }
+
+        // without a change output nothing is moved
+        let mut tx = original_tx.clone();
+        TxOrdering::ShuffleChange.sort_tx(&mut tx);
+        assert_eq!(original_tx, tx);
+    }
+
+    #[test]
+    fn test_output_ordering_custom() {
+        let original_tx = ordering_test_tx!();
+        let mut tx = original_tx.clone();
+
+        let ordering = TxOrdering::Custom {
+            input_sort: TxSortFn::new(|a: &TxIn, b: &TxIn| {
+                b.previous_output.vout.cmp(&a.previous_output.vout)
+            }),
+            output_sort: TxSortFn::new(|a: &TxOut, b: &TxOut| {
+                a.script_pubkey.len().cmp(&b.script_pubkey.len())
+            }),
+        };
+        ordering.sort_tx(&mut tx);
+
+        assert_eq!(
+            tx.input
+                .iter()
+                .map(|txin| txin.previous_output.vout)
+                .collect::<Vec<_>>(),
+            vec![5, 1, 0]
+        );
+        // stable sort: the two outputs with a one-byte script keep their relative order
+        assert_eq!(tx.output[0], original_tx.output[1]);
+        assert_eq!(tx.output[1], original_tx.output[2]);
+        assert_eq!(tx.output[2], original_tx.output[0]);
+
+        // capturing closures can be used too
+        let min_vout = 1;
+        let ordering = TxOrdering::Custom {
+            input_sort: TxSortFn::new(move |a: &TxIn, b: &TxIn| {
+                (a.previous_output.vout < min_vout).cmp(&(b.previous_output.vout < min_vout))
+            }),
+            output_sort: TxSortFn::new(|a: &TxOut, b: &TxOut| a.value.cmp(&b.value)),
+        };
+        let mut tx = original_tx.clone();
+        ordering.clone().sort_tx(&mut tx);
+        assert_eq!(
+            tx.input
+                .iter()
+                .map(|txin| txin.previous_output.vout)
+                .collect::<Vec<_>>(),
+            vec![1, 5, 0]
+        );
+    }
+
     fn get_test_utxos() -> Vec<UTXO> {
         vec![
             UTXO {
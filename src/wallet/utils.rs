@@ -23,14 +23,21 @@
 // SOFTWARE.
 
 use bitcoin::secp256k1::{All, Secp256k1};
+use bitcoin::util::address::{Address, Payload};
 use bitcoin::util::bip32;
+use bitcoin::Network;
 
 use miniscript::descriptor::DescriptorPublicKeyCtx;
 use miniscript::{MiniscriptKey, Satisfier, ToPublicKey};
 
+use rand::Rng;
+
 // De-facto standard "dust limit" (even though it should change based on the output type)
 const DUST_LIMIT_SATOSHI: u64 = 546;
 
+// Values of nLockTime below this threshold are interpreted as block heights, above as timestamps
+pub(crate) const BLOCKS_TIMELOCK_THRESHOLD: u32 = 500_000_000;
+
 /// Trait to check if a value is below the dust limit
 // we implement this trait to make sure we don't mess up the comparison with off-by-one like a <
 // instead of a <= etc. The constant value for the dust limit is not public on purpose, to
@@ -46,6 +53,19 @@ impl IsDust for u64 {
     }
 }
 
+/// Check whether `address` can be used on `network`
+// base58 addresses share the same prefixes on testnet and regtest, so they are always parsed as
+// testnet addresses
+pub(crate) fn is_network_compatible(address: &Address, network: Network) -> bool {
+    match (address.network, network) {
+        (a, b) if a == b => true,
+        (Network::Testnet, Network::Regtest) => {
+            !matches!(address.payload, Payload::WitnessProgram { .. })
+        }
+        _ => false,
+    }
+}
+
 pub struct After {
     pub current_height: Option<u32>,
     pub assume_height_reached: bool,
@@ -143,8 +163,24 @@ impl<I: Iterator> Iterator for ChunksIterator<I> {
     }
 }
 
+/// Compute the nLockTime used to discourage fee sniping
+// same logic as Bitcoin Core's `DiscourageFeeSniping()`: most of the times use the current height,
+// but occasionally move it back to help transactions that were delayed, like the ones created by
+// offline signers or CoinJoins, blend in
+pub(crate) fn anti_fee_sniping_locktime<R: Rng>(height: u32, rng: &mut R) -> u32 {
+    if rng.gen_range(0, 10) == 0 {
+        height.saturating_sub(rng.gen_range(0, 100))
+    } else {
+        height
+    }
+}
+
 #[cfg(test)]
 mod test {
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    use super::anti_fee_sniping_locktime;
     use crate::types::FeeRate;
 
     #[test]
@@ -164,4 +200,19 @@ mod test {
         let fee = FeeRate::default_min_relay_fee();
         assert!((fee.as_sat_vb() - 1.0).abs() < 0.0001);
     }
+
+    #[test]
+    fn test_anti_fee_sniping_locktime() {
+        let mut rng = StdRng::seed_from_u64(42);
+        let locktimes = (0..1000)
+            .map(|_| anti_fee_sniping_locktime(100_000, &mut rng))
+            .collect::<Vec<_>>();
+
+        assert!(locktimes.iter().all(|&l| l > 100_000 - 100 && l <= 100_000));
+        assert!(locktimes.iter().any(|&l| l < 100_000));
+        assert!(locktimes.iter().filter(|&&l| l == 100_000).count() > 800);
+
+        let mut rng = StdRng::seed_from_u64(42);
+        assert!((0..1000).all(|_| anti_fee_sniping_locktime(10, &mut rng) <= 10));
+    }
 }
@@ -83,6 +83,7 @@ pub fn bdk_blockchain_tests(attr: TokenStream, item: TokenStream) -> TokenStream
                 use #root_ident::descriptor::ExtendedDescriptor;
                 use #root_ident::database::MemoryDatabase;
                 use #root_ident::types::ScriptType;
+                use #root_ident::signer::SignOptions;
                 use #root_ident::{Wallet, TxBuilder, FeeRate};
 
                 use super::*;
@@ -308,7 +309,7 @@ pub fn bdk_blockchain_tests(attr: TokenStream, item: TokenStream) -> TokenStream
                     assert_eq!(wallet.get_balance().unwrap(), 50_000);
 
                     let (psbt, details) = wallet.create_tx(TxBuilder::with_recipients(vec![(node_addr.script_pubkey(), 25_000)])).unwrap();
-                    let (psbt, finalized) = wallet.sign(psbt, None).unwrap();
+                    let (psbt, finalized) = wallet.sign(psbt, SignOptions::default()).unwrap();
                     assert!(finalized, "Cannot finalize transaction");
                     let tx = psbt.extract_tx();
                     println!("{}", bitcoin::consensus::encode::serialize_hex(&tx));
@@ -335,7 +336,7 @@ pub fn bdk_blockchain_tests(attr: TokenStream, item: TokenStream) -> TokenStream
                     assert_eq!(wallet.get_balance().unwrap(), 50_000);
 
                     let (psbt, details) = wallet.create_tx(TxBuilder::with_recipients(vec![(node_addr.script_pubkey(), 25_000)])).unwrap();
-                    let (psbt, finalized) = wallet.sign(psbt, None).unwrap();
+                    let (psbt, finalized) = wallet.sign(psbt, SignOptions::default()).unwrap();
                     assert!(finalized, "Cannot finalize transaction");
                     let sent_txid = wallet.broadcast(psbt.extract_tx()).unwrap();
 
@@ -374,7 +375,7 @@ pub fn bdk_blockchain_tests(attr: TokenStream, item: TokenStream) -> TokenStream
                     let mut total_sent = 0;
                     for _ in 0..5 {
                         let (psbt, details) = wallet.create_tx(TxBuilder::with_recipients(vec![(node_addr.script_pubkey().clone(), 5_000)])).unwrap();
-                        let (psbt, finalized) = wallet.sign(psbt, None).unwrap();
+                        let (psbt, finalized) = wallet.sign(psbt, SignOptions::default()).unwrap();
                         assert!(finalized, "Cannot finalize transaction");
                         wallet.broadcast(psbt.extract_tx()).unwrap();
 
@@ -406,7 +407,7 @@ pub fn bdk_blockchain_tests(attr: TokenStream, item: TokenStream) -> TokenStream
                     assert_eq!(wallet.get_balance().unwrap(), 50_000);
 
                     let (psbt, details) = wallet.create_tx(TxBuilder::with_recipients(vec![(node_addr.script_pubkey().clone(), 5_000)]).enable_rbf()).unwrap();
-                    let (psbt, finalized) = wallet.sign(psbt, None).unwrap();
+                    let (psbt, finalized) = wallet.sign(psbt, SignOptions::default()).unwrap();
                     assert!(finalized, "Cannot finalize transaction");
                     wallet.broadcast(psbt.extract_tx()).unwrap();
                     wallet.sync(noop_progress(), None).unwrap();
@@ -414,7 +415,7 @@ pub fn bdk_blockchain_tests(attr: TokenStream, item: TokenStream) -> TokenStream
                     assert_eq!(wallet.get_balance().unwrap(), details.received);
 
                     let (new_psbt, new_details) = wallet.bump_fee(&details.txid, TxBuilder::new().fee_rate(FeeRate::from_sat_per_vb(2.1))).unwrap();
-                    let (new_psbt, finalized) = wallet.sign(new_psbt, None).unwrap();
+                    let (new_psbt, finalized) = wallet.sign(new_psbt, SignOptions::default()).unwrap();
                     assert!(finalized, "Cannot finalize transaction");
                     wallet.broadcast(new_psbt.extract_tx()).unwrap();
                     wallet.sync(noop_progress(), None).unwrap();
@@ -438,7 +439,7 @@ pub fn bdk_blockchain_tests(attr: TokenStream, item: TokenStream) -> TokenStream
                     assert_eq!(wallet.get_balance().unwrap(), 50_000);
 
                     let (psbt, details) = wallet.create_tx(TxBuilder::with_recipients(vec![(node_addr.script_pubkey().clone(), 49_000)]).enable_rbf()).unwrap();
-                    let (psbt, finalized) = wallet.sign(psbt, None).unwrap();
+                    let (psbt, finalized) = wallet.sign(psbt, SignOptions::default()).unwrap();
                     assert!(finalized, "Cannot finalize transaction");
                     wallet.broadcast(psbt.extract_tx()).unwrap();
                     wallet.sync(noop_progress(), None).unwrap();
@@ -447,7 +448,7 @@ pub fn bdk_blockchain_tests(attr: TokenStream, item: TokenStream) -> TokenStream
 
                     let (new_psbt, new_details) = wallet.bump_fee(&details.txid, TxBuilder::new().fee_rate(FeeRate::from_sat_per_vb(5.0))).unwrap();
 
-                    let (new_psbt, finalized) = wallet.sign(new_psbt, None).unwrap();
+                    let (new_psbt, finalized) = wallet.sign(new_psbt, SignOptions::default()).unwrap();
                     assert!(finalized, "Cannot finalize transaction");
                     wallet.broadcast(new_psbt.extract_tx()).unwrap();
                     wallet.sync(noop_progress(), None).unwrap();
@@ -471,7 +472,7 @@ pub fn bdk_blockchain_tests(attr: TokenStream, item: TokenStream) -> TokenStream
                     assert_eq!(wallet.get_balance().unwrap(), 75_000);
 
                     let (psbt, details) = wallet.create_tx(TxBuilder::with_recipients(vec![(node_addr.script_pubkey().clone(), 49_000)]).enable_rbf()).unwrap();
-                    let (psbt, finalized) = wallet.sign(psbt, None).unwrap();
+                    let (psbt, finalized) = wallet.sign(psbt, SignOptions::default()).unwrap();
                     assert!(finalized, "Cannot finalize transaction");
                     wallet.broadcast(psbt.extract_tx()).unwrap();
                     wallet.sync(noop_progress(), None).unwrap();
@@ -480,7 +481,7 @@ pub fn bdk_blockchain_tests(attr: TokenStream, item: TokenStream) -> TokenStream
 
                     let (new_psbt, new_details) = wallet.bump_fee(&details.txid, TxBuilder::new().fee_rate(FeeRate::from_sat_per_vb(10.0))).unwrap();
 
-                    let (new_psbt, finalized) = wallet.sign(new_psbt, None).unwrap();
+                    let (new_psbt, finalized) = wallet.sign(new_psbt, SignOptions::default()).unwrap();
                     assert!(finalized, "Cannot finalize transaction");
                     wallet.broadcast(new_psbt.extract_tx()).unwrap();
                     wallet.sync(noop_progress(), None).unwrap();
@@ -502,7 +503,7 @@ pub fn bdk_blockchain_tests(attr: TokenStream, item: TokenStream) -> TokenStream
                     assert_eq!(wallet.get_balance().unwrap(), 75_000);
 
                     let (psbt, details) = wallet.create_tx(TxBuilder::with_recipients(vec![(node_addr.script_pubkey().clone(), 49_000)]).enable_rbf()).unwrap();
-                    let (psbt, finalized) = wallet.sign(psbt, None).unwrap();
+                    let (psbt, finalized) = wallet.sign(psbt, SignOptions::default()).unwrap();
                     assert!(finalized, "Cannot finalize transaction");
                     wallet.broadcast(psbt.extract_tx()).unwrap();
                     wallet.sync(noop_progress(), None).unwrap();
@@ -512,7 +513,7 @@ pub fn bdk_blockchain_tests(attr: TokenStream, item: TokenStream) -> TokenStream
                     let (new_psbt, new_details) = wallet.bump_fee(&details.txid, TxBuilder::new().fee_rate(FeeRate::from_sat_per_vb(123.0))).unwrap();
                     println!("{:#?}", new_details);
 
-                    let (new_psbt, finalized) = wallet.sign(new_psbt, None).unwrap();
+                    let (new_psbt, finalized) = wallet.sign(new_psbt, SignOptions::default()).unwrap();
                     assert!(finalized, "Cannot finalize transaction");
                     wallet.broadcast(new_psbt.extract_tx()).unwrap();
                     wallet.sync(noop_progress(), None).unwrap();
@@ -535,6 +536,36 @@ pub fn bdk_blockchain_tests(attr: TokenStream, item: TokenStream) -> TokenStream
                     wallet.sync(noop_progress(), None).unwrap();
                     assert!(wallet.get_balance().unwrap() > 0);
                 }
+
+                #[test]
+                #[serial]
+                fn test_sync_sweep_private_key() {
+                    let (wallet, _, mut test_client) = init_single_sig();
+
+                    let key = bitcoin::PrivateKey::from_wif("cVpPVruEDdmutPzisEsYvtST1usBR3ntr8pXSyt6D2YYqXRyPcFW").unwrap();
+                    let public_key = key.public_key(&bitcoin::secp256k1::Secp256k1::new());
+                    let pkh_descriptors = (format!("pkh({})", public_key), None::<String>);
+                    let wpkh_descriptors = (format!("wpkh({})", public_key), None::<String>);
+
+                    test_client.receive(testutils! {
+                        @tx ( (@external pkh_descriptors, 0) => 20_000, (@external wpkh_descriptors, 0) => 30_000 )
+                    });
+
+                    wallet.sync(noop_progress(), None).unwrap();
+                    assert_eq!(wallet.get_balance().unwrap(), 0);
+
+                    let (psbt, details) = wallet.sweep_private_key(key, FeeRate::from_sat_per_vb(1.0)).unwrap();
+                    assert_eq!(psbt.global.unsigned_tx.input.len(), 2);
+                    assert_eq!(psbt.global.unsigned_tx.output.len(), 1);
+                    assert_eq!(details.sent, 50_000);
+                    assert_eq!(details.received, 0);
+                    let swept = details.sent - details.fees;
+                    assert_eq!(psbt.global.unsigned_tx.output[0].value, swept);
+                    wallet.broadcast(psbt.extract_tx()).unwrap();
+
+                    wallet.sync(noop_progress(), None).unwrap();
+                    assert_eq!(wallet.get_balance().unwrap(), swept);
+                }
             }
 
                         };